@@ -1,11 +1,12 @@
 use std::sync::{Arc, Mutex};
+use std::net::SocketAddr;
 use tauri::Manager;
 use log::{debug, error, info, warn};
 use local_ip_address::local_ip;
 
 use crate::app::{ServerState, ServerOptions, MonitorInfo};
-use crate::core::ScreenCapture;
-use crate::network::WebSocketServer;
+use crate::core::{ScreenCapture, TransferRoot};
+use crate::network::{AccessInfo, CertificateSource, ConsentDecision, OriginPolicy, TlsIdentity, WebSocketServer};
 
 #[tauri::command]
 pub fn greet(name: &str) -> String {
@@ -102,9 +103,42 @@ pub fn start_server(app_handle: tauri::AppHandle, port: Option<u16>, options: Op
         info!("✅ System optimizations applied successfully");
     }
     
+    // Load or generate the certificate when encryption is requested
+    let tls = if state.options.encryption.unwrap_or(false) {
+        let source = tls_certificate_source(&app_handle, &state.options);
+        let identity = TlsIdentity::load(&source).map_err(|e| {
+            error!("Failed to set up TLS: {}", e);
+            format!("Failed to set up TLS: {}", e)
+        })?;
+        Some(Arc::new(identity))
+    } else {
+        None
+    };
+
+    // File transfer is confined to the folder the host chose
+    let transfer_root = match state.options.transfer_root.as_deref().map(str::trim) {
+        Some(path) if !path.is_empty() => {
+            let root = TransferRoot::new(path).map_err(|e| {
+                error!("Failed to share folder for file transfer: {}", e);
+                format!("Failed to share folder for file transfer: {}", e)
+            })?;
+            info!("📁 File transfer enabled in {}", root.path().display());
+            Some(Arc::new(root))
+        }
+        _ => None,
+    };
+
     let app_handle_clone = app_handle.clone();
+    let auth = Arc::clone(&state.auth);
+    let server_tls = tls.clone();
+    let origins = OriginPolicy::new(
+        SocketAddr::from(([0, 0, 0, 0], port)),
+        tls.is_some(),
+        state.options.allowed_origins.iter().flatten(),
+    );
+    let consent = Arc::clone(&state.consent);
     let server = state.runtime.block_on(async move {
-        match WebSocketServer::new(port, app_handle_clone).await {
+        match WebSocketServer::new(port, app_handle_clone, auth, server_tls, origins, consent, transfer_root).await {
             Ok(server) => {
                 info!("Server started successfully");
                 Ok(server)
@@ -119,6 +153,7 @@ pub fn start_server(app_handle: tauri::AppHandle, port: Option<u16>, options: Op
     state.server_handle = Some(server);
     state.port = port;
     state.running = true;
+    state.tls = tls;
 
     // Get local IP address with better detection
     let ip = get_network_ip().unwrap_or_else(|| {
@@ -126,7 +161,7 @@ pub fn start_server(app_handle: tauri::AppHandle, port: Option<u16>, options: Op
         "127.0.0.1".to_string()
     });
 
-    let url = format!("http://{}:{}/kvm", ip, port);
+    let url = format!("{}://{}:{}/kvm", url_scheme(&state), ip, port);
     info!("Server URL: {}", url);
     info!("Server is now accessible from network at: {}", url);
     Ok(url)
@@ -151,6 +186,7 @@ pub fn stop_server(app_handle: tauri::AppHandle) -> Result<(), String> {
     }
 
     state.running = false;
+    state.tls = None;
     Ok(())
 }
 
@@ -203,12 +239,82 @@ pub fn get_server_url(app_handle: tauri::AppHandle) -> Result<String, String> {
         "127.0.0.1".to_string()
     });
 
-    let url = format!("http://{}:{}/kvm", ip, state.port);
+    let url = format!("{}://{}:{}/kvm", url_scheme(&state), ip, state.port);
     debug!("Returning server URL: {}", url);
     info!("KVM server accessible at: {}", url);
     Ok(url)
 }
 
+#[tauri::command]
+pub fn get_access_info(app_handle: tauri::AppHandle) -> Result<AccessInfo, String> {
+    let state = app_handle.state::<Arc<Mutex<ServerState>>>();
+    let state = state.lock().unwrap();
+    Ok(state.auth.access_info())
+}
+
+#[tauri::command]
+pub fn regenerate_access_pin(app_handle: tauri::AppHandle) -> Result<String, String> {
+    let state = app_handle.state::<Arc<Mutex<ServerState>>>();
+    let state = state.lock().unwrap();
+    Ok(state.auth.regenerate_pin())
+}
+
+#[tauri::command]
+pub fn set_access_password(app_handle: tauri::AppHandle, password: Option<String>) -> Result<(), String> {
+    if let Some(ref p) = password {
+        if !p.is_empty() && p.len() < 6 {
+            return Err("Password must be at least 6 characters".to_string());
+        }
+    }
+    
+    let state = app_handle.state::<Arc<Mutex<ServerState>>>();
+    let state = state.lock().unwrap();
+    state.auth.set_password(password.as_deref());
+    Ok(())
+}
+
+#[tauri::command]
+pub fn respond_to_connection_request(app_handle: tauri::AppHandle, id: u64, decision: ConsentDecision, remember: bool) -> Result<(), String> {
+    let state = app_handle.state::<Arc<Mutex<ServerState>>>();
+    let consent = Arc::clone(&state.lock().unwrap().consent);
+    consent.respond(id, decision, remember)
+}
+
+#[tauri::command]
+pub fn forget_trusted_clients(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let state = app_handle.state::<Arc<Mutex<ServerState>>>();
+    let state = state.lock().unwrap();
+    state.consent.forget_remembered();
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_tls_fingerprint(app_handle: tauri::AppHandle) -> Result<Option<String>, String> {
+    let state = app_handle.state::<Arc<Mutex<ServerState>>>();
+    let state = state.lock().unwrap();
+    Ok(state.tls.as_ref().map(|tls| tls.fingerprint().to_string()))
+}
+
+fn tls_certificate_source(app_handle: &tauri::AppHandle, options: &ServerOptions) -> CertificateSource {
+    match (&options.tls_cert_path, &options.tls_key_path) {
+        (Some(cert), Some(key)) => CertificateSource::Pem {
+            cert: cert.into(),
+            key: key.into(),
+        },
+        _ => {
+            let dir = app_handle.path_resolver()
+                .app_data_dir()
+                .unwrap_or_else(|| std::path::PathBuf::from("."))
+                .join("tls");
+            CertificateSource::SelfSigned { dir }
+        }
+    }
+}
+
+fn url_scheme(state: &ServerState) -> &'static str {
+    if state.tls.is_some() { "https" } else { "http" }
+}
+
 #[tauri::command]
 pub fn get_logs() -> Result<(String, String), String> {
     // Simplified log reading - get from default locations
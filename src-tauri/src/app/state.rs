@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
 
-use crate::network::WebSocketServer;
+use crate::network::{AuthManager, ConsentManager, TlsIdentity, WebSocketServer};
 use crate::lib::DEFAULT_SERVER_PORT;
 
 /// Server configuration options
@@ -14,6 +14,10 @@ pub struct ServerOptions {
     pub webrtc: Option<bool>,
     pub vp8: Option<bool>,
     pub monitor: Option<usize>,
+    pub tls_cert_path: Option<String>, // User-supplied PEM pair; self-signed when unset
+    pub tls_key_path: Option<String>,
+    pub allowed_origins: Option<Vec<String>>, // Extra page origins allowed to open /ws
+    pub transfer_root: Option<String>, // Folder clients may transfer files in and out of; disabled when unset
 }
 
 /// Monitor information for the frontend
@@ -35,6 +39,9 @@ pub struct ServerState {
     pub port: u16,
     pub running: bool,
     pub options: ServerOptions,
+    pub auth: Arc<AuthManager>, // Outlives server restarts so the host password sticks
+    pub tls: Option<Arc<TlsIdentity>>,
+    pub consent: Arc<ConsentManager>, // Keeps "always allow" choices across restarts
 }
 
 impl ServerState {
@@ -48,6 +55,9 @@ impl ServerState {
             port: DEFAULT_SERVER_PORT,
             running: false,
             options: ServerOptions::default(),
+            auth: Arc::new(AuthManager::default()),
+            tls: None,
+            consent: Arc::new(ConsentManager::default()),
         }
     }
 }
@@ -1,16 +1,363 @@
+//! Screen capture
+//!
+//! Encoders get their frames from a `CaptureSource`. The xcap backend grabs the
+//! real screen; the synthetic backend draws deterministic test frames so the
+//! whole pipeline can run without a display. Set `CLEVER_KVM_CAPTURE=synthetic`
+//! (optionally `synthetic:<pattern>`) to use it everywhere.
+
 use xcap::Monitor;
-use std::hash::Hash;
 use log::{info, warn};
+use rayon::prelude::*;
 use std::sync::Mutex;
+use thiserror::Error;
+
+/// Selects the capture backend: unset for the real screen, `synthetic[:pattern]` for test frames
+pub const CAPTURE_BACKEND_ENV: &str = "CLEVER_KVM_CAPTURE";
+
+/// Capture errors
+#[derive(Error, Debug)]
+pub enum CaptureError {
+    #[error("Failed to enumerate monitors: {0}")]
+    Enumerate(String),
+    #[error("Monitor not found: {0}")]
+    MonitorNotFound(usize),
+    #[error("Screen capture failed: {0}")]
+    Capture(String),
+}
+
+/// One captured frame as tightly packed RGBA
+pub struct CapturedFrame {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+/// Where screen frames come from
+pub trait CaptureSource: Send {
+    /// Human-readable name for logs
+    fn name(&self) -> &str;
+
+    /// Size of the frames this source produces
+    fn dimensions(&self) -> (u32, u32);
+
+    fn capture_frame(&mut self) -> Result<CapturedFrame, CaptureError>;
+}
+
+/// Real screen capture through xcap
+pub struct XcapSource {
+    monitor: Monitor,
+    name: String,
+}
+
+impl XcapSource {
+    pub fn open(monitor_index: usize) -> Result<Self, CaptureError> {
+        let monitors = Monitor::all().map_err(|e| CaptureError::Enumerate(format!("{:?}", e)))?;
+        let monitor = monitors.into_iter().nth(monitor_index)
+            .ok_or(CaptureError::MonitorNotFound(monitor_index))?;
+        let name = monitor.name().to_string();
+        Ok(Self { monitor, name })
+    }
+}
+
+impl CaptureSource for XcapSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        (self.monitor.width(), self.monitor.height())
+    }
+
+    fn capture_frame(&mut self) -> Result<CapturedFrame, CaptureError> {
+        let image = self.monitor.capture_image()
+            .map_err(|e| CaptureError::Capture(format!("{:?}", e)))?;
+        Ok(CapturedFrame {
+            width: image.width(),
+            height: image.height(),
+            rgba: image.into_raw(),
+        })
+    }
+}
+
+/// What the synthetic source draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntheticPattern {
+    /// Diagonal color bars that shift every frame, so every frame differs
+    MovingBars,
+    /// Lines of glyph-like blocks scrolling upward, like a terminal
+    ScrollingText,
+    /// The same gradient every frame
+    Static,
+}
+
+impl SyntheticPattern {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "moving_bars" => Some(SyntheticPattern::MovingBars),
+            "scrolling_text" => Some(SyntheticPattern::ScrollingText),
+            "static" => Some(SyntheticPattern::Static),
+            _ => None,
+        }
+    }
+}
+
+/// Deterministic test frames: frame N always has the same pixels
+pub struct SyntheticSource {
+    width: u32,
+    height: u32,
+    pattern: SyntheticPattern,
+    frame_number: u64,
+    name: String,
+}
+
+impl SyntheticSource {
+    pub fn new(width: u32, height: u32, pattern: SyntheticPattern) -> Self {
+        Self {
+            width,
+            height,
+            pattern,
+            frame_number: 0,
+            name: format!("synthetic {:?} {}x{}", pattern, width, height),
+        }
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let t = self.frame_number as u32;
+        match self.pattern {
+            SyntheticPattern::MovingBars => {
+                let band = ((x + y + t * 8) / 64) % 6;
+                let palette = [
+                    [230, 60, 60], [240, 200, 40], [60, 200, 80],
+                    [40, 180, 220], [70, 80, 230], [200, 70, 200],
+                ];
+                let [r, g, b] = palette[band as usize];
+                [r, g, b, 255]
+            }
+            SyntheticPattern::ScrollingText => {
+                // 8x16 character cells on a dark background, scrolling 2px per frame
+                let row = (y + t * 2) / 16;
+                let (cx, cy) = (x % 8, (y + t * 2) % 16);
+                let column = x / 8;
+                let line_length = 20 + glyph_hash(row, 0) % 80;
+                let is_space = glyph_hash(row, column) % 6 == 0;
+                let ink = column < line_length && !is_space
+                    && (2..14).contains(&cy) && cx < 7
+                    && glyph_hash(row * 131 + column, cx * 16 + cy) % 3 != 0;
+                if ink { [220, 220, 220, 255] } else { [24, 24, 32, 255] }
+            }
+            SyntheticPattern::Static => {
+                let r = (x * 255 / self.width.max(1)) as u8;
+                let g = (y * 255 / self.height.max(1)) as u8;
+                [r, g, 128, 255]
+            }
+        }
+    }
+}
+
+impl CaptureSource for SyntheticSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
 
-// For delta encoding
-#[derive(Clone)]
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn capture_frame(&mut self) -> Result<CapturedFrame, CaptureError> {
+        let mut rgba = Vec::with_capacity((self.width * self.height * 4) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                rgba.extend_from_slice(&self.pixel(x, y));
+            }
+        }
+        self.frame_number += 1;
+        Ok(CapturedFrame { width: self.width, height: self.height, rgba })
+    }
+}
+
+// Cheap integer hash so glyph shapes are stable across runs
+fn glyph_hash(a: u32, b: u32) -> u32 {
+    let mut h = a.wrapping_mul(0x9E37_79B1) ^ b.wrapping_mul(0x85EB_CA77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0xC2B2_AE3D);
+    h ^ (h >> 13)
+}
+
+/// Synthetic pattern requested through `CLEVER_KVM_CAPTURE`, if any
+fn synthetic_pattern_from_env() -> Option<SyntheticPattern> {
+    let value = std::env::var(CAPTURE_BACKEND_ENV).ok()?;
+    let mut parts = value.splitn(2, ':');
+    if parts.next()? != "synthetic" {
+        return None;
+    }
+    Some(parts.next().and_then(SyntheticPattern::from_name).unwrap_or(SyntheticPattern::MovingBars))
+}
+
+/// Open the configured capture backend for a monitor
+pub fn open_capture_source(monitor_index: usize) -> Result<Box<dyn CaptureSource>, CaptureError> {
+    match synthetic_pattern_from_env() {
+        Some(pattern) if monitor_index == 0 => Ok(Box::new(SyntheticSource::new(1920, 1080, pattern))),
+        Some(_) => Err(CaptureError::MonitorNotFound(monitor_index)),
+        None => Ok(Box::new(XcapSource::open(monitor_index)?)),
+    }
+}
+
+/// Monitors of the configured capture backend; the synthetic backend has one 1080p screen
+pub fn list_monitors() -> Result<Vec<MonitorInfo>, CaptureError> {
+    if synthetic_pattern_from_env().is_some() {
+        return Ok(vec![MonitorInfo {
+            id: "0".to_string(),
+            name: "Synthetic".to_string(),
+            is_primary: true,
+            width: 1920,
+            height: 1080,
+            position_x: 0,
+            position_y: 0,
+            scale_factor: 1.0,
+            rotation: 0,
+        }]);
+    }
+
+    let monitors = Monitor::all().map_err(|e| CaptureError::Enumerate(format!("{:?}", e)))?;
+    Ok(monitors.iter().enumerate().map(|(idx, monitor)| MonitorInfo {
+        id: idx.to_string(),
+        name: monitor.name().to_string(),
+        is_primary: idx == 0, // Assume first monitor is primary
+        width: monitor.width() as usize,
+        height: monitor.height() as usize,
+        position_x: monitor.x(),
+        position_y: monitor.y(),
+        scale_factor: 1.0, // xcap doesn't provide scale factor directly
+        rotation: 0,       // xcap doesn't provide rotation directly
+    }).collect())
+}
+
+/// Edge length of the square tiles frames are compared in
+pub const TILE_SIZE: usize = 64;
+
+/// Area of a frame that changed, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// One tile of the last frame compared
+#[derive(Clone, Default)]
 pub struct ScreenTile {
-    pub data: Vec<u8>,
     pub hash: u64,
     pub changed: bool,
 }
 
+/// Per-tile hashes of the last frame, used to find what changed in the next one
+///
+/// Only hashes are kept, not pixels, so comparing costs one pass over the new
+/// frame. A collision would hide a change until the next keyframe, which is one
+/// reason encoders still send those periodically.
+pub struct TileGrid {
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    tiles: Vec<ScreenTile>,
+    /// Whether `tiles` describe a frame of this size yet
+    primed: bool,
+}
+
+impl TileGrid {
+    pub fn new(width: usize, height: usize, tile_size: usize) -> Self {
+        let mut grid = Self { width: 0, height: 0, tile_size: tile_size.max(1), tiles: Vec::new(), primed: false };
+        grid.resize(width, height);
+        grid
+    }
+
+    /// (tiles across, tiles down, tile size)
+    pub fn dimensions(&self) -> (usize, usize, usize) {
+        (self.width.div_ceil(self.tile_size), self.height.div_ceil(self.tile_size), self.tile_size)
+    }
+
+    /// Forget the last frame, so the next one has nothing to be compared with
+    pub fn invalidate(&mut self) {
+        self.primed = false;
+    }
+
+    /// Hash `rgba` tile by tile and return the areas that changed since the last
+    /// frame, with neighbouring changed tiles in a row merged into one rectangle.
+    /// `None` when there is no earlier frame of the same size to compare with.
+    pub fn diff(&mut self, rgba: &[u8], width: usize, height: usize) -> Option<Vec<DirtyRect>> {
+        if (width, height) != (self.width, self.height) {
+            self.resize(width, height);
+        }
+        if rgba.len() < width * height * 4 {
+            self.primed = false;
+            return None;
+        }
+
+        let (tiles_x, _, tile_size) = self.dimensions();
+        self.tiles.par_iter_mut().enumerate().for_each(|(index, tile)| {
+            let x = (index % tiles_x) * tile_size;
+            let y = (index / tiles_x) * tile_size;
+            let hash = hash_region(rgba, width, x, y, tile_size.min(width - x), tile_size.min(height - y));
+            tile.changed = tile.hash != hash;
+            tile.hash = hash;
+        });
+
+        let compared = std::mem::replace(&mut self.primed, true);
+        compared.then(|| self.changed_rects())
+    }
+
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        let (tiles_x, tiles_y, _) = self.dimensions();
+        self.tiles = vec![ScreenTile { hash: 0, changed: true }; tiles_x * tiles_y];
+        self.primed = false;
+    }
+
+    fn changed_rects(&self) -> Vec<DirtyRect> {
+        let (tiles_x, _, tile_size) = self.dimensions();
+        let mut rects = Vec::new();
+        for (row, tiles) in self.tiles.chunks(tiles_x.max(1)).enumerate() {
+            let y = row * tile_size;
+            let height = tile_size.min(self.height - y);
+            let mut column = 0;
+            while column < tiles.len() {
+                if !tiles[column].changed {
+                    column += 1;
+                    continue;
+                }
+                let start = column;
+                while column < tiles.len() && tiles[column].changed {
+                    column += 1;
+                }
+                let x = start * tile_size;
+                rects.push(DirtyRect {
+                    x: x as u32,
+                    y: y as u32,
+                    width: ((column * tile_size).min(self.width) - x) as u32,
+                    height: height as u32,
+                });
+            }
+        }
+        rects
+    }
+}
+
+// FxHash-style mixing over 8-byte words: fast, and good enough to spot changed pixels
+fn hash_region(rgba: &[u8], stride: usize, x: usize, y: usize, width: usize, height: usize) -> u64 {
+    let mut hash = 0u64;
+    for row in y..y + height {
+        let start = (row * stride + x) * 4;
+        for chunk in rgba[start..start + width * 4].chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash = (hash.rotate_left(5) ^ u64::from_le_bytes(word)).wrapping_mul(0x517C_C1B7_2722_0A95);
+        }
+    }
+    hash
+}
+
 pub struct MonitorInfo {
     pub id: String,
     pub name: String,
@@ -24,12 +371,9 @@ pub struct MonitorInfo {
 }
 
 pub struct ScreenCapture {
-    monitor: Monitor,
+    source: Box<dyn CaptureSource>,
     width: usize,
     height: usize,
-    tile_size: usize,
-    tiles: Vec<ScreenTile>,
-    previous_frame: Option<Vec<u8>>,
     // Track quality based on network conditions
     adaptive_quality: Mutex<u8>,
     // Monitor info
@@ -49,7 +393,7 @@ impl ScreenCapture {
 
     pub fn new(monitor_index: Option<usize>) -> Result<Self, Box<dyn std::error::Error>> {
         // Get all monitors
-        let monitors = Monitor::all().map_err(|e| format!("Failed to get monitors: {:?}", e))?;
+        let monitors = list_monitors()?;
         
         if monitors.is_empty() {
             return Err("No monitors found".into());
@@ -68,40 +412,17 @@ impl ScreenCapture {
             None => 0, // Default to primary monitor
         };
         
-        let monitor = monitors.into_iter().nth(monitor_index)
-            .ok_or_else(|| format!("Monitor index {} not found", monitor_index))?;
-        
-        let width = monitor.width() as usize;
-        let height = monitor.height() as usize;
+        let source = open_capture_source(monitor_index)?;
+        let (width, height) = source.dimensions();
+        let (width, height) = (width as usize, height as usize);
         
         info!("Initialized screen capture for monitor {} ({}x{})", 
-              monitor.name(), width, height);
-        
-        // Define tile size (64x64 is a good balance)
-        let tile_size = 64;
+              source.name(), width, height);
         
-        // Calculate how many tiles we need
-        let tiles_x = (width + tile_size - 1) / tile_size;
-        let tiles_y = (height + tile_size - 1) / tile_size;
-        let total_tiles = tiles_x * tiles_y;
-        
-        // Initialize empty tiles
-        let tiles = vec![
-            ScreenTile {
-                data: Vec::new(),
-                hash: 0,
-                changed: true, // Mark all tiles as changed initially
-            };
-            total_tiles
-        ];
-
         Ok(ScreenCapture {
-            monitor,
+            source,
             width,
             height,
-            tile_size,
-            tiles,
-            previous_frame: None,
             adaptive_quality: Mutex::new(85), // Start with good quality
             monitor_id: monitor_index.to_string(),
             is_primary: monitor_index == 0,
@@ -110,44 +431,17 @@ impl ScreenCapture {
 
     // Get a list of all available monitors
     pub fn get_all_monitors() -> Result<Vec<MonitorInfo>, Box<dyn std::error::Error>> {
-        let monitors = Monitor::all().map_err(|e| format!("Failed to get monitors: {:?}", e))?;
-        let mut monitor_infos = Vec::new();
-        
-        for (idx, monitor) in monitors.iter().enumerate() {
-            let monitor_info = MonitorInfo {
-                id: idx.to_string(),
-                name: monitor.name().to_string(),
-                is_primary: idx == 0, // Assume first monitor is primary
-                width: monitor.width() as usize,
-                height: monitor.height() as usize,
-                position_x: monitor.x(),
-                position_y: monitor.y(),
-                scale_factor: 1.0, // xcap doesn't provide scale factor directly
-                rotation: 0,       // xcap doesn't provide rotation directly
-            };
-            monitor_infos.push(monitor_info);
-        }
-        
-        Ok(monitor_infos)
+        Ok(list_monitors()?)
     }
 
     // Enhanced capture_raw method with scaling support for high DPI screens
     pub fn capture_raw(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // Capture screen using xcap
-        let image = self.monitor.capture_image()
-            .map_err(|e| format!("Failed to capture screen: {:?}", e))?;
-        
-        // Convert to raw RGBA bytes
-        let rgba_buffer = image.into_raw();
-        
-        // Store previous frame for delta encoding
-        self.previous_frame = Some(rgba_buffer.clone());
-        
-        Ok(rgba_buffer)
+        // Capture from the configured backend, already raw RGBA bytes
+        Ok(self.source.capture_frame()?.rgba)
     }
 
     pub fn capture_rgba(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // For xcap, capture_raw already returns RGBA
+        // Every capture source returns RGBA
         self.capture_raw()
     }
 
@@ -155,12 +449,6 @@ impl ScreenCapture {
         (self.width, self.height)
     }
 
-    pub fn tile_dimensions(&self) -> (usize, usize, usize) {
-        let tiles_x = (self.width + self.tile_size - 1) / self.tile_size;
-        let tiles_y = (self.height + self.tile_size - 1) / self.tile_size;
-        (tiles_x, tiles_y, self.tile_size)
-    }
-
     pub fn update_quality(&self, quality: u8) {
         let mut current_quality = self.adaptive_quality.lock().unwrap();
         *current_quality = quality.clamp(1, 100);
@@ -173,4 +461,91 @@ impl ScreenCapture {
     pub fn is_primary(&self) -> bool {
         self.is_primary
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(source: &mut SyntheticSource) -> Vec<u8> {
+        source.capture_frame().expect("synthetic capture cannot fail").rgba
+    }
+
+    #[test]
+    fn synthetic_frames_are_deterministic() {
+        for pattern in [SyntheticPattern::MovingBars, SyntheticPattern::ScrollingText, SyntheticPattern::Static] {
+            let mut a = SyntheticSource::new(64, 48, pattern);
+            let mut b = SyntheticSource::new(64, 48, pattern);
+            for _ in 0..3 {
+                assert_eq!(capture(&mut a), capture(&mut b), "{:?} differs between runs", pattern);
+            }
+        }
+    }
+
+    #[test]
+    fn synthetic_frame_has_requested_size() {
+        let mut source = SyntheticSource::new(64, 48, SyntheticPattern::ScrollingText);
+        let frame = source.capture_frame().unwrap();
+        assert_eq!((frame.width, frame.height), (64, 48));
+        assert_eq!(frame.rgba.len(), 64 * 48 * 4);
+        assert_eq!(source.dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn only_static_pattern_repeats() {
+        let mut moving = SyntheticSource::new(64, 48, SyntheticPattern::MovingBars);
+        assert_ne!(capture(&mut moving), capture(&mut moving));
+
+        let mut text = SyntheticSource::new(64, 48, SyntheticPattern::ScrollingText);
+        assert_ne!(capture(&mut text), capture(&mut text));
+
+        let mut still = SyntheticSource::new(64, 48, SyntheticPattern::Static);
+        assert_eq!(capture(&mut still), capture(&mut still));
+    }
+
+    fn grey(width: usize, height: usize) -> Vec<u8> {
+        vec![128; width * height * 4]
+    }
+
+    #[test]
+    fn unchanged_frame_has_no_dirty_tiles() {
+        let mut grid = TileGrid::new(200, 100, 64);
+        assert_eq!(grid.dimensions(), (4, 2, 64));
+
+        // Nothing to compare the first frame with
+        assert_eq!(grid.diff(&grey(200, 100), 200, 100), None);
+        assert_eq!(grid.diff(&grey(200, 100), 200, 100), Some(vec![]));
+    }
+
+    #[test]
+    fn neighbouring_changed_tiles_merge_into_one_rect() {
+        let mut grid = TileGrid::new(200, 100, 64);
+        let mut frame = grey(200, 100);
+        grid.diff(&frame, 200, 100);
+
+        // Tiles 1 and 2 of the top row, and the clipped bottom-right tile
+        for (x, y) in [(70, 5), (130, 60), (199, 99)] {
+            frame[(y * 200 + x) * 4 + 1] = 0;
+        }
+        assert_eq!(grid.diff(&frame, 200, 100), Some(vec![
+            DirtyRect { x: 64, y: 0, width: 128, height: 64 },
+            DirtyRect { x: 192, y: 64, width: 8, height: 36 },
+        ]));
+        // Compared against the last frame, not the first
+        assert_eq!(grid.diff(&frame, 200, 100), Some(vec![]));
+    }
+
+    #[test]
+    fn resize_and_invalidate_start_over() {
+        let mut grid = TileGrid::new(200, 100, 64);
+        grid.diff(&grey(200, 100), 200, 100);
+
+        assert_eq!(grid.diff(&grey(100, 50), 100, 50), None);
+        assert_eq!(grid.dimensions(), (2, 1, 64));
+        assert_eq!(grid.diff(&grey(100, 50), 100, 50), Some(vec![]));
+
+        grid.invalidate();
+        assert_eq!(grid.diff(&grey(100, 50), 100, 50), None);
+        // A truncated frame is never compared
+        assert_eq!(grid.diff(&grey(10, 10), 100, 50), None);
+    }
+}
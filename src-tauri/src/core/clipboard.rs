@@ -0,0 +1,545 @@
+//! Clipboard synchronisation between the host and controlling clients.
+//!
+//! One `ClipboardHub` per server owns the host clipboard on a dedicated thread: it
+//! polls for changes while any session has sync turned on and applies what clients
+//! send. Each connection gets a `ClipboardSession`, which handles the client's
+//! `clipboard` and `clipboard_sync` messages and forwards host changes once the
+//! client opts in. Only sessions that may control the host take part, so view-only
+//! sessions never see the host clipboard.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::json;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::core::protocol::ServerMessage;
+use crate::core::session::{SessionContext, SessionRole};
+
+/// Largest clipboard text synced in either direction
+pub const MAX_CLIPBOARD_TEXT_BYTES: usize = 1024 * 1024;
+/// Largest PNG synced in either direction
+pub const MAX_CLIPBOARD_IMAGE_BYTES: usize = 8 * 1024 * 1024;
+/// Largest image a client may send, so a small PNG cannot decode into gigabytes
+const MAX_CLIPBOARD_IMAGE_PIXELS: u64 = 4096 * 4096;
+/// How often the host clipboard is checked while sessions are listening
+pub const CLIPBOARD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Text or image on a clipboard
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClipboardContent {
+    Text(String),
+    /// Straight RGBA pixels, row by row
+    Image { width: u32, height: u32, rgba: Vec<u8> },
+}
+
+/// Clipboard messages from the client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClipboardRequest {
+    /// Put this on the host clipboard
+    Clipboard(ClipboardData),
+    /// Start or stop receiving host clipboard changes
+    ClipboardSync { enabled: bool },
+}
+
+impl ClipboardRequest {
+    /// Message `type` tags that belong to clipboard requests
+    pub const TYPES: &'static [&'static str] = &["clipboard", "clipboard_sync"];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClipboardRequest::Clipboard(_) => "clipboard",
+            ClipboardRequest::ClipboardSync { .. } => "clipboard_sync",
+        }
+    }
+}
+
+/// Clipboard content as a client sends it
+#[derive(Debug, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum ClipboardData {
+    Text { text: String },
+    /// Base64 PNG
+    Image { data: String },
+}
+
+impl ClipboardContent {
+    /// Decode what a client sent, enforcing the size limits
+    pub fn from_data(data: ClipboardData) -> Result<Self, String> {
+        match data {
+            ClipboardData::Text { text } => {
+                if text.len() > MAX_CLIPBOARD_TEXT_BYTES {
+                    return Err(format!("Text is {} bytes, the limit is {}", text.len(), MAX_CLIPBOARD_TEXT_BYTES));
+                }
+                Ok(Self::Text(text))
+            }
+            ClipboardData::Image { data } => {
+                // Base64 takes 4 bytes for every 3
+                if data.len() / 4 * 3 > MAX_CLIPBOARD_IMAGE_BYTES {
+                    return Err(format!("Image is over the {} byte limit", MAX_CLIPBOARD_IMAGE_BYTES));
+                }
+                let png = BASE64.decode(data).map_err(|e| format!("Invalid image data: {}", e))?;
+                decode_png(&png)
+            }
+        }
+    }
+
+    /// `clipboard` message for clients; `None` if the content is over the size limits
+    pub fn to_message(&self) -> Option<String> {
+        let message = match self {
+            Self::Text(text) if text.len() > MAX_CLIPBOARD_TEXT_BYTES => {
+                debug!("Host clipboard text is {} bytes, not syncing", text.len());
+                return None;
+            }
+            Self::Text(text) => json!({ "type": "clipboard", "format": "text", "text": text }),
+            Self::Image { width, height, rgba } => {
+                let png = encode_png(*width, *height, rgba)?;
+                if png.len() > MAX_CLIPBOARD_IMAGE_BYTES {
+                    debug!("Host clipboard image is {} bytes as PNG, not syncing", png.len());
+                    return None;
+                }
+                json!({ "type": "clipboard", "format": "image", "data": BASE64.encode(png) })
+            }
+        };
+        Some(message.to_string())
+    }
+
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn encode_png(width: u32, height: u32, rgba: &[u8]) -> Option<Vec<u8>> {
+    let image = image::RgbaImage::from_raw(width, height, rgba.to_vec())?;
+    let mut png = Cursor::new(Vec::new());
+    if let Err(e) = image.write_to(&mut png, image::ImageOutputFormat::Png) {
+        warn!("Failed to encode clipboard image: {}", e);
+        return None;
+    }
+    Some(png.into_inner())
+}
+
+fn decode_png(png: &[u8]) -> Result<ClipboardContent, String> {
+    let (width, height) = image::io::Reader::with_format(Cursor::new(png), image::ImageFormat::Png)
+        .into_dimensions()
+        .map_err(|e| format!("Invalid PNG: {}", e))?;
+    if width as u64 * height as u64 > MAX_CLIPBOARD_IMAGE_PIXELS {
+        return Err(format!("Image is {}x{}, the limit is {} pixels", width, height, MAX_CLIPBOARD_IMAGE_PIXELS));
+    }
+
+    let image = image::load_from_memory_with_format(png, image::ImageFormat::Png)
+        .map_err(|e| format!("Invalid PNG: {}", e))?
+        .to_rgba8();
+    Ok(ClipboardContent::Image { width: image.width(), height: image.height(), rgba: image.into_raw() })
+}
+
+/// Where the host clipboard is read and written
+pub trait ClipboardBackend: Send {
+    /// Current content, `None` when empty or neither text nor an image
+    fn read(&mut self) -> Option<ClipboardContent>;
+    fn write(&mut self, content: &ClipboardContent) -> Result<(), String>;
+}
+
+/// The system clipboard, through arboard
+pub struct ArboardBackend {
+    clipboard: Option<arboard::Clipboard>, // None when no clipboard is available (e.g. no display)
+}
+
+impl ArboardBackend {
+    pub fn new() -> Self {
+        let clipboard = arboard::Clipboard::new()
+            .map_err(|e| warn!("Host clipboard unavailable: {}", e))
+            .ok();
+        Self { clipboard }
+    }
+}
+
+impl ClipboardBackend for ArboardBackend {
+    fn read(&mut self) -> Option<ClipboardContent> {
+        let clipboard = self.clipboard.as_mut()?;
+        if let Ok(text) = clipboard.get_text() {
+            return Some(ClipboardContent::Text(text));
+        }
+        let image = clipboard.get_image().ok()?;
+        Some(ClipboardContent::Image {
+            width: image.width as u32,
+            height: image.height as u32,
+            rgba: image.bytes.into_owned(),
+        })
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<(), String> {
+        let clipboard = self.clipboard.as_mut().ok_or_else(|| "Host clipboard is unavailable".to_string())?;
+        let result = match content {
+            ClipboardContent::Text(text) => clipboard.set_text(text.as_str()),
+            ClipboardContent::Image { width, height, rgba } => clipboard.set_image(arboard::ImageData {
+                width: *width as usize,
+                height: *height as usize,
+                bytes: Cow::Borrowed(rgba.as_slice()),
+            }),
+        };
+        result.map_err(|e| format!("Failed to set host clipboard: {}", e))
+    }
+}
+
+/// In-memory clipboard, shared between clones, for tests
+#[derive(Clone, Default)]
+pub struct MemoryClipboard {
+    content: Arc<Mutex<Option<ClipboardContent>>>,
+}
+
+impl MemoryClipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn content(&self) -> Option<ClipboardContent> {
+        self.content.lock().clone()
+    }
+
+    /// Change the content as another host application would
+    pub fn set(&self, content: ClipboardContent) {
+        *self.content.lock() = Some(content);
+    }
+}
+
+impl ClipboardBackend for MemoryClipboard {
+    fn read(&mut self) -> Option<ClipboardContent> {
+        self.content()
+    }
+
+    fn write(&mut self, content: &ClipboardContent) -> Result<(), String> {
+        self.set(content.clone());
+        Ok(())
+    }
+}
+
+/// A host clipboard change, ready to send to clients
+#[derive(Debug, Clone)]
+pub struct ClipboardUpdate {
+    pub message: Arc<String>,
+    /// Session whose write caused the change; it already has the content
+    pub origin: Option<u64>,
+}
+
+struct ClipboardWrite {
+    content: ClipboardContent,
+    origin: u64,
+    reply_tx: mpsc::Sender<String>,
+}
+
+/// Owner of the host clipboard, shared by every session
+pub struct ClipboardHub {
+    writes: std_mpsc::Sender<ClipboardWrite>,
+    updates: broadcast::Sender<ClipboardUpdate>,
+    current: Arc<Mutex<Option<Arc<String>>>>,
+}
+
+impl ClipboardHub {
+    /// Start the clipboard thread; it exits once the hub is dropped
+    pub fn spawn(mut backend: Box<dyn ClipboardBackend>, poll_interval: Duration) -> Result<Arc<Self>, String> {
+        let (writes, rx) = std_mpsc::channel::<ClipboardWrite>();
+        let (updates, _) = broadcast::channel(4);
+        let current = Arc::new(Mutex::new(None));
+
+        let thread_updates = updates.clone();
+        let thread_current = Arc::clone(&current);
+        thread::Builder::new()
+            .name("kvm-clipboard".to_string())
+            .spawn(move || {
+                let publish = |content: &ClipboardContent, origin: Option<u64>| {
+                    let message = content.to_message().map(Arc::new);
+                    *thread_current.lock() = message.clone();
+                    if let Some(message) = message {
+                        let _ = thread_updates.send(ClipboardUpdate { message, origin });
+                    }
+                };
+
+                // Fingerprint of the content last seen on, or written to, the host clipboard
+                let mut seen = None;
+                loop {
+                    match rx.recv_timeout(poll_interval) {
+                        Ok(write) => match backend.write(&write.content) {
+                            Ok(()) => {
+                                debug!("Host clipboard set by session {}", write.origin);
+                                seen = Some(write.content.fingerprint());
+                                publish(&write.content, Some(write.origin));
+                            }
+                            Err(e) => {
+                                warn!("{}", e);
+                                let _ = write.reply_tx.try_send(clipboard_error(&e));
+                            }
+                        },
+                        Err(RecvTimeoutError::Timeout) => {
+                            // Leave the host clipboard alone while nobody is syncing
+                            if thread_updates.receiver_count() == 0 {
+                                continue;
+                            }
+                            let Some(content) = backend.read() else {
+                                continue;
+                            };
+                            let fingerprint = content.fingerprint();
+                            if seen != Some(fingerprint) {
+                                debug!("Host clipboard changed");
+                                seen = Some(fingerprint);
+                                publish(&content, None);
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                info!("📋 Clipboard thread stopped");
+            })
+            .map_err(|e| format!("Failed to spawn clipboard thread: {}", e))?;
+
+        Ok(Arc::new(Self { writes, updates, current }))
+    }
+
+    /// Host clipboard changes from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ClipboardUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// Message for the host clipboard as last seen, if it is within the size limits
+    pub fn current(&self) -> Option<Arc<String>> {
+        self.current.lock().clone()
+    }
+
+    fn write(&self, content: ClipboardContent, origin: u64, reply_tx: mpsc::Sender<String>) -> Result<(), String> {
+        self.writes
+            .send(ClipboardWrite { content, origin, reply_tx })
+            .map_err(|_| "Clipboard thread is not running".to_string())
+    }
+}
+
+impl fmt::Debug for ClipboardHub {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClipboardHub")
+            .field("listeners", &self.updates.receiver_count())
+            .finish()
+    }
+}
+
+/// One connection's view of the clipboard hub
+pub struct ClipboardSession {
+    id: u64,
+    role: SessionRole,
+    hub: Option<Arc<ClipboardHub>>,
+    reply_tx: mpsc::Sender<String>,
+    // Forwards host changes to the client while sync is on
+    forwarder: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ClipboardSession {
+    pub fn new(session: &SessionContext, reply_tx: mpsc::Sender<String>) -> Self {
+        Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            role: session.role,
+            hub: session.clipboard().cloned(),
+            reply_tx,
+            forwarder: Mutex::new(None),
+        }
+    }
+
+    /// Handle a `clipboard` or `clipboard_sync` message from the client
+    pub fn handle_request(&self, request: ClipboardRequest) {
+        let kind = request.kind();
+        if !self.role.can_control() {
+            warn!("🚫 {} rejected for {} session", kind, self.role.name());
+            let _ = self.reply_tx.try_send(ServerMessage::permission_denied(kind, self.role).to_message());
+            return;
+        }
+
+        let result = match request {
+            ClipboardRequest::Clipboard(data) => self.apply(data),
+            ClipboardRequest::ClipboardSync { enabled } => self.set_enabled(enabled),
+        };
+        if let Err(e) = result {
+            debug!("Rejected {} message: {}", kind, e);
+            let _ = self.reply_tx.try_send(clipboard_error(&e));
+        }
+    }
+
+    fn hub(&self) -> Result<&Arc<ClipboardHub>, String> {
+        self.hub.as_ref().ok_or_else(|| "Clipboard sync is not available".to_string())
+    }
+
+    /// Put the client's clipboard on the host
+    fn apply(&self, data: ClipboardData) -> Result<(), String> {
+        let hub = self.hub()?;
+        let content = ClipboardContent::from_data(data)?;
+        hub.write(content, self.id, self.reply_tx.clone())
+    }
+
+    /// Start or stop sending host clipboard changes to this client
+    fn set_enabled(&self, enabled: bool) -> Result<(), String> {
+        let hub = self.hub()?;
+        let mut forwarder = self.forwarder.lock();
+        if let Some(task) = forwarder.take() {
+            task.abort();
+        }
+        info!("📋 Clipboard sync {} for session {}", if enabled { "on" } else { "off" }, self.id);
+        if !enabled {
+            return Ok(());
+        }
+
+        let mut updates = hub.subscribe();
+        let current = hub.current();
+        let id = self.id;
+        let reply_tx = self.reply_tx.clone();
+        *forwarder = Some(tokio::spawn(async move {
+            if let Some(message) = current {
+                if reply_tx.send(message.to_string()).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                match updates.recv().await {
+                    Ok(update) if update.origin == Some(id) => {}
+                    Ok(update) => {
+                        if reply_tx.send(update.message.to_string()).await.is_err() {
+                            break;
+                        }
+                    }
+                    // Only the newest content matters
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for ClipboardSession {
+    fn drop(&mut self) {
+        if let Some(task) = self.forwarder.lock().take() {
+            task.abort();
+        }
+    }
+}
+
+fn clipboard_error(error: &str) -> String {
+    ServerMessage::ClipboardError { error: error.to_string() }.to_message()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::session::SessionCommand;
+    use tokio::time::timeout;
+
+    const POLL: Duration = Duration::from_millis(10);
+
+    fn session(role: SessionRole, hub: &Arc<ClipboardHub>) -> SessionContext {
+        SessionContext::new(role, broadcast::channel::<SessionCommand>(1).0).with_clipboard(Arc::clone(hub))
+    }
+
+    fn request(message: serde_json::Value) -> ClipboardRequest {
+        serde_json::from_value(message).unwrap()
+    }
+
+    /// Decode a `clipboard` message the way a session would
+    fn parse(message: &str) -> Result<ClipboardContent, String> {
+        match serde_json::from_str::<ClipboardRequest>(message).map_err(|e| e.to_string())? {
+            ClipboardRequest::Clipboard(data) => ClipboardContent::from_data(data),
+            other => Err(format!("Not a clipboard message: {:?}", other)),
+        }
+    }
+
+    async fn next_message(rx: &mut mpsc::Receiver<String>) -> serde_json::Value {
+        let message = timeout(Duration::from_secs(2), rx.recv()).await.expect("no message").expect("channel closed");
+        serde_json::from_str(&message).unwrap()
+    }
+
+    #[test]
+    fn messages_round_trip_text_and_images() {
+        let text = ClipboardContent::Text("héllo".to_string());
+        assert_eq!(parse(&text.to_message().unwrap()), Ok(text));
+
+        let image = ClipboardContent::Image { width: 2, height: 1, rgba: vec![255, 0, 0, 255, 0, 0, 255, 128] };
+        let message = image.to_message().unwrap();
+        assert!(message.contains(r#""format":"image""#));
+        assert_eq!(parse(&message), Ok(image));
+    }
+
+    #[test]
+    fn oversized_and_malformed_content_is_rejected() {
+        let big = "x".repeat(MAX_CLIPBOARD_TEXT_BYTES + 1);
+        assert!(ClipboardContent::from_data(ClipboardData::Text { text: big.clone() }).is_err());
+        assert_eq!(ClipboardContent::Text(big).to_message(), None);
+        assert!(ClipboardContent::from_data(ClipboardData::Image { data: "not base64!".to_string() }).is_err());
+        assert!(parse(r#"{"type":"clipboard","format":"html","text":"<b>"}"#).is_err());
+        assert!(parse(r#"{"type":"clipboard","format":"text"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn host_changes_reach_controllers_that_enabled_sync() {
+        let host = MemoryClipboard::new();
+        let hub = ClipboardHub::spawn(Box::new(host.clone()), POLL).unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let clipboard = ClipboardSession::new(&session(SessionRole::Controller, &hub), tx);
+
+        clipboard.handle_request(request(json!({ "type": "clipboard_sync", "enabled": true })));
+        host.set(ClipboardContent::Text("from host".to_string()));
+
+        let message = next_message(&mut rx).await;
+        assert_eq!(message["type"], "clipboard");
+        assert_eq!(message["text"], "from host");
+        assert!(hub.current().is_some_and(|current| current.contains("from host")));
+    }
+
+    #[tokio::test]
+    async fn client_writes_reach_the_host_and_other_sessions_only() {
+        let host = MemoryClipboard::new();
+        let hub = ClipboardHub::spawn(Box::new(host.clone()), POLL).unwrap();
+        let (writer_tx, mut writer_rx) = mpsc::channel(8);
+        let (other_tx, mut other_rx) = mpsc::channel(8);
+        let writer = ClipboardSession::new(&session(SessionRole::Controller, &hub), writer_tx);
+        let other = ClipboardSession::new(&session(SessionRole::Admin, &hub), other_tx);
+        writer.handle_request(request(json!({ "type": "clipboard_sync", "enabled": true })));
+        other.handle_request(request(json!({ "type": "clipboard_sync", "enabled": true })));
+
+        writer.handle_request(request(json!({ "type": "clipboard", "format": "text", "text": "from client" })));
+
+        assert_eq!(next_message(&mut other_rx).await["text"], "from client");
+        assert_eq!(host.content(), Some(ClipboardContent::Text("from client".to_string())));
+        tokio::time::sleep(POLL * 5).await;
+        assert!(writer_rx.try_recv().is_err(), "writer got its own clipboard back");
+    }
+
+    #[tokio::test]
+    async fn viewers_never_receive_or_set_the_host_clipboard() {
+        let host = MemoryClipboard::new();
+        host.set(ClipboardContent::Text("secret".to_string()));
+        let hub = ClipboardHub::spawn(Box::new(host.clone()), POLL).unwrap();
+        let (tx, mut rx) = mpsc::channel(8);
+        let viewer = ClipboardSession::new(&session(SessionRole::Viewer, &hub), tx);
+
+        viewer.handle_request(request(json!({ "type": "clipboard_sync", "enabled": true })));
+        assert_eq!(next_message(&mut rx).await["type"], "permission_denied");
+        viewer.handle_request(request(json!({ "type": "clipboard", "format": "text", "text": "overwrite" })));
+        assert_eq!(next_message(&mut rx).await["type"], "permission_denied");
+
+        tokio::time::sleep(POLL * 5).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(host.content(), Some(ClipboardContent::Text("secret".to_string())));
+    }
+}
@@ -3,9 +3,26 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use log::{debug, info, warn};
 use std::thread; // Add missing thread import
+use std::sync::mpsc::{self as std_mpsc, RecvTimeoutError};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::core::capture::ScreenCapture;
+use crate::core::keymap::{key_for_code, scancode_for_code};
+use crate::core::layout::{HostLayout, KeyboardMode};
+use crate::core::protocol::ServerMessage;
+
+/// Longest string a single `text` event may type, so one message cannot flood the host
+const MAX_TEXT_CHARS: usize = 1024;
+/// Longest string a `type_text` job may type
+const MAX_TYPED_CHARS: usize = 16 * 1024;
+/// Pause between keystrokes of a `type_text` job unless the client picks one
+const DEFAULT_TYPING_DELAY_MS: u64 = 30;
+const MAX_TYPING_DELAY_MS: u64 = 2000;
+/// `typing_progress` is reported after this many characters, besides start and end
+const TYPING_PROGRESS_EVERY: usize = 10;
 
 lazy_static! {
     // Map for special keys that need more complex handling
@@ -30,7 +47,6 @@ lazy_static! {
         m.insert("End", Key::End);
         m.insert("PageUp", Key::PageUp);
         m.insert("PageDown", Key::PageDown);
-        // m.insert("Insert", Key::Insert); // Not available in this enigo version
         m.insert("Delete", Key::Delete);
         
         // Arrow keys
@@ -46,9 +62,8 @@ lazy_static! {
         m.insert("Escape", Key::Escape);
         m.insert("Space", Key::Space);
         m.insert("CapsLock", Key::CapsLock);
-        // m.insert("NumLock", Key::Numlock); // Not available in this enigo version
-        // m.insert("ScrollLock", Key::ScrollLock); // Not available in this enigo version
-        // m.insert("PrintScreen", Key::Print); // Not available in this enigo version
+        // Insert, NumLock, PrintScreen, media keys etc. share their name with their
+        // physical key code and are resolved through the key code map
         
         // Modifier keys
         m.insert("Control", Key::Control);
@@ -60,9 +75,6 @@ lazy_static! {
         
         m
     };
-    
-    // Keep track of pressed keys to handle key repeats correctly
-    static ref PRESSED_KEYS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
 }
 
 // Add new input event types
@@ -82,10 +94,10 @@ pub enum InputEvent {
     MouseWheel { delta_y: i32, delta_x: Option<i32>, monitor_id: Option<String> },
     
     #[serde(rename = "keydown")]
-    KeyDown { key: String, code: Option<String>, modifiers: Vec<String>, repeat: Option<bool> },
+    KeyDown { key: String, code: Option<String>, #[serde(default)] modifiers: Vec<String>, repeat: Option<bool> },
     
     #[serde(rename = "keyup")]
-    KeyUp { key: String, code: Option<String>, modifiers: Vec<String> },
+    KeyUp { key: String, code: Option<String>, #[serde(default)] modifiers: Vec<String> },
     
     #[serde(rename = "gesture")]
     Gesture { 
@@ -114,6 +126,62 @@ pub enum InputEvent {
     HotKey {
         combination: Vec<String>
     },
+    
+    /// Choose how this session's key events are translated for the host layout
+    #[serde(rename = "keyboard_mode")]
+    SetKeyboardMode { mode: KeyboardMode },
+    
+    /// Type a Unicode string as-is, e.g. text committed by the client's IME
+    #[serde(rename = "text")]
+    Text { text: String },
+    
+    /// Release every key and button this session still holds, e.g. when the client loses focus
+    #[serde(rename = "release_all")]
+    ReleaseAll,
+    
+    /// Type a string as individual keystrokes, `delay_ms` apart, for consoles and login
+    /// screens that accept neither the clipboard nor Unicode text input
+    #[serde(rename = "type_text")]
+    TypeText { text: String, delay_ms: Option<u64> },
+    
+    /// Stop the running `type_text` job
+    #[serde(rename = "cancel_typing")]
+    CancelTyping,
+}
+
+impl InputEvent {
+    /// Message `type` tags that belong to input events
+    pub const TYPES: &'static [&'static str] = &[
+        "mousemove", "mousedown", "mouseup", "wheel", "keydown", "keyup",
+        "gesture", "mousemultitouch", "gamepad", "hotkey", "keyboard_mode", "text", "release_all",
+        "type_text", "cancel_typing",
+    ];
+
+    /// Returns true if a client message with this `type` should be parsed as an input event
+    pub fn is_input_type(message_type: &str) -> bool {
+        Self::TYPES.contains(&message_type)
+    }
+
+    /// Wire name of the event, used when reporting errors back to the client
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InputEvent::MouseMove { .. } => "mousemove",
+            InputEvent::MouseDown { .. } => "mousedown",
+            InputEvent::MouseUp { .. } => "mouseup",
+            InputEvent::MouseWheel { .. } => "wheel",
+            InputEvent::KeyDown { .. } => "keydown",
+            InputEvent::KeyUp { .. } => "keyup",
+            InputEvent::Gesture { .. } => "gesture",
+            InputEvent::MouseMultiTouch { .. } => "mousemultitouch",
+            InputEvent::GamepadEvent { .. } => "gamepad",
+            InputEvent::HotKey { .. } => "hotkey",
+            InputEvent::SetKeyboardMode { .. } => "keyboard_mode",
+            InputEvent::Text { .. } => "text",
+            InputEvent::ReleaseAll => "release_all",
+            InputEvent::TypeText { .. } => "type_text",
+            InputEvent::CancelTyping => "cancel_typing",
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -124,25 +192,316 @@ pub struct TouchPoint {
     pub pressure: Option<f32>,
 }
 
-pub struct InputHandler {
+/// Where input is injected on the host
+///
+/// Keys and buttons use enigo's vocabulary; backends translate them to whatever
+/// injection method they use.
+pub trait InputBackend: Send {
+    fn move_to(&mut self, x: i32, y: i32);
+    fn button_down(&mut self, button: MouseButton);
+    fn button_up(&mut self, button: MouseButton);
+    /// Scroll by whole steps, using enigo's sign convention
+    fn scroll(&mut self, dx: i32, dy: i32);
+    fn key_down(&mut self, key: Key);
+    fn key_up(&mut self, key: Key);
+    /// Type a string as-is, bypassing key mapping
+    fn text(&mut self, text: &str);
+
+    fn key_click(&mut self, key: Key) {
+        self.key_down(key);
+        self.key_up(key);
+    }
+}
+
+/// Injects input through enigo
+pub struct EnigoBackend {
     enigo: Enigo,
+}
+
+impl EnigoBackend {
+    pub fn new() -> Self {
+        Self { enigo: Enigo::new() }
+    }
+}
+
+impl InputBackend for EnigoBackend {
+    fn move_to(&mut self, x: i32, y: i32) {
+        self.enigo.mouse_move_to(x, y);
+    }
+
+    fn button_down(&mut self, button: MouseButton) {
+        self.enigo.mouse_down(button);
+    }
+
+    fn button_up(&mut self, button: MouseButton) {
+        self.enigo.mouse_up(button);
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        if dx != 0 {
+            self.enigo.mouse_scroll_x(dx);
+        }
+        if dy != 0 {
+            self.enigo.mouse_scroll_y(dy);
+        }
+    }
+
+    fn key_down(&mut self, key: Key) {
+        self.enigo.key_down(key);
+    }
+
+    fn key_up(&mut self, key: Key) {
+        self.enigo.key_up(key);
+    }
+
+    fn text(&mut self, text: &str) {
+        self.enigo.key_sequence(text);
+    }
+
+    fn key_click(&mut self, key: Key) {
+        self.enigo.key_click(key);
+    }
+}
+
+/// One call made on an `InputBackend`
+#[derive(Debug, Clone, PartialEq)]
+pub enum InjectedInput {
+    MoveTo(i32, i32),
+    ButtonDown(MouseButton),
+    ButtonUp(MouseButton),
+    Scroll { dx: i32, dy: i32 },
+    KeyDown(Key),
+    KeyUp(Key),
+    Text(String),
+}
+
+/// Backend that records every call instead of touching the host, for tests
+#[derive(Clone, Default)]
+pub struct RecordingBackend {
+    log: Arc<Mutex<Vec<InjectedInput>>>,
+}
+
+impl RecordingBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything injected so far, in order
+    pub fn recorded(&self) -> Vec<InjectedInput> {
+        self.log.lock().unwrap().clone()
+    }
+
+    fn record(&self, input: InjectedInput) {
+        self.log.lock().unwrap().push(input);
+    }
+}
+
+impl InputBackend for RecordingBackend {
+    fn move_to(&mut self, x: i32, y: i32) {
+        self.record(InjectedInput::MoveTo(x, y));
+    }
+
+    fn button_down(&mut self, button: MouseButton) {
+        self.record(InjectedInput::ButtonDown(button));
+    }
+
+    fn button_up(&mut self, button: MouseButton) {
+        self.record(InjectedInput::ButtonUp(button));
+    }
+
+    fn scroll(&mut self, dx: i32, dy: i32) {
+        self.record(InjectedInput::Scroll { dx, dy });
+    }
+
+    fn key_down(&mut self, key: Key) {
+        self.record(InjectedInput::KeyDown(key));
+    }
+
+    fn key_up(&mut self, key: Key) {
+        self.record(InjectedInput::KeyUp(key));
+    }
+
+    fn text(&mut self, text: &str) {
+        self.record(InjectedInput::Text(text.to_string()));
+    }
+}
+
+/// What a key event does on the host
+enum KeyAction {
+    /// Keys held down, in press order. `typed` is set when they reproduce the client's
+    /// character, which already accounts for the Shift or AltGr used to produce it.
+    Press { keys: Vec<Key>, typed: bool },
+    /// Characters no host key produces, typed as text
+    Text(String),
+}
+
+impl KeyAction {
+    fn press(key: Key) -> Self {
+        KeyAction::Press { keys: vec![key], typed: false }
+    }
+    
+    /// Client modifiers to apply around this action
+    fn modifiers(&self, modifiers: &[String]) -> Vec<String> {
+        if let KeyAction::Press { typed: false, .. } = self {
+            return modifiers.to_vec();
+        }
+        
+        // Browsers report AltGr as Control+Alt
+        let held = |name: &str| modifiers.iter().any(|m| m == name);
+        let altgr = held("Control") && held("Alt");
+        modifiers.iter()
+            .filter(|m| match m.as_str() {
+                "Shift" => false,
+                "Control" | "Alt" => !altgr,
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Wheel units in one notch of a mouse wheel
+const WHEEL_NOTCH: i32 = 120;
+
+/// Add `delta` to a wheel remainder and take out the whole notches in it.
+/// Turning the other way starts over rather than first paying back the remainder.
+fn take_notches(remainder: &mut i32, delta: i32) -> i32 {
+    if remainder.signum() * delta.signum() < 0 {
+        *remainder = 0;
+    }
+    *remainder = remainder.saturating_add(delta);
+    let notches = *remainder / WHEEL_NOTCH;
+    *remainder -= notches * WHEEL_NOTCH;
+    notches
+}
+
+/// Where a `type_text` job stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypingState {
+    Typing,
+    Done,
+    Cancelled,
+}
+
+/// Progress of a `type_text` job, reported to the client as `typing_progress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TypingProgress {
+    pub typed: usize,
+    pub total: usize,
+    pub state: TypingState,
+}
+
+/// A `type_text` job, typed one character per step by the input thread
+struct TypingJob {
+    chars: Vec<char>,
+    typed: usize,
+    delay: Duration,
+    next_at: Instant,
+}
+
+impl TypingJob {
+    fn progress(&self, state: TypingState) -> TypingProgress {
+        TypingProgress { typed: self.typed, total: self.chars.len(), state }
+    }
+}
+
+pub struct InputHandler {
+    backend: Box<dyn InputBackend>,
     // Monitor positions and dimensions for multi-monitor support
     monitors: Vec<(String, i32, i32, i32, i32)>, // (id, x, y, width, height)
     active_monitor: usize, // Index of the active monitor
     // Key repeat handling
     key_repeat_delay: Duration,
     key_repeat_interval: Duration,
+    // Layout translation
+    keyboard_mode: KeyboardMode,
+    host_layout: Option<HostLayout>,
+    // Detect the host layout when the input thread starts
+    detect_host_layout: bool,
+    // When each client key was first pressed, for repeat timing
+    pressed_keys: HashMap<String, Instant>,
+    // What each client key pressed on the host, so key up releases exactly that
+    down_keys: HashMap<String, KeyAction>,
+    // What this session holds down on the host, in press order
+    held_keys: Vec<Key>,
+    held_buttons: Vec<MouseButton>,
+    // Wheel units short of a whole notch, (x, y)
+    wheel_remainder: (i32, i32),
+    // Paced typing
+    typing: Option<TypingJob>,
+    typing_report: Option<TypingProgress>,
 }
 
 impl InputHandler {
     pub fn new() -> Self {
+        Self::with_backend(Box::new(EnigoBackend::new()))
+    }
+    
+    /// Create a handler that injects through the given backend
+    pub fn with_backend(backend: Box<dyn InputBackend>) -> Self {
         Self {
-            enigo: Enigo::new(),
+            backend,
             monitors: Vec::new(),
             active_monitor: 0,
             key_repeat_delay: Duration::from_millis(500),     // Initial delay before repeating
             key_repeat_interval: Duration::from_millis(30),  // Interval between repeats
+            keyboard_mode: KeyboardMode::default(),
+            host_layout: None,
+            detect_host_layout: false,
+            pressed_keys: HashMap::new(),
+            down_keys: HashMap::new(),
+            held_keys: Vec::new(),
+            held_buttons: Vec::new(),
+            wheel_remainder: (0, 0),
+            typing: None,
+            typing_report: None,
+        }
+    }
+    
+    /// Create a handler with the host monitor layout loaded and `monitor_index` active,
+    /// so coordinates sent for a streamed monitor land on that monitor. The keyboard
+    /// layout is detected later, by the input thread.
+    pub fn for_monitor(monitor_index: usize) -> Self {
+        let mut handler = Self::new();
+        
+        match ScreenCapture::get_all_monitors() {
+            Ok(monitors) => {
+                handler.update_monitors(monitors.into_iter()
+                    .map(|m| (m.id, m.position_x, m.position_y, m.width as i32, m.height as i32))
+                    .collect());
+                if let Err(e) = handler.set_active_monitor(&monitor_index.to_string()) {
+                    warn!("{}, using primary monitor for input", e);
+                }
+            }
+            Err(e) => warn!("Failed to load monitor layout for input: {}", e),
+        }
+        
+        handler.detect_host_layout = true;
+        handler
+    }
+    
+    /// Read the host keyboard layout if `for_monitor` asked for it. This runs
+    /// `xmodmap` and `setxkbmap`, so only the input thread calls it.
+    fn load_host_layout(&mut self) {
+        if std::mem::take(&mut self.detect_host_layout) {
+            self.set_host_layout(HostLayout::detect());
+        }
+    }
+    
+    /// Use `layout` to translate characters in keysym mode; without one, enigo looks
+    /// characters up on the host layout itself
+    pub fn set_host_layout(&mut self, layout: Option<HostLayout>) {
+        match &layout {
+            Some(layout) => info!("Host keyboard layout: {}", layout.name().unwrap_or("unknown")),
+            None => debug!("Host keyboard layout not detected, characters are resolved by enigo"),
         }
+        self.host_layout = layout;
+    }
+    
+    pub fn set_keyboard_mode(&mut self, mode: KeyboardMode) {
+        info!("Keyboard mode set to {:?}", mode);
+        self.keyboard_mode = mode;
     }
     
     pub fn update_monitors(&mut self, monitors: Vec<(String, i32, i32, i32, i32)>) {
@@ -159,57 +518,52 @@ impl InputHandler {
             InputEvent::MouseMove { x, y, monitor_id } => {
                 // Translate coordinates to global screen space if monitor_id is provided
                 let (global_x, global_y) = self.translate_coordinates(x, y, monitor_id)?;
-                self.enigo.mouse_move_to(global_x, global_y);
+                self.backend.move_to(global_x, global_y);
                 debug!("Mouse move to ({}, {})", global_x, global_y);
             }
             
             InputEvent::MouseDown { button, x, y, monitor_id } => {
                 // Translate coordinates to global screen space if monitor_id is provided
                 let (global_x, global_y) = self.translate_coordinates(x, y, monitor_id)?;
-                self.enigo.mouse_move_to(global_x, global_y);
+                self.backend.move_to(global_x, global_y);
                 let button = self.map_mouse_button(&button)?;
-                self.enigo.mouse_down(button);
+                self.press_button(button);
                 debug!("Mouse down {:?} at ({}, {})", button, global_x, global_y);
             }
             
             InputEvent::MouseUp { button, x, y, monitor_id } => {
                 // Translate coordinates to global screen space if monitor_id is provided
                 let (global_x, global_y) = self.translate_coordinates(x, y, monitor_id)?;
-                self.enigo.mouse_move_to(global_x, global_y);
+                self.backend.move_to(global_x, global_y);
                 let button = self.map_mouse_button(&button)?;
-                self.enigo.mouse_up(button);
+                self.release_button(button);
                 debug!("Mouse up {:?} at ({}, {})", button, global_x, global_y);
             }
             
             InputEvent::MouseWheel { delta_y, delta_x, monitor_id: _ } => {
-                // Vertical scrolling
-                let y_click_count = (delta_y / 120).abs() as usize;
-                for _ in 0..y_click_count {
-                    if delta_y > 0 {
-                        self.enigo.mouse_scroll_y(-1);
-                    } else {
-                        self.enigo.mouse_scroll_y(1);
-                    }
+                // Trackpads send a few units at a time, so partial notches carry over
+                let y_notches = take_notches(&mut self.wheel_remainder.1, delta_y);
+                for _ in 0..y_notches.abs() {
+                    self.backend.scroll(0, -y_notches.signum());
                 }
                 
                 // Horizontal scrolling (if supported and provided)
                 if let Some(delta_x) = delta_x {
-                    let x_click_count = (delta_x / 120).abs() as usize;
-                    for _ in 0..x_click_count {
-                        if delta_x > 0 {
-                            self.enigo.mouse_scroll_x(1);
-                        } else {
-                            self.enigo.mouse_scroll_x(-1);
-                        }
+                    let x_notches = take_notches(&mut self.wheel_remainder.0, delta_x);
+                    for _ in 0..x_notches.abs() {
+                        self.backend.scroll(x_notches.signum(), 0);
                     }
                 }
             }
             
             InputEvent::KeyDown { key, code, modifiers, repeat } => {
+                // Keys are tracked by physical key; the key value changes with Shift
+                let id = code.clone().unwrap_or_else(|| key.clone());
+                
                 // Handle key repeats
                 let should_send = if repeat.unwrap_or(false) {
                     // Check if we should send a repeat based on timing
-                    if let Some(pressed_time) = PRESSED_KEYS.lock().unwrap().get(&key) {
+                    if let Some(pressed_time) = self.pressed_keys.get(&id) {
                         let elapsed = pressed_time.elapsed();
                         
                         if elapsed >= self.key_repeat_delay {
@@ -233,44 +587,41 @@ impl InputHandler {
                 } else {
                     // First press, not a repeat
                     // Store the key and current time
-                    PRESSED_KEYS.lock().unwrap().insert(key.clone(), Instant::now());
+                    self.pressed_keys.insert(id.clone(), Instant::now());
                     true
                 };
                 
                 if should_send {
-                    self.handle_modifiers(&modifiers, true)?;
-                    
-                    // Try to use code first if available (more reliable for keyboard layouts)
-                    if let Some(code_str) = code {
-                        if let Ok(key) = self.map_key_code(&code_str) {
-                            self.enigo.key_down(key);
-                            return Ok(());
+                    // Resolve the key before pressing modifiers so a bad key leaves nothing held
+                    let action = self.resolve_key(&key, code.as_deref())?;
+                    self.handle_modifiers(&action.modifiers(&modifiers), true)?;
+                    match &action {
+                        KeyAction::Press { keys, .. } => {
+                            for key in keys {
+                                self.press_key(*key);
+                            }
                         }
+                        KeyAction::Text(text) => self.backend.text(text),
                     }
-                    
-                    // Fall back to key if code mapping failed
-                    let key = self.map_key(&key)?;
-                    self.enigo.key_down(key);
+                    self.down_keys.insert(id, action);
                 }
             }
             
             InputEvent::KeyUp { key, code, modifiers } => {
-                // Remove from pressed keys map
-                PRESSED_KEYS.lock().unwrap().remove(&key);
+                let id = code.clone().unwrap_or_else(|| key.clone());
+                self.pressed_keys.remove(&id);
                 
-                // Try to use code first if available
-                if let Some(code_str) = code {
-                    if let Ok(key) = self.map_key_code(&code_str) {
-                        self.enigo.key_up(key);
-                        self.handle_modifiers(&modifiers, false)?;
-                        return Ok(());
+                // Release what the key down pressed, even if the key value has changed since
+                let action = match self.down_keys.remove(&id) {
+                    Some(action) => action,
+                    None => self.resolve_key(&key, code.as_deref())?,
+                };
+                if let KeyAction::Press { keys, .. } = &action {
+                    for key in keys.iter().rev() {
+                        self.release_key(*key);
                     }
                 }
-                
-                // Fall back to key
-                let key = self.map_key(&key)?;
-                self.enigo.key_up(key);
-                self.handle_modifiers(&modifiers, false)?;
+                self.handle_modifiers(&action.modifiers(&modifiers), false)?;
             }
             
             InputEvent::Gesture { gesture_type, scale, rotation, delta_x, delta_y, monitor_id: _ } => {
@@ -281,14 +632,14 @@ impl InputHandler {
                             // Convert pinch to zoom in/out
                             if scale > 1.0 {
                                 // Zoom in (Ctrl + '+')
-                                self.enigo.key_down(Key::Control);
-                                self.enigo.key_click(Key::Layout('+'));
-                                self.enigo.key_up(Key::Control);
+                                self.press_key(Key::Control);
+                                self.backend.key_click(Key::Layout('+'));
+                                self.release_key(Key::Control);
                             } else if scale < 1.0 {
                                 // Zoom out (Ctrl + '-')
-                                self.enigo.key_down(Key::Control);
-                                self.enigo.key_click(Key::Layout('-'));
-                                self.enigo.key_up(Key::Control);
+                                self.press_key(Key::Control);
+                                self.backend.key_click(Key::Layout('-'));
+                                self.release_key(Key::Control);
                             }
                         }
                     },
@@ -305,16 +656,16 @@ impl InputHandler {
                             if dx.abs() > dy.abs() {
                                 // Horizontal pan
                                 if dx > 0 {
-                                    self.enigo.key_click(Key::RightArrow);
+                                    self.backend.key_click(Key::RightArrow);
                                 } else {
-                                    self.enigo.key_click(Key::LeftArrow);
+                                    self.backend.key_click(Key::LeftArrow);
                                 }
                             } else {
                                 // Vertical pan
                                 if dy > 0 {
-                                    self.enigo.key_click(Key::DownArrow);
+                                    self.backend.key_click(Key::DownArrow);
                                 } else {
-                                    self.enigo.key_click(Key::UpArrow);
+                                    self.backend.key_click(Key::UpArrow);
                                 }
                             }
                         }
@@ -332,7 +683,7 @@ impl InputHandler {
                     // Just move to the first touch point for basic compatibility
                     let primary = &touches[0];
                     let (global_x, global_y) = self.translate_coordinates(primary.x, primary.y, monitor_id)?;
-                    self.enigo.mouse_move_to(global_x, global_y);
+                    self.backend.move_to(global_x, global_y);
                     debug!("Multi-touch primary point: ({}, {})", global_x, global_y);
                 }
             }
@@ -346,16 +697,16 @@ impl InputHandler {
                 match button {
                     0 => { // A button
                         if is_pressed {
-                            self.enigo.key_down(Key::Layout(' '));
+                            self.press_key(Key::Layout(' '));
                         } else {
-                            self.enigo.key_up(Key::Layout(' '));
+                            self.release_key(Key::Layout(' '));
                         }
                     },
                     1 => { // B button
                         if is_pressed {
-                            self.enigo.key_down(Key::Escape);
+                            self.press_key(Key::Escape);
                         } else {
-                            self.enigo.key_up(Key::Escape);
+                            self.release_key(Key::Escape);
                         }
                     },
                     // Map more buttons as needed
@@ -371,7 +722,7 @@ impl InputHandler {
                 let mut keys = Vec::new();
                 for key_name in &combination {
                     if let Ok(key) = self.map_key(key_name) {
-                        self.enigo.key_down(key);
+                        self.press_key(key);
                         keys.push(key);
                     }
                 }
@@ -381,14 +732,158 @@ impl InputHandler {
                 
                 // Release all keys in reverse order
                 for key in keys.into_iter().rev() {
-                    self.enigo.key_up(key);
+                    self.release_key(key);
                 }
             },
+            
+            InputEvent::SetKeyboardMode { mode } => self.set_keyboard_mode(mode),
+            
+            InputEvent::Text { text } => {
+                let length = text.chars().count();
+                if length > MAX_TEXT_CHARS {
+                    return Err(format!("Text is {} characters, the limit is {}", length, MAX_TEXT_CHARS));
+                }
+                self.backend.text(&text);
+                debug!("Typed {} characters of text", length);
+            }
+            
+            InputEvent::ReleaseAll => self.release_all(),
+            
+            InputEvent::TypeText { text, delay_ms } => self.start_typing(&text, delay_ms)?,
+            
+            InputEvent::CancelTyping => self.cancel_typing(),
         }
         
         Ok(())
     }
     
+    fn start_typing(&mut self, text: &str, delay_ms: Option<u64>) -> Result<(), String> {
+        if self.typing.is_some() {
+            return Err("Already typing, cancel the running job first".to_string());
+        }
+        
+        let chars: Vec<char> = text.replace("\r\n", "\n").chars().collect();
+        if chars.len() > MAX_TYPED_CHARS {
+            return Err(format!("Text is {} characters, the limit is {}", chars.len(), MAX_TYPED_CHARS));
+        }
+        
+        let delay = Duration::from_millis(delay_ms.unwrap_or(DEFAULT_TYPING_DELAY_MS).min(MAX_TYPING_DELAY_MS));
+        info!("Typing {} characters as keystrokes, {:?} apart", chars.len(), delay);
+        
+        let job = TypingJob { chars, typed: 0, delay, next_at: Instant::now() };
+        if job.chars.is_empty() {
+            self.typing_report = Some(job.progress(TypingState::Done));
+        } else {
+            self.typing_report = Some(job.progress(TypingState::Typing));
+            self.typing = Some(job);
+        }
+        Ok(())
+    }
+    
+    fn cancel_typing(&mut self) {
+        if let Some(job) = self.typing.take() {
+            info!("Typing cancelled after {} of {} characters", job.typed, job.chars.len());
+            self.typing_report = Some(job.progress(TypingState::Cancelled));
+        }
+    }
+    
+    /// When the running `type_text` job wants its next keystroke
+    fn typing_deadline(&self) -> Option<Instant> {
+        self.typing.as_ref().map(|job| job.next_at)
+    }
+    
+    /// Type the next character of the running `type_text` job
+    fn type_next_char(&mut self) {
+        let Some(ch) = self.typing.as_ref().and_then(|job| job.chars.get(job.typed).copied()) else {
+            return;
+        };
+        self.type_char(ch);
+        
+        let Some(job) = self.typing.as_mut() else {
+            return;
+        };
+        job.typed += 1;
+        job.next_at = Instant::now() + job.delay;
+        if job.typed == job.chars.len() {
+            debug!("Typed all {} characters", job.typed);
+            self.typing_report = Some(job.progress(TypingState::Done));
+            self.typing = None;
+        } else if job.typed % TYPING_PROGRESS_EVERY == 0 {
+            self.typing_report = Some(job.progress(TypingState::Typing));
+        }
+    }
+    
+    /// Progress worth telling the client about since the last call
+    fn take_typing_progress(&mut self) -> Option<TypingProgress> {
+        self.typing_report.take()
+    }
+    
+    /// Press and release the keys that produce `ch`. Consoles often ignore synthesized
+    /// Unicode input, so characters go through the host layout's own keys when it is known.
+    fn type_char(&mut self, ch: char) {
+        let keys = match ch {
+            '\n' | '\r' => vec![Key::Return],
+            '\t' => vec![Key::Tab],
+            _ if ch.is_control() => {
+                debug!("Skipping control character {:?}", ch);
+                return;
+            }
+            _ => match self.host_layout.as_ref().and_then(|layout| layout.keys_for(ch)) {
+                Some(keys) => keys,
+                None => {
+                    self.backend.text(ch.encode_utf8(&mut [0; 4]));
+                    return;
+                }
+            },
+        };
+        
+        for key in &keys {
+            self.press_key(*key);
+        }
+        for key in keys.into_iter().rev() {
+            self.release_key(key);
+        }
+    }
+    
+    /// Work out what a key does on the host in this session's keyboard mode
+    fn resolve_key(&self, key: &str, code: Option<&str>) -> Result<KeyAction, String> {
+        let mut chars = key.chars();
+        let character = match (chars.next(), chars.next()) {
+            (Some(ch), None) if !ch.is_control() => Some(ch),
+            _ => None,
+        };
+        
+        // Keysym mode types the client's character with whatever produces it on the host
+        if let (KeyboardMode::Keysym, Some(ch)) = (self.keyboard_mode, character) {
+            return Ok(match &self.host_layout {
+                Some(layout) => match layout.keys_for(ch) {
+                    Some(keys) => KeyAction::Press { keys, typed: true },
+                    None => KeyAction::Text(ch.to_string()),
+                },
+                None => KeyAction::press(Key::Layout(ch)),
+            });
+        }
+        
+        if let Some(code) = code {
+            // Scancode mode presses the key at the same physical position
+            if self.keyboard_mode == KeyboardMode::Scancode {
+                if let Some(key) = scancode_for_code(code) {
+                    return Ok(KeyAction::press(key));
+                }
+            }
+            if let Ok(key) = self.map_key_code(code) {
+                return Ok(KeyAction::press(key));
+            }
+        }
+        
+        // Composed characters (e.g. from an IME) have no single key; type them
+        if is_composed_text(key) {
+            return Ok(KeyAction::Text(key.to_string()));
+        }
+        
+        self.map_key(key).map(KeyAction::press)
+    }
+    
     fn translate_coordinates(&self, x: i32, y: i32, monitor_id: Option<String>) -> Result<(i32, i32), String> {
         if self.monitors.is_empty() {
             // No monitor configuration, use coordinates as-is
@@ -465,104 +960,18 @@ impl InputHandler {
             }
         }
         
+        // Keys named after their physical key, like Insert or MediaPlayPause
+        if let Some(mapped_key) = key_for_code(key) {
+            return Ok(mapped_key);
+        }
+        
         // Fall back for unknown keys
         Err(format!("Unsupported key: {}", key))
     }
     
     fn map_key_code(&self, code: &str) -> Result<Key, String> {
-        // Map from KeyboardEvent.code to Enigo Key
-        // See: https://developer.mozilla.org/en-US/docs/Web/API/KeyboardEvent/code
-        
-        // Key codes for letters (KeyA through KeyZ)
-        if code.len() == 4 && code.starts_with("Key") {
-            let ch = code.chars().nth(3).unwrap();
-            if ch.is_ascii_alphabetic() {
-                return Ok(Key::Layout(ch.to_ascii_lowercase()));
-            }
-        }
-        
-        // Digit keys (Digit0 through Digit9)
-        if code.len() == 6 && code.starts_with("Digit") {
-            let ch = code.chars().nth(5).unwrap();
-            if ch.is_digit(10) {
-                return Ok(Key::Layout(ch));
-            }
-        }
-        
-        // Function keys
-        match code {
-            "F1" => Ok(Key::F1),
-            "F2" => Ok(Key::F2),
-            "F3" => Ok(Key::F3),
-            "F4" => Ok(Key::F4),
-            "F5" => Ok(Key::F5),
-            "F6" => Ok(Key::F6),
-            "F7" => Ok(Key::F7),
-            "F8" => Ok(Key::F8),
-            "F9" => Ok(Key::F9),
-            "F10" => Ok(Key::F10),
-            "F11" => Ok(Key::F11),
-            "F12" => Ok(Key::F12),
-            // Navigation
-            "Home" => Ok(Key::Home),
-            "End" => Ok(Key::End),
-            "PageUp" => Ok(Key::PageUp),
-            "PageDown" => Ok(Key::PageDown),
-            // "Insert" => Ok(Key::Insert), // Not available in this enigo version
-            "Insert" => Err(format!("Insert key not supported in this enigo version")),
-            "Delete" => Ok(Key::Delete),
-            "ArrowUp" => Ok(Key::UpArrow),
-            "ArrowDown" => Ok(Key::DownArrow),
-            "ArrowLeft" => Ok(Key::LeftArrow),
-            "ArrowRight" => Ok(Key::RightArrow),
-            // Common keys
-            "Backspace" => Ok(Key::Backspace),
-            "Tab" => Ok(Key::Tab),
-            "Enter" => Ok(Key::Return),
-            "Escape" => Ok(Key::Escape),
-            "Space" => Ok(Key::Space),
-            "CapsLock" => Ok(Key::CapsLock),
-            // Modifiers
-            "ControlLeft" | "ControlRight" => Ok(Key::Control),
-            "AltLeft" | "AltRight" => Ok(Key::Alt),
-            "ShiftLeft" | "ShiftRight" => Ok(Key::Shift),
-            "MetaLeft" | "MetaRight" => Ok(Key::Meta),
-            // Other common mappings
-            "Backquote" => Ok(Key::Layout('`')),
-            "Minus" => Ok(Key::Layout('-')),
-            "Equal" => Ok(Key::Layout('=')),
-            "BracketLeft" => Ok(Key::Layout('[')),
-            "BracketRight" => Ok(Key::Layout(']')),
-            "Backslash" => Ok(Key::Layout('\\')),
-            "Semicolon" => Ok(Key::Layout(';')),
-            "Quote" => Ok(Key::Layout('\'')),
-            "Comma" => Ok(Key::Layout(',')),
-            "Period" => Ok(Key::Layout('.')),
-            "Slash" => Ok(Key::Layout('/')),
-            // Numpad
-            "Numpad0" => Ok(Key::Layout('0')),
-            "Numpad1" => Ok(Key::Layout('1')),
-            "Numpad2" => Ok(Key::Layout('2')),
-            "Numpad3" => Ok(Key::Layout('3')),
-            "Numpad4" => Ok(Key::Layout('4')),
-            "Numpad5" => Ok(Key::Layout('5')),
-            "Numpad6" => Ok(Key::Layout('6')),
-            "Numpad7" => Ok(Key::Layout('7')),
-            "Numpad8" => Ok(Key::Layout('8')),
-            "Numpad9" => Ok(Key::Layout('9')),
-            "NumpadAdd" => Ok(Key::Layout('+')),
-            "NumpadSubtract" => Ok(Key::Layout('-')),
-            "NumpadMultiply" => Ok(Key::Layout('*')),
-            "NumpadDivide" => Ok(Key::Layout('/')),
-            "NumpadDecimal" => Ok(Key::Layout('.')),
-            "NumpadEnter" => Ok(Key::Return),
-            // Keys not supported in this enigo version
-            "NumLock" => Err(format!("NumLock key not supported in this enigo version")),
-            "ScrollLock" => Err(format!("ScrollLock key not supported in this enigo version")),
-            "PrintScreen" => Err(format!("PrintScreen key not supported in this enigo version")),
-            // Add more as needed
-            _ => Err(format!("Unsupported key code: {}", code)),
-        }
+        // Map from KeyboardEvent.code, which names the physical key
+        key_for_code(code).ok_or_else(|| format!("Unsupported key code: {}", code))
     }
 
     fn handle_modifiers(&mut self, modifiers: &[String], is_down: bool) -> Result<(), String> {
@@ -576,15 +985,56 @@ impl InputHandler {
             };
             
             if is_down {
-                self.enigo.key_down(key);
+                self.press_key(key);
             } else {
-                self.enigo.key_up(key);
+                self.release_key(key);
             }
         }
         
         Ok(())
     }
     
+    fn press_key(&mut self, key: Key) {
+        self.backend.key_down(key);
+        if !self.held_keys.contains(&key) {
+            self.held_keys.push(key);
+        }
+    }
+    
+    fn release_key(&mut self, key: Key) {
+        self.backend.key_up(key);
+        self.held_keys.retain(|held| *held != key);
+    }
+    
+    fn press_button(&mut self, button: MouseButton) {
+        self.backend.button_down(button);
+        if !self.held_buttons.contains(&button) {
+            self.held_buttons.push(button);
+        }
+    }
+    
+    fn release_button(&mut self, button: MouseButton) {
+        self.backend.button_up(button);
+        self.held_buttons.retain(|held| *held != button);
+    }
+    
+    /// Release every key and mouse button this session pressed and has not released,
+    /// most recent first, so nothing stays held on the host after a session goes away
+    pub fn release_all(&mut self) {
+        if !self.held_keys.is_empty() || !self.held_buttons.is_empty() {
+            info!("Releasing {} held keys and {} held buttons", self.held_keys.len(), self.held_buttons.len());
+        }
+        
+        for button in std::mem::take(&mut self.held_buttons).into_iter().rev() {
+            self.backend.button_up(button);
+        }
+        for key in std::mem::take(&mut self.held_keys).into_iter().rev() {
+            self.backend.key_up(key);
+        }
+        self.pressed_keys.clear();
+        self.down_keys.clear();
+    }
+    
     pub fn set_active_monitor(&mut self, monitor_id: &str) -> Result<(), String> {
         if let Some(idx) = self.monitors.iter().position(|(id, _, _, _, _)| id == monitor_id) {
             self.active_monitor = idx;
@@ -595,3 +1045,617 @@ impl InputHandler {
         }
     }
 }
+
+impl Drop for InputHandler {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+/// Handle to a dedicated input thread that owns an `InputHandler`.
+///
+/// enigo talks to the windowing system synchronously, so events are injected from
+/// a plain OS thread and never block the tokio runtime. Failed events are reported
+/// back to the client as `input_error` messages on the session's control channel,
+/// along with `typing_progress` for `type_text` jobs. The thread types those jobs
+/// between other events, so the session keeps its mouse and can cancel mid-way.
+/// The thread exits once every clone of the dispatcher has been dropped, releasing
+/// anything the session still holds as the handler is dropped.
+#[derive(Clone)]
+pub struct InputDispatcher {
+    tx: Option<std_mpsc::Sender<InputEvent>>, // None for view-only sessions
+    control_tx: mpsc::Sender<String>,
+}
+
+impl InputDispatcher {
+    pub fn spawn(mut handler: InputHandler, control_tx: mpsc::Sender<String>) -> Result<Self, String> {
+        let (tx, rx) = std_mpsc::channel::<InputEvent>();
+        let thread_control_tx = control_tx.clone();
+        
+        thread::Builder::new()
+            .name("kvm-input".to_string())
+            .spawn(move || {
+                info!("🖱️ Input thread started");
+                handler.load_host_layout();
+                loop {
+                    let received = match handler.typing_deadline() {
+                        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                            Some(wait) if !wait.is_zero() => rx.recv_timeout(wait),
+                            _ => Err(RecvTimeoutError::Timeout),
+                        },
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    
+                    match received {
+                        Ok(event) => {
+                            let kind = event.kind();
+                            if let Err(e) = handler.handle_event(event) {
+                                warn!("Failed to handle {} event: {}", kind, e);
+                                if let Err(TrySendError::Closed(_)) = thread_control_tx.try_send(input_error(kind, &e)) {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => handler.type_next_char(),
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                    
+                    if let Some(progress) = handler.take_typing_progress() {
+                        let message = ServerMessage::TypingProgress(progress).to_message();
+                        if progress.state == TypingState::Typing {
+                            // Intermediate progress may be skipped while the channel is full
+                            let _ = thread_control_tx.try_send(message);
+                        } else if thread_control_tx.blocking_send(message).is_err() {
+                            // The final report waits for room, unless the session is gone
+                            break;
+                        }
+                    }
+                }
+                info!("🖱️ Input thread stopped");
+            })
+            .map_err(|e| format!("Failed to spawn input thread: {}", e))?;
+        
+        Ok(Self { tx: Some(tx), control_tx })
+    }
+    
+    /// Dispatcher for view-only sessions: input events are consumed and dropped
+    pub fn view_only(control_tx: mpsc::Sender<String>) -> Self {
+        info!("👀 View-only session, client input will be ignored");
+        Self { tx: None, control_tx }
+    }
+    
+    /// Spawn the input thread, or a view-only dispatcher when input is not allowed
+    pub fn for_session(handler: InputHandler, input_allowed: bool, control_tx: mpsc::Sender<String>) -> Result<Self, String> {
+        if input_allowed {
+            Self::spawn(handler, control_tx)
+        } else {
+            Ok(Self::view_only(control_tx))
+        }
+    }
+    
+    /// Ask the input thread to release everything the session holds; sessions call
+    /// this on the way out so keys come up without waiting for the thread to exit
+    pub fn release_all(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(InputEvent::ReleaseAll);
+        }
+    }
+    
+    /// Queue an event for the input thread
+    pub fn dispatch(&self, event: InputEvent) -> Result<(), String> {
+        let tx = self.tx.as_ref().ok_or_else(|| "Input is disabled for this session".to_string())?;
+        tx.send(event).map_err(|_| "Input thread is not running".to_string())
+    }
+    
+    /// Dispatch an input event from the client, reporting failures to it
+    pub fn handle_event(&self, event: InputEvent) {
+        // Pointer moves arrive constantly, so view-only sessions drop input silently
+        if self.tx.is_none() {
+            return;
+        }
+        
+        let kind = event.kind();
+        if let Err(e) = self.dispatch(event) {
+            debug!("Rejected input message: {}", e);
+            let _ = self.control_tx.try_send(input_error(kind, &e));
+        }
+    }
+}
+
+/// Non-ASCII strings longer than one character, such as IME output, that no key can produce
+fn is_composed_text(key: &str) -> bool {
+    key.chars().count() > 1 && key.chars().all(|c| !c.is_ascii() && !c.is_control())
+}
+
+fn input_error(kind: &str, error: &str) -> String {
+    ServerMessage::InputError { event: kind.to_string(), error: error.to_string() }.to_message()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recording_handler() -> (InputHandler, RecordingBackend) {
+        let backend = RecordingBackend::new();
+        (InputHandler::with_backend(Box::new(backend.clone())), backend)
+    }
+
+    fn two_monitors(handler: &mut InputHandler) {
+        handler.update_monitors(vec![
+            ("0".to_string(), 0, 0, 1920, 1080),
+            ("1".to_string(), 1920, -200, 1280, 1024),
+        ]);
+    }
+
+    fn modifiers(names: &[&str]) -> Vec<String> {
+        names.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn translate_coordinates_applies_monitor_offset() {
+        let (mut handler, _) = recording_handler();
+        assert_eq!(handler.translate_coordinates(10, 20, None), Ok((10, 20)));
+
+        two_monitors(&mut handler);
+        assert_eq!(handler.translate_coordinates(10, 20, None), Ok((10, 20)));
+        assert_eq!(handler.translate_coordinates(10, 20, Some("1".to_string())), Ok((1930, -180)));
+        // Unknown ids fall back to the active monitor
+        assert_eq!(handler.translate_coordinates(10, 20, Some("9".to_string())), Ok((10, 20)));
+
+        handler.set_active_monitor("1").unwrap();
+        assert_eq!(handler.translate_coordinates(10, 20, None), Ok((1930, -180)));
+        assert!(handler.set_active_monitor("9").is_err());
+    }
+
+    #[test]
+    fn mouse_events_move_then_press() {
+        let (mut handler, backend) = recording_handler();
+        two_monitors(&mut handler);
+
+        handler.handle_event(InputEvent::MouseMove { x: 5, y: 6, monitor_id: Some("1".to_string()) }).unwrap();
+        handler.handle_event(InputEvent::MouseDown { button: "left".to_string(), x: 7, y: 8, monitor_id: None }).unwrap();
+        handler.handle_event(InputEvent::MouseUp { button: "right".to_string(), x: 7, y: 8, monitor_id: None }).unwrap();
+        assert!(handler.handle_event(InputEvent::MouseDown { button: "back".to_string(), x: 0, y: 0, monitor_id: None }).is_err());
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::MoveTo(1925, -194),
+            InjectedInput::MoveTo(7, 8),
+            InjectedInput::ButtonDown(MouseButton::Left),
+            InjectedInput::MoveTo(7, 8),
+            InjectedInput::ButtonUp(MouseButton::Right),
+            // The pointer still moves before an unknown button is rejected
+            InjectedInput::MoveTo(0, 0),
+        ]);
+    }
+
+    #[test]
+    fn wheel_scrolls_one_step_per_notch() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(InputEvent::MouseWheel { delta_y: 240, delta_x: None, monitor_id: None }).unwrap();
+        handler.handle_event(InputEvent::MouseWheel { delta_y: -3, delta_x: Some(121), monitor_id: None }).unwrap();
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::Scroll { dx: 0, dy: -1 },
+            InjectedInput::Scroll { dx: 0, dy: -1 },
+            InjectedInput::Scroll { dx: 1, dy: 0 },
+        ]);
+    }
+
+    #[test]
+    fn small_wheel_deltas_add_up_to_notches() {
+        let (mut handler, backend) = recording_handler();
+
+        // A trackpad swipe: 40 events of 4 units is one notch with 40 left over
+        for _ in 0..40 {
+            handler.handle_event(InputEvent::MouseWheel { delta_y: -4, delta_x: None, monitor_id: None }).unwrap();
+        }
+        assert_eq!(backend.recorded(), vec![InjectedInput::Scroll { dx: 0, dy: 1 }]);
+
+        // Turning back drops what was left of the other direction
+        handler.handle_event(InputEvent::MouseWheel { delta_y: 100, delta_x: None, monitor_id: None }).unwrap();
+        handler.handle_event(InputEvent::MouseWheel { delta_y: 20, delta_x: None, monitor_id: None }).unwrap();
+        assert_eq!(backend.recorded()[1..], [InjectedInput::Scroll { dx: 0, dy: -1 }]);
+    }
+
+    /// `xmodmap -pk` excerpt of a US QWERTY host
+    const US_KEYMAP: &str = "\
+     11    \t0x0032 (2)\t0x0040 (at)\t0x0032 (2)\t0x0040 (at)
+     24    \t0x0071 (q)\t0x0051 (Q)\t0x0071 (q)\t0x0051 (Q)
+     38    \t0x0061 (a)\t0x0041 (A)\t0x0061 (a)\t0x0041 (A)
+     54    \t0x0063 (c)\t0x0043 (C)\t0x0063 (c)\t0x0043 (C)
+";
+
+    fn key_down(key: &str, code: &str, held: &[&str]) -> InputEvent {
+        InputEvent::KeyDown { key: key.to_string(), code: Some(code.to_string()), modifiers: modifiers(held), repeat: None }
+    }
+
+    fn key_up(key: &str, code: &str, held: &[&str]) -> InputEvent {
+        InputEvent::KeyUp { key: key.to_string(), code: Some(code.to_string()), modifiers: modifiers(held) }
+    }
+
+    #[test]
+    fn keysym_mode_reproduces_client_characters_on_host_layout() {
+        let (mut handler, backend) = recording_handler();
+        handler.set_host_layout(Some(HostLayout::from_xmodmap(Some("us".to_string()), US_KEYMAP)));
+
+        // An AZERTY client's A key sits where the host has Q
+        handler.handle_event(key_down("a", "KeyQ", &[])).unwrap();
+        handler.handle_event(key_up("a", "KeyQ", &[])).unwrap();
+        // The client's Shift is replaced by whatever the host layout needs
+        handler.handle_event(key_down("A", "KeyQ", &["Shift"])).unwrap();
+        handler.handle_event(key_up("a", "KeyQ", &[])).unwrap();
+        // AltGr+0 gives @ on AZERTY; the host needs Shift+2 and no AltGr
+        handler.handle_event(key_down("@", "Digit0", &["Control", "Alt"])).unwrap();
+        handler.handle_event(key_up("@", "Digit0", &["Control", "Alt"])).unwrap();
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::Raw(38)),
+            InjectedInput::KeyUp(Key::Raw(38)),
+            InjectedInput::KeyDown(Key::Shift),
+            InjectedInput::KeyDown(Key::Raw(38)),
+            InjectedInput::KeyUp(Key::Raw(38)),
+            InjectedInput::KeyUp(Key::Shift),
+            InjectedInput::KeyDown(Key::Shift),
+            InjectedInput::KeyDown(Key::Raw(11)),
+            InjectedInput::KeyUp(Key::Raw(11)),
+            InjectedInput::KeyUp(Key::Shift),
+        ]);
+    }
+
+    #[test]
+    fn keysym_mode_keeps_shortcuts_and_types_missing_characters() {
+        let (mut handler, backend) = recording_handler();
+        handler.set_host_layout(Some(HostLayout::from_xmodmap(None, US_KEYMAP)));
+
+        handler.handle_event(key_down("c", "KeyC", &["Control"])).unwrap();
+        handler.handle_event(key_down("é", "Digit2", &[])).unwrap();
+        handler.handle_event(key_up("é", "Digit2", &[])).unwrap();
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::Control),
+            InjectedInput::KeyDown(Key::Raw(54)),
+            InjectedInput::Text("é".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn scancode_mode_presses_physical_key_and_wraps_modifiers() {
+        let (mut handler, backend) = recording_handler();
+        handler.handle_event(InputEvent::SetKeyboardMode { mode: KeyboardMode::Scancode }).unwrap();
+        let physical_a = scancode_for_code("KeyA").or_else(|| key_for_code("KeyA")).unwrap();
+
+        handler.handle_event(InputEvent::KeyDown {
+            key: "q".to_string(),
+            code: Some("KeyA".to_string()),
+            modifiers: modifiers(&["Control", "Shift"]),
+            repeat: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::KeyUp {
+            key: "q".to_string(),
+            code: Some("KeyA".to_string()),
+            modifiers: modifiers(&["Control", "Shift"]),
+        }).unwrap();
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::Control),
+            InjectedInput::KeyDown(Key::Shift),
+            InjectedInput::KeyDown(physical_a),
+            InjectedInput::KeyUp(physical_a),
+            InjectedInput::KeyUp(Key::Control),
+            InjectedInput::KeyUp(Key::Shift),
+        ]);
+    }
+
+    #[test]
+    fn key_falls_back_to_key_name_when_code_is_unknown() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(InputEvent::KeyDown {
+            key: "F7".to_string(),
+            code: Some("IntlRo".to_string()),
+            modifiers: Vec::new(),
+            repeat: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::KeyUp { key: "F7".to_string(), code: None, modifiers: Vec::new() }).unwrap();
+        assert!(handler.handle_event(InputEvent::KeyDown {
+            key: "Unidentified".to_string(),
+            code: None,
+            modifiers: Vec::new(),
+            repeat: None,
+        }).is_err());
+        assert!(handler.handle_event(InputEvent::KeyDown {
+            key: "F8".to_string(),
+            code: None,
+            modifiers: modifiers(&["Hyper"]),
+            repeat: None,
+        }).is_err());
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::F7),
+            InjectedInput::KeyUp(Key::F7),
+        ]);
+    }
+
+    #[test]
+    fn composed_text_is_typed_once() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(InputEvent::KeyDown {
+            key: "日本".to_string(),
+            code: None,
+            modifiers: Vec::new(),
+            repeat: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::KeyUp { key: "日本".to_string(), code: None, modifiers: Vec::new() }).unwrap();
+
+        assert_eq!(backend.recorded(), vec![InjectedInput::Text("日本".to_string())]);
+    }
+
+    #[test]
+    fn text_events_type_unicode_verbatim() {
+        let (mut handler, backend) = recording_handler();
+
+        let event: InputEvent = serde_json::from_value(json!({ "type": "text", "text": "Grüße, 東京 🎉" })).unwrap();
+        assert_eq!(event.kind(), "text");
+        handler.handle_event(event).unwrap();
+        assert!(handler.handle_event(InputEvent::Text { text: "x".repeat(MAX_TEXT_CHARS + 1) }).is_err());
+
+        assert_eq!(backend.recorded(), vec![InjectedInput::Text("Grüße, 東京 🎉".to_string())]);
+    }
+
+    #[test]
+    fn early_key_repeats_are_suppressed() {
+        let (mut handler, backend) = recording_handler();
+        let key_down = |repeat| InputEvent::KeyDown {
+            key: "PageDown".to_string(),
+            code: None,
+            modifiers: Vec::new(),
+            repeat: Some(repeat),
+        };
+
+        handler.handle_event(key_down(false)).unwrap();
+        // Within the initial repeat delay
+        handler.handle_event(key_down(true)).unwrap();
+
+        assert_eq!(backend.recorded(), vec![InjectedInput::KeyDown(Key::PageDown)]);
+    }
+
+    #[test]
+    fn gestures_and_hotkeys_use_key_sequences() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(InputEvent::Gesture {
+            gesture_type: "pinch".to_string(),
+            scale: Some(1.5),
+            rotation: None,
+            delta_x: None,
+            delta_y: None,
+            monitor_id: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::HotKey { combination: modifiers(&["Alt", "Tab"]) }).unwrap();
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::Control),
+            InjectedInput::KeyDown(Key::Layout('+')),
+            InjectedInput::KeyUp(Key::Layout('+')),
+            InjectedInput::KeyUp(Key::Control),
+            InjectedInput::KeyDown(Key::Alt),
+            InjectedInput::KeyDown(Key::Tab),
+            InjectedInput::KeyUp(Key::Tab),
+            InjectedInput::KeyUp(Key::Alt),
+        ]);
+    }
+
+    #[test]
+    fn bad_key_leaves_no_modifier_held() {
+        let (mut handler, backend) = recording_handler();
+
+        assert!(handler.handle_event(InputEvent::KeyDown {
+            key: "Unidentified".to_string(),
+            code: None,
+            modifiers: modifiers(&["Control"]),
+            repeat: None,
+        }).is_err());
+        handler.release_all();
+
+        assert!(backend.recorded().is_empty());
+    }
+
+    #[test]
+    fn release_all_lets_go_of_held_input_most_recent_first() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(InputEvent::MouseDown { button: "left".to_string(), x: 1, y: 2, monitor_id: None }).unwrap();
+        handler.handle_event(InputEvent::KeyDown {
+            key: "x".to_string(),
+            code: Some("KeyX".to_string()),
+            modifiers: modifiers(&["Alt"]),
+            repeat: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::KeyDown {
+            key: "F2".to_string(),
+            code: Some("F2".to_string()),
+            modifiers: Vec::new(),
+            repeat: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::KeyUp { key: "F2".to_string(), code: Some("F2".to_string()), modifiers: Vec::new() }).unwrap();
+        handler.handle_event(InputEvent::ReleaseAll).unwrap();
+
+        assert_eq!(&backend.recorded()[6..], &[
+            InjectedInput::ButtonUp(MouseButton::Left),
+            InjectedInput::KeyUp(Key::Layout('x')),
+            InjectedInput::KeyUp(Key::Alt),
+        ]);
+
+        // Nothing is left to release a second time
+        handler.release_all();
+        assert_eq!(backend.recorded().len(), 9);
+    }
+
+    #[test]
+    fn dropping_handler_releases_held_input() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(InputEvent::KeyDown {
+            key: "Control".to_string(),
+            code: Some("ControlLeft".to_string()),
+            modifiers: modifiers(&["Control"]),
+            repeat: None,
+        }).unwrap();
+        handler.handle_event(InputEvent::MouseDown { button: "middle".to_string(), x: 0, y: 0, monitor_id: None }).unwrap();
+        drop(handler);
+
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::Control),
+            InjectedInput::KeyDown(Key::Control),
+            InjectedInput::MoveTo(0, 0),
+            InjectedInput::ButtonDown(MouseButton::Middle),
+            InjectedInput::ButtonUp(MouseButton::Middle),
+            InjectedInput::KeyUp(Key::Control),
+        ]);
+    }
+
+    #[test]
+    fn dispatcher_releases_when_session_ends() {
+        let backend = RecordingBackend::new();
+        let (control_tx, _control_rx) = mpsc::channel(4);
+        let dispatcher = InputDispatcher::spawn(InputHandler::with_backend(Box::new(backend.clone())), control_tx).unwrap();
+
+        dispatcher.dispatch(InputEvent::MouseDown { button: "right".to_string(), x: 3, y: 4, monitor_id: None }).unwrap();
+        dispatcher.release_all();
+
+        let released = InjectedInput::ButtonUp(MouseButton::Right);
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !backend.recorded().contains(&released) {
+            assert!(Instant::now() < deadline, "input thread never released the button");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn type_text(text: &str) -> InputEvent {
+        InputEvent::TypeText { text: text.to_string(), delay_ms: Some(0) }
+    }
+
+    #[test]
+    fn type_text_presses_host_keys_one_character_per_step() {
+        let (mut handler, backend) = recording_handler();
+        handler.set_host_layout(Some(HostLayout::from_xmodmap(None, US_KEYMAP)));
+
+        handler.handle_event(type_text("aA\r\né")).unwrap();
+        assert_eq!(handler.take_typing_progress(), Some(TypingProgress { typed: 0, total: 4, state: TypingState::Typing }));
+        assert!(handler.handle_event(type_text("more")).is_err());
+        while handler.typing_deadline().is_some() {
+            handler.type_next_char();
+        }
+
+        assert_eq!(handler.take_typing_progress(), Some(TypingProgress { typed: 4, total: 4, state: TypingState::Done }));
+        assert!(handler.held_keys.is_empty());
+        assert_eq!(backend.recorded(), vec![
+            InjectedInput::KeyDown(Key::Raw(38)),
+            InjectedInput::KeyUp(Key::Raw(38)),
+            InjectedInput::KeyDown(Key::Shift),
+            InjectedInput::KeyDown(Key::Raw(38)),
+            InjectedInput::KeyUp(Key::Raw(38)),
+            InjectedInput::KeyUp(Key::Shift),
+            InjectedInput::KeyDown(Key::Return),
+            InjectedInput::KeyUp(Key::Return),
+            InjectedInput::Text("é".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn cancelled_typing_stops_and_reports_where_it_got_to() {
+        let (mut handler, backend) = recording_handler();
+
+        handler.handle_event(type_text("password")).unwrap();
+        handler.type_next_char();
+        handler.type_next_char();
+        handler.handle_event(InputEvent::CancelTyping).unwrap();
+        handler.type_next_char();
+
+        assert_eq!(handler.typing_deadline(), None);
+        assert_eq!(handler.take_typing_progress(), Some(TypingProgress { typed: 2, total: 8, state: TypingState::Cancelled }));
+        assert_eq!(backend.recorded(), vec![InjectedInput::Text("p".to_string()), InjectedInput::Text("a".to_string())]);
+        assert!(handler.handle_event(type_text(&"x".repeat(MAX_TYPED_CHARS + 1))).is_err());
+    }
+
+    #[test]
+    fn final_typing_report_waits_for_a_full_channel() {
+        let backend = RecordingBackend::new();
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        control_tx.try_send("earlier message".to_string()).unwrap();
+        let dispatcher = InputDispatcher::spawn(InputHandler::with_backend(Box::new(backend.clone())), control_tx).unwrap();
+
+        dispatcher.dispatch(type_text("ab")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while backend.recorded().len() < 2 {
+            assert!(Instant::now() < deadline, "typing never finished");
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // Progress while typing was dropped, the final report was not
+        assert_eq!(control_rx.try_recv().unwrap(), "earlier message");
+        let done = loop {
+            match control_rx.try_recv() {
+                Ok(message) => break message,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                Err(e) => panic!("final report never arrived: {:?}", e),
+            }
+        };
+        let done: serde_json::Value = serde_json::from_str(&done).unwrap();
+        assert_eq!(done, json!({ "type": "typing_progress", "typed": 2, "total": 2, "state": "done" }));
+    }
+
+    #[test]
+    fn dispatcher_paces_typing_and_reports_progress() {
+        let backend = RecordingBackend::new();
+        let (control_tx, mut control_rx) = mpsc::channel(16);
+        let dispatcher = InputDispatcher::spawn(InputHandler::with_backend(Box::new(backend.clone())), control_tx).unwrap();
+
+        dispatcher.dispatch(InputEvent::TypeText { text: "abc".to_string(), delay_ms: Some(5) }).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut messages = Vec::new();
+        while !messages.iter().any(|m: &String| m.contains("\"done\"")) {
+            assert!(Instant::now() < deadline, "typing never finished");
+            match control_rx.try_recv() {
+                Ok(message) => messages.push(message),
+                Err(_) => thread::sleep(Duration::from_millis(5)),
+            }
+        }
+        assert!(messages[0].contains("\"typing\""));
+        assert_eq!(backend.recorded().len(), 3);
+    }
+
+    #[test]
+    fn map_key_handles_names_characters_and_numpad() {
+        let (handler, _) = recording_handler();
+        assert_eq!(handler.map_key("Enter"), Ok(Key::Return));
+        assert_eq!(handler.map_key("Command"), Ok(Key::Meta));
+        assert_eq!(handler.map_key("ß"), Ok(Key::Layout('ß')));
+        assert_eq!(handler.map_key("Numpad7"), Ok(Key::Layout('7')));
+        assert_eq!(handler.map_key("NumpadDivide"), Ok(Key::Layout('/')));
+        assert_eq!(handler.map_key("DeadCircumflex"), Ok(Key::Layout('^')));
+        assert_eq!(handler.map_key("F13"), Ok(Key::F13));
+        assert!(handler.map_key("Insert").is_ok());
+        assert!(handler.map_key("DeadHook").is_err());
+        assert!(handler.map_key("Unidentified").is_err());
+    }
+
+    #[test]
+    fn map_key_code_is_layout_independent() {
+        let (handler, _) = recording_handler();
+        assert_eq!(handler.map_key_code("KeyZ"), Ok(Key::Layout('z')));
+        assert_eq!(handler.map_key_code("Digit3"), Ok(Key::Layout('3')));
+        assert_eq!(handler.map_key_code("ArrowLeft"), Ok(Key::LeftArrow));
+        assert_eq!(handler.map_key_code("ShiftRight"), Ok(Key::Shift));
+        assert_eq!(handler.map_key_code("NumpadEnter"), Ok(Key::Return));
+        assert_eq!(handler.map_key_code("Quote"), Ok(Key::Layout('\'')));
+        assert!(handler.map_key_code("Insert").is_ok());
+        assert!(handler.map_key_code("PrintScreen").is_ok());
+        assert!(handler.map_key_code("Key1").is_err());
+        assert!(handler.map_key_code("IntlRo").is_err());
+    }
+}
@@ -0,0 +1,471 @@
+//! DOM `KeyboardEvent.code` values mapped to host keys.
+//!
+//! `code` names the physical key, so it reaches keys whose `key` value depends on the
+//! layout, NumLock or modifiers: the numpad, Insert, PrintScreen and friends. Covers the
+//! standard 104/105-key layout everywhere, plus media and browser keys where the host
+//! has them. enigo only names some of these keys on each platform; the rest are sent
+//! as raw key codes (X keycodes on Linux, virtual key codes on macOS).
+//!
+//! See: https://developer.mozilla.org/en-US/docs/Web/API/UI_Events/Keyboard_event_code_values
+
+use enigo::Key;
+
+/// Host key for a DOM `KeyboardEvent.code`, or `None` if this platform cannot send it
+pub fn key_for_code(code: &str) -> Option<Key> {
+    common_key(code).or_else(|| platform_key(code))
+}
+
+/// Keys every platform can send the same way
+fn common_key(code: &str) -> Option<Key> {
+    // Key codes for letters (KeyA through KeyZ)
+    if code.len() == 4 && code.starts_with("Key") {
+        let ch = code.chars().nth(3).unwrap();
+        if ch.is_ascii_uppercase() {
+            return Some(Key::Layout(ch.to_ascii_lowercase()));
+        }
+    }
+
+    // Digit keys (Digit0 through Digit9)
+    if code.len() == 6 && code.starts_with("Digit") {
+        let ch = code.chars().nth(5).unwrap();
+        if ch.is_ascii_digit() {
+            return Some(Key::Layout(ch));
+        }
+    }
+
+    let key = match code {
+        "F1" => Key::F1,
+        "F2" => Key::F2,
+        "F3" => Key::F3,
+        "F4" => Key::F4,
+        "F5" => Key::F5,
+        "F6" => Key::F6,
+        "F7" => Key::F7,
+        "F8" => Key::F8,
+        "F9" => Key::F9,
+        "F10" => Key::F10,
+        "F11" => Key::F11,
+        "F12" => Key::F12,
+        "F13" => Key::F13,
+        "F14" => Key::F14,
+        "F15" => Key::F15,
+        "F16" => Key::F16,
+        "F17" => Key::F17,
+        "F18" => Key::F18,
+        "F19" => Key::F19,
+        "F20" => Key::F20,
+        // Navigation
+        "Home" => Key::Home,
+        "End" => Key::End,
+        "PageUp" => Key::PageUp,
+        "PageDown" => Key::PageDown,
+        "Delete" => Key::Delete,
+        "ArrowUp" => Key::UpArrow,
+        "ArrowDown" => Key::DownArrow,
+        "ArrowLeft" => Key::LeftArrow,
+        "ArrowRight" => Key::RightArrow,
+        // Common keys
+        "Backspace" => Key::Backspace,
+        "Tab" => Key::Tab,
+        "Enter" | "NumpadEnter" => Key::Return,
+        "Escape" => Key::Escape,
+        "Space" => Key::Space,
+        "CapsLock" => Key::CapsLock,
+        // Modifiers share one key per side so they pair with the event's modifier list
+        "ControlLeft" | "ControlRight" => Key::Control,
+        "AltLeft" | "AltRight" => Key::Alt,
+        "ShiftLeft" | "ShiftRight" => Key::Shift,
+        "MetaLeft" | "MetaRight" | "OSLeft" | "OSRight" => Key::Meta,
+        // Punctuation
+        "Backquote" => Key::Layout('`'),
+        "Minus" => Key::Layout('-'),
+        "Equal" => Key::Layout('='),
+        "BracketLeft" => Key::Layout('['),
+        "BracketRight" => Key::Layout(']'),
+        "Backslash" => Key::Layout('\\'),
+        "Semicolon" => Key::Layout(';'),
+        "Quote" => Key::Layout('\''),
+        "Comma" => Key::Layout(','),
+        "Period" => Key::Layout('.'),
+        "Slash" => Key::Layout('/'),
+        // Volume
+        "AudioVolumeUp" => Key::VolumeUp,
+        "AudioVolumeDown" => Key::VolumeDown,
+        "AudioVolumeMute" => Key::VolumeMute,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+#[cfg(target_os = "windows")]
+fn platform_key(code: &str) -> Option<Key> {
+    let key = match code {
+        "Insert" => Key::Insert,
+        "NumLock" => Key::Numlock,
+        "ScrollLock" => Key::Scroll,
+        "PrintScreen" => Key::Snapshot,
+        "Pause" => Key::Pause,
+        "ContextMenu" => Key::Apps,
+        "IntlBackslash" => Key::OEM102,
+        "F21" => Key::F21,
+        "F22" => Key::F22,
+        "F23" => Key::F23,
+        "F24" => Key::F24,
+        // Numpad
+        "Numpad0" => Key::Numpad0,
+        "Numpad1" => Key::Numpad1,
+        "Numpad2" => Key::Numpad2,
+        "Numpad3" => Key::Numpad3,
+        "Numpad4" => Key::Numpad4,
+        "Numpad5" => Key::Numpad5,
+        "Numpad6" => Key::Numpad6,
+        "Numpad7" => Key::Numpad7,
+        "Numpad8" => Key::Numpad8,
+        "Numpad9" => Key::Numpad9,
+        "NumpadAdd" => Key::Add,
+        "NumpadSubtract" => Key::Subtract,
+        "NumpadMultiply" => Key::Multiply,
+        "NumpadDivide" => Key::Divide,
+        "NumpadDecimal" => Key::Decimal,
+        "NumpadComma" => Key::Separator,
+        "NumpadEqual" => Key::Layout('='),
+        // Media and browser keys
+        "MediaPlayPause" => Key::MediaPlayPause,
+        "MediaStop" => Key::MediaStop,
+        "MediaTrackNext" => Key::MediaNextTrack,
+        "MediaTrackPrevious" => Key::MediaPrevTrack,
+        "MediaSelect" => Key::LaunchMediaSelect,
+        "LaunchMail" => Key::LaunchMail,
+        "LaunchApp1" => Key::LaunchApp1,
+        "LaunchApp2" => Key::LaunchApp2,
+        "BrowserBack" => Key::BrowserBack,
+        "BrowserForward" => Key::BrowserForward,
+        "BrowserRefresh" => Key::BrowserRefresh,
+        "BrowserStop" => Key::BrowserStop,
+        "BrowserSearch" => Key::BrowserSearch,
+        "BrowserFavorites" => Key::BrowserFavorites,
+        "BrowserHome" => Key::BrowserHome,
+        "Sleep" => Key::Sleep,
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// xdo accepts a bare number as an X keycode (evdev code + 8), which reaches keys
+/// that have no layout-independent keysym
+#[cfg(target_os = "linux")]
+fn platform_key(code: &str) -> Option<Key> {
+    let key = match code {
+        "Insert" => Key::Insert,
+        "NumLock" => Key::Numlock,
+        // enigo's keysym name for ScrollLock is misspelled, so send the keycode
+        "ScrollLock" => Key::Raw(78),
+        "PrintScreen" => Key::Print,
+        "Pause" => Key::Pause,
+        "ContextMenu" => Key::Raw(135),
+        "IntlBackslash" => Key::Raw(94),
+        "F21" => Key::F21,
+        "F22" => Key::F22,
+        "F23" => Key::F23,
+        "F24" => Key::F24,
+        // Numpad keycodes follow the host's NumLock state, like a physical keypad
+        "Numpad0" => Key::Raw(90),
+        "Numpad1" => Key::Raw(87),
+        "Numpad2" => Key::Raw(88),
+        "Numpad3" => Key::Raw(89),
+        "Numpad4" => Key::Raw(83),
+        "Numpad5" => Key::Raw(84),
+        "Numpad6" => Key::Raw(85),
+        "Numpad7" => Key::Raw(79),
+        "Numpad8" => Key::Raw(80),
+        "Numpad9" => Key::Raw(81),
+        "NumpadAdd" => Key::Raw(86),
+        "NumpadSubtract" => Key::Raw(82),
+        "NumpadMultiply" => Key::Raw(63),
+        "NumpadDivide" => Key::Raw(106),
+        "NumpadDecimal" => Key::Raw(91),
+        "NumpadComma" => Key::Raw(129),
+        "NumpadEqual" => Key::Raw(125),
+        // Media and browser keys
+        "MediaPlayPause" => Key::MediaPlayPause,
+        "MediaStop" => Key::MediaStop,
+        "MediaTrackNext" => Key::MediaNextTrack,
+        "MediaTrackPrevious" => Key::MediaPrevTrack,
+        "MediaSelect" => Key::Raw(234),
+        "LaunchMail" => Key::Raw(163),
+        "LaunchApp1" => Key::Raw(165),
+        "LaunchApp2" => Key::Raw(148),
+        "BrowserBack" => Key::Raw(166),
+        "BrowserForward" => Key::Raw(167),
+        "BrowserRefresh" => Key::Raw(181),
+        "BrowserStop" => Key::Raw(136),
+        "BrowserSearch" => Key::Raw(225),
+        "BrowserFavorites" => Key::Raw(164),
+        "BrowserHome" => Key::Raw(180),
+        "Sleep" => Key::Raw(150),
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+/// Virtual key codes from Carbon's Events.h. Mac keyboards put Help where Insert is,
+/// Clear where NumLock is, and F13-F15 above the arrows where PC keyboards have
+/// PrintScreen, ScrollLock and Pause. Media keys are system events, not key codes.
+#[cfg(target_os = "macos")]
+fn platform_key(code: &str) -> Option<Key> {
+    let key = match code {
+        "Insert" | "Help" => Key::Raw(0x72),
+        "NumLock" => Key::Raw(0x47),
+        "PrintScreen" => Key::F13,
+        "ScrollLock" => Key::F14,
+        "Pause" => Key::F15,
+        "ContextMenu" => Key::Raw(0x6E),
+        "IntlBackslash" => Key::Raw(0x0A),
+        // Numpad
+        "Numpad0" => Key::Raw(0x52),
+        "Numpad1" => Key::Raw(0x53),
+        "Numpad2" => Key::Raw(0x54),
+        "Numpad3" => Key::Raw(0x55),
+        "Numpad4" => Key::Raw(0x56),
+        "Numpad5" => Key::Raw(0x57),
+        "Numpad6" => Key::Raw(0x58),
+        "Numpad7" => Key::Raw(0x59),
+        "Numpad8" => Key::Raw(0x5B),
+        "Numpad9" => Key::Raw(0x5C),
+        "NumpadAdd" => Key::Raw(0x45),
+        "NumpadSubtract" => Key::Raw(0x4E),
+        "NumpadMultiply" => Key::Raw(0x43),
+        "NumpadDivide" => Key::Raw(0x4B),
+        "NumpadDecimal" => Key::Raw(0x41),
+        "NumpadComma" => Key::Raw(0x5F),
+        "NumpadEqual" => Key::Raw(0x51),
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+fn platform_key(_code: &str) -> Option<Key> {
+    None
+}
+
+/// Key at the same physical position for keys whose character depends on the layout
+/// (letters, digits and punctuation), or `None` where the platform has no positional
+/// codes. enigo on Windows only sends virtual keys, which follow the host layout, so
+/// Windows hosts fall back to `key_for_code`.
+pub fn scancode_for_code(code: &str) -> Option<Key> {
+    positional_keycode(code).map(Key::Raw)
+}
+
+/// X keycodes (evdev code + 8) of the alphanumeric block
+#[cfg(target_os = "linux")]
+fn positional_keycode(code: &str) -> Option<u16> {
+    let keycode = match code {
+        "Backquote" => 49,
+        "Digit1" => 10,
+        "Digit2" => 11,
+        "Digit3" => 12,
+        "Digit4" => 13,
+        "Digit5" => 14,
+        "Digit6" => 15,
+        "Digit7" => 16,
+        "Digit8" => 17,
+        "Digit9" => 18,
+        "Digit0" => 19,
+        "Minus" => 20,
+        "Equal" => 21,
+        "KeyQ" => 24,
+        "KeyW" => 25,
+        "KeyE" => 26,
+        "KeyR" => 27,
+        "KeyT" => 28,
+        "KeyY" => 29,
+        "KeyU" => 30,
+        "KeyI" => 31,
+        "KeyO" => 32,
+        "KeyP" => 33,
+        "BracketLeft" => 34,
+        "BracketRight" => 35,
+        "KeyA" => 38,
+        "KeyS" => 39,
+        "KeyD" => 40,
+        "KeyF" => 41,
+        "KeyG" => 42,
+        "KeyH" => 43,
+        "KeyJ" => 44,
+        "KeyK" => 45,
+        "KeyL" => 46,
+        "Semicolon" => 47,
+        "Quote" => 48,
+        "Backslash" => 51,
+        "KeyZ" => 52,
+        "KeyX" => 53,
+        "KeyC" => 54,
+        "KeyV" => 55,
+        "KeyB" => 56,
+        "KeyN" => 57,
+        "KeyM" => 58,
+        "Comma" => 59,
+        "Period" => 60,
+        "Slash" => 61,
+        "IntlBackslash" => 94,
+        _ => return None,
+    };
+
+    Some(keycode)
+}
+
+/// `kVK_ANSI_*` virtual key codes, which name positions on the ANSI layout
+#[cfg(target_os = "macos")]
+fn positional_keycode(code: &str) -> Option<u16> {
+    let keycode = match code {
+        "Backquote" => 0x32,
+        "Digit1" => 0x12,
+        "Digit2" => 0x13,
+        "Digit3" => 0x14,
+        "Digit4" => 0x15,
+        "Digit5" => 0x17,
+        "Digit6" => 0x16,
+        "Digit7" => 0x1A,
+        "Digit8" => 0x1C,
+        "Digit9" => 0x19,
+        "Digit0" => 0x1D,
+        "Minus" => 0x1B,
+        "Equal" => 0x18,
+        "KeyQ" => 0x0C,
+        "KeyW" => 0x0D,
+        "KeyE" => 0x0E,
+        "KeyR" => 0x0F,
+        "KeyT" => 0x11,
+        "KeyY" => 0x10,
+        "KeyU" => 0x20,
+        "KeyI" => 0x22,
+        "KeyO" => 0x1F,
+        "KeyP" => 0x23,
+        "BracketLeft" => 0x21,
+        "BracketRight" => 0x1E,
+        "KeyA" => 0x00,
+        "KeyS" => 0x01,
+        "KeyD" => 0x02,
+        "KeyF" => 0x03,
+        "KeyG" => 0x05,
+        "KeyH" => 0x04,
+        "KeyJ" => 0x26,
+        "KeyK" => 0x28,
+        "KeyL" => 0x25,
+        "Semicolon" => 0x29,
+        "Quote" => 0x27,
+        "Backslash" => 0x2A,
+        "KeyZ" => 0x06,
+        "KeyX" => 0x07,
+        "KeyC" => 0x08,
+        "KeyV" => 0x09,
+        "KeyB" => 0x0B,
+        "KeyN" => 0x2D,
+        "KeyM" => 0x2E,
+        "Comma" => 0x2B,
+        "Period" => 0x2F,
+        "Slash" => 0x2C,
+        "IntlBackslash" => 0x0A,
+        _ => return None,
+    };
+
+    Some(keycode)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn positional_keycode(_code: &str) -> Option<u16> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every physical key on a full-size 105-key ISO board (the 104-key ANSI board
+    /// lacks only IntlBackslash)
+    const STANDARD_105_KEY_CODES: &[&str] = &[
+        "Escape", "F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+        "PrintScreen", "ScrollLock", "Pause",
+        "Backquote", "Digit1", "Digit2", "Digit3", "Digit4", "Digit5", "Digit6", "Digit7",
+        "Digit8", "Digit9", "Digit0", "Minus", "Equal", "Backspace",
+        "Tab", "KeyQ", "KeyW", "KeyE", "KeyR", "KeyT", "KeyY", "KeyU", "KeyI", "KeyO", "KeyP",
+        "BracketLeft", "BracketRight", "Backslash",
+        "CapsLock", "KeyA", "KeyS", "KeyD", "KeyF", "KeyG", "KeyH", "KeyJ", "KeyK", "KeyL",
+        "Semicolon", "Quote", "Enter",
+        "ShiftLeft", "IntlBackslash", "KeyZ", "KeyX", "KeyC", "KeyV", "KeyB", "KeyN", "KeyM",
+        "Comma", "Period", "Slash", "ShiftRight",
+        "ControlLeft", "MetaLeft", "AltLeft", "Space", "AltRight", "MetaRight", "ContextMenu",
+        "ControlRight",
+        "Insert", "Home", "PageUp", "Delete", "End", "PageDown",
+        "ArrowUp", "ArrowLeft", "ArrowDown", "ArrowRight",
+        "NumLock", "NumpadDivide", "NumpadMultiply", "NumpadSubtract",
+        "Numpad7", "Numpad8", "Numpad9", "NumpadAdd",
+        "Numpad4", "Numpad5", "Numpad6",
+        "Numpad1", "Numpad2", "Numpad3", "NumpadEnter",
+        "Numpad0", "NumpadDecimal",
+    ];
+
+    #[test]
+    fn standard_layout_is_fully_mapped() {
+        assert_eq!(STANDARD_105_KEY_CODES.len(), 105);
+        let missing: Vec<_> = STANDARD_105_KEY_CODES.iter()
+            .filter(|code| key_for_code(code).is_none())
+            .collect();
+        assert!(missing.is_empty(), "unmapped key codes: {:?}", missing);
+    }
+
+    #[test]
+    fn letters_and_digits_use_layout_characters() {
+        assert_eq!(key_for_code("KeyQ"), Some(Key::Layout('q')));
+        assert_eq!(key_for_code("Digit0"), Some(Key::Layout('0')));
+        assert_eq!(key_for_code("Keya"), None);
+        assert_eq!(key_for_code("Key1"), None);
+        assert_eq!(key_for_code("DigitX"), None);
+    }
+
+    #[test]
+    fn unknown_codes_are_not_mapped() {
+        assert_eq!(key_for_code(""), None);
+        assert_eq!(key_for_code("Unidentified"), None);
+        assert_eq!(key_for_code("Hyper"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_numpad_uses_keypad_keycodes() {
+        assert_eq!(key_for_code("Numpad7"), Some(Key::Raw(79)));
+        assert_eq!(key_for_code("Numpad0"), Some(Key::Raw(90)));
+        assert_eq!(key_for_code("NumpadDecimal"), Some(Key::Raw(91)));
+        assert_eq!(key_for_code("ScrollLock"), Some(Key::Raw(78)));
+        assert_eq!(key_for_code("PrintScreen"), Some(Key::Print));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_scancodes_follow_physical_positions() {
+        assert_eq!(scancode_for_code("KeyQ"), Some(Key::Raw(24)));
+        assert_eq!(scancode_for_code("KeyA"), Some(Key::Raw(38)));
+        assert_eq!(scancode_for_code("Digit0"), Some(Key::Raw(19)));
+        // Keys that mean the same on every layout are not positional
+        assert_eq!(scancode_for_code("Enter"), None);
+        assert_eq!(scancode_for_code("Numpad1"), None);
+    }
+
+    #[cfg(any(target_os = "windows", target_os = "linux"))]
+    #[test]
+    fn media_and_browser_keys_are_mapped() {
+        for code in [
+            "MediaPlayPause", "MediaStop", "MediaTrackNext", "MediaTrackPrevious",
+            "AudioVolumeUp", "AudioVolumeDown", "AudioVolumeMute",
+            "BrowserBack", "BrowserForward", "BrowserRefresh", "BrowserHome",
+            "F13", "F24",
+        ] {
+            assert!(key_for_code(code).is_some(), "{} is not mapped", code);
+        }
+    }
+}
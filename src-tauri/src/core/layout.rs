@@ -0,0 +1,229 @@
+//! Keyboard layout translation between client and host.
+//!
+//! Each session picks how keys are forwarded. `Scancode` presses the key at the same
+//! physical position, so the host layout decides the character. `Keysym` presses
+//! whatever produces the client's character on the host layout, so an AZERTY client
+//! types correctly on a QWERTY host. On Linux/X11 the host layout is read from the
+//! X keymap; elsewhere enigo's own per-character layout lookup is used.
+
+use enigo::Key;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// X keysym of the AltGr modifier
+const ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+
+/// How a session's key events reach the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyboardMode {
+    /// Forward physical key positions
+    Scancode,
+    /// Reproduce the client's characters on the host layout
+    #[default]
+    Keysym,
+}
+
+/// Key and shift level that produce a character on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStroke {
+    pub keycode: u16,
+    pub shift: bool,
+    pub level3: bool,
+}
+
+impl KeyStroke {
+    /// Modifiers needed, used to prefer the simplest way to type a character
+    fn cost(&self) -> u8 {
+        self.shift as u8 + 2 * self.level3 as u8
+    }
+}
+
+/// Characters of the host's active keyboard layout
+#[derive(Debug, Clone, Default)]
+pub struct HostLayout {
+    name: Option<String>,
+    strokes: HashMap<char, KeyStroke>,
+    level3_keycode: Option<u16>,
+}
+
+impl HostLayout {
+    /// Read the active layout from the X server. `None` on other platforms, or when
+    /// the keymap cannot be read (no X server, `xmodmap` missing).
+    #[cfg(target_os = "linux")]
+    pub fn detect() -> Option<Self> {
+        use std::process::Command;
+
+        let output = Command::new("xmodmap").arg("-pk").output().ok()?;
+        if !output.status.success() {
+            debug!("xmodmap failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+            return None;
+        }
+
+        let name = Command::new("setxkbmap")
+            .arg("-query")
+            .output()
+            .ok()
+            .and_then(|query| parse_setxkbmap_layout(&String::from_utf8_lossy(&query.stdout)));
+
+        let layout = Self::from_xmodmap(name, &String::from_utf8_lossy(&output.stdout));
+        if layout.strokes.is_empty() {
+            return None;
+        }
+        Some(layout)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn detect() -> Option<Self> {
+        None
+    }
+
+    /// Build the character table from `xmodmap -pk` output
+    pub fn from_xmodmap(name: Option<String>, keymap: &str) -> Self {
+        let mut layout = Self { name, ..Self::default() };
+
+        for line in keymap.lines() {
+            let mut fields = line.split_whitespace();
+            let Some(keycode) = fields.next().and_then(|k| k.parse::<u16>().ok()) else {
+                continue;
+            };
+            let keysyms: Vec<u32> = fields
+                .filter_map(|f| f.strip_prefix("0x"))
+                .filter_map(|hex| u32::from_str_radix(hex, 16).ok())
+                .collect();
+
+            if keysyms.first() == Some(&ISO_LEVEL3_SHIFT) && layout.level3_keycode.is_none() {
+                layout.level3_keycode = Some(keycode);
+            }
+
+            // Columns are group 1 levels 1-2, group 2 levels 1-2, then group 1 levels 3-4
+            for (column, shift, level3) in [(0, false, false), (1, true, false), (4, false, true), (5, true, true)] {
+                let Some(ch) = keysyms.get(column).copied().and_then(keysym_to_char) else {
+                    continue;
+                };
+                let stroke = KeyStroke { keycode, shift, level3 };
+                let known_simpler = matches!(layout.strokes.get(&ch), Some(existing) if existing.cost() <= stroke.cost());
+                if !known_simpler {
+                    layout.strokes.insert(ch, stroke);
+                }
+            }
+        }
+
+        debug!("Host layout {:?}: {} characters", layout.name, layout.strokes.len());
+        layout
+    }
+
+    /// Layout name reported by the host, e.g. "us" or "fr,us"
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Keys to hold, in press order, to type `ch`; `None` if the layout cannot produce it
+    pub fn keys_for(&self, ch: char) -> Option<Vec<Key>> {
+        let stroke = self.strokes.get(&ch)?;
+        let mut keys = Vec::with_capacity(3);
+        if stroke.shift {
+            keys.push(Key::Shift);
+        }
+        if stroke.level3 {
+            keys.push(Key::Raw(self.level3_keycode?));
+        }
+        keys.push(Key::Raw(stroke.keycode));
+        Some(keys)
+    }
+}
+
+/// Printable character for an X keysym, if it stands for one
+fn keysym_to_char(keysym: u32) -> Option<char> {
+    let codepoint = match keysym {
+        // Latin-1 keysyms equal their code points
+        0x20..=0x7e | 0xa0..=0xff => keysym,
+        // Unicode keysyms
+        0x0100_0100..=0x0110_ffff => keysym - 0x0100_0000,
+        0x20ac => 0x20ac, // EuroSign
+        _ => return None,
+    };
+    char::from_u32(codepoint).filter(|c| !c.is_control())
+}
+
+/// `layout:` line of `setxkbmap -query`
+fn parse_setxkbmap_layout(query: &str) -> Option<String> {
+    query.lines()
+        .find_map(|line| line.strip_prefix("layout:"))
+        .map(|layout| layout.trim().to_string())
+        .filter(|layout| !layout.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Excerpt of `xmodmap -pk` on a French AZERTY host
+    const FRENCH_KEYMAP: &str = "\
+There are 7 KeySyms per KeyCode; KeyCodes range from 8 to 255.
+
+    KeyCode\tKeysym (Keysym)\t...
+    Value  \tValue   (Name) \t...
+
+      9    \t0xff1b (Escape)\t0x0000 (NoSymbol)\t0xff1b (Escape)
+     10    \t0x0026 (ampersand)\t0x0031 (1)\t0x0026 (ampersand)\t0x0031 (1)\t0x00b9 (onesuperior)\t0x00a1 (exclamdown)
+     19    \t0x00e0 (agrave)\t0x0030 (0)\t0x00e0 (agrave)\t0x0030 (0)\t0x0040 (at)\t0x00b0 (degree)
+     24    \t0x0061 (a)\t0x0041 (A)\t0x0061 (a)\t0x0041 (A)\t0x00e6 (ae)\t0x00c6 (AE)
+     26    \t0x0065 (e)\t0x0045 (E)\t0x0065 (e)\t0x0045 (E)\t0x20ac (EuroSign)\t0x00a2 (cent)
+     58    \t0x002c (comma)\t0x003f (question)\t0x002c (comma)\t0x003f (question)\t0x1002019 (U2019)\t0x00bf (questiondown)
+     65    \t0x0020 (space)\t0x0020 (space)\t0x0020 (space)\t0x0020 (space)
+     87    \t0xff9c (KP_End)\t0xffb1 (KP_1)\t0xff9c (KP_End)\t0xffb1 (KP_1)
+     92    \t0xfe03 (ISO_Level3_Shift)\t0x0000 (NoSymbol)
+    108    \t0xfe03 (ISO_Level3_Shift)\t0x0000 (NoSymbol)\t0xfe03 (ISO_Level3_Shift)
+";
+
+    fn french() -> HostLayout {
+        HostLayout::from_xmodmap(Some("fr".to_string()), FRENCH_KEYMAP)
+    }
+
+    #[test]
+    fn characters_resolve_to_keycode_and_level() {
+        let layout = french();
+        assert_eq!(layout.name(), Some("fr"));
+        assert_eq!(layout.keys_for('a'), Some(vec![Key::Raw(24)]));
+        assert_eq!(layout.keys_for('A'), Some(vec![Key::Shift, Key::Raw(24)]));
+        assert_eq!(layout.keys_for('1'), Some(vec![Key::Shift, Key::Raw(10)]));
+        assert_eq!(layout.keys_for('@'), Some(vec![Key::Raw(92), Key::Raw(19)]));
+        assert_eq!(layout.keys_for('€'), Some(vec![Key::Raw(92), Key::Raw(26)]));
+        assert_eq!(layout.keys_for('\u{2019}'), Some(vec![Key::Raw(92), Key::Raw(58)]));
+        assert_eq!(layout.keys_for(' '), Some(vec![Key::Raw(65)]));
+    }
+
+    #[test]
+    fn unknown_and_non_printing_keysyms_are_skipped() {
+        let layout = french();
+        assert_eq!(layout.keys_for('z'), None);
+        assert_eq!(layout.keys_for('東'), None);
+        assert_eq!(keysym_to_char(0xff1b), None);
+        assert_eq!(keysym_to_char(0xffb1), None);
+        assert_eq!(keysym_to_char(0x1f), None);
+    }
+
+    #[test]
+    fn level3_characters_need_an_altgr_key() {
+        let keymap: String = FRENCH_KEYMAP.lines().filter(|l| !l.contains("ISO_Level3_Shift")).collect::<Vec<_>>().join("\n");
+        let layout = HostLayout::from_xmodmap(None, &keymap);
+        assert_eq!(layout.keys_for('@'), None);
+        assert_eq!(layout.keys_for('a'), Some(vec![Key::Raw(24)]));
+    }
+
+    #[test]
+    fn setxkbmap_layout_is_parsed() {
+        let query = "rules:      evdev\nmodel:      pc105\nlayout:     fr,us\nvariant:    ,\n";
+        assert_eq!(parse_setxkbmap_layout(query), Some("fr,us".to_string()));
+        assert_eq!(parse_setxkbmap_layout("rules: evdev\n"), None);
+    }
+
+    #[test]
+    fn keyboard_mode_uses_snake_case_names() {
+        assert_eq!(serde_json::from_str::<KeyboardMode>("\"scancode\"").unwrap(), KeyboardMode::Scancode);
+        assert_eq!(serde_json::to_string(&KeyboardMode::Keysym).unwrap(), "\"keysym\"");
+        assert_eq!(KeyboardMode::default(), KeyboardMode::Keysym);
+    }
+}
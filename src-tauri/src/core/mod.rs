@@ -1,5 +1,17 @@
 pub mod capture;
+pub mod clipboard;
 pub mod input;
+pub mod keymap;
+pub mod layout;
+pub mod protocol;
+pub mod session;
+pub mod transfer;
 
 pub use capture::*;
+pub use clipboard::*;
 pub use input::*;
+pub use keymap::*;
+pub use layout::*;
+pub use protocol::*;
+pub use session::*;
+pub use transfer::*;
@@ -0,0 +1,429 @@
+//! WebSocket control protocol shared by every stream handler.
+//!
+//! Video travels as binary frames; everything else is a JSON text message with a
+//! `type` tag. `ClientMessage` is everything a client may send and `ServerMessage`
+//! the replies the session helpers send back. Handlers parse each text message
+//! once with `ClientMessage::parse`, hand the typed request to whoever owns it and
+//! answer anything unparseable with an `error` message instead of dropping it.
+//!
+//! The first message a handler sends (`server_info` or `stream_info`) carries
+//! `protocol`; clients answer with `hello` and are told if their version is not
+//! one this host speaks. Clients acknowledge every media frame with `frame_ack`,
+//! which paces the stream (see `streaming::flow_control`) and lets the host
+//! measure the link itself; `streaming_stats` reports what it measured.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::core::capture::MonitorInfo;
+use crate::core::clipboard::ClipboardRequest;
+use crate::core::input::{InputEvent, TypingProgress};
+use crate::core::session::{SessionCommand, SessionRole};
+use crate::core::transfer::{FileEntry, TransferRequest};
+use crate::network::models::NetworkStats;
+use crate::streaming::flow_control::NetworkEstimate;
+
+/// Version of the message schema below; bump it when a change breaks old clients
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Everything a client may send, grouped by who handles it
+#[derive(Debug)]
+pub enum ClientMessage {
+    /// Handled by the stream handler itself
+    Stream(StreamRequest),
+    /// Injected on the host by the session's input thread
+    Input(InputEvent),
+    /// Broadcast to every session; admins only
+    Admin(SessionCommand),
+    Clipboard(ClipboardRequest),
+    Transfer(TransferRequest),
+}
+
+impl ClientMessage {
+    /// Parse a text message, routing it by its `type` tag
+    pub fn parse(text: &str) -> Result<Self, ProtocolError> {
+        let value: Value = serde_json::from_str(text).map_err(|e| ProtocolError::InvalidJson(e.to_string()))?;
+        let kind = match value.get("type").and_then(Value::as_str) {
+            Some(kind) => kind.to_string(),
+            None => return Err(ProtocolError::MissingType),
+        };
+
+        let parsed = if StreamRequest::TYPES.contains(&kind.as_str()) {
+            serde_json::from_value(value).map(ClientMessage::Stream)
+        } else if InputEvent::is_input_type(&kind) {
+            serde_json::from_value(value).map(ClientMessage::Input)
+        } else if SessionCommand::TYPES.contains(&kind.as_str()) {
+            serde_json::from_value(value).map(ClientMessage::Admin)
+        } else if ClipboardRequest::TYPES.contains(&kind.as_str()) {
+            serde_json::from_value(value).map(ClientMessage::Clipboard)
+        } else if TransferRequest::TYPES.contains(&kind.as_str()) {
+            serde_json::from_value(value).map(ClientMessage::Transfer)
+        } else {
+            return Err(ProtocolError::UnknownType(kind));
+        };
+
+        parsed.map_err(|e| ProtocolError::InvalidMessage { kind, error: e.to_string() })
+    }
+}
+
+/// Requests about the stream itself
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamRequest {
+    /// Client's half of the handshake
+    Hello { protocol: u32 },
+    Ping { timestamp: Option<u64> },
+    /// Media frame `sequence`, and every frame before it, has arrived
+    FrameAck { sequence: u32 },
+    RequestKeyframe,
+    /// Client's own view of the link; only logged, since the host measures it from acks
+    NetworkStats { stats: NetworkStats },
+    /// Quality this client would like, 10-100
+    QualityUpdate {
+        quality: u8,
+        #[serde(default)]
+        adaptive: bool,
+    },
+    /// Capture/encode trade-off for everyone watching the monitor (RGBA streams only)
+    PerformanceMode { mode: PerformancePreset },
+    /// Leave the emergency mode a struggling RGBA stream drops into
+    EmergencyReset,
+}
+
+impl StreamRequest {
+    /// Message `type` tags that belong to stream requests
+    pub const TYPES: &'static [&'static str] = &[
+        "hello", "ping", "frame_ack", "request_keyframe", "network_stats", "quality_update",
+        "performance_mode", "emergency_reset",
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PerformancePreset {
+    Gaming,
+    Ultra,
+    Balanced,
+}
+
+/// Check the version a client sent in its `hello`
+pub fn check_protocol_version(client: u32) -> Result<(), ProtocolError> {
+    if client == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err(ProtocolError::UnsupportedVersion { client, server: PROTOCOL_VERSION })
+    }
+}
+
+/// A client message that could not be accepted
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ProtocolError {
+    #[error("Message is not valid JSON: {0}")]
+    InvalidJson(String),
+    #[error("Message has no type")]
+    MissingType,
+    #[error("Unknown message type '{0}'")]
+    UnknownType(String),
+    #[error("Invalid {kind} message: {error}")]
+    InvalidMessage { kind: String, error: String },
+    #[error("Client speaks protocol version {client}, this host speaks {server}")]
+    UnsupportedVersion { client: u32, server: u32 },
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::InvalidJson(_) => ErrorCode::InvalidJson,
+            ProtocolError::MissingType => ErrorCode::MissingType,
+            ProtocolError::UnknownType(_) => ErrorCode::UnknownType,
+            ProtocolError::InvalidMessage { .. } => ErrorCode::InvalidMessage,
+            ProtocolError::UnsupportedVersion { .. } => ErrorCode::UnsupportedVersion,
+        }
+    }
+
+    /// `type` of the rejected message, when it had one
+    pub fn request(&self) -> Option<&str> {
+        match self {
+            ProtocolError::UnknownType(kind) | ProtocolError::InvalidMessage { kind, .. } => Some(kind),
+            ProtocolError::UnsupportedVersion { .. } => Some("hello"),
+            ProtocolError::InvalidJson(_) | ProtocolError::MissingType => None,
+        }
+    }
+
+    /// `error` message telling the client what was wrong
+    pub fn to_message(&self) -> String {
+        ServerMessage::Error {
+            code: self.code(),
+            request: self.request().map(str::to_string),
+            error: self.to_string(),
+        }
+        .to_message()
+    }
+}
+
+/// Machine-readable reason in an `error` message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidJson,
+    MissingType,
+    UnknownType,
+    InvalidMessage,
+    UnsupportedVersion,
+}
+
+/// One monitor in a `monitors` list
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MonitorEntry {
+    pub id: String,
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub is_primary: bool,
+}
+
+impl From<&MonitorInfo> for MonitorEntry {
+    fn from(monitor: &MonitorInfo) -> Self {
+        Self {
+            id: monitor.id.clone(),
+            name: monitor.name.clone(),
+            width: monitor.width,
+            height: monitor.height,
+            is_primary: monitor.is_primary,
+        }
+    }
+}
+
+/// Replies and notifications sent to a client.
+///
+/// The VP8 handler's `stream_info` handshake is built there; host clipboard
+/// content is encoded once by the clipboard hub and shared by every session.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// First message of the RGBA streams
+    ServerInfo {
+        width: u32,
+        height: u32,
+        hostname: String,
+        monitor: usize,
+        protocol: u32,
+        /// Always `rgba`; each binary frame's header names its exact codec
+        codec: String,
+        audio: bool,
+        role: SessionRole,
+    },
+    /// Monitors the client may switch to
+    Monitors { monitors: Vec<MonitorEntry> },
+    /// Connection is waiting for the host to approve it
+    ConsentPending,
+    ConsentDenied,
+    ConsentGranted { control: bool, role: SessionRole },
+    Pong {
+        timestamp: Option<u64>,
+        server_timestamp: u64,
+    },
+    /// A client message was rejected before anyone handled it
+    Error {
+        code: ErrorCode,
+        request: Option<String>,
+        error: String,
+    },
+    /// The session's role does not allow `action`
+    PermissionDenied { action: String, role: SessionRole },
+    InputError { event: String, error: String },
+    TypingProgress(TypingProgress),
+    /// What the host measured about this connection, sent every `congestion::UPDATE_INTERVAL`
+    StreamingStats {
+        frames_sent: u64,
+        /// Bitrate the encoder is asked for, on streams that have one
+        current_bitrate_kbps: Option<u32>,
+        network: NetworkEstimate,
+    },
+    /// Admin changed every session's quality
+    SetQuality { quality: u8 },
+    /// Admin moved every session to this monitor
+    SetMonitor { monitor: usize },
+    ClipboardError { error: String },
+    FileList { path: String, entries: Vec<FileEntry> },
+    FileUploadReady { id: String, path: String, offset: u64, chunk_size: usize },
+    FileUploadAck { id: String, offset: u64 },
+    FileUploadDone { id: String, path: String, size: u64 },
+    FileUploadCancelled { id: String, path: String },
+    FileDownloadReady { path: String, size: u64, sha256: String, chunk_size: usize },
+    FileDownloadData { path: String, offset: u64, data: String, sha256: String },
+    FileError {
+        request: String,
+        id: Option<String>,
+        path: Option<String>,
+        /// Where an interrupted upload should carry on from
+        offset: Option<u64>,
+        error: String,
+    },
+}
+
+impl ServerMessage {
+    /// Handshake for an RGBA stream of `monitor`, `width` x `height` pixels
+    pub fn server_info(width: u32, height: u32, monitor: usize, role: SessionRole) -> Self {
+        ServerMessage::ServerInfo {
+            width,
+            height,
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            monitor,
+            protocol: PROTOCOL_VERSION,
+            codec: "rgba".to_string(),
+            audio: false,
+            role,
+        }
+    }
+
+    pub fn monitors(monitors: &[MonitorInfo]) -> Self {
+        ServerMessage::Monitors { monitors: monitors.iter().map(MonitorEntry::from).collect() }
+    }
+
+    pub fn pong(timestamp: Option<u64>) -> Self {
+        let server_timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.as_millis() as u64)
+            .unwrap_or(0);
+        ServerMessage::Pong { timestamp, server_timestamp }
+    }
+
+    pub fn permission_denied(action: &str, role: SessionRole) -> Self {
+        ServerMessage::PermissionDenied { action: action.to_string(), role }
+    }
+
+    /// Text message for the session's control channel
+    pub fn to_message(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl From<SessionCommand> for ServerMessage {
+    fn from(command: SessionCommand) -> Self {
+        match command {
+            SessionCommand::SetQuality { quality } => ServerMessage::SetQuality { quality },
+            SessionCommand::SetMonitor { monitor } => ServerMessage::SetMonitor { monitor },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::input::TypingState;
+    use serde_json::json;
+
+    fn to_json(message: &ServerMessage) -> Value {
+        serde_json::from_str(&message.to_message()).unwrap()
+    }
+
+    #[test]
+    fn messages_are_routed_by_type() {
+        assert!(matches!(ClientMessage::parse(r#"{"type":"ping","timestamp":5}"#), Ok(ClientMessage::Stream(StreamRequest::Ping { timestamp: Some(5) }))));
+        assert!(matches!(ClientMessage::parse(r#"{"type":"hello","protocol":1}"#), Ok(ClientMessage::Stream(StreamRequest::Hello { protocol: 1 }))));
+        assert!(matches!(ClientMessage::parse(r#"{"type":"frame_ack","sequence":7}"#), Ok(ClientMessage::Stream(StreamRequest::FrameAck { sequence: 7 }))));
+        assert!(matches!(ClientMessage::parse(r#"{"type":"keydown","key":"a"}"#), Ok(ClientMessage::Input(InputEvent::KeyDown { .. }))));
+        assert!(matches!(ClientMessage::parse(r#"{"type":"set_monitor","monitor":1}"#), Ok(ClientMessage::Admin(SessionCommand::SetMonitor { monitor: 1 }))));
+        assert!(matches!(ClientMessage::parse(r#"{"type":"clipboard_sync","enabled":true}"#), Ok(ClientMessage::Clipboard(ClipboardRequest::ClipboardSync { enabled: true }))));
+        assert!(matches!(ClientMessage::parse(r#"{"type":"file_list","path":"logs"}"#), Ok(ClientMessage::Transfer(TransferRequest::FileList { .. }))));
+        assert!(matches!(
+            ClientMessage::parse(r#"{"type":"performance_mode","mode":"gaming"}"#),
+            Ok(ClientMessage::Stream(StreamRequest::PerformanceMode { mode: PerformancePreset::Gaming }))
+        ));
+    }
+
+    #[test]
+    fn bad_messages_are_explained() {
+        assert!(matches!(ClientMessage::parse("not json"), Err(ProtocolError::InvalidJson(_))));
+        assert_eq!(ClientMessage::parse(r#"{"key":"a"}"#).unwrap_err(), ProtocolError::MissingType);
+        assert_eq!(ClientMessage::parse(r#"{"type":"teleport"}"#).unwrap_err(), ProtocolError::UnknownType("teleport".to_string()));
+
+        let error = ClientMessage::parse(r#"{"type":"set_quality","quality":"high"}"#).unwrap_err();
+        assert!(matches!(&error, ProtocolError::InvalidMessage { kind, .. } if kind == "set_quality"));
+        let reply: Value = serde_json::from_str(&error.to_message()).unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], "invalid_message");
+        assert_eq!(reply["request"], "set_quality");
+    }
+
+    #[test]
+    fn only_the_current_version_is_accepted() {
+        assert_eq!(check_protocol_version(PROTOCOL_VERSION), Ok(()));
+        let error = check_protocol_version(PROTOCOL_VERSION + 1).unwrap_err();
+        assert_eq!(error.code(), ErrorCode::UnsupportedVersion);
+        assert_eq!(error.request(), Some("hello"));
+    }
+
+    #[test]
+    fn server_messages_keep_their_wire_format() {
+        assert_eq!(
+            to_json(&ServerMessage::permission_denied("set_quality", SessionRole::Viewer)),
+            json!({ "type": "permission_denied", "action": "set_quality", "role": "viewer" })
+        );
+        assert_eq!(
+            to_json(&ServerMessage::TypingProgress(TypingProgress { typed: 3, total: 9, state: TypingState::Typing })),
+            json!({ "type": "typing_progress", "typed": 3, "total": 9, "state": "typing" })
+        );
+        assert_eq!(
+            to_json(&ServerMessage::from(SessionCommand::SetQuality { quality: 60 })),
+            json!({ "type": "set_quality", "quality": 60 })
+        );
+        assert_eq!(to_json(&ServerMessage::pong(Some(42)))["timestamp"], 42);
+        let mut server_info = to_json(&ServerMessage::server_info(1280, 720, 1, SessionRole::Controller));
+        assert!(server_info["hostname"].as_str().is_some_and(|hostname| !hostname.is_empty()));
+        server_info["hostname"] = json!("host");
+        assert_eq!(
+            server_info,
+            json!({
+                "type": "server_info",
+                "width": 1280,
+                "height": 720,
+                "hostname": "host",
+                "monitor": 1,
+                "protocol": PROTOCOL_VERSION,
+                "codec": "rgba",
+                "audio": false,
+                "role": "controller"
+            })
+        );
+        let monitor = MonitorInfo {
+            id: "1".to_string(),
+            name: "HDMI-1".to_string(),
+            is_primary: false,
+            width: 1280,
+            height: 720,
+            position_x: 1920,
+            position_y: 0,
+            scale_factor: 1.0,
+            rotation: 0,
+        };
+        assert_eq!(
+            to_json(&ServerMessage::monitors(&[monitor])),
+            json!({
+                "type": "monitors",
+                "monitors": [{ "id": "1", "name": "HDMI-1", "width": 1280, "height": 720, "is_primary": false }]
+            })
+        );
+        assert_eq!(
+            to_json(&ServerMessage::StreamingStats {
+                frames_sent: 5,
+                current_bitrate_kbps: Some(1200),
+                network: NetworkEstimate { rtt_ms: Some(30), ..Default::default() },
+            }),
+            json!({
+                "type": "streaming_stats",
+                "frames_sent": 5,
+                "current_bitrate_kbps": 1200,
+                "network": {
+                    "rtt_ms": 30,
+                    "min_rtt_ms": null,
+                    "delivery_rate_kbps": null,
+                    "send_delay_ms": 0,
+                    "lost_frames": 0
+                }
+            })
+        );
+    }
+}
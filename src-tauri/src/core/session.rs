@@ -0,0 +1,270 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::core::capture::list_monitors;
+use crate::core::clipboard::ClipboardHub;
+use crate::core::protocol::{ProtocolError, ServerMessage};
+use crate::core::transfer::TransferRoot;
+
+/// What a connected client is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionRole {
+    /// Watches the screen; input is dropped
+    Viewer,
+    /// Watches and sends keyboard/mouse input
+    Controller,
+    /// Controller that may also change quality or monitor for every session
+    Admin,
+}
+
+impl SessionRole {
+    pub fn can_control(self) -> bool {
+        self >= SessionRole::Controller
+    }
+
+    pub fn is_admin(self) -> bool {
+        self == SessionRole::Admin
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SessionRole::Viewer => "viewer",
+            SessionRole::Controller => "controller",
+            SessionRole::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "viewer" => Some(SessionRole::Viewer),
+            "controller" => Some(SessionRole::Controller),
+            "admin" => Some(SessionRole::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Settings an admin pushes to every session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionCommand {
+    /// Quality 10-100, relative to each session's configured bitrate
+    SetQuality { quality: u8 },
+    /// Move every viewer to this monitor
+    SetMonitor { monitor: usize },
+}
+
+/// Why an admin command was refused
+#[derive(Error, Debug, PartialEq)]
+pub enum SessionCommandError {
+    #[error("Monitor {0} does not exist")]
+    MonitorOutOfRange(usize),
+}
+
+impl SessionCommand {
+    /// Message `type` tags that belong to admin commands
+    pub const TYPES: &'static [&'static str] = &["set_quality", "set_monitor"];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SessionCommand::SetQuality { .. } => "set_quality",
+            SessionCommand::SetMonitor { .. } => "set_monitor",
+        }
+    }
+
+    /// Clamp the quality and check the monitor exists; an unknown monitor count lets any monitor through
+    fn validated(self, monitor_count: Option<usize>) -> Result<Self, SessionCommandError> {
+        match self {
+            SessionCommand::SetQuality { quality } => Ok(SessionCommand::SetQuality { quality: quality.clamp(10, 100) }),
+            SessionCommand::SetMonitor { monitor } if monitor_count.is_some_and(|count| monitor >= count) => {
+                Err(SessionCommandError::MonitorOutOfRange(monitor))
+            }
+            command => Ok(command),
+        }
+    }
+
+    /// Scale a session's configured bitrate by the requested quality
+    pub fn scaled_bitrate(quality: u8, base_bitrate: u32) -> u32 {
+        base_bitrate * quality as u32 / 100
+    }
+
+    /// Text message telling the client what changed
+    pub fn to_message(&self) -> String {
+        ServerMessage::from(self.clone()).to_message()
+    }
+}
+
+/// Per-connection role plus the channels shared by all sessions
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub role: SessionRole,
+    commands: broadcast::Sender<SessionCommand>,
+    clipboard: Option<Arc<ClipboardHub>>,
+    transfer_root: Option<Arc<TransferRoot>>,
+}
+
+impl SessionContext {
+    pub fn new(role: SessionRole, commands: broadcast::Sender<SessionCommand>) -> Self {
+        Self { role, commands, clipboard: None, transfer_root: None }
+    }
+
+    /// Let the session sync its clipboard with the host
+    pub fn with_clipboard(mut self, clipboard: Arc<ClipboardHub>) -> Self {
+        self.clipboard = Some(clipboard);
+        self
+    }
+
+    pub fn clipboard(&self) -> Option<&Arc<ClipboardHub>> {
+        self.clipboard.as_ref()
+    }
+
+    /// Let the session transfer files in and out of the host's shared folder
+    pub fn with_transfer_root(mut self, root: Arc<TransferRoot>) -> Self {
+        self.transfer_root = Some(root);
+        self
+    }
+
+    pub fn transfer_root(&self) -> Option<&Arc<TransferRoot>> {
+        self.transfer_root.as_ref()
+    }
+
+    /// Watch only, not connected to other sessions. Handlers start with this
+    /// until `with_session` hands them the role the client authenticated as.
+    pub fn standalone() -> Self {
+        Self::new(SessionRole::Viewer, broadcast::channel(1).0)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionCommand> {
+        self.commands.subscribe()
+    }
+
+    /// Handle an admin command from the client.
+    ///
+    /// Admin commands are broadcast to every session; non-admins get a
+    /// `permission_denied` reply instead, and commands naming a monitor that
+    /// does not exist an `error`.
+    pub fn handle_command(&self, command: SessionCommand, reply_tx: &mpsc::Sender<String>) {
+        let monitor_count = match command {
+            SessionCommand::SetMonitor { .. } => list_monitors().ok().map(|monitors| monitors.len()),
+            SessionCommand::SetQuality { .. } => None,
+        };
+        self.apply_command(command, monitor_count, reply_tx);
+    }
+
+    fn apply_command(&self, command: SessionCommand, monitor_count: Option<usize>, reply_tx: &mpsc::Sender<String>) {
+        if !self.role.is_admin() {
+            warn!("🚫 {} rejected for {} session", command.kind(), self.role.name());
+            let _ = reply_tx.try_send(ServerMessage::permission_denied(command.kind(), self.role).to_message());
+            return;
+        }
+
+        let kind = command.kind();
+        let command = match command.validated(monitor_count) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("🚫 {} rejected: {}", kind, e);
+                let error = ProtocolError::InvalidMessage { kind: kind.to_string(), error: e.to_string() };
+                let _ = reply_tx.try_send(error.to_message());
+                return;
+            }
+        };
+        info!("👑 Admin command for all sessions: {:?}", command);
+        // No receivers just means no other session is streaming
+        let _ = self.commands.send(command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broadcast::error::TryRecvError;
+
+    const ROLES: [SessionRole; 3] = [SessionRole::Viewer, SessionRole::Controller, SessionRole::Admin];
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(SessionRole::Viewer < SessionRole::Controller && SessionRole::Controller < SessionRole::Admin);
+        let control: Vec<bool> = ROLES.iter().map(|role| role.can_control()).collect();
+        assert_eq!(control, [false, true, true]);
+        let admin: Vec<bool> = ROLES.iter().map(|role| role.is_admin()).collect();
+        assert_eq!(admin, [false, false, true]);
+    }
+
+    #[test]
+    fn role_names_round_trip() {
+        for role in ROLES {
+            assert_eq!(SessionRole::from_name(role.name()), Some(role));
+            assert_eq!(serde_json::to_value(role).unwrap(), role.name());
+        }
+        assert_eq!(SessionRole::from_name("Admin"), None);
+        assert_eq!(SessionRole::from_name("root"), None);
+    }
+
+    #[test]
+    fn standalone_sessions_only_watch() {
+        assert_eq!(SessionContext::standalone().role, SessionRole::Viewer);
+    }
+
+    #[test]
+    fn non_admins_get_permission_denied_instead_of_a_broadcast() {
+        for role in [SessionRole::Viewer, SessionRole::Controller] {
+            let (commands, mut broadcasts) = broadcast::channel(4);
+            let (reply_tx, mut replies) = mpsc::channel(4);
+            let session = SessionContext::new(role, commands);
+
+            session.handle_command(SessionCommand::SetMonitor { monitor: 1 }, &reply_tx);
+            let reply: serde_json::Value = serde_json::from_str(&replies.try_recv().unwrap()).unwrap();
+            assert_eq!(
+                reply,
+                serde_json::json!({ "type": "permission_denied", "action": "set_monitor", "role": role.name() })
+            );
+            assert_eq!(broadcasts.try_recv(), Err(TryRecvError::Empty));
+        }
+    }
+
+    #[test]
+    fn admin_commands_reach_every_session_clamped() {
+        let (commands, mut broadcasts) = broadcast::channel(4);
+        let admin = SessionContext::new(SessionRole::Admin, commands.clone());
+        let other = SessionContext::new(SessionRole::Viewer, commands);
+        let mut other_broadcasts = other.subscribe();
+        let (reply_tx, mut replies) = mpsc::channel(4);
+
+        admin.handle_command(SessionCommand::SetQuality { quality: 250 }, &reply_tx);
+        let expected = SessionCommand::SetQuality { quality: 100 };
+        assert_eq!(broadcasts.try_recv(), Ok(expected.clone()));
+        assert_eq!(other_broadcasts.try_recv(), Ok(expected));
+        assert!(replies.try_recv().is_err());
+
+        admin.handle_command(SessionCommand::SetQuality { quality: 0 }, &reply_tx);
+        assert_eq!(broadcasts.try_recv(), Ok(SessionCommand::SetQuality { quality: 10 }));
+    }
+
+    #[test]
+    fn monitors_that_do_not_exist_are_refused() {
+        let (commands, mut broadcasts) = broadcast::channel(4);
+        let admin = SessionContext::new(SessionRole::Admin, commands);
+        let (reply_tx, mut replies) = mpsc::channel(4);
+
+        admin.apply_command(SessionCommand::SetMonitor { monitor: 2 }, Some(2), &reply_tx);
+        let reply: serde_json::Value = serde_json::from_str(&replies.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            reply,
+            serde_json::json!({
+                "type": "error",
+                "code": "invalid_message",
+                "request": "set_monitor",
+                "error": "Invalid set_monitor message: Monitor 2 does not exist",
+            })
+        );
+        assert_eq!(broadcasts.try_recv(), Err(TryRecvError::Empty));
+
+        admin.apply_command(SessionCommand::SetMonitor { monitor: 1 }, Some(2), &reply_tx);
+        assert_eq!(broadcasts.try_recv(), Ok(SessionCommand::SetMonitor { monitor: 1 }));
+        assert!(replies.try_recv().is_err());
+    }
+}
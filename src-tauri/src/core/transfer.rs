@@ -0,0 +1,604 @@
+//! File transfer between clients and a host folder.
+//!
+//! The host shares one root folder; controllers browse it, upload into it and
+//! download from it over the session's WebSocket. Every path a client sends is
+//! relative to that root and goes through `TransferRoot::resolve`, which refuses
+//! anything that would land outside it, symlinks included.
+//!
+//! Files move in base64 chunks, each with its SHA-256, and whole files are checked
+//! against the SHA-256 given when they started. An upload is written to a partial
+//! file named after its path, size and checksum, so a client that reconnects starts
+//! the same upload again and carries on from what is already on disk. Downloads are
+//! pulled one chunk at a time from whatever offset the client has reached.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use crate::core::protocol::ServerMessage;
+use crate::core::session::{SessionContext, SessionRole};
+
+/// Largest chunk in either direction, before base64
+pub const TRANSFER_CHUNK_BYTES: usize = 256 * 1024;
+/// Largest file a client may upload
+pub const MAX_UPLOAD_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+/// Uploads in progress are kept as `.<name>.<id>.part` next to their target
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// File transfer errors, reported to the client as `file_error`
+#[derive(Error, Debug, PartialEq)]
+pub enum TransferError {
+    #[error("'{0}' is outside the shared folder")]
+    OutsideRoot(String),
+    #[error("'{0}' does not exist")]
+    NotFound(String),
+    #[error("'{0}' already exists")]
+    AlreadyExists(String),
+    #[error("'{0}' is not a file")]
+    NotAFile(String),
+    #[error("'{0}' is not a folder")]
+    NotAFolder(String),
+    #[error("Unknown upload '{0}'")]
+    UnknownUpload(String),
+    #[error("Expected data at offset {expected}, got {got}")]
+    WrongOffset { expected: u64, got: u64 },
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+    #[error("{0}")]
+    Invalid(String),
+    #[error("I/O error: {0}")]
+    Io(String),
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e.to_string())
+    }
+}
+
+/// The host folder clients may transfer files in and out of
+#[derive(Debug)]
+pub struct TransferRoot {
+    root: PathBuf,
+}
+
+impl TransferRoot {
+    /// Share `path`, which must be an existing folder
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, TransferError> {
+        let display = path.as_ref().display().to_string();
+        let root = fs::canonicalize(path.as_ref()).map_err(|_| TransferError::NotFound(display.clone()))?;
+        if !root.is_dir() {
+            return Err(TransferError::NotAFolder(display));
+        }
+        Ok(Self { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Host path for a client path such as "logs/app.log"; "" is the root itself.
+    ///
+    /// Only plain names are accepted, and the deepest part of the path that exists
+    /// must still be inside the root once symlinks are followed.
+    pub fn resolve(&self, relative: &str) -> Result<PathBuf, TransferError> {
+        let outside = || TransferError::OutsideRoot(relative.to_string());
+
+        let mut path = self.root.clone();
+        for component in Path::new(relative).components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::CurDir => {}
+                // "..", "/" and drive prefixes
+                _ => return Err(outside()),
+            }
+        }
+
+        // symlink_metadata so a dangling link still counts as existing and is checked
+        let mut existing = path.as_path();
+        while fs::symlink_metadata(existing).is_err() {
+            existing = existing.parent().ok_or_else(outside)?;
+        }
+        let real = fs::canonicalize(existing).map_err(|_| outside())?;
+        if !real.starts_with(&self.root) {
+            return Err(outside());
+        }
+        Ok(path)
+    }
+}
+
+/// File transfer messages from the client
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TransferRequest {
+    FileList { #[serde(default)] path: String },
+    FileUploadStart { path: String, size: u64, sha256: String, #[serde(default)] overwrite: bool },
+    FileUploadChunk { id: String, offset: u64, data: String, sha256: String },
+    FileUploadCancel { id: String },
+    FileDownloadStart { path: String },
+    FileDownloadChunk { path: String, offset: u64 },
+}
+
+impl TransferRequest {
+    /// Message `type` tags that belong to file transfer requests
+    pub const TYPES: &'static [&'static str] = &[
+        "file_list", "file_upload_start", "file_upload_chunk",
+        "file_upload_cancel", "file_download_start", "file_download_chunk",
+    ];
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransferRequest::FileList { .. } => "file_list",
+            TransferRequest::FileUploadStart { .. } => "file_upload_start",
+            TransferRequest::FileUploadChunk { .. } => "file_upload_chunk",
+            TransferRequest::FileUploadCancel { .. } => "file_upload_cancel",
+            TransferRequest::FileDownloadStart { .. } => "file_download_start",
+            TransferRequest::FileDownloadChunk { .. } => "file_download_chunk",
+        }
+    }
+
+    /// Upload id or path the request is about, echoed back in `file_error`
+    fn target(&self) -> (Option<String>, Option<String>) {
+        match self {
+            TransferRequest::FileUploadChunk { id, .. } | TransferRequest::FileUploadCancel { id } => (Some(id.clone()), None),
+            TransferRequest::FileList { path }
+            | TransferRequest::FileUploadStart { path, .. }
+            | TransferRequest::FileDownloadStart { path }
+            | TransferRequest::FileDownloadChunk { path, .. } => (None, Some(path.clone())),
+        }
+    }
+}
+
+/// One entry of a `file_list` reply
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub dir: bool,
+    pub size: u64,
+    /// Seconds since the Unix epoch
+    pub modified: Option<u64>,
+}
+
+struct Upload {
+    path: String,
+    target: PathBuf,
+    partial: PathBuf,
+    size: u64,
+    sha256: String,
+    overwrite: bool,
+}
+
+/// One session's transfers; runs on the session's transfer thread
+struct Transfers {
+    root: Arc<TransferRoot>,
+    uploads: HashMap<String, Upload>,
+}
+
+impl Transfers {
+    fn new(root: Arc<TransferRoot>) -> Self {
+        Self { root, uploads: HashMap::new() }
+    }
+
+    /// Carry out a request and build the reply
+    fn handle(&mut self, request: TransferRequest) -> Result<ServerMessage, TransferError> {
+        match request {
+            TransferRequest::FileList { path } => self.list(path),
+            TransferRequest::FileUploadStart { path, size, sha256, overwrite } => self.start_upload(path, size, sha256, overwrite),
+            TransferRequest::FileUploadChunk { id, offset, data, sha256 } => self.upload_chunk(&id, offset, &data, &sha256),
+            TransferRequest::FileUploadCancel { id } => {
+                let upload = self.uploads.remove(&id).ok_or_else(|| TransferError::UnknownUpload(id.clone()))?;
+                let _ = fs::remove_file(&upload.partial);
+                info!("📁 Upload of {} cancelled", upload.path);
+                Ok(ServerMessage::FileUploadCancelled { id, path: upload.path })
+            }
+            TransferRequest::FileDownloadStart { path } => {
+                let file = self.existing_file(&path)?;
+                let size = fs::metadata(&file)?.len();
+                info!("📁 Download of {} ({} bytes) started", path, size);
+                Ok(ServerMessage::FileDownloadReady {
+                    path,
+                    size,
+                    sha256: sha256_file(&file)?,
+                    chunk_size: TRANSFER_CHUNK_BYTES,
+                })
+            }
+            TransferRequest::FileDownloadChunk { path, offset } => {
+                let mut file = File::open(self.existing_file(&path)?)?;
+                if offset > file.metadata()?.len() {
+                    return Err(TransferError::Invalid(format!("Offset {} is past the end of the file", offset)));
+                }
+                file.seek(SeekFrom::Start(offset))?;
+                let mut chunk = Vec::with_capacity(TRANSFER_CHUNK_BYTES);
+                file.take(TRANSFER_CHUNK_BYTES as u64).read_to_end(&mut chunk)?;
+                Ok(ServerMessage::FileDownloadData {
+                    path,
+                    offset,
+                    data: BASE64.encode(&chunk),
+                    sha256: sha256_hex(&chunk),
+                })
+            }
+        }
+    }
+
+    fn list(&self, path: String) -> Result<ServerMessage, TransferError> {
+        let folder = self.root.resolve(&path)?;
+        if !folder.is_dir() {
+            return Err(TransferError::NotAFolder(path));
+        }
+
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&folder)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_partial(&name) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let modified = metadata.modified().ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|age| age.as_secs());
+            entries.push(FileEntry { name, dir: metadata.is_dir(), size: metadata.len(), modified });
+        }
+        // Folders first, then by name
+        entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+
+        Ok(ServerMessage::FileList { path, entries })
+    }
+
+    fn existing_file(&self, path: &str) -> Result<PathBuf, TransferError> {
+        let file = self.root.resolve(path)?;
+        if !file.exists() {
+            return Err(TransferError::NotFound(path.to_string()));
+        }
+        if !file.is_file() {
+            return Err(TransferError::NotAFile(path.to_string()));
+        }
+        Ok(file)
+    }
+
+    fn start_upload(&mut self, path: String, size: u64, sha256: String, overwrite: bool) -> Result<ServerMessage, TransferError> {
+        if size > MAX_UPLOAD_BYTES {
+            return Err(TransferError::Invalid(format!("File is {} bytes, the limit is {}", size, MAX_UPLOAD_BYTES)));
+        }
+        let sha256 = sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(TransferError::Invalid("sha256 must be 64 hex digits".to_string()));
+        }
+
+        let target = self.root.resolve(&path)?;
+        let name = match target.file_name() {
+            Some(name) if target != self.root.path() => name.to_string_lossy().into_owned(),
+            _ => return Err(TransferError::NotAFile(path)),
+        };
+        if target.is_dir() {
+            return Err(TransferError::NotAFile(path));
+        }
+        if target.exists() && !overwrite {
+            return Err(TransferError::AlreadyExists(path));
+        }
+
+        // Same file to the same place always gets the same id, and so the same partial file
+        let id = sha256_hex(format!("{}\n{}\n{}", path, size, sha256).as_bytes())[..16].to_string();
+        let partial = target.with_file_name(format!(".{}.{}{}", name, id, PARTIAL_SUFFIX));
+        let mut offset = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+        if offset > size {
+            fs::remove_file(&partial)?;
+            offset = 0;
+        }
+        OpenOptions::new().create(true).append(true).open(&partial)?;
+
+        if offset > 0 {
+            info!("📁 Resuming upload of {} at {} of {} bytes", path, offset, size);
+        } else {
+            info!("📁 Upload of {} ({} bytes) started", path, size);
+        }
+        self.uploads.insert(id.clone(), Upload { path: path.clone(), target, partial, size, sha256, overwrite });
+
+        if offset == size {
+            return self.finish_upload(&id);
+        }
+        Ok(ServerMessage::FileUploadReady { id, path, offset, chunk_size: TRANSFER_CHUNK_BYTES })
+    }
+
+    fn upload_chunk(&mut self, id: &str, offset: u64, data: &str, sha256: &str) -> Result<ServerMessage, TransferError> {
+        let upload = self.uploads.get(id).ok_or_else(|| TransferError::UnknownUpload(id.to_string()))?;
+
+        // Base64 takes 4 bytes for every 3
+        if data.len() / 4 * 3 > TRANSFER_CHUNK_BYTES {
+            return Err(TransferError::Invalid(format!("Chunks are limited to {} bytes", TRANSFER_CHUNK_BYTES)));
+        }
+        let chunk = BASE64.decode(data).map_err(|e| TransferError::Invalid(format!("Invalid chunk data: {}", e)))?;
+        if !sha256_hex(&chunk).eq_ignore_ascii_case(sha256) {
+            return Err(TransferError::ChecksumMismatch);
+        }
+
+        let written = fs::metadata(&upload.partial)?.len();
+        if offset != written {
+            return Err(TransferError::WrongOffset { expected: written, got: offset });
+        }
+        if written + chunk.len() as u64 > upload.size {
+            return Err(TransferError::Invalid("Chunk runs past the end of the file".to_string()));
+        }
+
+        let mut partial = OpenOptions::new().append(true).open(&upload.partial)?;
+        partial.write_all(&chunk)?;
+        partial.sync_data()?;
+
+        let written = written + chunk.len() as u64;
+        if written == upload.size {
+            return self.finish_upload(id);
+        }
+        Ok(ServerMessage::FileUploadAck { id: id.to_string(), offset: written })
+    }
+
+    fn finish_upload(&mut self, id: &str) -> Result<ServerMessage, TransferError> {
+        let upload = self.uploads.remove(id).ok_or_else(|| TransferError::UnknownUpload(id.to_string()))?;
+
+        if sha256_file(&upload.partial)? != upload.sha256 {
+            warn!("📁 Upload of {} failed its checksum, discarding it", upload.path);
+            let _ = fs::remove_file(&upload.partial);
+            return Err(TransferError::ChecksumMismatch);
+        }
+        if upload.target.exists() {
+            if !upload.overwrite {
+                return Err(TransferError::AlreadyExists(upload.path));
+            }
+            // rename does not replace existing files on Windows
+            fs::remove_file(&upload.target)?;
+        }
+        fs::rename(&upload.partial, &upload.target)?;
+
+        info!("📁 Upload of {} ({} bytes) complete", upload.path, upload.size);
+        Ok(ServerMessage::FileUploadDone { id: id.to_string(), path: upload.path, size: upload.size })
+    }
+
+    /// Offset the client should resume an upload from after an error
+    fn upload_offset(&self, id: &str) -> Option<u64> {
+        let upload = self.uploads.get(id)?;
+        fs::metadata(&upload.partial).ok().map(|m| m.len())
+    }
+}
+
+fn is_partial(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(PARTIAL_SUFFIX)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// One connection's file transfers.
+///
+/// Requests run in order on a dedicated thread, so file I/O never blocks the
+/// runtime and upload chunks are written in the order they arrive. The thread
+/// exits when the session drops this handle.
+pub struct TransferSession {
+    role: SessionRole,
+    tx: Option<std_mpsc::Sender<TransferRequest>>, // None when the host shares no folder
+    reply_tx: mpsc::Sender<String>,
+}
+
+impl TransferSession {
+    pub fn new(session: &SessionContext, reply_tx: mpsc::Sender<String>) -> Self {
+        let tx = match session.transfer_root() {
+            Some(root) if session.role.can_control() => Self::spawn(Arc::clone(root), reply_tx.clone())
+                .map_err(|e| warn!("File transfer unavailable: {}", e))
+                .ok(),
+            _ => None,
+        };
+        Self { role: session.role, tx, reply_tx }
+    }
+
+    fn spawn(root: Arc<TransferRoot>, reply_tx: mpsc::Sender<String>) -> Result<std_mpsc::Sender<TransferRequest>, String> {
+        let (tx, rx) = std_mpsc::channel::<TransferRequest>();
+
+        thread::Builder::new()
+            .name("kvm-transfer".to_string())
+            .spawn(move || {
+                let mut transfers = Transfers::new(root);
+                while let Ok(request) = rx.recv() {
+                    let kind = request.kind();
+                    let (id, path) = request.target();
+
+                    let reply = match transfers.handle(request) {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            debug!("{} failed: {}", kind, e);
+                            // Tell an uploading client where to carry on from
+                            let offset = id.as_deref().and_then(|id| transfers.upload_offset(id));
+                            transfer_error(kind, id.as_deref(), path.as_deref(), offset, &e.to_string())
+                        }
+                    };
+                    // Waiting here slows a fast client down to what the socket can take
+                    if reply_tx.blocking_send(reply.to_message()).is_err() {
+                        break;
+                    }
+                }
+                debug!("File transfer thread stopped");
+            })
+            .map_err(|e| format!("Failed to spawn file transfer thread: {}", e))?;
+
+        Ok(tx)
+    }
+
+    /// Handle a `file_*` message from the client
+    pub fn handle_request(&self, request: TransferRequest) {
+        let kind = request.kind();
+        if !self.role.can_control() {
+            warn!("🚫 {} rejected for {} session", kind, self.role.name());
+            let _ = self.reply_tx.try_send(ServerMessage::permission_denied(kind, self.role).to_message());
+            return;
+        }
+
+        let Some(tx) = &self.tx else {
+            let (id, path) = request.target();
+            let error = transfer_error(kind, id.as_deref(), path.as_deref(), None, "File transfer is not enabled on this host");
+            let _ = self.reply_tx.try_send(error.to_message());
+            return;
+        };
+        if let Err(std_mpsc::SendError(request)) = tx.send(request) {
+            let (id, path) = request.target();
+            let error = transfer_error(kind, id.as_deref(), path.as_deref(), None, "File transfer is not running");
+            let _ = self.reply_tx.try_send(error.to_message());
+        }
+    }
+}
+
+fn transfer_error(kind: &str, id: Option<&str>, path: Option<&str>, offset: Option<u64>, error: &str) -> ServerMessage {
+    ServerMessage::FileError {
+        request: kind.to_string(),
+        id: id.map(str::to_string),
+        path: path.map(str::to_string),
+        offset,
+        error: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Fresh shared folder, with a file outside it, removed on drop
+    struct TempRoot(TempDir);
+
+    impl TempRoot {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            fs::create_dir_all(dir.path().join("shared/logs")).unwrap();
+            fs::write(dir.path().join("secret.txt"), "outside").unwrap();
+            Self(dir)
+        }
+
+        fn shared(&self) -> PathBuf {
+            self.0.path().join("shared")
+        }
+
+        fn transfers(&self) -> Transfers {
+            Transfers::new(Arc::new(TransferRoot::new(self.shared()).unwrap()))
+        }
+    }
+
+    /// A successful reply as it goes over the wire
+    fn reply(result: Result<ServerMessage, TransferError>) -> serde_json::Value {
+        serde_json::to_value(result.unwrap()).unwrap()
+    }
+
+    fn chunk(id: &str, offset: u64, data: &[u8]) -> TransferRequest {
+        TransferRequest::FileUploadChunk { id: id.to_string(), offset, data: BASE64.encode(data), sha256: sha256_hex(data) }
+    }
+
+    fn start(path: &str, data: &[u8]) -> TransferRequest {
+        TransferRequest::FileUploadStart { path: path.to_string(), size: data.len() as u64, sha256: sha256_hex(data), overwrite: false }
+    }
+
+    #[test]
+    fn paths_cannot_leave_the_root() {
+        let temp = TempRoot::new();
+        let root = TransferRoot::new(temp.shared()).unwrap();
+
+        assert_eq!(root.resolve("logs/new.txt").unwrap(), root.path().join("logs/new.txt"));
+        assert_eq!(root.resolve("").unwrap(), root.path());
+        for path in ["../secret.txt", "logs/../../secret.txt", "/etc/passwd"] {
+            assert_eq!(root.resolve(path), Err(TransferError::OutsideRoot(path.to_string())), "{}", path);
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(temp.0.path().join("secret.txt"), temp.shared().join("link")).unwrap();
+            std::os::unix::fs::symlink(temp.0.path(), temp.shared().join("escape")).unwrap();
+            std::os::unix::fs::symlink(temp.0.path().join("missing"), temp.shared().join("dangling")).unwrap();
+            assert!(root.resolve("link").is_err());
+            assert!(root.resolve("escape/secret.txt").is_err());
+            assert!(root.resolve("dangling").is_err());
+        }
+    }
+
+    #[test]
+    fn upload_is_chunked_verified_and_renamed_into_place() {
+        let temp = TempRoot::new();
+        let mut transfers = temp.transfers();
+        let data = b"hello, host";
+
+        let ready = reply(transfers.handle(start("logs/hello.txt", data)));
+        assert_eq!(ready["offset"], 0);
+        let id = ready["id"].as_str().unwrap().to_string();
+
+        let ack = reply(transfers.handle(chunk(&id, 0, &data[..5])));
+        assert_eq!(ack["type"], "file_upload_ack");
+        assert_eq!(transfers.handle(chunk(&id, 0, &data[5..])), Err(TransferError::WrongOffset { expected: 5, got: 0 }));
+        let corrupt = TransferRequest::FileUploadChunk { id: id.clone(), offset: 5, data: BASE64.encode(&data[5..]), sha256: sha256_hex(b"x") };
+        assert_eq!(transfers.handle(corrupt), Err(TransferError::ChecksumMismatch));
+        assert_eq!(reply(transfers.handle(chunk(&id, 5, &data[5..])))["type"], "file_upload_done");
+
+        assert_eq!(fs::read(temp.shared().join("logs/hello.txt")).unwrap(), data);
+        let listing = reply(transfers.handle(TransferRequest::FileList { path: "logs".to_string() }));
+        assert_eq!(listing["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(transfers.handle(start("logs/hello.txt", data)), Err(TransferError::AlreadyExists("logs/hello.txt".to_string())));
+    }
+
+    #[test]
+    fn interrupted_upload_resumes_from_partial_file() {
+        let temp = TempRoot::new();
+        let data = b"resumable upload";
+
+        let mut first = temp.transfers();
+        let id = reply(first.handle(start("big.bin", data)))["id"].as_str().unwrap().to_string();
+        reply(first.handle(chunk(&id, 0, &data[..8])));
+        drop(first);
+
+        // A new session, as after a reconnect, picks up where the last one stopped
+        let mut second = temp.transfers();
+        let ready = reply(second.handle(start("big.bin", data)));
+        assert_eq!(ready["id"], id.as_str());
+        assert_eq!(ready["offset"], 8);
+        let listing = reply(second.handle(TransferRequest::FileList { path: String::new() }));
+        assert!(listing["entries"].as_array().unwrap().iter().all(|e| e["name"] != format!(".big.bin.{}.part", id)));
+
+        assert_eq!(reply(second.handle(chunk(&id, 8, &data[8..])))["type"], "file_upload_done");
+        assert_eq!(fs::read(temp.shared().join("big.bin")).unwrap(), data);
+    }
+
+    #[test]
+    fn downloads_are_chunked_with_checksums() {
+        let temp = TempRoot::new();
+        let data = vec![7u8; TRANSFER_CHUNK_BYTES + 10];
+        fs::write(temp.shared().join("logs/app.log"), &data).unwrap();
+        let mut transfers = temp.transfers();
+
+        let ready = reply(transfers.handle(TransferRequest::FileDownloadStart { path: "logs/app.log".to_string() }));
+        assert_eq!(ready["size"], data.len());
+        assert_eq!(ready["sha256"], sha256_hex(&data));
+
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            let message = reply(transfers.handle(TransferRequest::FileDownloadChunk { path: "logs/app.log".to_string(), offset: received.len() as u64 }));
+            let chunk = BASE64.decode(message["data"].as_str().unwrap()).unwrap();
+            assert_eq!(message["sha256"], sha256_hex(&chunk));
+            received.extend(chunk);
+        }
+        assert_eq!(received, data);
+
+        assert!(transfers.handle(TransferRequest::FileDownloadStart { path: "../secret.txt".to_string() }).is_err());
+        assert_eq!(transfers.handle(TransferRequest::FileDownloadStart { path: "logs".to_string() }), Err(TransferError::NotAFile("logs".to_string())));
+    }
+}
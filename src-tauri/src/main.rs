@@ -52,6 +52,12 @@ fn main() {
             get_server_config,
             get_server_status,
             get_server_url,
+            get_access_info,
+            regenerate_access_pin,
+            set_access_password,
+            get_tls_fingerprint,
+            respond_to_connection_request,
+            forget_trusted_clients,
             get_logs,
             get_network_interfaces,
             test_network_connectivity,
@@ -0,0 +1,267 @@
+//! Web client assets compiled into the binary
+//!
+//! Files are looked up by exact name in a fixed table, so a request path can
+//! never reach outside the asset set. Setting `CLEVER_KVM_WEB_DIR` serves the
+//! same names from disk instead, which lets the client be edited without a
+//! rebuild during development.
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use brotli::enc::BrotliEncoderParams;
+use flate2::{write::GzEncoder, Compression};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Environment variable pointing at an on-disk `web-client` directory
+pub const OVERRIDE_DIR_ENV: &str = "CLEVER_KVM_WEB_DIR";
+
+macro_rules! embed {
+    ($($name:literal),* $(,)?) => {
+        &[$(($name, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/web-client/", $name)) as &[u8])),*]
+    };
+}
+
+static EMBEDDED: &[(&str, &[u8])] = embed![
+    "index.html",
+    "kvm-template.html",
+    "kvm-template-parts.js",
+    "kvm-client.js",
+    "kvm-client.css",
+];
+
+struct Asset {
+    mime: &'static str,
+    etag: String,
+    identity: Bytes,
+    gzip: Option<Bytes>,
+    brotli: Option<Bytes>,
+}
+
+impl Asset {
+    fn new(name: &str, bytes: Bytes, compress: bool) -> Self {
+        let mime = mime_type(name);
+        let compressible = compress && (mime.starts_with("text/") || mime == "application/javascript");
+        let smaller = |encoded: Vec<u8>| (encoded.len() < bytes.len()).then(|| Bytes::from(encoded));
+        Self {
+            mime,
+            etag: etag(&bytes),
+            gzip: compressible.then(|| gzip(&bytes)).flatten().and_then(smaller),
+            brotli: compressible.then(|| brotli(&bytes)).flatten().and_then(smaller),
+            identity: bytes,
+        }
+    }
+}
+
+lazy_static! {
+    // Compressed once on first use; the embedded files never change at runtime
+    static ref ASSETS: Vec<(&'static str, Asset)> = EMBEDDED
+        .iter()
+        .map(|(name, bytes)| (*name, Asset::new(name, Bytes::from_static(bytes), true)))
+        .collect();
+}
+
+fn override_dir() -> Option<PathBuf> {
+    std::env::var_os(OVERRIDE_DIR_ENV).map(PathBuf::from)
+}
+
+/// Raw contents of an asset, e.g. the HTML template
+pub fn read(name: &str) -> Option<Cow<'static, [u8]>> {
+    let (name, bytes) = EMBEDDED.iter().find(|(n, _)| *n == name)?;
+    if let Some(dir) = override_dir() {
+        match std::fs::read(dir.join(name)) {
+            Ok(contents) => return Some(Cow::Owned(contents)),
+            Err(e) => log::warn!("Override asset {} unreadable, using embedded copy: {}", name, e),
+        }
+    }
+    Some(Cow::Borrowed(*bytes))
+}
+
+/// Serve an asset with caching and content negotiation
+pub fn serve(name: &str, request_headers: &HeaderMap) -> Response {
+    let from_disk;
+    let asset = match override_dir() {
+        Some(_) => {
+            from_disk = read(name).map(|bytes| Asset::new(name, Bytes::from(bytes.into_owned()), false));
+            from_disk.as_ref()
+        }
+        None => ASSETS.iter().find(|(n, _)| *n == name).map(|(_, a)| a),
+    };
+    let Some(asset) = asset else {
+        log::debug!("Static asset not found: {}", name);
+        return (StatusCode::NOT_FOUND, [(header::CONTENT_TYPE, "text/plain")], "File not found").into_response();
+    };
+
+    let mut response = if if_none_match(request_headers, &asset.etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let accepted = request_headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        let (encoding, body) = match (&asset.brotli, &asset.gzip) {
+            (Some(br), _) if accepts(accepted, "br") => (Some("br"), br.clone()),
+            (_, Some(gz)) if accepts(accepted, "gzip") => (Some("gzip"), gz.clone()),
+            _ => (None, asset.identity.clone()),
+        };
+
+        let mut response = Response::new(Body::from(body));
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(asset.mime));
+        if let Some(encoding) = encoding {
+            response.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        response
+    };
+
+    let headers = response.headers_mut();
+    // Names are not content-hashed, so always revalidate against the ETag
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    if let Ok(etag) = HeaderValue::from_str(&asset.etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    response
+}
+
+fn mime_type(name: &str) -> &'static str {
+    match name.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("png") => "image/png",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+fn etag(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        }))
+        .unwrap_or(false)
+}
+
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|part| {
+        let mut fields = part.split(';');
+        let name = fields.next().unwrap_or("").trim();
+        let rejected = fields.any(|f| matches!(f.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+        name.eq_ignore_ascii_case(encoding) && !rejected
+    })
+}
+
+fn gzip(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(bytes).ok()?;
+    encoder.finish().ok()
+}
+
+fn brotli(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let params = BrotliEncoderParams { quality: 11, ..Default::default() };
+    brotli::BrotliCompress(&mut &bytes[..], &mut out, &params).ok()?;
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[test]
+    fn unknown_and_escaping_paths_are_not_found() {
+        let names = [
+            "missing.js",
+            "../Cargo.toml",
+            "../web-client/index.html",
+            "/etc/passwd",
+            "static/../index.html",
+            "%2e%2e/index.html",
+            "",
+        ];
+        for name in names {
+            let response = serve(name, &HeaderMap::new());
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{:?}", name);
+            assert!(read(name).is_none(), "{:?}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn matching_etag_is_not_modified() {
+        let first = serve("kvm-client.js", &HeaderMap::new());
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()[header::CONTENT_TYPE], "application/javascript");
+        let etag = first.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        let cached = serve("kvm-client.js", &request(&[(header::IF_NONE_MATCH, &etag)]));
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(cached.headers()[header::ETAG], etag.as_str());
+        assert!(body(cached).await.is_empty());
+
+        let weak = format!("\"other\", W/{}", etag);
+        assert_eq!(serve("kvm-client.js", &request(&[(header::IF_NONE_MATCH, &weak)])).status(), StatusCode::NOT_MODIFIED);
+        let stale = serve("kvm-client.js", &request(&[(header::IF_NONE_MATCH, "\"0000\"")]));
+        assert_eq!(stale.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn picks_the_best_accepted_encoding() {
+        let original = read("kvm-client.js").unwrap().into_owned();
+        let encoded = |accept: &'static str| serve("kvm-client.js", &request(&[(header::ACCEPT_ENCODING, accept)]));
+        let encoding = |response: &Response| {
+            response.headers().get(header::CONTENT_ENCODING).map(|v| v.to_str().unwrap().to_string())
+        };
+
+        let response = encoded("gzip, deflate, br");
+        assert_eq!(encoding(&response).as_deref(), Some("br"));
+        assert_eq!(response.headers()[header::VARY], "Accept-Encoding");
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&body(response).await[..], 4096).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        let response = encoded("br;q=0, gzip");
+        assert_eq!(encoding(&response).as_deref(), Some("gzip"));
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&body(response).await[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        for accept in ["", "identity", "deflate"] {
+            let response = encoded(accept);
+            assert_eq!(encoding(&response), None, "{:?}", accept);
+            assert_eq!(body(response).await, original);
+        }
+    }
+
+    #[test]
+    fn parses_accept_encoding_quality() {
+        assert!(accepts("gzip;q=0.5", "gzip"));
+        assert!(accepts("GZIP", "gzip"));
+        assert!(!accepts("gzip;q=0", "gzip"));
+        assert!(!accepts("x-gzip", "gzip"));
+    }
+}
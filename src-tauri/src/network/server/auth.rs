@@ -0,0 +1,403 @@
+//! Session authentication for remote viewers
+//!
+//! Clients exchange the host password or the one-time PIN shown in the app for
+//! a signed, expiring token. The token is required to open `/ws`, and repeated
+//! failures from the same address lock that address out for a while.
+//!
+//! The token also carries the session role: the host password grants `admin`,
+//! the PIN grants `controller`. The consent prompt can lower either to `viewer`.
+
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use parking_lot::Mutex;
+use rand::Rng;
+use ring::pbkdf2;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+use crate::core::SessionRole;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authentication errors
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Invalid password or PIN")]
+    InvalidCredentials,
+    #[error("Too many failed attempts, try again in {0} seconds")]
+    LockedOut(u64),
+    #[error("Invalid session token")]
+    InvalidToken,
+    #[error("Session token expired")]
+    TokenExpired,
+}
+
+/// Authentication policy
+#[derive(Clone, Debug)]
+pub struct AuthConfig {
+    pub token_ttl: Duration,
+    pub max_failures: u32,
+    pub lockout_duration: Duration,
+    pub pin_length: usize,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            token_ttl: Duration::from_secs(60 * 60), // 1 hour
+            max_failures: 5,
+            lockout_duration: Duration::from_secs(5 * 60),
+            pin_length: 6,
+        }
+    }
+}
+
+/// Token handed to a client after a successful login
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionToken {
+    pub token: String,
+    pub expires_at: u64, // Unix timestamp in seconds
+    pub role: SessionRole,
+}
+
+/// What the host UI shows about access control
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessInfo {
+    pub pin: String,
+    pub password_set: bool,
+}
+
+/// Login request body for `POST /auth/login`
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub secret: String,
+}
+
+/// Most addresses with recent failures kept at once; past that the oldest are forgotten
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+
+/// PBKDF2-HMAC-SHA256 rounds for the host password, as OWASP recommends
+#[cfg(not(test))]
+const PASSWORD_ITERATIONS: u32 = 600_000;
+// Unoptimized test builds take seconds per hash at the real count
+#[cfg(test)]
+const PASSWORD_ITERATIONS: u32 = 1_000;
+
+/// The host password, salted and stretched; the password itself is never kept
+struct PasswordHash {
+    salt: [u8; 16],
+    hash: [u8; 32],
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> Self {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+        let mut hash = [0u8; 32];
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &salt, password.as_bytes(), &mut hash);
+        Self { salt, hash }
+    }
+
+    /// Constant-time comparison of the derived key
+    fn verify(&self, password: &str) -> bool {
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations(), &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+#[derive(Debug)]
+struct FailureRecord {
+    count: u32,
+    locked_until: Option<Instant>,
+    /// When the record is dropped: the end of the lockout, or one lockout
+    /// duration after the last failure
+    expires: Instant,
+}
+
+/// Issues and verifies session tokens
+pub struct AuthManager {
+    config: AuthConfig,
+    signing_key: [u8; 32],
+    password_hash: Mutex<Option<PasswordHash>>,
+    pin: Mutex<String>,
+    failures: Mutex<HashMap<IpAddr, FailureRecord>>,
+}
+
+impl AuthManager {
+    pub fn new(config: AuthConfig) -> Self {
+        let manager = Self {
+            signing_key: rand::thread_rng().gen(),
+            pin: Mutex::new(generate_pin(config.pin_length)),
+            password_hash: Mutex::new(None),
+            failures: Mutex::new(HashMap::new()),
+            config,
+        };
+        info!("🔐 Authentication enabled; access PIN generated");
+        manager
+    }
+
+    /// Set or clear the host password. The PIN keeps working either way.
+    pub fn set_password(&self, password: Option<&str>) {
+        *self.password_hash.lock() = password
+            .filter(|p| !p.is_empty())
+            .map(PasswordHash::new);
+        info!("🔐 Host password {}", if self.password_hash.lock().is_some() { "updated" } else { "cleared" });
+    }
+
+    /// Current one-time PIN and whether a password is configured
+    pub fn access_info(&self) -> AccessInfo {
+        AccessInfo {
+            pin: self.pin.lock().clone(),
+            password_set: self.password_hash.lock().is_some(),
+        }
+    }
+
+    /// Replace the PIN, invalidating the old one
+    pub fn regenerate_pin(&self) -> String {
+        let pin = generate_pin(self.config.pin_length);
+        *self.pin.lock() = pin.clone();
+        info!("🔐 Access PIN regenerated");
+        pin
+    }
+
+    /// Exchange a password or PIN for a session token
+    pub fn login(&self, addr: IpAddr, secret: &str) -> Result<SessionToken, AuthError> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock();
+        // Forget finished lockouts, so rotating source addresses cannot grow the map forever
+        failures.retain(|_, record| record.expires > now);
+
+        if let Some(until) = failures.get(&addr).and_then(|record| record.locked_until) {
+            return Err(AuthError::LockedOut((until - now).as_secs().max(1)));
+        }
+
+        if let Some(role) = self.check_secret(secret) {
+            failures.remove(&addr);
+            info!("🔓 Client {} authenticated as {}", addr, role.name());
+            return Ok(self.issue_token(role));
+        }
+
+        if !failures.contains_key(&addr) && failures.len() >= MAX_TRACKED_ADDRESSES {
+            let oldest = failures.iter().min_by_key(|(_, record)| record.expires).map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                failures.remove(&oldest);
+            }
+        }
+        let record = failures.entry(addr).or_insert(FailureRecord { count: 0, locked_until: None, expires: now });
+        record.count += 1;
+        record.expires = now + self.config.lockout_duration;
+        warn!("🔒 Failed login from {} ({}/{})", addr, record.count, self.config.max_failures);
+        if record.count >= self.config.max_failures {
+            record.locked_until = Some(record.expires);
+            warn!("🔒 Client {} locked out for {}s", addr, self.config.lockout_duration.as_secs());
+            return Err(AuthError::LockedOut(self.config.lockout_duration.as_secs()));
+        }
+        Err(AuthError::InvalidCredentials)
+    }
+
+    /// Check a token's signature and expiry, returning the role it grants
+    pub fn verify_token(&self, token: &str) -> Result<SessionRole, AuthError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::InvalidToken)?;
+        let signature = decode_hex(signature).ok_or(AuthError::InvalidToken)?;
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| AuthError::InvalidToken)?;
+
+        let mut fields = payload.split('.');
+        let expires_at: u64 = fields
+            .next()
+            .and_then(|expiry| expiry.parse().ok())
+            .ok_or(AuthError::InvalidToken)?;
+        let role = fields
+            .next()
+            .and_then(SessionRole::from_name)
+            .ok_or(AuthError::InvalidToken)?;
+        if unix_now() >= expires_at {
+            return Err(AuthError::TokenExpired);
+        }
+        Ok(role)
+    }
+
+    /// Password and PIN comparisons run in constant time. A correct PIN is used up.
+    /// The password grants admin, the PIN grants control.
+    fn check_secret(&self, secret: &str) -> Option<SessionRole> {
+        let password_ok = self.password_hash.lock()
+            .as_ref()
+            .is_some_and(|hash| hash.verify(secret));
+
+        let mut pin = self.pin.lock();
+        let pin_ok = bool::from(pin.as_bytes().ct_eq(secret.trim().as_bytes()));
+        if pin_ok {
+            *pin = generate_pin(self.config.pin_length);
+        }
+
+        if password_ok {
+            Some(SessionRole::Admin)
+        } else if pin_ok {
+            Some(SessionRole::Controller)
+        } else {
+            None
+        }
+    }
+
+    fn issue_token(&self, role: SessionRole) -> SessionToken {
+        let expires_at = unix_now() + self.config.token_ttl.as_secs();
+        let nonce: [u8; 16] = rand::thread_rng().gen();
+        let payload = format!("{}.{}.{}", expires_at, role.name(), encode_hex(&nonce));
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = mac.finalize().into_bytes();
+
+        SessionToken {
+            token: format!("{}.{}", payload, encode_hex(&signature)),
+            expires_at,
+            role,
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.signing_key).expect("HMAC accepts any key length")
+    }
+}
+
+impl Default for AuthManager {
+    fn default() -> Self {
+        Self::new(AuthConfig::default())
+    }
+}
+
+fn iterations() -> NonZeroU32 {
+    NonZeroU32::new(PASSWORD_ITERATIONS).expect("PBKDF2 needs at least one round")
+}
+
+fn generate_pin(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..length).map(|_| char::from(b'0' + rng.gen_range(0..10))).collect()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 20));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 168, 1, 21));
+
+    fn manager_with_password() -> AuthManager {
+        let auth = AuthManager::default();
+        auth.set_password(Some("correct horse"));
+        auth
+    }
+
+    #[test]
+    fn tokens_carry_the_role_of_the_secret_used() {
+        let auth = manager_with_password();
+
+        let admin = auth.login(CLIENT, "correct horse").unwrap();
+        assert_eq!(admin.role, SessionRole::Admin);
+        assert_eq!(auth.verify_token(&admin.token), Ok(SessionRole::Admin));
+
+        let pin = auth.access_info().pin;
+        let controller = auth.login(CLIENT, &pin).unwrap();
+        assert_eq!(controller.role, SessionRole::Controller);
+        assert_eq!(auth.verify_token(&controller.token), Ok(SessionRole::Controller));
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let auth = manager_with_password();
+        let token = auth.login(CLIENT, &auth.access_info().pin).unwrap().token;
+
+        // Raising the role invalidates the signature
+        let promoted = token.replacen("controller", "admin", 1);
+        assert_eq!(auth.verify_token(&promoted), Err(AuthError::InvalidToken));
+
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let flipped = if signature.starts_with('0') { "1" } else { "0" };
+        assert_eq!(auth.verify_token(&format!("{}.{}{}", payload, flipped, &signature[1..])), Err(AuthError::InvalidToken));
+        assert_eq!(auth.verify_token(payload), Err(AuthError::InvalidToken));
+        assert_eq!(auth.verify_token(""), Err(AuthError::InvalidToken));
+
+        // Another server instance signs with another key
+        assert_eq!(AuthManager::default().verify_token(&token), Err(AuthError::InvalidToken));
+    }
+
+    #[test]
+    fn passwords_are_salted() {
+        let first = PasswordHash::new("correct horse");
+        let second = PasswordHash::new("correct horse");
+        assert_ne!((first.salt, first.hash), (second.salt, second.hash));
+
+        assert!(first.verify("correct horse") && second.verify("correct horse"));
+        assert!(!first.verify("correct horse "));
+        assert!(!first.verify(""));
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let auth = AuthManager::new(AuthConfig { token_ttl: Duration::ZERO, ..AuthConfig::default() });
+        let token = auth.login(CLIENT, &auth.access_info().pin).unwrap().token;
+        assert_eq!(auth.verify_token(&token), Err(AuthError::TokenExpired));
+    }
+
+    #[test]
+    fn pin_works_once() {
+        let auth = AuthManager::default();
+        let pin = auth.access_info().pin;
+
+        assert!(auth.login(CLIENT, &pin).is_ok());
+        assert_eq!(auth.login(CLIENT, &pin).unwrap_err(), AuthError::InvalidCredentials);
+        assert!(auth.login(CLIENT, &auth.access_info().pin).is_ok());
+    }
+
+    #[test]
+    fn five_failures_lock_the_address_out() {
+        let auth = manager_with_password();
+        for _ in 0..4 {
+            assert_eq!(auth.login(CLIENT, "guess").unwrap_err(), AuthError::InvalidCredentials);
+        }
+        assert!(matches!(auth.login(CLIENT, "guess"), Err(AuthError::LockedOut(_))));
+
+        // Even the right password waits out the lockout, but other addresses are unaffected
+        assert!(matches!(auth.login(CLIENT, "correct horse"), Err(AuthError::LockedOut(_))));
+        assert!(auth.login(OTHER_CLIENT, "correct horse").is_ok());
+    }
+
+    #[test]
+    fn stale_failure_records_are_dropped() {
+        let auth = AuthManager::new(AuthConfig { lockout_duration: Duration::ZERO, ..AuthConfig::default() });
+        for _ in 0..5 {
+            let _ = auth.login(CLIENT, "guess");
+        }
+        assert!(auth.login(OTHER_CLIENT, "guess").is_err());
+        // Records expire as soon as the lockout would end and go with the next attempt
+        assert!(auth.login(OTHER_CLIENT, &auth.access_info().pin).is_ok());
+        assert!(auth.failures.lock().is_empty());
+    }
+}
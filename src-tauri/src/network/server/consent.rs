@@ -0,0 +1,293 @@
+//! Host approval for incoming sessions
+//!
+//! Every new `/ws` session is held until the host answers a prompt in the app
+//! (or the prompt times out, which counts as a denial). Nothing is captured
+//! before that. Clients the host marks "always allow" skip the prompt next time.
+//! A prompt is withdrawn as soon as its client disconnects, so an answer given
+//! afterwards cannot remember a client that is gone.
+//!
+//! The prompt is the app's own dialog rather than a native message box: it
+//! needs three answers plus an "always allow" checkbox, and it must be closed
+//! from here on timeout or disconnect. Native message boxes offer at most two
+//! buttons and stay open until the host clicks one. The main window is raised
+//! and flagged for attention instead, so the prompt is not missed.
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, UserAttentionType};
+use tokio::sync::oneshot;
+
+/// Event emitted to the host UI when a client asks to connect
+pub const CONSENT_REQUEST_EVENT: &str = "connection-request";
+/// Event emitted when a request is answered or expires
+pub const CONSENT_RESOLVED_EVENT: &str = "connection-request-resolved";
+
+/// Host's answer to a connection request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentDecision {
+    Deny,
+    AllowView,
+    AllowControl,
+}
+
+impl ConsentDecision {
+    pub fn allows_control(self) -> bool {
+        self == ConsentDecision::AllowControl
+    }
+}
+
+/// Payload of [`CONSENT_REQUEST_EVENT`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsentRequest {
+    pub id: u64,
+    pub client: String,
+    pub timeout_secs: u64,
+}
+
+/// Payload of [`CONSENT_RESOLVED_EVENT`]
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsentResolved {
+    pub id: u64,
+    pub decision: ConsentDecision,
+}
+
+/// Where connection requests are shown to the host
+pub trait ConsentPrompt {
+    fn show(&self, request: &ConsentRequest);
+    /// The request was answered, expired or withdrawn
+    fn resolved(&self, resolved: &ConsentResolved);
+}
+
+impl ConsentPrompt for AppHandle {
+    fn show(&self, request: &ConsentRequest) {
+        if let Err(e) = self.emit_all(CONSENT_REQUEST_EVENT, request) {
+            warn!("Failed to emit connection request: {}", e);
+        }
+        if let Some(window) = self.get_window("main") {
+            let _ = window.unminimize();
+            let _ = window.set_focus();
+            let _ = window.request_user_attention(Some(UserAttentionType::Critical));
+        }
+    }
+
+    fn resolved(&self, resolved: &ConsentResolved) {
+        let _ = self.emit_all(CONSENT_RESOLVED_EVENT, resolved);
+    }
+}
+
+struct PendingRequest {
+    client: IpAddr,
+    reply: oneshot::Sender<(ConsentDecision, bool)>,
+}
+
+/// Tracks pending prompts and remembered clients
+pub struct ConsentManager {
+    timeout: Duration,
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<u64, PendingRequest>>,
+    remembered: Mutex<HashMap<IpAddr, ConsentDecision>>,
+}
+
+impl ConsentManager {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_id: AtomicU64::new(1),
+            pending: Mutex::new(HashMap::new()),
+            remembered: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ask the host whether `client` may connect. Resolves to `Deny` on timeout,
+    /// or as soon as `disconnected` completes.
+    pub async fn request(
+        &self,
+        prompt: &impl ConsentPrompt,
+        client: IpAddr,
+        disconnected: impl Future<Output = ()>,
+    ) -> ConsentDecision {
+        if let Some(decision) = self.remembered.lock().get(&client).copied() {
+            info!("✅ {} is remembered as {:?}", client, decision);
+            return decision;
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, response) = oneshot::channel();
+        self.pending.lock().insert(id, PendingRequest { client, reply });
+
+        info!("🙋 Asking host to approve connection {} from {}", id, client);
+        prompt.show(&ConsentRequest {
+            id,
+            client: client.to_string(),
+            timeout_secs: self.timeout.as_secs(),
+        });
+
+        let decision = tokio::select! {
+            answer = tokio::time::timeout(self.timeout, response) => match answer {
+                Ok(Ok((decision, remember))) => {
+                    if remember && decision != ConsentDecision::Deny {
+                        self.remembered.lock().insert(client, decision);
+                    }
+                    decision
+                }
+                // Timed out, or the manager dropped the request
+                _ => {
+                    warn!("⌛ Connection request {} from {} expired", id, client);
+                    ConsentDecision::Deny
+                }
+            },
+            _ = disconnected => {
+                info!("🙋 {} disconnected, withdrawing connection request {}", client, id);
+                ConsentDecision::Deny
+            }
+        };
+        self.pending.lock().remove(&id);
+
+        prompt.resolved(&ConsentResolved { id, decision });
+        info!("🙋 Connection {} from {}: {:?}", id, client, decision);
+        decision
+    }
+
+    /// Answer a pending request from the host UI
+    pub fn respond(&self, id: u64, decision: ConsentDecision, remember: bool) -> Result<(), String> {
+        let request = self
+            .pending
+            .lock()
+            .remove(&id)
+            .ok_or_else(|| format!("Connection request {} is no longer pending", id))?;
+        request
+            .reply
+            .send((decision, remember))
+            .map_err(|_| format!("Client {} already disconnected", request.client))
+    }
+
+    /// Forget every "always allow" choice
+    pub fn forget_remembered(&self) {
+        self.remembered.lock().clear();
+        info!("🙋 Cleared remembered clients");
+    }
+}
+
+impl Default for ConsentManager {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::pending;
+    use std::net::Ipv4Addr;
+
+    /// Records requests instead of showing them
+    #[derive(Default)]
+    struct RecordingPrompt {
+        shown: Mutex<Vec<u64>>,
+        resolved: Mutex<Vec<(u64, ConsentDecision)>>,
+    }
+
+    impl ConsentPrompt for RecordingPrompt {
+        fn show(&self, request: &ConsentRequest) {
+            self.shown.lock().push(request.id);
+        }
+
+        fn resolved(&self, resolved: &ConsentResolved) {
+            self.resolved.lock().push((resolved.id, resolved.decision));
+        }
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+
+    /// Wait for request `id` to be shown
+    async fn shown(prompt: &RecordingPrompt, id: u64) {
+        while !prompt.shown.lock().contains(&id) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Answer request `id` once it is shown
+    async fn answer(consent: &ConsentManager, prompt: &RecordingPrompt, id: u64, decision: ConsentDecision, remember: bool) {
+        shown(prompt, id).await;
+        consent.respond(id, decision, remember).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_are_denied() {
+        let consent = ConsentManager::new(Duration::from_millis(20));
+        let prompt = RecordingPrompt::default();
+
+        let decision = consent.request(&prompt, CLIENT, pending()).await;
+        assert_eq!(decision, ConsentDecision::Deny);
+        assert_eq!(*prompt.resolved.lock(), vec![(1, ConsentDecision::Deny)]);
+        assert!(consent.respond(1, ConsentDecision::AllowControl, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn remembered_clients_skip_the_prompt() {
+        let consent = ConsentManager::new(Duration::from_secs(30));
+        let prompt = RecordingPrompt::default();
+
+        let (decision, _) = tokio::join!(
+            consent.request(&prompt, CLIENT, pending()),
+            answer(&consent, &prompt, 1, ConsentDecision::AllowView, true),
+        );
+        assert_eq!(decision, ConsentDecision::AllowView);
+
+        let decision = consent.request(&prompt, CLIENT, pending()).await;
+        assert_eq!(decision, ConsentDecision::AllowView);
+        assert_eq!(prompt.shown.lock().len(), 1);
+
+        // Other clients are still asked about
+        let other = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21));
+        let (decision, _) = tokio::join!(
+            consent.request(&prompt, other, pending()),
+            answer(&consent, &prompt, 2, ConsentDecision::Deny, true),
+        );
+        assert_eq!(decision, ConsentDecision::Deny);
+        assert_eq!(prompt.shown.lock().len(), 2);
+
+        consent.forget_remembered();
+        let (decision, _) = tokio::join!(
+            consent.request(&prompt, CLIENT, pending()),
+            answer(&consent, &prompt, 3, ConsentDecision::AllowControl, false),
+        );
+        assert_eq!(decision, ConsentDecision::AllowControl);
+        assert_eq!(prompt.shown.lock().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn disconnecting_withdraws_the_request() {
+        let consent = ConsentManager::new(Duration::from_secs(30));
+        let prompt = RecordingPrompt::default();
+        let (disconnect, disconnected) = oneshot::channel::<()>();
+
+        let (decision, _) = tokio::join!(
+            consent.request(&prompt, CLIENT, async {
+                let _ = disconnected.await;
+            }),
+            async {
+                shown(&prompt, 1).await;
+                drop(disconnect);
+            },
+        );
+        assert_eq!(decision, ConsentDecision::Deny);
+        assert_eq!(*prompt.resolved.lock(), vec![(1, ConsentDecision::Deny)]);
+
+        // A late "always allow" neither succeeds nor sticks
+        assert!(consent.respond(1, ConsentDecision::AllowControl, true).is_err());
+        let (decision, _) = tokio::join!(
+            consent.request(&prompt, CLIENT, pending()),
+            answer(&consent, &prompt, 2, ConsentDecision::Deny, false),
+        );
+        assert_eq!(decision, ConsentDecision::Deny);
+        assert_eq!(prompt.shown.lock().len(), 2);
+    }
+}
@@ -1,165 +1,187 @@
 use axum::{
-    extract::{ws::WebSocketUpgrade, Query},
-    response::{Html, IntoResponse},
-    http::{StatusCode, header},
+    extract::{ws::{CloseFrame, Message, WebSocketUpgrade}, ConnectInfo, Query},
+    response::{Html, IntoResponse, Response},
+    http::{HeaderMap, StatusCode, Uri},
+    Json,
 };
 use std::collections::HashMap;
-use std::fs;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tauri::AppHandle;
 use tokio::sync::broadcast;
 
-use super::websocket::{handle_socket_wrapper, handle_socket_wrapper_with_stop, handle_socket_ultra};
+use crate::core::{ClipboardHub, ScreenCapture, ServerMessage, SessionCommand, SessionContext, SessionRole, TransferRoot};
 
-fn get_web_client_path() -> PathBuf {
-    // Try multiple possible locations for the web-client directory
-    let possible_paths = vec![
-        "web-client",                           // Current working directory
-        "src-tauri/web-client",                 // From project root
-        "../src-tauri/web-client",              // From dist directory  
-        "./src-tauri/web-client",               // Alternative from root
-    ];
-    
-    for path in possible_paths {
-        let full_path = PathBuf::from(path);
-        if full_path.exists() && full_path.is_dir() {
-            return full_path;
-        }
-    }
-    
-    // Fallback to the default path
-    PathBuf::from("web-client")
-}
+use super::assets;
+use super::template::{self, KvmPageParams};
+use super::auth::{AuthError, AuthManager, LoginRequest};
+use super::consent::{ConsentDecision, ConsentManager};
+use super::security::OriginPolicy;
+use super::websocket::handle_socket_wrapper_with_stop;
+
+/// WebSocket close code sent when the host denies or ignores a connection
+const CONSENT_DENIED_CLOSE_CODE: u16 = 4003;
 
-pub async fn kvm_client_handler(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+pub async fn kvm_client_handler(Query(params): Query<HashMap<String, String>>) -> Response {
     log::info!("KVM client page requested with parameters: {:?}", params);
     
-    // Parse the query parameters
-    let stretch = params.get("stretch").map(|v| v == "true").unwrap_or(false);
-    let mute = params.get("mute").map(|v| v == "true").unwrap_or(false);
-    let audio = params.get("audio").map(|v| v == "true").unwrap_or(false);
-    let remote_only = params.get("remoteOnly").map(|v| v == "true").unwrap_or(false);
-    let encryption = params.get("encryption").map(|v| v == "true").unwrap_or(false);
-    let monitor = params.get("monitor").map(|v| v.parse::<usize>().unwrap_or(0)).unwrap_or(0);
-    let codec = params.get("codec").unwrap_or(&"vp8".to_string()).clone();
+    // Validate the query parameters against their allow-lists
+    let monitor_count = ScreenCapture::get_all_monitors().ok().map(|m| m.len());
+    let page = match KvmPageParams::from_query(&params, monitor_count) {
+        Ok(page) => page,
+        Err(e) => {
+            log::warn!("Rejected KVM client request: {}", e);
+            return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+        }
+    };
     
     log::debug!("KVM client configuration - stretch: {}, mute: {}, audio: {}, monitor: {}, codec: {}", 
-               stretch, mute, audio, monitor, codec);    // Prepare template replacements
-    let replacements = [
-        ("{{stretch}}", stretch.to_string()),
-        ("{{mute}}", mute.to_string()),
-        ("{{audio}}", audio.to_string()),
-        ("{{remote_only}}", remote_only.to_string()),
-        ("{{encryption}}", encryption.to_string()),
-        ("{{monitor}}", monitor.to_string()),
-        ("{{codec}}", codec.to_string()),
-        ("{{mute_attr}}", if mute { "muted".to_string() } else { "".to_string() }),
-        ("{{stretch_checked}}", if stretch { "checked".to_string() } else { "".to_string() }),
-        ("{{audio_checked}}", if audio { "checked".to_string() } else { "".to_string() }),
-        ("{{mute_checked}}", if mute { "checked".to_string() } else { "".to_string() }),
-    ];
+               page.stretch, page.mute, page.audio, page.monitor, page.codec);
 
-    // Load the HTML template
-    let web_client_base = get_web_client_path();
-    let template_path = web_client_base.join("kvm-template.html");
-    
-    log::info!("Looking for template at: {:?}", template_path);
-    
-    let html = match fs::read_to_string(&template_path) {
-        Ok(template) => {
-            // Apply all replacements
-            let mut result = template;
-            for (pattern, replacement) in replacements.iter() {
-                result = result.replace(pattern, replacement);
-            }
-            result
-        },
-        Err(err) => {
-            // If template loading fails, return a simple error page
-            log::error!("Failed to load KVM template: {}", err);
-            format!(
-                r#"<!DOCTYPE html>
-<html><body>
-<h1>Error</h1>
-<p>Failed to load KVM client template: {}</p>
-<p>Please ensure the web-client directory is properly configured.</p>
-</body></html>"#,
-                err
-            )
-        }
+    // Load and render the HTML template
+    let Some(template) = assets::read("kvm-template.html") else {
+        log::error!("KVM template missing from embedded assets");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load KVM client template").into_response();
     };
 
-    Html(html)
+    match template::render(&String::from_utf8_lossy(&template), &page.template_values()) {
+        Ok(html) => Html(html).into_response(),
+        Err(e) => {
+            log::error!("Failed to render KVM template: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render KVM client template").into_response()
+        }
+    }
 }
 
-// New handler for serving static files
+// Serve web client assets under /static
 pub async fn static_file_handler(
-    axum::extract::Path(path): axum::extract::Path<String>
-) -> impl IntoResponse {
-    let web_client_base = get_web_client_path();
-    let file_path = web_client_base.join(&path);
-    
-    log::info!("Looking for static file at: {:?}", file_path);
-    
-    match fs::read(&file_path) {
-        Ok(contents) => {
-            // Determine content type based on file extension
-            let content_type = match path.split('.').last() {
-                Some("css") => "text/css",
-                Some("js") => "application/javascript",
-                Some("html") => "text/html",
-                Some("png") => "image/png",
-                Some("jpg") | Some("jpeg") => "image/jpeg",
-                Some("svg") => "image/svg+xml",
-                Some("ico") => "image/x-icon",
-                _ => "application/octet-stream",
+    axum::extract::Path(path): axum::extract::Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    assets::serve(&path, &headers)
+}
+
+// Serve assets by name at the root, e.g. / and /index.html
+pub async fn root_asset_handler(uri: Uri, headers: HeaderMap) -> Response {
+    let name = match uri.path().trim_start_matches('/') {
+        "" => "index.html",
+        name => name,
+    };
+    assets::serve(name, &headers)
+}
+
+/// Exchange a password or one-time PIN for a session token
+pub async fn login_handler(
+    auth: Arc<AuthManager>,
+    origins: Arc<OriginPolicy>,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<LoginRequest>,
+) -> Response {
+    if let Err(e) = origins.check(&headers) {
+        log::warn!("🔒 Rejected login from {}: {}", addr, e);
+        return (StatusCode::FORBIDDEN, Json(serde_json::json!({ "error": e }))).into_response();
+    }
+
+    match auth.login(addr.ip(), &request.secret) {
+        Ok(token) => Json(token).into_response(),
+        Err(e) => {
+            let status = match e {
+                AuthError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::UNAUTHORIZED,
             };
-            
-            (
-                StatusCode::OK,
-                [(header::CONTENT_TYPE, content_type)],
-                contents
-            )
-        },
-        Err(_) => {
-            (
-                StatusCode::NOT_FOUND,
-                [(header::CONTENT_TYPE, "text/plain")],
-                b"File not found".to_vec()
-            )
+            (status, Json(serde_json::json!({ "error": e.to_string() }))).into_response()
         }
     }
 }
 
-pub async fn ws_handler(ws: WebSocketUpgrade, Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
-    // Extract monitor parameter
-    let monitor = params.get("monitor").map(|v| v.parse::<usize>().unwrap_or(0)).unwrap_or(0);
-    // Parse codec parameter and default to vp8 if not specified
-    let codec = params.get("codec").unwrap_or(&"vp8".to_string()).clone();
-    let audio = params.get("audio").map(|v| v == "true").unwrap_or(false);
-    
-    log::debug!("WebSocket connection - monitor: {}, codec: {}, audio: {}", monitor, codec, audio);
-    
-    // Pass connection parameters to the WebSocket handler - use 'move' to take ownership
-    ws.on_upgrade(move |socket| handle_socket_wrapper(socket, monitor, codec, audio))
+/// Report whether a session token is still accepted
+pub async fn auth_check_handler(
+    auth: Arc<AuthManager>,
+    Query(params): Query<HashMap<String, String>>,
+) -> StatusCode {
+    let token = params.get("token").map(String::as_str).unwrap_or("");
+    match auth.verify_token(token) {
+        Ok(_) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::UNAUTHORIZED,
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_handler_with_stop(
     ws: WebSocketUpgrade, 
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Query(params): Query<HashMap<String, String>>,
-    stop_rx: broadcast::Receiver<()>
-) -> impl IntoResponse {
+    stop_rx: broadcast::Receiver<()>,
+    commands: broadcast::Sender<SessionCommand>,
+    clipboard: Option<Arc<ClipboardHub>>,
+    transfer_root: Option<Arc<TransferRoot>>,
+    auth: Arc<AuthManager>,
+    origins: Arc<OriginPolicy>,
+    consent: Arc<ConsentManager>,
+    app_handle: AppHandle,
+) -> Response {
+    // WebSocket upgrades bypass CORS, so cross-site pages must be turned away here
+    if let Err(e) = origins.check(&headers) {
+        log::warn!("🔒 Rejected WebSocket connection: {}", e);
+        return (StatusCode::FORBIDDEN, e).into_response();
+    }
+
+    // Browsers cannot set headers on WebSocket requests, so the token travels in the query
+    let token = params.get("token").map(String::as_str).unwrap_or("");
+    let token_role = match auth.verify_token(token) {
+        Ok(role) => role,
+        Err(e) => {
+            log::warn!("🔒 Rejected WebSocket connection: {}", e);
+            return (StatusCode::UNAUTHORIZED, e.to_string()).into_response();
+        }
+    };
+    
     // Extract monitor parameter
     let monitor = params.get("monitor").map(|v| v.parse::<usize>().unwrap_or(0)).unwrap_or(0);
     // Parse codec parameter and default to vp8 if not specified
     let codec = params.get("codec").unwrap_or(&"vp8".to_string()).clone();
     let audio = params.get("audio").map(|v| v == "true").unwrap_or(false);
     
+    // The query holds the session token, so only the stream settings are logged
     log::info!("WebSocket connection request - monitor: {}, codec: {}, audio: {}", monitor, codec, audio);
-    log::debug!("WebSocket query parameters: {:?}", params);
     
-    // Pass connection parameters to the WebSocket handler with stop signal
-    ws.on_upgrade(move |socket| {
-        log::info!("WebSocket connection established");
-        handle_socket_wrapper_with_stop(socket, monitor, codec, audio, stop_rx)
-    })
+    // Hold the session until the host approves it; nothing is captured before that
+    ws.on_upgrade(move |mut socket| async move {
+        log::info!("WebSocket connection established, waiting for host approval");
+        let _ = socket.send(Message::Text(ServerMessage::ConsentPending.to_message())).await;
+        
+        // Watch the socket while the prompt is open so a client that leaves
+        // withdraws it; anything else it sends before approval is dropped
+        let disconnected = async {
+            while let Some(Ok(message)) = socket.recv().await {
+                if matches!(message, Message::Close(_)) {
+                    break;
+                }
+            }
+        };
+        let decision = consent.request(&app_handle, addr.ip(), disconnected).await;
+        if decision == ConsentDecision::Deny {
+            let _ = socket.send(Message::Text(ServerMessage::ConsentDenied.to_message())).await;
+            let _ = socket.send(Message::Close(Some(CloseFrame {
+                code: CONSENT_DENIED_CLOSE_CODE,
+                reason: "Connection denied by host".into(),
+            }))).await;
+            return;
+        }
+        
+        // "View only" caps the role the token grants
+        let role = if decision.allows_control() { token_role } else { SessionRole::Viewer };
+        let granted = ServerMessage::ConsentGranted { control: role.can_control(), role };
+        let _ = socket.send(Message::Text(granted.to_message())).await;
+        let mut session = SessionContext::new(role, commands);
+        if let Some(clipboard) = clipboard {
+            session = session.with_clipboard(clipboard);
+        }
+        if let Some(root) = transfer_root {
+            session = session.with_transfer_root(root);
+        }
+        handle_socket_wrapper_with_stop(socket, monitor, codec, audio, session, stop_rx).await
+    }).into_response()
 }
\ No newline at end of file
@@ -1,7 +1,17 @@
+mod assets;
+pub mod auth;
+pub mod consent;
 mod handlers;
 pub mod models;  // Make models public
+mod security;
 mod server;
+mod template;
+pub mod tls;
 mod websocket;
 
 // Only export what's needed by the external code
+pub use auth::{AccessInfo, AuthConfig, AuthError, AuthManager};
+pub use consent::{ConsentDecision, ConsentManager};
+pub use security::OriginPolicy;
 pub use server::WebSocketServer;
+pub use tls::{CertificateSource, TlsError, TlsIdentity};
@@ -0,0 +1,207 @@
+//! Cross-origin protection and security headers
+//!
+//! Browsers attach an `Origin` header to WebSocket upgrades but never apply
+//! CORS to them, so the server itself must refuse upgrades started by other
+//! sites. Only origins the server is actually reachable at (its bound
+//! addresses, host name and port) are accepted, plus any the host adds. The
+//! request's own `Host` header is never trusted: after DNS rebinding an
+//! attacker's page has a matching `Origin` and `Host` under its own name.
+
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Which page origins may open `/ws` and call `/auth/*`
+#[derive(Debug, Clone, Default)]
+pub struct OriginPolicy {
+    allowed: BTreeSet<String>,
+}
+
+impl OriginPolicy {
+    /// Origins of the pages this server serves on `bind`, plus the given
+    /// origins, e.g. `https://kvm.example.com`
+    pub fn new<I, S>(bind: SocketAddr, secure: bool, extra_origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut hosts = vec!["localhost".to_string()];
+        hosts.extend(gethostname::gethostname().to_str().map(str::to_string));
+        hosts.extend(bound_addresses(bind.ip()).iter().map(|ip| match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        }));
+        Self::with_hosts(&hosts, bind.port(), secure, extra_origins)
+    }
+
+    fn with_hosts<I, S>(hosts: &[String], port: u16, secure: bool, extra_origins: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let (scheme, default_port) = if secure { ("https", 443) } else { ("http", 80) };
+        let mut allowed: BTreeSet<String> = hosts
+            .iter()
+            .flat_map(|host| {
+                let host = host.to_ascii_lowercase();
+                // Browsers leave the default port out of the origin
+                let bare = (port == default_port).then(|| format!("{}://{}", scheme, host));
+                std::iter::once(format!("{}://{}:{}", scheme, host, port)).chain(bare)
+            })
+            .collect();
+        allowed.extend(
+            extra_origins
+                .into_iter()
+                .map(|o| o.as_ref().trim().trim_end_matches('/').to_ascii_lowercase())
+                .filter(|o| !o.is_empty()),
+        );
+        Self { allowed }
+    }
+
+    /// Check the request's `Origin` against the allow-list. Requests without
+    /// an `Origin` come from non-browser clients and cannot be forged
+    /// cross-site, so they pass.
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), String> {
+        let Some(origin) = headers.get(header::ORIGIN) else {
+            return Ok(());
+        };
+        let origin = origin
+            .to_str()
+            .map_err(|_| "Malformed Origin header".to_string())?
+            .trim_end_matches('/')
+            .to_ascii_lowercase();
+
+        if self.allowed.contains(&origin) {
+            Ok(())
+        } else {
+            Err(format!("Origin {} is not allowed", origin))
+        }
+    }
+}
+
+/// Addresses a server bound to `ip` answers on; a wildcard bind covers
+/// loopback and every interface
+fn bound_addresses(ip: IpAddr) -> Vec<IpAddr> {
+    if !ip.is_unspecified() {
+        return vec![ip];
+    }
+    let mut addresses = vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+    if let Ok(interfaces) = local_ip_address::list_afinet_netifas() {
+        addresses.extend(interfaces.into_iter().map(|(_, ip)| ip));
+    }
+    addresses
+}
+
+/// Middleware adding security headers; HTML pages also get CSP and framing rules
+pub async fn security_headers(request: Request, next: Next) -> Response {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .filter(|h| h.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c)))
+        .map(str::to_string);
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("x-content-type-options", HeaderValue::from_static("nosniff"));
+
+    let is_html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("text/html"))
+        .unwrap_or(false);
+    if !is_html {
+        return response;
+    }
+
+    // Older browsers do not count ws:/wss: as 'self', so name the host explicitly
+    let connect_src = match host {
+        Some(host) => format!("'self' ws://{host} wss://{host}"),
+        None => "'self'".to_string(),
+    };
+    let csp = format!(
+        "default-src 'self'; script-src 'self'; style-src 'self' 'unsafe-inline'; \
+         img-src 'self' data: blob:; media-src 'self' blob:; connect-src {}; \
+         object-src 'none'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'",
+        connect_src
+    );
+    if let Ok(csp) = HeaderValue::from_str(&csp) {
+        headers.insert(header::CONTENT_SECURITY_POLICY, csp);
+    }
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    headers.insert(header::REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> OriginPolicy {
+        let hosts = ["localhost".to_string(), "192.168.1.20".to_string(), "Desk-PC".to_string()];
+        OriginPolicy::with_hosts(&hosts, 8080, false, ["https://kvm.example.com/"])
+    }
+
+    fn request(origin: Option<&str>, host: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_str(host).unwrap());
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, HeaderValue::from_str(origin).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn accepts_the_servers_own_origins() {
+        let policy = policy();
+        assert!(policy.check(&request(Some("http://192.168.1.20:8080"), "192.168.1.20:8080")).is_ok());
+        assert!(policy.check(&request(Some("http://desk-pc:8080"), "desk-pc:8080")).is_ok());
+        assert!(policy.check(&request(Some("https://kvm.example.com"), "kvm.example.com")).is_ok());
+    }
+
+    #[test]
+    fn rejects_foreign_origins() {
+        let policy = policy();
+        assert!(policy.check(&request(Some("http://evil.example"), "192.168.1.20:8080")).is_err());
+        // Right host, wrong scheme or port
+        assert!(policy.check(&request(Some("https://192.168.1.20:8080"), "192.168.1.20:8080")).is_err());
+        assert!(policy.check(&request(Some("http://192.168.1.20:9090"), "192.168.1.20:8080")).is_err());
+    }
+
+    #[test]
+    fn rejects_rebound_hosts_even_when_origin_matches_host() {
+        let policy = policy();
+        let rebound = request(Some("http://attacker.example:8080"), "attacker.example:8080");
+        assert!(policy.check(&rebound).is_err());
+    }
+
+    #[test]
+    fn requests_without_origin_pass() {
+        assert!(policy().check(&request(None, "192.168.1.20:8080")).is_ok());
+    }
+
+    #[test]
+    fn default_ports_may_be_left_out() {
+        let policy = OriginPolicy::with_hosts(&["localhost".to_string()], 443, true, Vec::<String>::new());
+        assert!(policy.check(&request(Some("https://localhost"), "localhost")).is_ok());
+        assert!(policy.check(&request(Some("https://localhost:443"), "localhost")).is_ok());
+        assert!(policy.check(&request(Some("http://localhost"), "localhost")).is_err());
+    }
+
+    #[test]
+    fn each_origin_is_listed_once() {
+        let hosts = ["localhost".to_string(), "127.0.0.1".to_string(), "LOCALHOST".to_string()];
+        let policy = OriginPolicy::with_hosts(&hosts, 8080, false, ["http://localhost:8080/", "http://127.0.0.1:8080"]);
+        assert_eq!(policy.allowed.len(), 2);
+    }
+
+    #[test]
+    fn wildcard_binds_cover_loopback() {
+        let addresses = bound_addresses(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert!(addresses.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert_eq!(bound_addresses(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))).len(), 1);
+    }
+}
@@ -1,10 +1,15 @@
 use axum::{
-    routing::get,
+    extract::ConnectInfo,
+    http::HeaderMap,
+    middleware,
+    routing::{get, post},
+    Extension,
     Router,
-    handler::HandlerWithoutStateExt,
 };
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::{
     sync::{mpsc, broadcast},
     task::JoinHandle,
@@ -12,32 +17,15 @@ use tokio::{
 };
 use tauri::AppHandle;
 use tower_http::trace::TraceLayer;
-use tower_http::services::ServeDir;
-use std::convert::Infallible;
-use axum::http::{StatusCode, Response};
-use axum::body::Body;
-
-use super::handlers::{kvm_client_handler, static_file_handler, ws_handler_with_stop};
-
-fn get_web_client_path() -> PathBuf {
-    // Try multiple possible locations for the web-client directory
-    let possible_paths = vec![
-        "web-client",                           // Current working directory
-        "src-tauri/web-client",                 // From project root
-        "../src-tauri/web-client",              // From dist directory  
-        "./src-tauri/web-client",               // Alternative from root
-    ];
-    
-    for path in possible_paths {
-        let full_path = PathBuf::from(path);
-        if full_path.exists() && full_path.is_dir() {
-            return full_path;
-        }
-    }
-    
-    // Fallback to the default path
-    PathBuf::from("web-client")
-}
+use tokio_rustls::TlsAcceptor;
+
+use crate::core::{ArboardBackend, ClipboardHub, SessionCommand, TransferRoot, CLIPBOARD_POLL_INTERVAL};
+
+use super::auth::AuthManager;
+use super::consent::ConsentManager;
+use super::handlers::{auth_check_handler, kvm_client_handler, login_handler, root_asset_handler, static_file_handler, ws_handler_with_stop};
+use super::security::{security_headers, OriginPolicy};
+use super::tls::TlsIdentity;
 
 pub struct WebSocketServer {
     shutdown_tx: mpsc::Sender<()>,
@@ -46,40 +34,98 @@ pub struct WebSocketServer {
     stop_broadcast: broadcast::Sender<()>,
 }
 
-// Define a simple function that returns a 404 response
-async fn handle_404() -> Result<Response<Body>, Infallible> {
-    Ok(Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::empty())
-        .unwrap())
+// Accept loop for HTTPS/WSS; axum::serve only handles plain TCP
+async fn serve_tls(listener: TcpListener, acceptor: TlsAcceptor, app: Router, mut shutdown_rx: mpsc::Receiver<()>) {
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            _ = shutdown_rx.recv() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+        };
+
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone().layer(Extension(ConnectInfo(remote_addr))));
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // Expected once per client until the self-signed certificate is accepted
+                    log::debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                    return;
+                }
+            };
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                log::debug!("Connection from {} ended with error: {}", remote_addr, e);
+            }
+        });
+    }
 }
 
 impl WebSocketServer {
-    pub async fn new(port: u16, _app_handle: AppHandle) -> Result<Self, String> {
+    pub async fn new(
+        port: u16,
+        app_handle: AppHandle,
+        auth: Arc<AuthManager>,
+        tls: Option<Arc<TlsIdentity>>,
+        origins: OriginPolicy,
+        consent: Arc<ConsentManager>,
+        transfer_root: Option<Arc<TransferRoot>>,
+    ) -> Result<Self, String> {
         // Channel for shutdown signal
         let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
         
         // Broadcast channel for stopping all connections
         let (stop_broadcast, _) = broadcast::channel::<()>(10);
         let stop_broadcast_clone = stop_broadcast.clone();
-        
-        // Get the correct web-client path
-        let web_client_path = get_web_client_path();
-        log::info!("Using web-client directory: {:?}", web_client_path);
+
+        // Admin commands (quality, monitor) shared by every session
+        let (session_commands, _) = broadcast::channel::<SessionCommand>(16);
+
+        // Host clipboard shared by every session; sessions work without it if it fails
+        let clipboard = ClipboardHub::spawn(Box::new(ArboardBackend::new()), CLIPBOARD_POLL_INTERVAL)
+            .map_err(|e| log::warn!("Clipboard sync disabled: {}", e))
+            .ok();
         
         // Set up the router
+        let ws_auth = Arc::clone(&auth);
+        let check_auth = Arc::clone(&auth);
+        let origins = Arc::new(origins);
+        let ws_origins = Arc::clone(&origins);
         let app = Router::new()
-            .route("/ws", get(move |ws: axum::extract::ws::WebSocketUpgrade, query: axum::extract::Query<std::collections::HashMap<String, String>>| {
+            .route("/ws", get(move |ws: axum::extract::ws::WebSocketUpgrade, headers: HeaderMap, addr: ConnectInfo<SocketAddr>, query: axum::extract::Query<std::collections::HashMap<String, String>>| {
                 let stop_rx = stop_broadcast_clone.subscribe();
-                async move { ws_handler_with_stop(ws, query, stop_rx).await }
+                let commands = session_commands.clone();
+                let clipboard = clipboard.clone();
+                let transfer_root = transfer_root.clone();
+                let auth = Arc::clone(&ws_auth);
+                let origins = Arc::clone(&ws_origins);
+                let consent = Arc::clone(&consent);
+                let app_handle = app_handle.clone();
+                async move { ws_handler_with_stop(ws, headers, addr, query, stop_rx, commands, clipboard, transfer_root, auth, origins, consent, app_handle).await }
+            }))
+            .route("/auth/check", get(move |query: axum::extract::Query<std::collections::HashMap<String, String>>| {
+                let auth = Arc::clone(&check_auth);
+                async move { auth_check_handler(auth, query).await }
+            }))
+            .route("/auth/login", post(move |headers: HeaderMap, addr: axum::extract::ConnectInfo<SocketAddr>, body: axum::Json<super::auth::LoginRequest>| {
+                let auth = Arc::clone(&auth);
+                let origins = Arc::clone(&origins);
+                async move { login_handler(auth, origins, headers, addr, body).await }
             }))
             .route("/kvm", get(kvm_client_handler))
             .route("/static/*path", get(static_file_handler))
-            .fallback_service(
-                ServeDir::new(&web_client_path)
-                    .append_index_html_on_directories(true)
-                    .not_found_service(handle_404.into_service())
-            )
+            .fallback(root_asset_handler)
+            .layer(middleware::from_fn(security_headers))
             .layer(TraceLayer::new_for_http());
 
         // Create TCP listener - bind to all interfaces (0.0.0.0)
@@ -101,22 +147,31 @@ impl WebSocketServer {
             },
         };
         
-        log::info!("WebSocket server listening on {}", addr);
-
-        // Create server with axum
-        let server = axum::serve(
-            listener,
-            app.into_make_service()
-        ).with_graceful_shutdown(async move {
-            shutdown_rx.recv().await;
-        });
+        let server_handle = match tls {
+            Some(identity) => {
+                log::info!("WebSocket server listening on {} (HTTPS/WSS)", addr);
+                let acceptor = TlsAcceptor::from(identity.server_config());
+                tokio::spawn(serve_tls(listener, acceptor, app, shutdown_rx))
+            }
+            None => {
+                log::info!("WebSocket server listening on {}", addr);
 
-        // Spawn the server task
-        let server_handle = tokio::spawn(async move {
-            if let Err(e) = server.await {
-                log::error!("Server error: {}", e);
+                // Create server with axum
+                let server = axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>()
+                ).with_graceful_shutdown(async move {
+                    shutdown_rx.recv().await;
+                });
+
+                // Spawn the server task
+                tokio::spawn(async move {
+                    if let Err(e) = server.await {
+                        log::error!("Server error: {}", e);
+                    }
+                })
             }
-        });
+        };
 
         Ok(WebSocketServer {
             shutdown_tx,
@@ -0,0 +1,261 @@
+//! KVM client page templating
+//!
+//! Query parameters are validated against allow-lists before they reach the
+//! page, and every substitution is escaped for the context it lands in: HTML
+//! text/attributes or JSON inside a `<script>` element.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Codecs the browser client knows how to request
+pub const CLIENT_CODECS: &[&str] = &["vp8", "rgba"];
+
+/// Keyboard translation modes, see `core::layout::KeyboardMode`
+pub const KEYBOARD_MODES: &[&str] = &["keysym", "scancode"];
+
+/// Template and parameter errors
+#[derive(Error, Debug, PartialEq)]
+pub enum TemplateError {
+    #[error("Invalid value for parameter '{0}'")]
+    InvalidParameter(&'static str),
+    #[error("Monitor {0} does not exist")]
+    MonitorOutOfRange(usize),
+    #[error("Template references unknown placeholder '{0}'")]
+    UnknownPlaceholder(String),
+    #[error("Unterminated placeholder in template")]
+    Unterminated,
+}
+
+/// A value substituted into the template
+pub enum TemplateValue {
+    /// Escaped for HTML text and quoted attribute values
+    Html(String),
+    /// Serialized as JSON that is safe inside a `<script>` element
+    Script(Value),
+}
+
+/// Validated `/kvm` query parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct KvmPageParams {
+    pub stretch: bool,
+    pub mute: bool,
+    pub audio: bool,
+    pub remote_only: bool,
+    pub encryption: bool,
+    pub clipboard: bool,
+    pub monitor: usize,
+    pub codec: &'static str,
+    pub keyboard: &'static str,
+}
+
+impl KvmPageParams {
+    /// Validate the parameters the page uses; anything else in the query is ignored.
+    /// The monitor index is range-checked when the monitor count is known.
+    pub fn from_query(params: &HashMap<String, String>, monitor_count: Option<usize>) -> Result<Self, TemplateError> {
+        let flag = |name: &'static str, key: &str| match params.get(key).map(String::as_str) {
+            None | Some("false") | Some("0") => Ok(false),
+            Some("true") | Some("1") => Ok(true),
+            Some(_) => Err(TemplateError::InvalidParameter(name)),
+        };
+
+        let monitor = match params.get("monitor") {
+            Some(v) => v.parse::<usize>().map_err(|_| TemplateError::InvalidParameter("monitor"))?,
+            None => 0,
+        };
+        if let Some(count) = monitor_count {
+            if monitor >= count {
+                return Err(TemplateError::MonitorOutOfRange(monitor));
+            }
+        }
+
+        let codec = match params.get("codec") {
+            Some(v) => CLIENT_CODECS
+                .iter()
+                .find(|c| c.eq_ignore_ascii_case(v))
+                .copied()
+                .ok_or(TemplateError::InvalidParameter("codec"))?,
+            None => CLIENT_CODECS[0],
+        };
+
+        let keyboard = match params.get("keyboard") {
+            Some(v) => KEYBOARD_MODES
+                .iter()
+                .find(|m| m.eq_ignore_ascii_case(v))
+                .copied()
+                .ok_or(TemplateError::InvalidParameter("keyboard"))?,
+            None => KEYBOARD_MODES[0],
+        };
+
+        Ok(Self {
+            stretch: flag("stretch", "stretch")?,
+            mute: flag("mute", "mute")?,
+            audio: flag("audio", "audio")?,
+            remote_only: flag("remoteOnly", "remoteOnly")?,
+            encryption: flag("encryption", "encryption")?,
+            clipboard: flag("clipboard", "clipboard")?,
+            monitor,
+            codec,
+            keyboard,
+        })
+    }
+
+    /// Values for `kvm-template.html`
+    pub fn template_values(&self) -> Vec<(&'static str, TemplateValue)> {
+        let attr = |on: bool, name: &str| TemplateValue::Html(if on { name.to_string() } else { String::new() });
+        vec![
+            ("codec", TemplateValue::Html(self.codec.to_string())),
+            ("mute_attr", attr(self.mute, "muted")),
+            ("stretch_checked", attr(self.stretch, "checked")),
+            ("audio_checked", attr(self.audio, "checked")),
+            ("mute_checked", attr(self.mute, "checked")),
+            ("clipboard_checked", attr(self.clipboard, "checked")),
+            ("config", TemplateValue::Script(json!({
+                "stretch": self.stretch,
+                "mute": self.mute,
+                "audio": self.audio,
+                "remoteOnly": self.remote_only,
+                "encryption": self.encryption,
+                "clipboard": self.clipboard,
+                "monitor": self.monitor,
+                "codec": self.codec,
+                "keyboard": self.keyboard,
+            }))),
+        ]
+    }
+}
+
+/// Replace every `{{name}}` in one pass; unknown names are a template bug
+pub fn render(template: &str, values: &[(&str, TemplateValue)]) -> Result<String, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}").ok_or(TemplateError::Unterminated)?;
+        let name = after[..end].trim();
+
+        let (_, value) = values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .ok_or_else(|| TemplateError::UnknownPlaceholder(name.to_string()))?;
+        match value {
+            TemplateValue::Html(text) => out.push_str(&escape_html(text)),
+            TemplateValue::Script(value) => out.push_str(&escape_script(value)),
+        }
+
+        rest = &after[end + 2..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// JSON with `<`, `>`, `&` and the JS line separators escaped, so a string
+/// value can neither close the script element nor break the expression
+pub fn escape_script(value: &Value) -> String {
+    let json = value.to_string();
+    let mut out = String::with_capacity(json.len());
+    for c in json.chars() {
+        match c {
+            '<' => out.push_str("\\u003c"),
+            '>' => out.push_str("\\u003e"),
+            '&' => out.push_str("\\u0026"),
+            '\u{2028}' => out.push_str("\\u2028"),
+            '\u{2029}' => out.push_str("\\u2029"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOSTILE: &str = "\"></script><script>alert('x')</script>&";
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn kvm_page(params: &KvmPageParams) -> String {
+        let template = super::super::assets::read("kvm-template.html").unwrap();
+        render(&String::from_utf8_lossy(&template), &params.template_values()).unwrap()
+    }
+
+    #[test]
+    fn rejects_values_outside_the_allow_lists() {
+        for key in ["codec", "keyboard", "monitor", "stretch", "remoteOnly"] {
+            let result = KvmPageParams::from_query(&query(&[(key, HOSTILE)]), None);
+            assert_eq!(result, Err(TemplateError::InvalidParameter(key)));
+        }
+        let result = KvmPageParams::from_query(&query(&[("monitor", "2")]), Some(2));
+        assert_eq!(result, Err(TemplateError::MonitorOutOfRange(2)));
+    }
+
+    #[test]
+    fn unknown_keys_never_reach_the_page() {
+        let params = KvmPageParams::from_query(&query(&[("evil", HOSTILE), ("config", HOSTILE)]), None).unwrap();
+        let html = kvm_page(&params);
+        assert!(!html.contains("alert"));
+        assert!(!html.contains("{{"));
+    }
+
+    #[test]
+    fn html_values_are_escaped() {
+        let values = [("title", TemplateValue::Html(HOSTILE.to_string()))];
+        let html = render("<p title=\"{{title}}\">{{ title }}</p>", &values).unwrap();
+        let escaped = "&quot;&gt;&lt;/script&gt;&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;&amp;";
+        assert_eq!(html, format!("<p title=\"{0}\">{0}</p>", escaped));
+    }
+
+    #[test]
+    fn script_values_cannot_close_the_script_element() {
+        let value = json!({ "text": HOSTILE, "separators": "\u{2028}\u{2029}" });
+        let values = [("config", TemplateValue::Script(value.clone()))];
+        let html = render("<script type=\"application/json\">{{config}}</script>", &values).unwrap();
+
+        let json = html
+            .strip_prefix("<script type=\"application/json\">")
+            .and_then(|rest| rest.strip_suffix("</script>"))
+            .unwrap();
+        assert!(!json.contains('<') && !json.contains('>') && !json.contains('&'));
+        assert!(!json.contains('\u{2028}') && !json.contains('\u{2029}'));
+        // Still the same JSON once parsed
+        assert_eq!(serde_json::from_str::<Value>(json).unwrap(), value);
+    }
+
+    #[test]
+    fn template_mistakes_are_errors() {
+        let values = [("known", TemplateValue::Html(String::new()))];
+        assert_eq!(render("{{unknown}}", &values), Err(TemplateError::UnknownPlaceholder("unknown".to_string())));
+        assert_eq!(render("{{known", &values), Err(TemplateError::Unterminated));
+    }
+
+    #[test]
+    fn renders_the_client_page() {
+        let query = query(&[("codec", "RGBA"), ("mute", "1"), ("monitor", "1")]);
+        let params = KvmPageParams::from_query(&query, Some(2)).unwrap();
+        let html = kvm_page(&params);
+        assert!(html.contains("<audio id=\"remote-audio\" autoplay muted>"));
+        assert!(html.contains(r#""codec":"rgba""#));
+        assert!(html.contains(r#""monitor":1"#));
+    }
+}
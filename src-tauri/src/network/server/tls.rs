@@ -0,0 +1,224 @@
+//! TLS support for HTTPS/WSS
+//!
+//! The server either loads a user-supplied PEM certificate and key, or generates
+//! a self-signed pair on first run and keeps it in the app data directory so the
+//! fingerprint stays stable across restarts. Users verify the fingerprint shown
+//! in the app against the one their browser reports.
+
+use log::info;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// TLS setup errors
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to access {path:?}: {source}")]
+    Io { path: PathBuf, source: std::io::Error },
+    #[error("Invalid PEM in {path:?}: {message}")]
+    Pem { path: PathBuf, message: String },
+    #[error("No certificate found in {0:?}")]
+    NoCertificate(PathBuf),
+    #[error("Certificate generation failed: {0}")]
+    Generate(String),
+    #[error("TLS configuration failed: {0}")]
+    Config(String),
+}
+
+/// Where the server certificate comes from
+#[derive(Debug, Clone)]
+pub enum CertificateSource {
+    /// Self-signed pair generated on first use and stored in this directory
+    SelfSigned { dir: PathBuf },
+    /// PEM certificate chain and private key supplied by the user
+    Pem { cert: PathBuf, key: PathBuf },
+}
+
+/// Loaded certificate ready to serve
+pub struct TlsIdentity {
+    config: Arc<ServerConfig>,
+    fingerprint: String,
+}
+
+impl TlsIdentity {
+    pub fn load(source: &CertificateSource) -> Result<Self, TlsError> {
+        let (cert_path, key_path) = match source {
+            CertificateSource::SelfSigned { dir } => {
+                let paths = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+                if !paths.0.exists() || !paths.1.exists() {
+                    generate_self_signed(dir, &paths.0, &paths.1)?;
+                }
+                paths
+            }
+            CertificateSource::Pem { cert, key } => (cert.clone(), key.clone()),
+        };
+
+        let certs = read_certificates(&cert_path)?;
+        let key = read_private_key(&key_path)?;
+        let fingerprint = fingerprint(&certs[0]);
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| TlsError::Config(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| TlsError::Config(e.to_string()))?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()]; // WebSocket upgrades need HTTP/1.1
+
+        info!("🔒 TLS certificate loaded from {:?}", cert_path);
+        info!("🔒 Certificate SHA-256 fingerprint: {}", fingerprint);
+
+        Ok(Self {
+            config: Arc::new(config),
+            fingerprint,
+        })
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+
+    /// SHA-256 of the leaf certificate, formatted like browsers show it
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+}
+
+fn generate_self_signed(dir: &Path, cert_path: &Path, key_path: &Path) -> Result<(), TlsError> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    if let Ok(ip) = local_ip_address::local_ip() {
+        names.push(ip.to_string());
+    }
+    if let Some(host) = gethostname::gethostname().to_str() {
+        names.push(host.to_string());
+    }
+
+    info!("🔑 Generating self-signed certificate for {:?}", names);
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| TlsError::Generate(e.to_string()))?;
+
+    let io_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| TlsError::Io { path, source }
+    };
+    fs::create_dir_all(dir).map_err(io_err(dir))?;
+    write_private_key(key_path, &certified.key_pair.serialize_pem()).map_err(io_err(key_path))?;
+    fs::write(cert_path, certified.cert.pem()).map_err(io_err(cert_path))?;
+    Ok(())
+}
+
+/// Write a new key file that only the owner can read from the moment it exists,
+/// replacing any key left over without its certificate
+fn write_private_key(path: &Path, pem: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem = fs::read(path).map_err(|source| TlsError::Io { path: path.to_path_buf(), source })?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Pem { path: path.to_path_buf(), message: e.to_string() })?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let pem = fs::read(path).map_err(|source| TlsError::Io { path: path.to_path_buf(), source })?;
+    PrivateKeyDer::from_pem_slice(&pem)
+        .map_err(|e| TlsError::Pem { path: path.to_path_buf(), message: e.to_string() })
+}
+
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn source(dir: &TempDir) -> CertificateSource {
+        CertificateSource::SelfSigned { dir: dir.path().to_path_buf() }
+    }
+
+    #[test]
+    fn generates_a_certificate_on_first_use() {
+        let dir = TempDir::new().unwrap();
+        let identity = TlsIdentity::load(&source(&dir)).unwrap();
+
+        assert!(dir.path().join(CERT_FILE).is_file());
+        assert!(dir.path().join(KEY_FILE).is_file());
+        // 32 colon-separated bytes, as browsers show it
+        assert_eq!(identity.fingerprint().len(), 32 * 3 - 1);
+        assert!(identity.fingerprint().split(':').all(|byte| byte.len() == 2));
+        assert_eq!(identity.server_config().alpn_protocols, vec![b"http/1.1".to_vec()]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join(KEY_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn reloading_keeps_the_fingerprint() {
+        let dir = TempDir::new().unwrap();
+        let first = TlsIdentity::load(&source(&dir)).unwrap();
+        let key = fs::read(dir.path().join(KEY_FILE)).unwrap();
+
+        let second = TlsIdentity::load(&source(&dir)).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(fs::read(dir.path().join(KEY_FILE)).unwrap(), key);
+
+        // The same files given as a user-supplied pair are the same identity
+        let pem = CertificateSource::Pem { cert: dir.path().join(CERT_FILE), key: dir.path().join(KEY_FILE) };
+        assert_eq!(TlsIdentity::load(&pem).unwrap().fingerprint(), first.fingerprint());
+    }
+
+    #[test]
+    fn a_key_without_its_certificate_is_replaced() {
+        let dir = TempDir::new().unwrap();
+        let first = TlsIdentity::load(&source(&dir)).unwrap();
+        fs::remove_file(dir.path().join(CERT_FILE)).unwrap();
+
+        let second = TlsIdentity::load(&source(&dir)).unwrap();
+        assert_ne!(first.fingerprint(), second.fingerprint());
+    }
+
+    #[test]
+    fn rejects_files_without_a_certificate() {
+        let dir = TempDir::new().unwrap();
+        TlsIdentity::load(&source(&dir)).unwrap();
+        fs::write(dir.path().join(CERT_FILE), "").unwrap();
+
+        assert!(matches!(TlsIdentity::load(&source(&dir)), Err(TlsError::NoCertificate(_))));
+    }
+}
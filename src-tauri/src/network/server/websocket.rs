@@ -4,57 +4,14 @@ use crate::streaming::{
     IntegratedStreamConfig,
     RealtimeStreamHandler,
     UltraStreamHandler,
+    VideoCodec,
     // EnhancedVideoEncoder,
     // EnhancedAudioEncoder
 };
+use crate::core::{list_monitors, SessionContext};
 use axum::extract::ws::WebSocket;
 use tokio::{sync::broadcast};
 use log::{error, info, warn};
-use serde::{Deserialize, Serialize};
-
-// Control messages for WebSocket communication
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(tag = "type")]
-pub enum ControlMessage {
-    #[serde(rename = "ping")]
-    Ping { timestamp: Option<u64> },
-    
-    #[serde(rename = "switch_codec")]
-    SwitchCodec { codec: String },
-    
-    #[serde(rename = "request_keyframe")]
-    RequestKeyframe,
-    
-    #[serde(rename = "quality_setting")]
-    QualitySetting { quality: u8 },
-    
-    #[serde(rename = "bitrate_setting")]
-    BitrateSetting { bitrate: u32 },
-    
-    #[serde(rename = "webm_config")]
-    WebMConfig { 
-        enable_vp8: bool,
-        enable_opus: bool,
-        target_bitrate: Option<u32>
-    },
-    
-    #[serde(rename = "network_stats")]
-    NetworkStats { 
-        latency: Option<u32>,
-        bandwidth: Option<f32>,
-        packet_loss: Option<f32>
-    },
-}
-
-// Helper function to make the future Send - now uses integrated YUV420 + WebM streaming
-pub async fn handle_socket_wrapper(socket: WebSocket, monitor: usize, codec: String, enable_audio: bool) {
-    info!("🎬 New YUV420 + WebM streaming WebSocket connection - Monitor: {}, Codec: {}, Audio: {}", 
-          monitor, codec, enable_audio);
-    
-    handle_integrated_webm_socket(socket, monitor, enable_audio, None).await;
-    
-    info!("✅ YUV420 + WebM streaming WebSocket connection closed - Monitor: {}", monitor);
-}
 
 // New helper function with stop signal - uses integrated WebM streaming
 pub async fn handle_socket_wrapper_with_stop(
@@ -62,55 +19,47 @@ pub async fn handle_socket_wrapper_with_stop(
     monitor: usize, 
     codec: String, 
     enable_audio: bool, 
+    session: SessionContext,
     stop_rx: broadcast::Receiver<()>
 ) {
-    info!("🎬 New YUV420 + WebM streaming WebSocket connection with stop signal - Monitor: {}, Codec: {}, Audio: {}", 
-          monitor, codec, enable_audio);
+    info!("🎬 New YUV420 + WebM streaming WebSocket connection with stop signal - Monitor: {}, Codec: {}, Audio: {}, Role: {}", 
+          monitor, codec, enable_audio, session.role.name());
     
-    handle_integrated_webm_socket(socket, monitor, enable_audio, Some(stop_rx)).await;
+    handle_integrated_webm_socket(socket, monitor, &codec, enable_audio, session, Some(stop_rx)).await;
     
     info!("✅ YUV420 + WebM streaming WebSocket connection with stop signal closed - Monitor: {}", monitor);
 }
 
-pub async fn handle_socket_with_stop(
-    socket: WebSocket, 
-    monitor: usize, 
-    codec: String, 
-    enable_audio: bool, 
-    stop_rx: broadcast::Receiver<()>
-) {
-    info!("🎬 New WebSocket connection with stop signal: monitor={}, codec={}, audio={}", 
-          monitor, codec, enable_audio);
-    
-    // Always use integrated WebM streaming for connections with stop signal
-    handle_integrated_webm_socket(socket, monitor, enable_audio, Some(stop_rx)).await;
-}
-
-pub async fn handle_socket(socket: WebSocket, monitor: usize, codec: String, enable_audio: bool) {
-    info!("🎬 New WebSocket connection: monitor={}, codec={}, audio={}", 
-          monitor, codec, enable_audio);
-    
-    // Always use integrated WebM streaming for direct connections
-    handle_integrated_webm_socket(socket, monitor, enable_audio, None).await;
-}
-
 // New integrated YUV420 + WebM streaming socket handler
 async fn handle_integrated_webm_socket(
     socket: WebSocket, 
     monitor: usize, 
+    codec: &str,
     enable_audio: bool,
+    session: SessionContext,
     stop_rx: Option<broadcast::Receiver<()>>
 ) {
-    info!("🚀 WebM streaming requested for monitor {} - falling back to RGBA streaming for now", monitor);
-    
-    // TODO: Implement proper WebM/VP8 encoding
-    // For now, fall back to working RGBA streaming
-    info!("🔄 Using RGBA streaming until WebM/VP8 encoding is implemented");
+    if VideoCodec::from_name(codec) == Some(VideoCodec::Vp8) {
+        info!("🚀 VP8 streaming requested for monitor {}", monitor);
+        
+        match IntegratedStreamHandler::new(vp8_stream_config(monitor, enable_audio)) {
+            Ok(handler) => {
+                info!("✅ VP8 streaming handler initialized successfully");
+                handler.with_session(session).handle_connection(socket, stop_rx).await;
+                return;
+            }
+            Err(e) => {
+                warn!("⚠️ Failed to create VP8 streaming handler: {} - falling back to RGBA streaming", e);
+            }
+        }
+    } else {
+        info!("🔄 Using RGBA streaming for codec '{}' on monitor {}", codec, monitor);
+    }
     
     match UltraStreamHandler::new(monitor) {
         Ok(handler) => {
             info!("✅ RGBA streaming handler initialized successfully");
-            handler.handle_connection(socket, stop_rx).await;
+            handler.with_session(session).handle_connection(socket, stop_rx).await;
         }
         Err(e) => {
             error!("❌ Failed to create RGBA streaming handler: {}", e);
@@ -130,7 +79,7 @@ async fn handle_integrated_webm_socket(
             match RealtimeStreamHandler::new(enhanced_config) {
                 Ok(handler) => {
                     info!("✅ Enhanced real-time fallback handler initialized");
-                    handler.handle_connection(socket, stop_rx).await;
+                    handler.with_session(session).handle_connection(socket, stop_rx).await;
                 }
                 Err(e) => {
                     error!("❌ All streaming handlers failed to initialize: {}", e);
@@ -140,58 +89,20 @@ async fn handle_integrated_webm_socket(
     }
 }
 
-pub async fn handle_socket_ultra(
-    ws: axum::extract::WebSocketUpgrade,
-    query: axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> impl axum::response::IntoResponse {
-    let monitor = query.get("monitor")
-        .and_then(|m| m.parse::<usize>().ok())
-        .unwrap_or(0);
-
-    info!("🔌 Ultra WebSocket connection request for monitor {}", monitor);
-
-    ws.on_upgrade(move |socket| async move {
-        if let Err(e) = handle_ultra_connection(socket, monitor).await {
-            error!("❌ Ultra WebSocket connection failed: {}", e);
-        }
-    })
-}
-
-async fn handle_ultra_connection(socket: WebSocket, monitor: usize) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    info!("⚡ Starting ultra-performance YUV420 + WebM streaming for monitor {}", monitor);
-    
-    // Try ultra-performance WebM streaming first
-    match crate::streaming::UltraStreamHandler::new(monitor) {
-        Ok(ultra_handler) => {
-            info!("🚀 Using ULTRA-PERFORMANCE YUV420 + WebM streaming mode");
-            ultra_handler.handle_connection(socket, Some(tokio::sync::broadcast::channel(1).1)).await;
-        },
-        Err(e) => {
-            warn!("⚠️  Ultra-performance WebM mode failed: {} - falling back to enhanced mode", e);
-            
-            // Fallback to enhanced real-time streaming with WebM support
-            let enhanced_config = crate::streaming::RealtimeConfig {
-                monitor_id: monitor,
-                width: 1920,
-                height: 1080,  
-                bitrate: 10000, // Very high bitrate for excellent WebM quality
-                framerate: 60,  // Smooth framerate
-                keyframe_interval: 60, // Frequent keyframes for WebM stability
-                target_latency_ms: 120, // Optimized latency for WebM
-            };
-            
-            match crate::streaming::RealtimeStreamHandler::new(enhanced_config) {
-                Ok(fallback_handler) => {
-                    info!("🔄 Using ENHANCED WebM real-time streaming mode");
-                    fallback_handler.handle_connection(socket, Some(tokio::sync::broadcast::channel(1).1)).await;
-                },
-                Err(e) => {
-                    error!("❌ Both ultra and enhanced WebM streaming failed: {}", e);
-                    return Err(e.into());
-                }
-            }
-        }
+/// VP8 session config at the monitor's native size, scaled down to fit 1080p
+fn vp8_stream_config(monitor: usize, enable_audio: bool) -> IntegratedStreamConfig {
+    let mut config = IntegratedStreamConfig::balanced(monitor);
+    config.enable_audio = enable_audio;
+    config.video.codec = VideoCodec::Vp8;
+    config.video.use_webm_container = false;
+    
+    if let Some(m) = list_monitors().ok().and_then(|all| all.into_iter().nth(monitor)) {
+        let (width, height) = ((m.width as u32).max(1), (m.height as u32).max(1));
+        let scale = (1920.0 / width as f64).min(1080.0 / height as f64).min(1.0);
+        // Keep dimensions even so the chroma planes line up with the luma plane
+        config.video.width = ((width as f64 * scale) as u32 & !1).max(2);
+        config.video.height = ((height as f64 * scale) as u32 & !1).max(2);
     }
     
-    Ok(())
+    config
 }
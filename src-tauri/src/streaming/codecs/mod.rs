@@ -3,8 +3,17 @@
 //! This module contains various codec implementations optimized
 //! for real-time streaming with minimal latency.
 
+pub mod motion;
 pub mod realtime_codec;
+pub mod tile_codec;
 pub mod yuv420_encoder;
+pub mod vp8_encoder;
+mod vp8_tables;
+#[cfg(test)]
+mod vp8_decoder;
 
+pub use motion::*;
 pub use realtime_codec::*;
+pub use tile_codec::*;
 pub use yuv420_encoder::*;
+pub use vp8_encoder::*;
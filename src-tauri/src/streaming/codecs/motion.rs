@@ -0,0 +1,342 @@
+//! Scroll and move detection
+//!
+//! Scrolling a document or dragging a window dirties every tile it touches,
+//! although most of those pixels were already on screen, just somewhere else.
+//! This finds where dirty tiles came from in the previous frame so they can be
+//! sent as copies, leaving only the newly exposed strips as tile updates.
+//!
+//! Candidate offsets come from short pixel runs ("probes") sampled in the dirty
+//! tiles and looked up in the previous frame nearby. Every dirty tile is then
+//! checked exactly against the best candidates, so a wrong guess only costs
+//! time, never a wrong picture.
+
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+use crate::core::capture::DirtyRect;
+
+/// Farthest a scroll is looked for, straight up, down, left or right
+const MAX_SCROLL: i32 = 512;
+/// Farthest a moved window is looked for along each axis
+const MAX_MOVE: i32 = 128;
+/// Below this many dirty tiles, sending them is cheaper than searching
+const MIN_DIRTY_TILES: usize = 4;
+/// Probes sampled from the dirty tiles
+const MAX_PROBES: usize = 16;
+/// Pixels per probe
+const PROBE_LEN: usize = 16;
+/// Probes matching in more places than this are too ambiguous to vote
+const MAX_PROBE_MATCHES: usize = 4;
+/// Offsets checked tile by tile, most voted first
+const MAX_CANDIDATES: usize = 2;
+
+/// Area of the new frame that is a copy of another area of the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyRect {
+    pub src_x: u32,
+    pub src_y: u32,
+    pub dst: DirtyRect,
+}
+
+/// Split `dirty` into areas copied from elsewhere in `previous` and areas that
+/// need new pixels. Both are merged into runs along each row of tiles.
+pub fn detect_moves(
+    previous: &[u8],
+    current: &[u8],
+    width: usize,
+    height: usize,
+    tile_size: usize,
+    dirty: &[DirtyRect],
+) -> (Vec<CopyRect>, Vec<DirtyRect>) {
+    let frame = width * height * 4;
+    let tiles = split_into_tiles(dirty, tile_size);
+    if tiles.len() < MIN_DIRTY_TILES || previous.len() < frame || current.len() < frame {
+        return (Vec::new(), dirty.to_vec());
+    }
+    let (previous, current) = (Frame { rgba: previous, width, height }, Frame { rgba: current, width, height });
+
+    let candidates = candidate_offsets(&previous, &current, &tiles);
+    if candidates.is_empty() {
+        return (Vec::new(), dirty.to_vec());
+    }
+
+    // Which candidate, if any, each tile is an exact copy under
+    let sources: Vec<Option<(i32, i32)>> = tiles
+        .par_iter()
+        .map(|tile| candidates.iter().copied().find(|&offset| is_moved(&previous, &current, tile, offset)))
+        .collect();
+
+    let mut copies: Vec<CopyRect> = Vec::new();
+    let mut fresh: Vec<DirtyRect> = Vec::new();
+    for (tile, source) in tiles.iter().zip(sources) {
+        match source {
+            Some((dx, dy)) => {
+                let src_x = (tile.x as i32 - dx) as u32;
+                let src_y = (tile.y as i32 - dy) as u32;
+                match copies.last_mut() {
+                    Some(last) if follows(&last.dst, tile) && last.src_x + last.dst.width == src_x && last.src_y == src_y => {
+                        last.dst.width += tile.width;
+                    }
+                    _ => copies.push(CopyRect { src_x, src_y, dst: *tile }),
+                }
+            }
+            None => match fresh.last_mut() {
+                Some(last) if follows(last, tile) => last.width += tile.width,
+                _ => fresh.push(*tile),
+            },
+        }
+    }
+    (copies, fresh)
+}
+
+struct Frame<'a> {
+    rgba: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl Frame<'_> {
+    /// `len` pixels of row `y` starting at column `x`
+    fn run(&self, x: usize, y: usize, len: usize) -> &[u8] {
+        let start = (y * self.width + x) * 4;
+        &self.rgba[start..start + len * 4]
+    }
+}
+
+fn split_into_tiles(rects: &[DirtyRect], tile_size: usize) -> Vec<DirtyRect> {
+    let step = tile_size as u32;
+    let mut tiles = Vec::new();
+    for rect in rects {
+        for y in (rect.y..rect.y + rect.height).step_by(tile_size) {
+            for x in (rect.x..rect.x + rect.width).step_by(tile_size) {
+                tiles.push(DirtyRect {
+                    x,
+                    y,
+                    width: step.min(rect.x + rect.width - x),
+                    height: step.min(rect.y + rect.height - y),
+                });
+            }
+        }
+    }
+    tiles
+}
+
+/// Whether `tile` directly continues the run ending in `last`
+fn follows(last: &DirtyRect, tile: &DirtyRect) -> bool {
+    last.y == tile.y && last.height == tile.height && last.x + last.width == tile.x
+}
+
+/// Offsets (dx, dy) such that new pixel (x, y) may equal old pixel (x - dx, y - dy),
+/// most voted first
+fn candidate_offsets(previous: &Frame, current: &Frame, tiles: &[DirtyRect]) -> Vec<(i32, i32)> {
+    let step = tiles.len().div_ceil(MAX_PROBES).max(1);
+    let matches: Vec<Vec<(i32, i32)>> = tiles
+        .par_iter()
+        .step_by(step)
+        .filter_map(|tile| probe(current, tile))
+        .map(|(x, y)| find_probe(previous, current.run(x, y, PROBE_LEN), x, y))
+        .collect();
+
+    let mut votes: HashMap<(i32, i32), usize> = HashMap::new();
+    for offsets in matches.iter().filter(|offsets| offsets.len() <= MAX_PROBE_MATCHES) {
+        for &offset in offsets {
+            *votes.entry(offset).or_default() += 1;
+        }
+    }
+
+    let mut candidates: Vec<((i32, i32), usize)> = votes.into_iter().filter(|&(_, count)| count >= 2).collect();
+    // Most votes first, then the shortest move, so ties resolve the same way every time
+    candidates.sort_by_key(|&((dx, dy), count)| (std::cmp::Reverse(count), dx.abs() + dy.abs(), dx, dy));
+    candidates.into_iter().take(MAX_CANDIDATES).map(|(offset, _)| offset).collect()
+}
+
+/// A run of pixels in the tile that is not all one color, since a flat run matches everywhere
+fn probe(frame: &Frame, tile: &DirtyRect) -> Option<(usize, usize)> {
+    if (tile.width as usize) < PROBE_LEN {
+        return None;
+    }
+    let x = tile.x as usize + (tile.width as usize - PROBE_LEN) / 2;
+    let height = tile.height as usize;
+    [height / 2, height / 4, height * 3 / 4, 0]
+        .into_iter()
+        .map(|row| tile.y as usize + row)
+        .find(|&y| {
+            let run = frame.run(x, y, PROBE_LEN);
+            run.chunks_exact(4).any(|pixel| pixel != &run[..4])
+        })
+        .map(|y| (x, y))
+}
+
+/// Where near (x, y) the previous frame has `run`, as offsets. Scrolls are the
+/// common case and can be long, so straight lines are searched first.
+fn find_probe(previous: &Frame, run: &[u8], x: usize, y: usize) -> Vec<(i32, i32)> {
+    let mut found = Vec::new();
+    for d in -MAX_SCROLL..=MAX_SCROLL {
+        if match_at(previous, run, x, y, (0, d), &mut found) || match_at(previous, run, x, y, (d, 0), &mut found) {
+            return found;
+        }
+    }
+    if !found.is_empty() {
+        return found;
+    }
+    for dy in -MAX_MOVE..=MAX_MOVE {
+        for dx in -MAX_MOVE..=MAX_MOVE {
+            if match_at(previous, run, x, y, (dx, dy), &mut found) {
+                return found;
+            }
+        }
+    }
+    found
+}
+
+/// Record `(dx, dy)` if the previous frame has `run` there; true once the probe is too ambiguous
+fn match_at(
+    previous: &Frame,
+    run: &[u8],
+    x: usize,
+    y: usize,
+    (dx, dy): (i32, i32),
+    found: &mut Vec<(i32, i32)>,
+) -> bool {
+    let (src_x, src_y) = (x as i32 - dx, y as i32 - dy);
+    if (dx, dy) == (0, 0)
+        || src_x < 0
+        || src_y < 0
+        || src_x as usize + PROBE_LEN > previous.width
+        || src_y as usize >= previous.height
+    {
+        return false;
+    }
+    let start = (src_y as usize * previous.width + src_x as usize) * 4;
+    // Cheap first-pixel check before comparing the whole run
+    if pixel_at(previous.rgba, start) == pixel_at(run, 0) && previous.rgba[start..start + run.len()] == *run {
+        found.push((dx, dy));
+    }
+    found.len() > MAX_PROBE_MATCHES
+}
+
+fn pixel_at(rgba: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([rgba[offset], rgba[offset + 1], rgba[offset + 2], rgba[offset + 3]])
+}
+
+/// Whether the tile is an exact copy of the previous frame shifted by `(dx, dy)`
+fn is_moved(previous: &Frame, current: &Frame, tile: &DirtyRect, (dx, dy): (i32, i32)) -> bool {
+    let src_x = tile.x as i32 - dx;
+    let src_y = tile.y as i32 - dy;
+    if src_x < 0
+        || src_y < 0
+        || src_x as usize + tile.width as usize > previous.width
+        || src_y as usize + tile.height as usize > previous.height
+    {
+        return false;
+    }
+    (0..tile.height as usize).all(|row| {
+        current.run(tile.x as usize, tile.y as usize + row, tile.width as usize)
+            == previous.run(src_x as usize, src_y as usize + row, tile.width as usize)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::capture::TileGrid;
+
+    const WIDTH: usize = 320;
+    const HEIGHT: usize = 256;
+
+    /// Busy, non-repeating pixels, like text, offset by a scroll position
+    fn page(scroll_x: usize, scroll_y: usize) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(WIDTH * HEIGHT * 4);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (u, v) = ((x + scroll_x) as u32, (y + scroll_y) as u32);
+                let h = u.wrapping_mul(0x9E37_79B1) ^ v.wrapping_mul(0x85EB_CA77);
+                let h = (h ^ (h >> 15)).wrapping_mul(0xC2B2_AE3D);
+                rgba.extend_from_slice(&[(h >> 24) as u8, (h >> 16) as u8, (h >> 8) as u8, 255]);
+            }
+        }
+        rgba
+    }
+
+    fn dirty(previous: &[u8], current: &[u8]) -> Vec<DirtyRect> {
+        let mut grid = TileGrid::new(WIDTH, HEIGHT, 64);
+        grid.diff(previous, WIDTH, HEIGHT);
+        grid.diff(current, WIDTH, HEIGHT).unwrap()
+    }
+
+    /// What `kvm-client.js` does with the copies, followed by the fresh rectangles
+    fn apply(previous: &[u8], current: &[u8], copies: &[CopyRect], fresh: &[DirtyRect]) -> Vec<u8> {
+        let mut picture = previous.to_vec();
+        let mut paint = |x: u32, y: u32, rect: &DirtyRect, source: &[u8]| {
+            for row in 0..rect.height as usize {
+                let from = ((y as usize + row) * WIDTH + x as usize) * 4;
+                let to = ((rect.y as usize + row) * WIDTH + rect.x as usize) * 4;
+                let len = rect.width as usize * 4;
+                picture[to..to + len].copy_from_slice(&source[from..from + len]);
+            }
+        };
+        for copy in copies {
+            paint(copy.src_x, copy.src_y, &copy.dst, previous);
+        }
+        for rect in fresh {
+            paint(rect.x, rect.y, rect, current);
+        }
+        picture
+    }
+
+    #[test]
+    fn vertical_scroll_leaves_only_the_exposed_strip() {
+        let (previous, current) = (page(0, 0), page(0, 40));
+        let (copies, fresh) = detect_moves(&previous, &current, WIDTH, HEIGHT, 64, &dirty(&previous, &current));
+
+        // Content moved up 40px: every tile row above the bottom one is a copy
+        assert_eq!(copies.len(), 3);
+        assert!(copies.iter().all(|copy| copy.src_y == copy.dst.y + 40 && copy.src_x == copy.dst.x));
+        assert_eq!(fresh, vec![DirtyRect { x: 0, y: 192, width: 320, height: 64 }]);
+        assert_eq!(apply(&previous, &current, &copies, &fresh), current);
+    }
+
+    #[test]
+    fn horizontal_scroll_is_detected() {
+        let (previous, current) = (page(30, 0), page(0, 0));
+        let (copies, fresh) = detect_moves(&previous, &current, WIDTH, HEIGHT, 64, &dirty(&previous, &current));
+
+        // Content moved right 30px: the first tile column is new
+        assert_eq!(copies.len(), 4);
+        assert!(copies.iter().all(|copy| copy.src_x + 30 == copy.dst.x && copy.dst.width == 256));
+        assert!(fresh.iter().all(|rect| rect.x == 0 && rect.width == 64));
+        assert_eq!(apply(&previous, &current, &copies, &fresh), current);
+    }
+
+    #[test]
+    fn moved_window_is_copied_from_its_old_place() {
+        // A busy 150x120 window dragged by (+37, +21) across a flat desktop
+        let mut previous = vec![40u8; WIDTH * HEIGHT * 4];
+        let mut current = previous.clone();
+        let window = page(0, 0);
+        for y in 0..120 {
+            for x in 0..150 {
+                let pixel = &window[(y * WIDTH + x) * 4..][..4];
+                previous[((y + 10) * WIDTH + x + 20) * 4..][..4].copy_from_slice(pixel);
+                current[((y + 31) * WIDTH + x + 57) * 4..][..4].copy_from_slice(pixel);
+            }
+        }
+
+        let dirty = dirty(&previous, &current);
+        let (copies, fresh) = detect_moves(&previous, &current, WIDTH, HEIGHT, 64, &dirty);
+        assert!(!copies.is_empty());
+        assert!(copies.iter().all(|copy| copy.src_x + 37 == copy.dst.x && copy.src_y + 21 == copy.dst.y));
+        assert_eq!(apply(&previous, &current, &copies, &fresh), current);
+    }
+
+    #[test]
+    fn unrelated_changes_are_left_alone() {
+        let (previous, current) = (page(0, 0), page(1000, 1000));
+        let dirty = dirty(&previous, &current);
+        assert_eq!(detect_moves(&previous, &current, WIDTH, HEIGHT, 64, &dirty), (Vec::new(), dirty));
+
+        // A couple of tiles are not worth searching for
+        let small = &[DirtyRect { x: 0, y: 0, width: 128, height: 64 }];
+        assert_eq!(detect_moves(&previous, &page(0, 40), WIDTH, HEIGHT, 64, small).0, vec![]);
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::Result;
+use std::borrow::Cow;
 use thiserror::Error;
 use log::{debug, error, info};
 use std::time::Instant;
-use xcap::Monitor;
-use crate::network::models::NetworkStats;
+use crate::core::capture::{open_capture_source, CaptureError, CaptureSource};
+use crate::streaming::congestion::EncoderTarget;
+use crate::streaming::frame::{frame_pts, FrameCodec, FrameHeader};
+use super::tile_codec::{compress_rle, ChangeTracker, REFRESH_INTERVAL};
 
 // Custom error type for real-time codec operations
 #[derive(Error, Debug)]
@@ -20,6 +23,15 @@ pub enum RealtimeCodecError {
     Config(String),
 }
 
+impl From<CaptureError> for RealtimeCodecError {
+    fn from(e: CaptureError) -> Self {
+        match e {
+            CaptureError::MonitorNotFound(index) => RealtimeCodecError::MonitorNotFound(index),
+            e => RealtimeCodecError::Capture(e.to_string()),
+        }
+    }
+}
+
 // Real-time encoder configuration
 #[derive(Clone)]
 pub struct RealtimeConfig {
@@ -28,6 +40,7 @@ pub struct RealtimeConfig {
     pub height: u32,
     pub bitrate: u32, // kbps
     pub framerate: u32,
+    /// Frames sent between keyframes; unchanged frames are not sent and do not count
     pub keyframe_interval: u32,
     pub target_latency_ms: u32, // Target latency in milliseconds
 }
@@ -40,7 +53,7 @@ impl Default for RealtimeConfig {
             height: 1080,
             bitrate: 4000, // Increased to 4 Mbps for better quality
             framerate: 60, // Increased to 60 FPS for smoother streaming
-            keyframe_interval: 120, // Every 2 seconds at 60fps
+            keyframe_interval: 120, // At most 2 seconds of motion at 60fps
             target_latency_ms: 50, // Reduced to 50ms for faster response
         }
     }
@@ -48,64 +61,67 @@ impl Default for RealtimeConfig {
 
 // Real-time screen streaming encoder
 pub struct RealtimeStreamEncoder {
-    monitor: Monitor,
+    source: Box<dyn CaptureSource>,
     config: RealtimeConfig,
     frame_count: u64,
-    last_keyframe: u64,
+    frames_since_keyframe: u32,
+    last_keyframe_time: Instant,
+    keyframe_requested: bool,
     last_capture_time: Instant,
     capture_duration_ms: f64,
     encode_duration_ms: f64,
-    previous_frame_data: Option<Vec<u8>>, // For delta compression
+    // What changed or moved since the last frame
+    changes: ChangeTracker,
 }
 
 impl RealtimeStreamEncoder {
     pub fn new(config: RealtimeConfig) -> Result<Self, RealtimeCodecError> {
+        let source = open_capture_source(config.monitor_id)?;
+        Ok(Self::with_source(config, source))
+    }
+
+    /// Create an encoder that takes frames from the given capture source
+    pub fn with_source(config: RealtimeConfig, source: Box<dyn CaptureSource>) -> Self {
         info!("Initializing real-time stream encoder for monitor {} ({}x{} @ {}fps, {}kbps)", 
               config.monitor_id, config.width, config.height, config.framerate, config.bitrate);
 
-        // Get the specified monitor
-        let monitors = Monitor::all().map_err(|e| 
-            RealtimeCodecError::MonitorNotFound(format!("Failed to enumerate monitors: {:?}", e).parse().unwrap_or(0)))?;
-        
-        let monitor = monitors.get(config.monitor_id)
-            .ok_or_else(|| RealtimeCodecError::MonitorNotFound(config.monitor_id))?
-            .clone();
-
-        info!("Using monitor: {} ({}x{})", monitor.name(), monitor.width(), monitor.height());
+        let (width, height) = source.dimensions();
+        info!("Using capture source: {} ({}x{})", source.name(), width, height);
 
-        Ok(Self {
-            monitor,
+        Self {
+            source,
             config,
             frame_count: 0,
-            last_keyframe: 0,
+            frames_since_keyframe: 0,
+            last_keyframe_time: Instant::now(),
+            keyframe_requested: false,
             last_capture_time: Instant::now(),
             capture_duration_ms: 0.0,
             encode_duration_ms: 0.0,
-            previous_frame_data: None,
-        })
+            changes: ChangeTracker::new(width as usize, height as usize),
+        }
     }
 
+    /// Capture the screen and encode what changed; `None` when nothing did
     pub fn capture_and_encode(&mut self, force_keyframe: bool) -> Result<Option<Vec<u8>>, RealtimeCodecError> {
         let capture_start = Instant::now();
         
         // Capture screen
-        let image = self.monitor.capture_image()
-            .map_err(|e| RealtimeCodecError::Capture(format!("Screen capture failed: {:?}", e)))?;
+        let frame = self.source.capture_frame()?;
         
         self.capture_duration_ms = capture_start.elapsed().as_secs_f64() * 1000.0;
         
-        // Convert image to raw RGBA
-        let rgba_data = image.as_raw();
-        let width = image.width() as u32;
-        let height = image.height() as u32;
+        let rgba_data = &frame.rgba;
+        let width = frame.width;
+        let height = frame.height;
 
         // Check if we need to resize (for efficiency)
         let (final_width, final_height, processed_data) = if width != self.config.width || height != self.config.height {
             // Simple resize (you might want to use a better algorithm in production)
             let resized = self.resize_image(rgba_data, width, height, self.config.width, self.config.height)?;
-            (self.config.width, self.config.height, resized)
+            (self.config.width, self.config.height, Cow::Owned(resized))
         } else {
-            (width, height, rgba_data.to_vec())
+            (width, height, Cow::Borrowed(&rgba_data[..]))
         };
 
         // Encode frame
@@ -124,125 +140,48 @@ impl RealtimeStreamEncoder {
         self.frame_count += 1;
         self.last_capture_time = Instant::now();
 
-        // Store current frame for delta compression
-        self.previous_frame_data = Some(processed_data.to_vec());
-
-        Ok(Some(encoded_data))
+        Ok(encoded_data)
     }
 
-    fn encode_frame_data(&mut self, rgba_data: &[u8], width: u32, height: u32, force_keyframe: bool) -> Result<Vec<u8>, RealtimeCodecError> {
-        // Check if we should force a keyframe
-        let should_keyframe = force_keyframe || 
-            (self.frame_count - self.last_keyframe) >= self.config.keyframe_interval as u64;
+    fn encode_frame_data(&mut self, rgba_data: &[u8], width: u32, height: u32, force_keyframe: bool) -> Result<Option<Vec<u8>>, RealtimeCodecError> {
+        // Track every frame, keyframes included, so the next one compares against it
+        let changes = self.changes.update(rgba_data, width as usize, height as usize);
+
+        let should_keyframe = force_keyframe
+            || std::mem::take(&mut self.keyframe_requested)
+            || self.frames_since_keyframe >= self.config.keyframe_interval
+            || self.last_keyframe_time.elapsed() >= REFRESH_INTERVAL;
+
+        // Copies and changed rectangles when there is a previous frame to patch,
+        // otherwise a run-length keyframe. This is a basic implementation - in
+        // production you'd use proper VP8/H264 encoding
+        let (codec, keyframe, compressed) = match changes {
+            Some(changes) if !should_keyframe && changes.is_empty() => return Ok(None),
+            Some(changes) if !should_keyframe && !changes.mostly_new(width, height) => {
+                debug!("Frame {}: {} copies, {} changed rectangles",
+                       self.frame_count, changes.copies.len(), changes.rects.len());
+                let (codec, payload) = changes.encode(rgba_data, width);
+                (codec, false, payload)
+            }
+            _ => (FrameCodec::RgbaRle, true, compress_rle(rgba_data)),
+        };
 
-        // Create a simple encoded format for real-time streaming
-        // This is a basic implementation - in production you'd use proper VP8/H264 encoding
-        let mut encoded_data = Vec::new();
-        
-        // Header
-        if should_keyframe {
-            encoded_data.extend_from_slice(&[0xAA, 0xBB, 0x01]); // Keyframe marker
-            self.last_keyframe = self.frame_count;
-            debug!("Encoding keyframe {}", self.frame_count);
+        if keyframe {
+            self.frames_since_keyframe = 0;
+            self.last_keyframe_time = Instant::now();
         } else {
-            encoded_data.extend_from_slice(&[0xAA, 0xBB, 0x02]); // Inter-frame marker
+            self.frames_since_keyframe += 1;
         }
         
-        // Frame metadata
-        encoded_data.extend_from_slice(&(width as u32).to_le_bytes());
-        encoded_data.extend_from_slice(&(height as u32).to_le_bytes());
-        encoded_data.extend_from_slice(&(self.frame_count as u64).to_le_bytes());
+        let encoded_data = FrameHeader::video(codec, width, height, keyframe, frame_pts()).encode(&compressed);
         
-        // For real-time streaming, we'll compress the frame data
-        let compressed = if should_keyframe || self.previous_frame_data.is_none() {
-            // Full frame compression
-            self.compress_rgba_data(rgba_data)?
-        } else {
-            // Delta compression against previous frame
-            let previous = self.previous_frame_data.as_ref().unwrap();
-            self.compress_delta_frame(rgba_data, previous)?
-        };
-        
-        // Add compressed data length and data
-        encoded_data.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
-        encoded_data.extend_from_slice(&compressed);
-        
-        if should_keyframe {
+        if keyframe {
             debug!("Encoded keyframe {} ({} -> {} bytes)", self.frame_count, rgba_data.len(), encoded_data.len());
         }
 
-        Ok(encoded_data)
-    }
-    
-    fn compress_rgba_data(&self, rgba_data: &[u8]) -> Result<Vec<u8>, RealtimeCodecError> {
-        // Optimized RLE compression for real-time processing
-        let mut compressed = Vec::with_capacity(rgba_data.len() / 4); // Pre-allocate with reasonable size
-        let mut i = 0;
-        
-        // Process data in 4-byte pixel chunks for better cache locality
-        while i + 4 <= rgba_data.len() {
-            let current_pixel = [rgba_data[i], rgba_data[i+1], rgba_data[i+2], rgba_data[i+3]];
-            
-            // Count consecutive identical pixels (limited to 255 for single byte count)
-            let mut count = 1u8;
-            let mut j = i + 4;
-            
-            while j + 4 <= rgba_data.len() && count < 255 {
-                let next_pixel = [rgba_data[j], rgba_data[j+1], rgba_data[j+2], rgba_data[j+3]];
-                if current_pixel == next_pixel {
-                    count += 1;
-                    j += 4;
-                } else {
-                    break;
-                }
-            }
-            
-            // Store count and pixel data efficiently
-            compressed.push(count);
-            compressed.extend_from_slice(&current_pixel);
-            
-            i = j;
-        }
-        
-        Ok(compressed)
+        Ok(Some(encoded_data))
     }
     
-    fn compress_delta_frame(&self, current_rgba: &[u8], previous_rgba: &[u8]) -> Result<Vec<u8>, RealtimeCodecError> {
-        // Optimized delta compression with early allocation and chunked processing
-        let pixel_count = current_rgba.len() / 4;
-        let mut delta_data = Vec::with_capacity(pixel_count * 8); // Reasonable pre-allocation
-        let mut changes = Vec::new();
-        
-        // Process in 4-byte chunks (pixels) for better performance
-        for (pixel_idx, (curr_chunk, prev_chunk)) in current_rgba.chunks_exact(4).zip(previous_rgba.chunks_exact(4)).enumerate() {
-            // Compare pixels efficiently using array comparison
-            if curr_chunk != prev_chunk {
-                changes.push((pixel_idx as u32, [curr_chunk[0], curr_chunk[1], curr_chunk[2], curr_chunk[3]]));
-                
-                // Limit number of changes to prevent excessive data
-                if changes.len() > pixel_count / 4 {
-                    // Too many changes, fall back to full frame compression
-                    debug!("Too many delta changes ({}), falling back to full frame", changes.len());
-                    return self.compress_rgba_data(current_rgba);
-                }
-            }
-        }
-        
-        // Store number of changes efficiently
-        delta_data.extend_from_slice(&(changes.len() as u32).to_le_bytes());
-        
-        // Store changes in compact format
-        for (index, pixel) in changes {
-            delta_data.extend_from_slice(&index.to_le_bytes());
-            delta_data.extend_from_slice(&pixel);
-        }
-        
-        debug!("Delta frame: {} pixel changes out of {} total pixels", 
-               delta_data.len() / 8, pixel_count);
-        
-        Ok(delta_data)
-    }
-
     fn resize_image(&self, rgba_data: &[u8], src_width: u32, src_height: u32, dst_width: u32, dst_height: u32) -> Result<Vec<u8>, RealtimeCodecError> {
         // Simple nearest neighbor resizing for speed
         let mut resized = vec![0u8; (dst_width * dst_height * 4) as usize];
@@ -267,35 +206,24 @@ impl RealtimeStreamEncoder {
         Ok(resized)
     }
 
-    pub fn update_bitrate(&mut self, new_bitrate: u32) -> Result<(), RealtimeCodecError> {
-        info!("Updating bitrate from {} to {} kbps", self.config.bitrate, new_bitrate);
-        self.config.bitrate = new_bitrate;
-        Ok(())
+    /// Apply settings picked by the congestion controller
+    pub fn retarget(&mut self, target: EncoderTarget) {
+        info!("Retargeting encoder: {}x{} @ {}fps, {}kbps",
+              target.width, target.height, target.framerate, target.bitrate_kbps);
+        self.config.bitrate = target.bitrate_kbps;
+        self.config.width = target.width;
+        self.config.height = target.height;
+        self.config.framerate = target.framerate;
     }
 
-    pub fn adapt_to_network_conditions(&mut self, stats: &NetworkStats) -> Result<(), RealtimeCodecError> {
-        // Adaptive bitrate based on network conditions
-        let target_bitrate = if stats.packet_loss > 5.0 {
-            // High packet loss - reduce bitrate significantly
-            (self.config.bitrate as f32 * 0.5) as u32
-        } else if stats.packet_loss > 2.0 {
-            // Medium packet loss - reduce bitrate moderately
-            (self.config.bitrate as f32 * 0.7) as u32
-        } else if stats.latency > 200 {
-            // High latency - reduce bitrate to help
-            (self.config.bitrate as f32 * 0.8) as u32
-        } else if stats.latency < 50 && stats.packet_loss < 0.5 {
-            // Good conditions - can increase bitrate
-            std::cmp::min((self.config.bitrate as f32 * 1.2) as u32, 8000) // Cap at 8Mbps
-        } else {
-            self.config.bitrate // Keep current bitrate
-        };
-
-        if target_bitrate != self.config.bitrate {
-            self.update_bitrate(target_bitrate)?;
+    /// Settings the encoder currently produces
+    pub fn target(&self) -> EncoderTarget {
+        EncoderTarget {
+            bitrate_kbps: self.config.bitrate,
+            width: self.config.width,
+            height: self.config.height,
+            framerate: self.config.framerate,
         }
-
-        Ok(())
     }
 
     pub fn get_performance_stats(&self) -> (f64, f64, u64) {
@@ -303,11 +231,11 @@ impl RealtimeStreamEncoder {
     }
 
     pub fn get_dimensions(&self) -> (u32, u32) {
-        (self.config.width, self.config.height)
+        self.source.dimensions()
     }
 
     pub fn force_keyframe(&mut self) {
-        self.last_keyframe = 0; // Force next frame to be keyframe
+        self.keyframe_requested = true;
     }
 }
 
@@ -392,3 +320,81 @@ impl WebRTCEncoderConfig {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::capture::{CapturedFrame, DirtyRect, SyntheticPattern, SyntheticSource};
+    use crate::streaming::frame::parse_frame;
+
+    /// A grey screen where a test can flip single pixels between captures
+    struct Canvas {
+        rgba: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl CaptureSource for Canvas {
+        fn name(&self) -> &str {
+            "canvas"
+        }
+
+        fn dimensions(&self) -> (u32, u32) {
+            (160, 96)
+        }
+
+        fn capture_frame(&mut self) -> Result<CapturedFrame, CaptureError> {
+            Ok(CapturedFrame { width: 160, height: 96, rgba: self.rgba.lock().unwrap().clone() })
+        }
+    }
+
+    fn encoder(source: Box<dyn CaptureSource>) -> RealtimeStreamEncoder {
+        let (width, height) = source.dimensions();
+        RealtimeStreamEncoder::with_source(RealtimeConfig { width, height, ..RealtimeConfig::default() }, source)
+    }
+
+    fn header(frame: &[u8]) -> FrameHeader {
+        parse_frame(frame).expect("media frame").0
+    }
+
+    #[test]
+    fn unchanged_screen_sends_nothing_after_the_first_keyframe() {
+        let mut encoder = encoder(Box::new(SyntheticSource::new(64, 48, SyntheticPattern::Static)));
+
+        let first = encoder.capture_and_encode(false).unwrap().expect("keyframe");
+        assert_eq!(header(&first).codec, FrameCodec::RgbaRle);
+        assert!(header(&first).keyframe);
+        for _ in 0..5 {
+            assert!(encoder.capture_and_encode(false).unwrap().is_none());
+        }
+
+        // A client asking to resync still gets the whole picture
+        encoder.force_keyframe();
+        assert!(header(&encoder.capture_and_encode(false).unwrap().expect("keyframe")).keyframe);
+        assert!(encoder.capture_and_encode(false).unwrap().is_none());
+    }
+
+    #[test]
+    fn changed_tiles_are_sent_as_rectangles() {
+        let rgba = std::sync::Arc::new(std::sync::Mutex::new(vec![90u8; 160 * 96 * 4]));
+        let mut encoder = encoder(Box::new(Canvas { rgba: rgba.clone() }));
+        encoder.capture_and_encode(false).unwrap().expect("keyframe");
+
+        // One pixel in the second tile column, one in the bottom-right tile
+        for (x, y) in [(70, 10), (150, 90)] {
+            rgba.lock().unwrap()[(y * 160 + x) * 4] = 255;
+        }
+        let update = encoder.capture_and_encode(false).unwrap().expect("tile update");
+        let (header, payload) = parse_frame(&update).unwrap();
+        assert_eq!(header.codec, FrameCodec::RgbaTiles);
+        assert!(!header.keyframe);
+        assert_eq!(payload[..4], 2u32.to_le_bytes());
+
+        let rect_at = |offset: usize| {
+            let field = |at: usize| u16::from_le_bytes([payload[offset + at], payload[offset + at + 1]]) as u32;
+            DirtyRect { x: field(0), y: field(2), width: field(4), height: field(6) }
+        };
+        assert_eq!(rect_at(4), DirtyRect { x: 64, y: 0, width: 64, height: 64 });
+        let first_len = u32::from_le_bytes(payload[12..16].try_into().unwrap()) as usize;
+        // Edge tiles are cut to the frame
+        assert_eq!(rect_at(16 + first_len), DirtyRect { x: 128, y: 64, width: 32, height: 32 });
+    }
+}
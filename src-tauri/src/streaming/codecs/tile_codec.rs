@@ -0,0 +1,224 @@
+//! Rectangle updates for RGBA streams
+//!
+//! [`ChangeTracker`] finds what changed since the last frame: tiles whose hash
+//! differs, minus those that only moved (see `motion`). This module packs the
+//! result into an `RgbaTiles` or `RgbaCopyTiles` payload. Encoders send nothing
+//! at all for an unchanged screen, so while nothing moves the only traffic is a
+//! keyframe every [`REFRESH_INTERVAL`].
+
+use std::time::Duration;
+
+use crate::core::capture::{DirtyRect, TileGrid, TILE_SIZE};
+use crate::streaming::frame::FrameCodec;
+use super::motion::{detect_moves, CopyRect};
+
+/// Longest a stream goes without a keyframe, however little changes. Repairs
+/// anything a hash collision hid and gives late or resynced viewers a start.
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks the last frame of an RGBA stream to work out what the next one changed
+pub struct ChangeTracker {
+    tiles: TileGrid,
+    /// The frame `tiles` describes, to find where moved content came from
+    previous: Vec<u8>,
+}
+
+/// What a frame changed: areas copied within the previous picture, then new pixels
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct FrameChanges {
+    pub copies: Vec<CopyRect>,
+    pub rects: Vec<DirtyRect>,
+}
+
+impl ChangeTracker {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { tiles: TileGrid::new(width, height, TILE_SIZE), previous: Vec::new() }
+    }
+
+    /// Forget the last frame, e.g. because viewers never got it
+    pub fn invalidate(&mut self) {
+        self.tiles.invalidate();
+    }
+
+    /// Compare `rgba` with the last frame; `None` when there is nothing to compare it with
+    pub fn update(&mut self, rgba: &[u8], width: usize, height: usize) -> Option<FrameChanges> {
+        let changes = self.tiles.diff(rgba, width, height).map(|rects| {
+            if rects.is_empty() {
+                return FrameChanges::default();
+            }
+            let (copies, rects) = detect_moves(&self.previous, rgba, width, height, TILE_SIZE, &rects);
+            FrameChanges { copies, rects }
+        });
+        // An unchanged frame is the same as the one kept, so skip the copy
+        if changes.as_ref().is_none_or(|changes| !changes.is_empty()) {
+            self.previous.clear();
+            self.previous.extend_from_slice(rgba);
+        }
+        changes
+    }
+}
+
+impl FrameChanges {
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty() && self.rects.is_empty()
+    }
+
+    /// Whether so many pixels are new that a full keyframe costs about the same
+    pub fn mostly_new(&self, width: u32, height: u32) -> bool {
+        let changed: u64 = self.rects.iter().map(|rect| rect.width as u64 * rect.height as u64).sum();
+        changed * 2 > width as u64 * height as u64
+    }
+
+    /// Codec and payload for these changes to a `frame_width` wide `rgba` frame
+    pub fn encode(&self, rgba: &[u8], frame_width: u32) -> (FrameCodec, Vec<u8>) {
+        let tiles = encode_tiles(rgba, frame_width, &self.rects);
+        if self.copies.is_empty() {
+            return (FrameCodec::RgbaTiles, tiles);
+        }
+        (FrameCodec::RgbaCopyTiles, [encode_copies(&self.copies), tiles].concat())
+    }
+}
+
+/// Run-length encode RGBA pixels: a count byte (1-255) followed by one pixel per run
+pub fn compress_rle(rgba: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::with_capacity(rgba.len() / 4);
+    append_rle(rgba.chunks_exact(4), &mut compressed);
+    compressed
+}
+
+/// `RgbaTiles` payload for the given areas of a `frame_width` wide frame:
+/// a u32 count, then per rectangle u16 x, y, width and height, a u32 length
+/// and that many bytes of run-length encoded pixels, row by row
+pub fn encode_tiles(rgba: &[u8], frame_width: u32, rects: &[DirtyRect]) -> Vec<u8> {
+    let stride = frame_width as usize * 4;
+    let mut payload = Vec::with_capacity(4 + rects.len() * 256);
+    payload.extend_from_slice(&(rects.len() as u32).to_le_bytes());
+
+    for rect in rects {
+        for value in [rect.x, rect.y, rect.width, rect.height] {
+            payload.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        let length_at = payload.len();
+        payload.extend_from_slice(&[0; 4]);
+
+        let (x, width) = (rect.x as usize * 4, rect.width as usize * 4);
+        let rows = (rect.y as usize..(rect.y + rect.height) as usize)
+            .flat_map(|row| rgba[row * stride + x..row * stride + x + width].chunks_exact(4));
+        append_rle(rows, &mut payload);
+
+        let length = (payload.len() - length_at - 4) as u32;
+        payload[length_at..length_at + 4].copy_from_slice(&length.to_le_bytes());
+    }
+    payload
+}
+
+/// Copies in front of an `RgbaCopyTiles` payload: a u32 count, then per copy
+/// u16 source x and y followed by destination x, y, width and height
+fn encode_copies(copies: &[CopyRect]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(4 + copies.len() * 12);
+    payload.extend_from_slice(&(copies.len() as u32).to_le_bytes());
+    for copy in copies {
+        for value in [copy.src_x, copy.src_y, copy.dst.x, copy.dst.y, copy.dst.width, copy.dst.height] {
+            payload.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+    }
+    payload
+}
+
+fn append_rle<'a>(pixels: impl Iterator<Item = &'a [u8]>, out: &mut Vec<u8>) {
+    let mut run: Option<(&[u8], u8)> = None;
+    for pixel in pixels {
+        run = match run {
+            Some((current, count)) if current == pixel && count < u8::MAX => Some((current, count + 1)),
+            Some((current, count)) => {
+                out.push(count);
+                out.extend_from_slice(current);
+                Some((pixel, 1))
+            }
+            None => Some((pixel, 1)),
+        };
+    }
+    if let Some((current, count)) = run {
+        out.push(count);
+        out.extend_from_slice(current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `kvm-client.js` does with a tile payload
+    fn apply_tiles(payload: &[u8], picture: &mut [u8], frame_width: usize) {
+        let u16_at = |offset: usize| u16::from_le_bytes([payload[offset], payload[offset + 1]]) as usize;
+        let count = u32::from_le_bytes(payload[..4].try_into().unwrap());
+        let mut offset = 4;
+        for _ in 0..count {
+            let (x, y, width) = (u16_at(offset), u16_at(offset + 2), u16_at(offset + 4));
+            let length = u32::from_le_bytes(payload[offset + 8..offset + 12].try_into().unwrap()) as usize;
+            offset += 12;
+
+            let mut pixel = 0;
+            for run in payload[offset..offset + length].chunks_exact(5) {
+                for _ in 0..run[0] {
+                    let at = ((y + pixel / width) * frame_width + x + pixel % width) * 4;
+                    picture[at..at + 4].copy_from_slice(&run[1..]);
+                    pixel += 1;
+                }
+            }
+            offset += length;
+        }
+    }
+
+    #[test]
+    fn runs_are_capped_at_255_pixels() {
+        let compressed = compress_rle(&[7; 300 * 4]);
+        assert_eq!(compressed, [&[255, 7, 7, 7, 7][..], &[45, 7, 7, 7, 7]].concat());
+        assert!(compress_rle(&[]).is_empty());
+    }
+
+    #[test]
+    fn tiles_patch_the_previous_picture() {
+        let (width, height) = (10usize, 6usize);
+        let previous = vec![0u8; width * height * 4];
+        let mut current = previous.clone();
+        for (y, x) in [(1, 2), (1, 3), (2, 2), (4, 9)] {
+            current[(y * width + x) * 4..][..4].copy_from_slice(&[y as u8, x as u8, 1, 255]);
+        }
+        let rects = [
+            DirtyRect { x: 2, y: 1, width: 2, height: 2 },
+            DirtyRect { x: 8, y: 3, width: 2, height: 3 },
+        ];
+
+        let payload = encode_tiles(&current, width as u32, &rects);
+        let mut picture = previous;
+        apply_tiles(&payload, &mut picture, width);
+        assert_eq!(picture, current);
+    }
+
+    #[test]
+    fn keyframe_once_most_of_the_frame_is_new() {
+        let half = DirtyRect { x: 0, y: 0, width: 64, height: 32 };
+        let changes = FrameChanges { copies: Vec::new(), rects: vec![half] };
+        assert!(!changes.mostly_new(64, 64));
+
+        let more = FrameChanges { rects: vec![half, DirtyRect { x: 0, y: 32, width: 8, height: 8 }], ..changes };
+        assert!(more.mostly_new(64, 64));
+        // Copied areas cost next to nothing
+        let copied = FrameChanges { copies: vec![CopyRect { src_x: 0, src_y: 0, dst: half }; 2], rects: vec![] };
+        assert!(!copied.mostly_new(64, 64));
+    }
+
+    #[test]
+    fn copies_go_in_front_of_the_tiles() {
+        let copy = CopyRect { src_x: 0, src_y: 40, dst: DirtyRect { x: 0, y: 0, width: 8, height: 4 } };
+        let changes = FrameChanges { copies: vec![copy], rects: vec![] };
+
+        let (codec, payload) = changes.encode(&[0; 8 * 8 * 4], 8);
+        assert_eq!(codec, FrameCodec::RgbaCopyTiles);
+        assert_eq!(payload, [&1u32.to_le_bytes()[..], &[0, 0, 40, 0, 0, 0, 0, 0, 8, 0, 4, 0], &[0; 4]].concat());
+
+        let (codec, _) = FrameChanges::default().encode(&[], 8);
+        assert_eq!(codec, FrameCodec::RgbaTiles);
+    }
+}
@@ -0,0 +1,577 @@
+//! Reference VP8 decoder for checking `Vp8Encoder` output in tests
+//!
+//! `image-webp` only decodes key frames, so inter frames need a decoder of our
+//! own. This one follows RFC 6386 for frames without a loop filter, segmentation,
+//! B_PRED or motion vectors, which covers everything the encoder writes. Anything
+//! else panics, so an encoder that starts using more of the format fails its
+//! tests until this decoder learns it too.
+
+use super::vp8_tables::*;
+
+// Intra prediction modes, numbered as in the bitstream trees
+const DC_PRED: u8 = 0;
+const V_PRED: u8 = 1;
+const H_PRED: u8 = 2;
+const TM_PRED: u8 = 3;
+
+/// Boolean entropy decoder (section 7.3)
+struct BoolDecoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    value: u32,
+    range: u32,
+    bit_count: u32,
+}
+
+impl<'a> BoolDecoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        let mut decoder = Self { data, pos: 0, value: 0, range: 255, bit_count: 0 };
+        decoder.value = (decoder.next_byte() << 8) | decoder.next_byte();
+        decoder
+    }
+
+    /// Reading past the end yields zeros, like the padding the encoder writes
+    fn next_byte(&mut self) -> u32 {
+        let byte = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte as u32
+    }
+
+    fn read(&mut self, prob: u8) -> bool {
+        let split = 1 + (((self.range - 1) * prob as u32) >> 8);
+        let big_split = split << 8;
+        let bit = if self.value >= big_split {
+            self.range -= split;
+            self.value -= big_split;
+            true
+        } else {
+            self.range = split;
+            false
+        };
+
+        while self.range < 128 {
+            self.value <<= 1;
+            self.range <<= 1;
+            self.bit_count += 1;
+            if self.bit_count == 8 {
+                self.bit_count = 0;
+                self.value |= self.next_byte();
+            }
+        }
+        bit
+    }
+
+    fn flag(&mut self) -> bool {
+        self.read(128)
+    }
+
+    fn literal(&mut self, bits: u32) -> u32 {
+        (0..bits).fold(0, |value, _| (value << 1) | self.flag() as u32)
+    }
+
+    /// Magnitude followed by a sign bit
+    fn signed(&mut self, bits: u32) -> i32 {
+        let value = self.literal(bits) as i32;
+        if self.flag() { -value } else { value }
+    }
+}
+
+/// Macroblock-aligned image plane
+#[derive(Clone)]
+struct Plane {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Self { data: vec![0; width * height], stride: width }
+    }
+
+    fn at(&self, x: usize, y: usize) -> i32 {
+        self.data[y * self.stride + x] as i32
+    }
+
+    fn block(&self, x0: usize, y0: usize, size: usize) -> Vec<i32> {
+        (0..size * size).map(|i| self.at(x0 + i % size, y0 + i / size)).collect()
+    }
+
+    fn put_block(&mut self, x0: usize, y0: usize, size: usize, block: &[i32]) {
+        for (i, &px) in block.iter().enumerate() {
+            self.data[(y0 + i / size) * self.stride + x0 + i % size] = px as u8;
+        }
+    }
+
+    /// Intra predictor for a `size` square block, with 127 above the frame and
+    /// 129 left of it (section 12.2)
+    fn predict(&self, mbx: usize, mby: usize, size: usize, mode: u8) -> Vec<i32> {
+        let (x0, y0) = (mbx * size, mby * size);
+        let above: Vec<i32> = (0..size).map(|x| if mby > 0 { self.at(x0 + x, y0 - 1) } else { 127 }).collect();
+        let left: Vec<i32> = (0..size).map(|y| if mbx > 0 { self.at(x0 - 1, y0 + y) } else { 129 }).collect();
+        let corner = match (mbx, mby) {
+            (_, 0) => 127,
+            (0, _) => 129,
+            _ => self.at(x0 - 1, y0 - 1),
+        };
+
+        let log2 = size.trailing_zeros();
+        let dc = match (mby > 0, mbx > 0) {
+            (true, true) => (above.iter().sum::<i32>() + left.iter().sum::<i32>() + size as i32) >> (log2 + 1),
+            (true, false) => (above.iter().sum::<i32>() + size as i32 / 2) >> log2,
+            (false, true) => (left.iter().sum::<i32>() + size as i32 / 2) >> log2,
+            (false, false) => 128,
+        };
+
+        (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size, i / size);
+                match mode {
+                    V_PRED => above[x],
+                    H_PRED => left[y],
+                    TM_PRED => (left[y] + above[x] - corner).clamp(0, 255),
+                    _ => dc,
+                }
+            })
+            .collect()
+    }
+
+    fn cropped(&self, width: usize, height: usize) -> Vec<u8> {
+        (0..height).flat_map(|y| &self.data[y * self.stride..][..width]).copied().collect()
+    }
+}
+
+#[derive(Clone)]
+struct Planes {
+    y: Plane,
+    u: Plane,
+    v: Plane,
+}
+
+/// A decoded picture at the frame's own size
+pub(crate) struct DecodedFrame {
+    pub width: usize,
+    pub height: usize,
+    pub y: Vec<u8>,
+    pub u: Vec<u8>,
+    pub v: Vec<u8>,
+    /// Macroblocks coded with intra prediction; all of them in a key frame
+    pub intra_macroblocks: usize,
+}
+
+/// Dequantization factors, as [DC, AC] pairs (section 14.1)
+struct Dequant {
+    y: [i32; 2],
+    y2: [i32; 2],
+    uv: [i32; 2],
+}
+
+impl Dequant {
+    fn new(q: i32, [y_dc, y2_dc, y2_ac, uv_dc, uv_ac]: [i32; 5]) -> Self {
+        let dc = |delta: i32| DC_QUANT[(q + delta).clamp(0, 127) as usize] as i32;
+        let ac = |delta: i32| AC_QUANT[(q + delta).clamp(0, 127) as usize] as i32;
+        Self {
+            y: [dc(y_dc), ac(0)],
+            y2: [dc(y2_dc) * 2, (ac(y2_ac) * 155 / 100).max(8)],
+            uv: [dc(uv_dc).min(132), ac(uv_ac)],
+        }
+    }
+}
+
+/// Decodes a stream of VP8 frames, keeping the reference and probabilities between them
+pub(crate) struct Vp8Decoder {
+    width: usize,
+    height: usize,
+    reference: Option<Planes>,
+    coeff_probs: TokenProbTables,
+    ymode_probs: [u8; 4],
+    uv_mode_probs: [u8; 3],
+}
+
+impl Vp8Decoder {
+    pub fn new() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            reference: None,
+            coeff_probs: COEFF_PROBS,
+            ymode_probs: YMODE_PROBS,
+            uv_mode_probs: UV_MODE_PROBS,
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> DecodedFrame {
+        let tag = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+        let keyframe = tag & 1 == 0;
+        assert_eq!((tag >> 4) & 1, 1, "hidden frames are not supported");
+        let first_len = (tag >> 5) as usize;
+
+        let mut offset = 3;
+        if keyframe {
+            assert_eq!(data[3..6], [0x9d, 0x01, 0x2a], "key frame start code");
+            let size = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+            assert_eq!((size(6) >> 14, size(8) >> 14), (0, 0), "scaling is not supported");
+            self.width = size(6) as usize;
+            self.height = size(8) as usize;
+            self.coeff_probs = COEFF_PROBS;
+            self.ymode_probs = YMODE_PROBS;
+            self.uv_mode_probs = UV_MODE_PROBS;
+            offset = 10;
+        }
+        assert!(keyframe || self.reference.is_some(), "inter frame before any key frame");
+
+        let mut header = BoolDecoder::new(&data[offset..offset + first_len]);
+        let mut tokens = BoolDecoder::new(&data[offset + first_len..]);
+
+        if keyframe {
+            header.literal(2); // color space and clamping
+        }
+        assert!(!header.flag(), "segmentation is not supported");
+        header.literal(1); // filter type
+        assert_eq!(header.literal(6), 0, "the loop filter is not supported");
+        header.literal(3); // sharpness
+        if header.flag() && header.flag() {
+            for _ in 0..8 {
+                if header.flag() {
+                    header.signed(6); // loop filter deltas, unused without a filter
+                }
+            }
+        }
+        assert_eq!(header.literal(2), 0, "multiple token partitions are not supported");
+
+        let q = header.literal(7) as i32;
+        let mut deltas = [0; 5];
+        for delta in deltas.iter_mut() {
+            if header.flag() {
+                *delta = header.signed(4);
+            }
+        }
+        let dequant = Dequant::new(q, deltas);
+
+        let mut refresh_last = true;
+        let refresh_entropy = if keyframe {
+            header.flag()
+        } else {
+            let refresh_golden = header.flag();
+            let refresh_alternate = header.flag();
+            if !refresh_golden {
+                header.literal(2); // copy buffer to golden
+            }
+            if !refresh_alternate {
+                header.literal(2); // copy buffer to alternate
+            }
+            header.literal(2); // sign biases
+            let refresh_entropy = header.flag();
+            refresh_last = header.flag();
+            refresh_entropy
+        };
+        // Probabilities updated for one frame only go back afterwards
+        let saved = (!refresh_entropy).then_some((self.coeff_probs, self.ymode_probs, self.uv_mode_probs));
+
+        for (i, plane) in COEFF_UPDATE_PROBS.iter().enumerate() {
+            for (j, band) in plane.iter().enumerate() {
+                for (k, ctx) in band.iter().enumerate() {
+                    for (l, &prob) in ctx.iter().enumerate() {
+                        if header.read(prob) {
+                            self.coeff_probs[i][j][k][l] = header.literal(8) as u8;
+                        }
+                    }
+                }
+            }
+        }
+
+        let prob_skip = header.flag().then(|| header.literal(8) as u8);
+        let mut inter_probs = None;
+        if !keyframe {
+            let prob_intra = header.literal(8) as u8;
+            let prob_last = header.literal(8) as u8;
+            header.literal(8); // prob_gf, only used once golden frames are referenced
+            if header.flag() {
+                for prob in self.ymode_probs.iter_mut() {
+                    *prob = header.literal(8) as u8;
+                }
+            }
+            if header.flag() {
+                for prob in self.uv_mode_probs.iter_mut() {
+                    *prob = header.literal(8) as u8;
+                }
+            }
+            for component in MV_UPDATE_PROBS.iter() {
+                for &prob in component.iter() {
+                    if header.read(prob) {
+                        header.literal(7); // motion vector probabilities, unused with ZEROMV only
+                    }
+                }
+            }
+            inter_probs = Some((prob_intra, prob_last));
+        }
+
+        let (mb_cols, mb_rows) = (self.width.div_ceil(16), self.height.div_ceil(16));
+        let mut frame = Planes {
+            y: Plane::new(mb_cols * 16, mb_rows * 16),
+            u: Plane::new(mb_cols * 8, mb_rows * 8),
+            v: Plane::new(mb_cols * 8, mb_rows * 8),
+        };
+        let mut inter_map = vec![false; mb_cols * mb_rows];
+        let mut above_ctx = vec![[false; 9]; mb_cols];
+        let mut intra_macroblocks = 0;
+
+        for mby in 0..mb_rows {
+            let mut left_ctx = [false; 9];
+            for mbx in 0..mb_cols {
+                let skip = prob_skip.is_some_and(|prob| header.read(prob));
+                let modes = match inter_probs {
+                    None => Some((read_kf_y_mode(&mut header), read_uv_mode(&mut header, &KF_UV_MODE_PROBS))),
+                    Some((prob_intra, prob_last)) if header.read(prob_intra) => {
+                        assert!(!header.read(prob_last), "golden and alternate references are not supported");
+                        // Every inter macroblock has a zero vector, so neighbours only add
+                        // to the zero count (section 16.3)
+                        let inter_at = |x: Option<usize>, y: Option<usize>| match (x, y) {
+                            (Some(x), Some(y)) => inter_map[y * mb_cols + x] as usize,
+                            _ => 0,
+                        };
+                        let (left, up) = (mbx.checked_sub(1), mby.checked_sub(1));
+                        let zero_count = 2 * inter_at(Some(mbx), up) + 2 * inter_at(left, Some(mby)) + inter_at(left, up);
+                        assert!(!header.read(MODE_CONTEXTS[zero_count][0]), "only ZEROMV is supported");
+                        None
+                    }
+                    Some(_) => {
+                        let y_mode = read_y_mode(&mut header, &self.ymode_probs);
+                        Some((y_mode, read_uv_mode(&mut header, &self.uv_mode_probs)))
+                    }
+                };
+                inter_map[mby * mb_cols + mbx] = modes.is_none();
+
+                let (mut pred_y, mut pred_u, mut pred_v) = match (modes, &self.reference) {
+                    (Some((y_mode, uv_mode)), _) => {
+                        intra_macroblocks += 1;
+                        (
+                            frame.y.predict(mbx, mby, 16, y_mode),
+                            frame.u.predict(mbx, mby, 8, uv_mode),
+                            frame.v.predict(mbx, mby, 8, uv_mode),
+                        )
+                    }
+                    (None, Some(reference)) => (
+                        reference.y.block(mbx * 16, mby * 16, 16),
+                        reference.u.block(mbx * 8, mby * 8, 8),
+                        reference.v.block(mbx * 8, mby * 8, 8),
+                    ),
+                    (None, None) => unreachable!(),
+                };
+
+                if skip {
+                    above_ctx[mbx] = [false; 9];
+                    left_ctx = [false; 9];
+                } else {
+                    let above = &mut above_ctx[mbx];
+                    let mut y2 = [0; 16];
+                    let ctx = above[8] as usize + left_ctx[8] as usize;
+                    let nonzero = read_block(&mut tokens, &self.coeff_probs[1], 0, ctx, dequant.y2, &mut y2);
+                    above[8] = nonzero;
+                    left_ctx[8] = nonzero;
+                    let dc = inverse_wht(&y2);
+
+                    for (b, &dc) in dc.iter().enumerate() {
+                        let (bx, by) = (b % 4, b / 4);
+                        let mut coeffs = [0; 16];
+                        let ctx = above[bx] as usize + left_ctx[by] as usize;
+                        let nonzero = read_block(&mut tokens, &self.coeff_probs[0], 1, ctx, dequant.y, &mut coeffs);
+                        above[bx] = nonzero;
+                        left_ctx[by] = nonzero;
+                        coeffs[0] = dc;
+                        add_residual(&mut pred_y, 16, bx, by, &inverse_dct(&coeffs));
+                    }
+
+                    for (slot, pred) in [(4, &mut pred_u), (6, &mut pred_v)] {
+                        for b in 0..4 {
+                            let (bx, by) = (b % 2, b / 2);
+                            let mut coeffs = [0; 16];
+                            let ctx = above[slot + bx] as usize + left_ctx[slot + by] as usize;
+                            let nonzero = read_block(&mut tokens, &self.coeff_probs[2], 0, ctx, dequant.uv, &mut coeffs);
+                            above[slot + bx] = nonzero;
+                            left_ctx[slot + by] = nonzero;
+                            add_residual(pred, 8, bx, by, &inverse_dct(&coeffs));
+                        }
+                    }
+                }
+
+                frame.y.put_block(mbx * 16, mby * 16, 16, &pred_y);
+                frame.u.put_block(mbx * 8, mby * 8, 8, &pred_u);
+                frame.v.put_block(mbx * 8, mby * 8, 8, &pred_v);
+            }
+        }
+
+        if let Some((coeff_probs, ymode_probs, uv_mode_probs)) = saved {
+            self.coeff_probs = coeff_probs;
+            self.ymode_probs = ymode_probs;
+            self.uv_mode_probs = uv_mode_probs;
+        }
+
+        let (chroma_width, chroma_height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let decoded = DecodedFrame {
+            width: self.width,
+            height: self.height,
+            y: frame.y.cropped(self.width, self.height),
+            u: frame.u.cropped(chroma_width, chroma_height),
+            v: frame.v.cropped(chroma_width, chroma_height),
+            intra_macroblocks,
+        };
+        if keyframe || refresh_last {
+            self.reference = Some(frame);
+        }
+        decoded
+    }
+}
+
+fn read_kf_y_mode(bd: &mut BoolDecoder) -> u8 {
+    let p = &KF_YMODE_PROBS;
+    assert!(bd.read(p[0]), "B_PRED is not supported");
+    if !bd.read(p[1]) {
+        if bd.read(p[2]) { V_PRED } else { DC_PRED }
+    } else if bd.read(p[3]) {
+        TM_PRED
+    } else {
+        H_PRED
+    }
+}
+
+fn read_y_mode(bd: &mut BoolDecoder, p: &[u8; 4]) -> u8 {
+    if !bd.read(p[0]) {
+        return DC_PRED;
+    }
+    if !bd.read(p[1]) {
+        return if bd.read(p[2]) { H_PRED } else { V_PRED };
+    }
+    assert!(!bd.read(p[3]), "B_PRED is not supported");
+    TM_PRED
+}
+
+fn read_uv_mode(bd: &mut BoolDecoder, p: &[u8; 3]) -> u8 {
+    if !bd.read(p[0]) {
+        DC_PRED
+    } else if !bd.read(p[1]) {
+        V_PRED
+    } else if bd.read(p[2]) {
+        TM_PRED
+    } else {
+        H_PRED
+    }
+}
+
+/// Read one block's tokens into dequantized coefficients in raster order (section 13);
+/// returns whether any token other than an immediate end of block was coded
+fn read_block(
+    bd: &mut BoolDecoder,
+    probs: &[[[u8; 11]; 3]; 8],
+    first: usize,
+    ctx: usize,
+    dequant: [i32; 2],
+    out: &mut [i32; 16],
+) -> bool {
+    let mut ctx = ctx;
+    let mut after_zero = false;
+    let mut i = first;
+    while i < 16 {
+        let p = &probs[COEFF_BANDS[i]][ctx];
+        // No end of block straight after a zero
+        if !after_zero && !bd.read(p[0]) {
+            break;
+        }
+        if !bd.read(p[1]) {
+            ctx = 0;
+            after_zero = true;
+            i += 1;
+            continue;
+        }
+
+        let abs = if !bd.read(p[2]) {
+            1
+        } else if !bd.read(p[3]) {
+            if !bd.read(p[4]) { 2 } else { 3 + bd.read(p[5]) as i32 }
+        } else {
+            let cat = if !bd.read(p[6]) {
+                bd.read(p[7]) as usize
+            } else if !bd.read(p[8]) {
+                2 + bd.read(p[9]) as usize
+            } else {
+                4 + bd.read(p[10]) as usize
+            };
+            let extra = DCT_CAT_PROBS[cat].iter().fold(0, |extra, &prob| (extra << 1) | bd.read(prob) as i32);
+            DCT_CAT_BASE[cat] + extra
+        };
+        ctx = if abs == 1 { 1 } else { 2 };
+        let value = if bd.flag() { -abs } else { abs };
+        out[ZIGZAG[i]] = value * dequant[(i > 0) as usize];
+        after_zero = false;
+        i += 1;
+    }
+    i > first
+}
+
+fn add_residual(block: &mut [i32], size: usize, bx: usize, by: usize, residual: &[i32; 16]) {
+    for (i, &r) in residual.iter().enumerate() {
+        let px = &mut block[(by * 4 + i / 4) * size + bx * 4 + i % 4];
+        *px = (*px + r).clamp(0, 255);
+    }
+}
+
+/// Inverse DCT as in the libvpx reference decoder (section 14.3)
+fn inverse_dct(input: &[i32; 16]) -> [i32; 16] {
+    const COS: i32 = 20091;
+    const SIN: i32 = 35468;
+
+    let mut tmp = [0; 16];
+    for i in 0..4 {
+        let (i0, i1, i2, i3) = (input[i], input[4 + i], input[8 + i], input[12 + i]);
+        let a1 = i0 + i2;
+        let b1 = i0 - i2;
+        let c1 = ((i1 * SIN) >> 16) - (i3 + ((i3 * COS) >> 16));
+        let d1 = (i1 + ((i1 * COS) >> 16)) + ((i3 * SIN) >> 16);
+        tmp[i] = a1 + d1;
+        tmp[4 + i] = b1 + c1;
+        tmp[8 + i] = b1 - c1;
+        tmp[12 + i] = a1 - d1;
+    }
+
+    let mut out = [0; 16];
+    for row in 0..4 {
+        let t = &tmp[row * 4..row * 4 + 4];
+        let a1 = t[0] + t[2];
+        let b1 = t[0] - t[2];
+        let c1 = ((t[1] * SIN) >> 16) - (t[3] + ((t[3] * COS) >> 16));
+        let d1 = (t[1] + ((t[1] * COS) >> 16)) + ((t[3] * SIN) >> 16);
+        out[row * 4] = (a1 + d1 + 4) >> 3;
+        out[row * 4 + 1] = (b1 + c1 + 4) >> 3;
+        out[row * 4 + 2] = (b1 - c1 + 4) >> 3;
+        out[row * 4 + 3] = (a1 - d1 + 4) >> 3;
+    }
+    out
+}
+
+/// Inverse Walsh-Hadamard transform of the luma DC terms (section 14.3)
+fn inverse_wht(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0; 16];
+    for i in 0..4 {
+        let a1 = input[i] + input[12 + i];
+        let b1 = input[4 + i] + input[8 + i];
+        let c1 = input[4 + i] - input[8 + i];
+        let d1 = input[i] - input[12 + i];
+        tmp[i] = a1 + b1;
+        tmp[4 + i] = c1 + d1;
+        tmp[8 + i] = a1 - b1;
+        tmp[12 + i] = d1 - c1;
+    }
+
+    let mut out = [0; 16];
+    for row in 0..4 {
+        let t = &tmp[row * 4..row * 4 + 4];
+        let a1 = t[0] + t[3];
+        let b1 = t[1] + t[2];
+        let c1 = t[1] - t[2];
+        let d1 = t[0] - t[3];
+        out[row * 4] = (a1 + b1 + 3) >> 3;
+        out[row * 4 + 1] = (c1 + d1 + 3) >> 3;
+        out[row * 4 + 2] = (a1 - b1 + 3) >> 3;
+        out[row * 4 + 3] = (d1 - c1 + 3) >> 3;
+    }
+    out
+}
@@ -0,0 +1,1320 @@
+//! Software VP8 encoder
+//!
+//! Produces standard VP8 bitstreams (RFC 6386) from `YUV420Frame`s without any
+//! native dependencies. Key frames use 16x16 intra prediction; inter frames
+//! predict each macroblock from the previous frame without motion (ZEROMV) and
+//! fall back to intra prediction where that is cheaper, which suits mostly-static
+//! desktop content. The loop filter is disabled so the encoder's reconstruction
+//! stays bit-exact with the decoder's reference frame.
+
+use log::{debug, info};
+use thiserror::Error;
+
+use super::vp8_tables::*;
+use super::yuv420_encoder::YUV420Frame;
+
+/// VP8 encoder errors
+#[derive(Error, Debug)]
+pub enum Vp8EncoderError {
+    #[error("Invalid frame dimensions: {0}x{1}")]
+    InvalidDimensions(u32, u32),
+    #[error("Frame does not match encoder: {0}")]
+    FrameMismatch(String),
+    #[error("First partition too large: {0} bytes")]
+    PartitionOverflow(usize),
+}
+
+/// VP8 encoder configuration
+#[derive(Clone, Debug)]
+pub struct Vp8Config {
+    pub width: u32,
+    pub height: u32,
+    pub bitrate: u32,       // Target bitrate in kbps
+    pub framerate: u32,
+    pub min_quantizer: u8,  // Quantizer index range 0-127 (lower is better)
+    pub max_quantizer: u8,
+    pub initial_quantizer: u8,
+}
+
+impl Default for Vp8Config {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            bitrate: 2000,
+            framerate: 30,
+            min_quantizer: 4,
+            max_quantizer: 120,
+            initial_quantizer: 40,
+        }
+    }
+}
+
+/// A single encoded VP8 frame
+#[derive(Debug, Clone)]
+pub struct EncodedVp8Frame {
+    pub data: Vec<u8>,
+    pub is_keyframe: bool,
+    pub quantizer: u8,
+}
+
+// Largest first partition size that fits in the 19-bit frame tag field
+const MAX_FIRST_PARTITION_SIZE: usize = (1 << 19) - 1;
+
+// Largest coefficient magnitude representable by the DCT token alphabet
+const MAX_COEFF: i32 = 2048;
+
+// Intra prediction modes, numbered as in the bitstream trees
+const DC_PRED: u8 = 0;
+const V_PRED: u8 = 1;
+const H_PRED: u8 = 2;
+const TM_PRED: u8 = 3;
+
+// Token probability plane types (section 13.3)
+const PLANE_Y_AFTER_Y2: usize = 0;
+const PLANE_Y2: usize = 1;
+const PLANE_CHROMA: usize = 2;
+
+// Entropy context slots per macroblock: 4 luma, 2 U, 2 V, 1 Y2
+const CTX_U: usize = 4;
+const CTX_V: usize = 6;
+const CTX_Y2: usize = 8;
+
+/// Boolean entropy encoder (section 7)
+struct BoolEncoder {
+    buffer: Vec<u8>,
+    range: u32,
+    lowvalue: u32,
+    count: i32,
+}
+
+impl BoolEncoder {
+    fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            range: 255,
+            lowvalue: 0,
+            count: -24,
+        }
+    }
+
+    fn put(&mut self, bit: bool, prob: u8) {
+        let split = 1 + (((self.range - 1) * prob as u32) >> 8);
+        let mut lowvalue = self.lowvalue;
+        let mut range = split;
+
+        if bit {
+            lowvalue = lowvalue.wrapping_add(split);
+            range = self.range - split;
+        }
+
+        let mut shift = range.leading_zeros() as i32 - 24;
+        range <<= shift;
+        let mut count = self.count + shift;
+
+        if count >= 0 {
+            let offset = shift - count;
+
+            // Propagate carry into bytes already written
+            if (lowvalue << (offset - 1)) & 0x8000_0000 != 0 {
+                let mut x = self.buffer.len();
+                while x > 0 && self.buffer[x - 1] == 0xff {
+                    self.buffer[x - 1] = 0;
+                    x -= 1;
+                }
+                if x > 0 {
+                    self.buffer[x - 1] += 1;
+                }
+            }
+
+            self.buffer.push((lowvalue >> (24 - offset)) as u8);
+            lowvalue <<= offset;
+            shift = count;
+            lowvalue &= 0xff_ffff;
+            count -= 8;
+        }
+
+        self.lowvalue = lowvalue << shift;
+        self.count = count;
+        self.range = range;
+    }
+
+    fn put_flag(&mut self, bit: bool) {
+        self.put(bit, 128);
+    }
+
+    fn put_literal(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            self.put_flag((value >> i) & 1 == 1);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..32 {
+            self.put_flag(false);
+        }
+        self.buffer
+    }
+}
+
+/// Macroblock-aligned image plane
+#[derive(Clone)]
+struct Plane {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            data: vec![0; width * height],
+            stride: width,
+        }
+    }
+
+    /// Copy `src` into an aligned plane, replicating the right and bottom edges
+    fn from_padded(src: &[u8], width: usize, height: usize, aligned_width: usize, aligned_height: usize) -> Self {
+        let mut plane = Self::new(aligned_width, aligned_height);
+        for y in 0..aligned_height {
+            let src_row = &src[y.min(height - 1) * width..][..width];
+            let dst_row = &mut plane.data[y * aligned_width..][..aligned_width];
+            dst_row[..width].copy_from_slice(src_row);
+            let edge = src_row[width - 1];
+            dst_row[width..].fill(edge);
+        }
+        plane
+    }
+
+    fn block<const N: usize>(&self, x0: usize, y0: usize) -> [[u8; N]; N] {
+        let mut out = [[0u8; N]; N];
+        for (y, row) in out.iter_mut().enumerate() {
+            row.copy_from_slice(&self.data[(y0 + y) * self.stride + x0..][..N]);
+        }
+        out
+    }
+
+    fn put_block<const N: usize>(&mut self, x0: usize, y0: usize, block: &[[u8; N]; N]) {
+        for (y, row) in block.iter().enumerate() {
+            self.data[(y0 + y) * self.stride + x0..][..N].copy_from_slice(row);
+        }
+    }
+
+    /// Build an intra predictor from already reconstructed neighbours (section 12.2)
+    fn intra_predict<const N: usize>(&self, mbx: usize, mby: usize, mode: u8) -> [[u8; N]; N] {
+        let x0 = mbx * N;
+        let y0 = mby * N;
+        let above: Vec<u8> = if mby > 0 {
+            self.data[(y0 - 1) * self.stride + x0..][..N].to_vec()
+        } else {
+            vec![127; N]
+        };
+        let left: Vec<u8> = if mbx > 0 {
+            (0..N).map(|y| self.data[(y0 + y) * self.stride + x0 - 1]).collect()
+        } else {
+            vec![129; N]
+        };
+
+        let mut out = [[0u8; N]; N];
+        match mode {
+            V_PRED => {
+                for row in out.iter_mut() {
+                    row.copy_from_slice(&above);
+                }
+            }
+            H_PRED => {
+                for (y, row) in out.iter_mut().enumerate() {
+                    row.fill(left[y]);
+                }
+            }
+            TM_PRED => {
+                let top_left = self.data[(y0 - 1) * self.stride + x0 - 1] as i32;
+                for (y, row) in out.iter_mut().enumerate() {
+                    for (x, px) in row.iter_mut().enumerate() {
+                        *px = (left[y] as i32 + above[x] as i32 - top_left).clamp(0, 255) as u8;
+                    }
+                }
+            }
+            _ => {
+                let shift = N.trailing_zeros() - 1;
+                let mut sum = 0u32;
+                let mut shift_total = 0;
+                if mby > 0 {
+                    sum += above.iter().map(|&p| p as u32).sum::<u32>();
+                    shift_total += 1;
+                }
+                if mbx > 0 {
+                    sum += left.iter().map(|&p| p as u32).sum::<u32>();
+                    shift_total += 1;
+                }
+                let dc = if shift_total == 0 {
+                    128
+                } else {
+                    let shift = shift + shift_total;
+                    ((sum + (1 << (shift - 1))) >> shift) as u8
+                };
+                for row in out.iter_mut() {
+                    row.fill(dc);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Y, U and V planes padded to whole macroblocks
+#[derive(Clone)]
+struct Planes {
+    y: Plane,
+    u: Plane,
+    v: Plane,
+}
+
+impl Planes {
+    fn new(mb_cols: usize, mb_rows: usize) -> Self {
+        Self {
+            y: Plane::new(mb_cols * 16, mb_rows * 16),
+            u: Plane::new(mb_cols * 8, mb_rows * 8),
+            v: Plane::new(mb_cols * 8, mb_rows * 8),
+        }
+    }
+
+    fn macroblock_eq(&self, other: &Planes, mbx: usize, mby: usize) -> bool {
+        self.y.block::<16>(mbx * 16, mby * 16) == other.y.block::<16>(mbx * 16, mby * 16)
+            && self.u.block::<8>(mbx * 8, mby * 8) == other.u.block::<8>(mbx * 8, mby * 8)
+            && self.v.block::<8>(mbx * 8, mby * 8) == other.v.block::<8>(mbx * 8, mby * 8)
+    }
+
+    fn copy_macroblock(&mut self, from: &Planes, mbx: usize, mby: usize) {
+        self.y.put_block(mbx * 16, mby * 16, &from.y.block::<16>(mbx * 16, mby * 16));
+        self.u.put_block(mbx * 8, mby * 8, &from.u.block::<8>(mbx * 8, mby * 8));
+        self.v.put_block(mbx * 8, mby * 8, &from.v.block::<8>(mbx * 8, mby * 8));
+    }
+}
+
+/// Dequantization factors derived from a quantizer index (section 14.1)
+#[derive(Clone, Copy)]
+struct Quantizer {
+    y_ac: i32,
+    y2_dc: i32,
+    y2_ac: i32,
+    uv_dc: i32,
+    uv_ac: i32,
+}
+
+impl Quantizer {
+    fn new(qindex: u8) -> Self {
+        let q = qindex.min(127) as usize;
+        Self {
+            y_ac: AC_QUANT[q] as i32,
+            y2_dc: DC_QUANT[q] as i32 * 2,
+            y2_ac: (AC_QUANT[q] as i32 * 155 / 100).max(8),
+            uv_dc: (DC_QUANT[q] as i32).min(132),
+            uv_ac: AC_QUANT[q] as i32,
+        }
+    }
+}
+
+/// Quantize with rounding for DC terms and a small dead zone for AC terms
+fn quantize(coeff: i32, step: i32, is_dc: bool) -> i16 {
+    let rounding = if is_dc { step / 2 } else { step * 3 / 8 };
+    let level = ((coeff.abs() + rounding) / step).min(MAX_COEFF);
+    (if coeff < 0 { -level } else { level }) as i16
+}
+
+/// Forward 4x4 DCT, matching the libvpx reference implementation
+fn forward_dct(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let ip = &input[i * 4..i * 4 + 4];
+        let a1 = (ip[0] + ip[3]) * 8;
+        let b1 = (ip[1] + ip[2]) * 8;
+        let c1 = (ip[1] - ip[2]) * 8;
+        let d1 = (ip[0] - ip[3]) * 8;
+        tmp[i * 4] = a1 + b1;
+        tmp[i * 4 + 2] = a1 - b1;
+        tmp[i * 4 + 1] = (c1 * 2217 + d1 * 5352 + 14500) >> 12;
+        tmp[i * 4 + 3] = (d1 * 2217 - c1 * 5352 + 7500) >> 12;
+    }
+
+    let mut out = [0i32; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[12 + i];
+        let b1 = tmp[4 + i] + tmp[8 + i];
+        let c1 = tmp[4 + i] - tmp[8 + i];
+        let d1 = tmp[i] - tmp[12 + i];
+        out[i] = (a1 + b1 + 7) >> 4;
+        out[8 + i] = (a1 - b1 + 7) >> 4;
+        out[4 + i] = ((c1 * 2217 + d1 * 5352 + 12000) >> 16) + (d1 != 0) as i32;
+        out[12 + i] = (d1 * 2217 - c1 * 5352 + 51000) >> 16;
+    }
+    out
+}
+
+/// Inverse 4x4 DCT (section 14.3), bit-exact with the decoder
+fn inverse_dct(input: &[i32; 16]) -> [i32; 16] {
+    const COS: i32 = 20091;
+    const SIN: i32 = 35468;
+
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let a1 = input[i] + input[8 + i];
+        let b1 = input[i] - input[8 + i];
+        let c1 = ((input[4 + i] * SIN) >> 16) - (input[12 + i] + ((input[12 + i] * COS) >> 16));
+        let d1 = (input[4 + i] + ((input[4 + i] * COS) >> 16)) + ((input[12 + i] * SIN) >> 16);
+        tmp[i] = (a1 + d1) as i16 as i32;
+        tmp[12 + i] = (a1 - d1) as i16 as i32;
+        tmp[4 + i] = (b1 + c1) as i16 as i32;
+        tmp[8 + i] = (b1 - c1) as i16 as i32;
+    }
+
+    let mut out = [0i32; 16];
+    for i in 0..4 {
+        let ip = &tmp[i * 4..i * 4 + 4];
+        let a1 = ip[0] + ip[2];
+        let b1 = ip[0] - ip[2];
+        let c1 = ((ip[1] * SIN) >> 16) - (ip[3] + ((ip[3] * COS) >> 16));
+        let d1 = (ip[1] + ((ip[1] * COS) >> 16)) + ((ip[3] * SIN) >> 16);
+        out[i * 4] = (a1 + d1 + 4) >> 3;
+        out[i * 4 + 3] = (a1 - d1 + 4) >> 3;
+        out[i * 4 + 1] = (b1 + c1 + 4) >> 3;
+        out[i * 4 + 2] = (b1 - c1 + 4) >> 3;
+    }
+    out
+}
+
+/// Forward Walsh-Hadamard transform of the luma DC terms
+fn forward_wht(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let ip = &input[i * 4..i * 4 + 4];
+        let a1 = (ip[0] + ip[2]) * 4;
+        let d1 = (ip[1] + ip[3]) * 4;
+        let c1 = (ip[1] - ip[3]) * 4;
+        let b1 = (ip[0] - ip[2]) * 4;
+        tmp[i * 4] = a1 + d1 + (a1 != 0) as i32;
+        tmp[i * 4 + 1] = b1 + c1;
+        tmp[i * 4 + 2] = b1 - c1;
+        tmp[i * 4 + 3] = a1 - d1;
+    }
+
+    let mut out = [0i32; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[8 + i];
+        let d1 = tmp[4 + i] + tmp[12 + i];
+        let c1 = tmp[4 + i] - tmp[12 + i];
+        let b1 = tmp[i] - tmp[8 + i];
+        let mut a2 = a1 + d1;
+        let mut b2 = b1 + c1;
+        let mut c2 = b1 - c1;
+        let mut d2 = a1 - d1;
+        a2 += (a2 < 0) as i32;
+        b2 += (b2 < 0) as i32;
+        c2 += (c2 < 0) as i32;
+        d2 += (d2 < 0) as i32;
+        out[i] = (a2 + 3) >> 3;
+        out[4 + i] = (b2 + 3) >> 3;
+        out[8 + i] = (c2 + 3) >> 3;
+        out[12 + i] = (d2 + 3) >> 3;
+    }
+    out
+}
+
+/// Inverse Walsh-Hadamard transform (section 14.3), bit-exact with the decoder
+fn inverse_wht(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0i32; 16];
+    for i in 0..4 {
+        let a1 = input[i] + input[12 + i];
+        let b1 = input[4 + i] + input[8 + i];
+        let c1 = input[4 + i] - input[8 + i];
+        let d1 = input[i] - input[12 + i];
+        tmp[i] = (a1 + b1) as i16 as i32;
+        tmp[4 + i] = (c1 + d1) as i16 as i32;
+        tmp[8 + i] = (a1 - b1) as i16 as i32;
+        tmp[12 + i] = (d1 - c1) as i16 as i32;
+    }
+
+    let mut out = [0i32; 16];
+    for i in 0..4 {
+        let ip = &tmp[i * 4..i * 4 + 4];
+        let a1 = ip[0] + ip[3];
+        let b1 = ip[1] + ip[2];
+        let c1 = ip[1] - ip[2];
+        let d1 = ip[0] - ip[3];
+        out[i * 4] = (a1 + b1 + 3) >> 3;
+        out[i * 4 + 1] = (c1 + d1 + 3) >> 3;
+        out[i * 4 + 2] = (a1 - b1 + 3) >> 3;
+        out[i * 4 + 3] = (d1 - c1 + 3) >> 3;
+    }
+    out
+}
+
+fn sad<const N: usize>(a: &[[u8; N]; N], b: &[[u8; N]; N]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .flat_map(|(ra, rb)| ra.iter().zip(rb.iter()))
+        .map(|(&pa, &pb)| (pa as i32 - pb as i32).unsigned_abs())
+        .sum()
+}
+
+/// Residual 4x4 block at (`bx`, `by`) of an N x N source/prediction pair
+fn residual_block<const N: usize>(src: &[[u8; N]; N], pred: &[[u8; N]; N], bx: usize, by: usize) -> [i32; 16] {
+    let mut out = [0i32; 16];
+    for y in 0..4 {
+        for x in 0..4 {
+            out[y * 4 + x] = src[by * 4 + y][bx * 4 + x] as i32 - pred[by * 4 + y][bx * 4 + x] as i32;
+        }
+    }
+    out
+}
+
+/// Add an inverse transformed residual onto the prediction in place
+fn add_residual<const N: usize>(recon: &mut [[u8; N]; N], residual: &[i32; 16], bx: usize, by: usize) {
+    for y in 0..4 {
+        for x in 0..4 {
+            let px = &mut recon[by * 4 + y][bx * 4 + x];
+            *px = (*px as i32 + residual[y * 4 + x]).clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Quantized coefficients of one macroblock in natural (raster) order
+struct MacroblockCoeffs {
+    y2: [i16; 16],
+    y: [[i16; 16]; 16],
+    u: [[i16; 16]; 4],
+    v: [[i16; 16]; 4],
+}
+
+impl MacroblockCoeffs {
+    fn is_empty(&self) -> bool {
+        self.y2.iter()
+            .chain(self.y.iter().flatten())
+            .chain(self.u.iter().flatten())
+            .chain(self.v.iter().flatten())
+            .all(|&c| c == 0)
+    }
+}
+
+/// Transform, quantize and reconstruct a 16x16 luma block
+fn encode_luma(src: &[[u8; 16]; 16], pred: &[[u8; 16]; 16], q: &Quantizer) -> ([i16; 16], [[i16; 16]; 16], [[u8; 16]; 16]) {
+    let mut dct = [[0i32; 16]; 16];
+    let mut dc = [0i32; 16];
+    for b in 0..16 {
+        dct[b] = forward_dct(&residual_block(src, pred, b % 4, b / 4));
+        dc[b] = dct[b][0];
+    }
+
+    let wht = forward_wht(&dc);
+    let mut y2 = [0i16; 16];
+    let mut y2_dequant = [0i32; 16];
+    for i in 0..16 {
+        let step = if i == 0 { q.y2_dc } else { q.y2_ac };
+        y2[i] = quantize(wht[i], step, true);
+        y2_dequant[i] = y2[i] as i32 * step;
+    }
+    let dc_recon = inverse_wht(&y2_dequant);
+
+    let mut y = [[0i16; 16]; 16];
+    let mut recon = *pred;
+    for b in 0..16 {
+        let mut dequant = [0i32; 16];
+        dequant[0] = dc_recon[b];
+        for i in 1..16 {
+            y[b][i] = quantize(dct[b][i], q.y_ac, false);
+            dequant[i] = y[b][i] as i32 * q.y_ac;
+        }
+        add_residual(&mut recon, &inverse_dct(&dequant), b % 4, b / 4);
+    }
+
+    (y2, y, recon)
+}
+
+/// Transform, quantize and reconstruct an 8x8 chroma block
+fn encode_chroma(src: &[[u8; 8]; 8], pred: &[[u8; 8]; 8], q: &Quantizer) -> ([[i16; 16]; 4], [[u8; 8]; 8]) {
+    let mut coeffs = [[0i16; 16]; 4];
+    let mut recon = *pred;
+    for b in 0..4 {
+        let dct = forward_dct(&residual_block(src, pred, b % 2, b / 2));
+        let mut dequant = [0i32; 16];
+        for i in 0..16 {
+            let step = if i == 0 { q.uv_dc } else { q.uv_ac };
+            coeffs[b][i] = quantize(dct[i], step, i == 0);
+            dequant[i] = coeffs[b][i] as i32 * step;
+        }
+        add_residual(&mut recon, &inverse_dct(&dequant), b % 2, b / 2);
+    }
+    (coeffs, recon)
+}
+
+/// Write one block's tokens (section 13); returns whether any coefficient was coded
+fn write_block_tokens(bw: &mut BoolEncoder, probs: &[[[u8; 11]; 3]; 8], coeffs: &[i16; 16], first: usize, ctx: usize) -> bool {
+    let last = (first..16).rev().find(|&i| coeffs[ZIGZAG[i]] != 0);
+    let last = match last {
+        Some(last) => last,
+        None => {
+            bw.put(false, probs[COEFF_BANDS[first]][ctx][0]);
+            return false;
+        }
+    };
+
+    let mut ctx = ctx;
+    let mut after_zero = false;
+    for i in first..=last {
+        let p = &probs[COEFF_BANDS[i]][ctx];
+        let value = coeffs[ZIGZAG[i]] as i32;
+        let abs = value.abs();
+
+        // EOB cannot directly follow a zero token
+        if !after_zero {
+            bw.put(true, p[0]);
+        }
+
+        if abs == 0 {
+            bw.put(false, p[1]);
+            ctx = 0;
+            after_zero = true;
+            continue;
+        }
+        bw.put(true, p[1]);
+
+        if abs == 1 {
+            bw.put(false, p[2]);
+            ctx = 1;
+        } else {
+            bw.put(true, p[2]);
+            if abs <= 4 {
+                bw.put(false, p[3]);
+                if abs == 2 {
+                    bw.put(false, p[4]);
+                } else {
+                    bw.put(true, p[4]);
+                    bw.put(abs == 4, p[5]);
+                }
+            } else {
+                bw.put(true, p[3]);
+                let cat = match abs {
+                    5..=6 => 0,
+                    7..=10 => 1,
+                    11..=18 => 2,
+                    19..=34 => 3,
+                    35..=66 => 4,
+                    _ => 5,
+                };
+                if cat < 2 {
+                    bw.put(false, p[6]);
+                    bw.put(cat == 1, p[7]);
+                } else {
+                    bw.put(true, p[6]);
+                    if cat < 4 {
+                        bw.put(false, p[8]);
+                        bw.put(cat == 3, p[9]);
+                    } else {
+                        bw.put(true, p[8]);
+                        bw.put(cat == 5, p[10]);
+                    }
+                }
+                let extra = abs - DCT_CAT_BASE[cat];
+                let cat_probs = DCT_CAT_PROBS[cat];
+                for (bit, &prob) in cat_probs.iter().enumerate() {
+                    let shift = cat_probs.len() - 1 - bit;
+                    bw.put((extra >> shift) & 1 == 1, prob);
+                }
+            }
+            ctx = 2;
+        }
+
+        bw.put_flag(value < 0);
+        after_zero = false;
+    }
+
+    if last < 15 {
+        bw.put(false, probs[COEFF_BANDS[last + 1]][ctx][0]);
+    }
+    true
+}
+
+/// Per-macroblock coding decisions needed for the first partition
+#[derive(Clone, Copy, Default)]
+struct MacroblockMode {
+    inter: bool,
+    y_mode: u8,
+    uv_mode: u8,
+    skip: bool,
+}
+
+/// Software VP8 encoder with simple bitrate control
+pub struct Vp8Encoder {
+    config: Vp8Config,
+    mb_cols: usize,
+    mb_rows: usize,
+    quantizer: u8,
+    reference: Option<Planes>,
+    previous_source: Option<Planes>,
+    frame_count: u64,
+}
+
+impl Vp8Encoder {
+    pub fn new(config: Vp8Config) -> Result<Self, Vp8EncoderError> {
+        if config.width == 0 || config.height == 0 || config.width > 0x3fff || config.height > 0x3fff {
+            return Err(Vp8EncoderError::InvalidDimensions(config.width, config.height));
+        }
+
+        let mb_cols = (config.width as usize + 15) / 16;
+        let mb_rows = (config.height as usize + 15) / 16;
+        let quantizer = config.initial_quantizer.clamp(config.min_quantizer, config.max_quantizer.min(127));
+
+        info!("🎞️ VP8 encoder ready: {}x{} ({}x{} macroblocks), {}kbps @ {}fps, q={}",
+              config.width, config.height, mb_cols, mb_rows, config.bitrate, config.framerate, quantizer);
+
+        Ok(Self {
+            config,
+            mb_cols,
+            mb_rows,
+            quantizer,
+            reference: None,
+            previous_source: None,
+            frame_count: 0,
+        })
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.config.width, self.config.height)
+    }
+
+    pub fn quantizer(&self) -> u8 {
+        self.quantizer
+    }
+
+    /// Change the target bitrate; the quantizer adapts over the next frames
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32, framerate: u32) {
+        self.config.bitrate = bitrate_kbps.max(1);
+        self.config.framerate = framerate.max(1);
+    }
+
+    /// Encode one frame. The first frame is always a key frame.
+    pub fn encode(&mut self, frame: &YUV420Frame, force_keyframe: bool) -> Result<EncodedVp8Frame, Vp8EncoderError> {
+        let source = self.source_planes(frame)?;
+        let is_keyframe = force_keyframe || self.reference.is_none();
+        let quantizer = self.quantizer;
+        let q = Quantizer::new(quantizer);
+
+        let mut recon = Planes::new(self.mb_cols, self.mb_rows);
+        let mut modes = vec![MacroblockMode::default(); self.mb_cols * self.mb_rows];
+        let mut tokens = BoolEncoder::new();
+        let mut above_ctx = vec![[0u8; 9]; self.mb_cols];
+
+        for mby in 0..self.mb_rows {
+            let mut left_ctx = [0u8; 9];
+            for mbx in 0..self.mb_cols {
+                let reference = if is_keyframe { None } else { self.reference.as_ref() };
+
+                // Untouched screen areas reuse the reference without re-coding
+                // the residual, so quantization noise does not keep costing bits
+                if let (Some(reference), Some(previous)) = (reference, self.previous_source.as_ref()) {
+                    if previous.macroblock_eq(&source, mbx, mby) {
+                        recon.copy_macroblock(reference, mbx, mby);
+                        above_ctx[mbx] = [0; 9];
+                        left_ctx = [0; 9];
+                        modes[mby * self.mb_cols + mbx] = MacroblockMode { inter: true, skip: true, ..Default::default() };
+                        continue;
+                    }
+                }
+
+                let (mode, coeffs) = Self::encode_macroblock(&source, reference, &mut recon, mbx, mby, &q);
+                let mode = MacroblockMode { skip: coeffs.is_empty(), ..mode };
+
+                if mode.skip {
+                    above_ctx[mbx] = [0; 9];
+                    left_ctx = [0; 9];
+                } else {
+                    Self::write_macroblock_tokens(&mut tokens, &coeffs, &mut above_ctx[mbx], &mut left_ctx);
+                }
+                modes[mby * self.mb_cols + mbx] = mode;
+            }
+        }
+
+        let first_partition = self.write_first_partition(&modes, is_keyframe, quantizer);
+        if first_partition.len() > MAX_FIRST_PARTITION_SIZE {
+            return Err(Vp8EncoderError::PartitionOverflow(first_partition.len()));
+        }
+        let token_partition = tokens.finish();
+
+        let mut data = Vec::with_capacity(10 + first_partition.len() + token_partition.len());
+        let tag = (!is_keyframe as u32) | (1 << 4) | ((first_partition.len() as u32) << 5);
+        data.extend_from_slice(&tag.to_le_bytes()[..3]);
+        if is_keyframe {
+            data.extend_from_slice(&[0x9d, 0x01, 0x2a]);
+            data.extend_from_slice(&(self.config.width as u16).to_le_bytes());
+            data.extend_from_slice(&(self.config.height as u16).to_le_bytes());
+        }
+        data.extend_from_slice(&first_partition);
+        data.extend_from_slice(&token_partition);
+
+        self.reference = Some(recon);
+        self.previous_source = Some(source);
+        self.frame_count += 1;
+        self.update_rate_control(data.len(), is_keyframe);
+
+        debug!("🎞️ VP8 frame {}: {} bytes, keyframe={}, q={}",
+               self.frame_count, data.len(), is_keyframe, quantizer);
+
+        Ok(EncodedVp8Frame { data, is_keyframe, quantizer })
+    }
+
+    /// Validate the frame and pad its planes to whole macroblocks
+    fn source_planes(&self, frame: &YUV420Frame) -> Result<Planes, Vp8EncoderError> {
+        if frame.width != self.config.width || frame.height != self.config.height {
+            return Err(Vp8EncoderError::FrameMismatch(format!(
+                "frame is {}x{}, encoder expects {}x{}",
+                frame.width, frame.height, self.config.width, self.config.height
+            )));
+        }
+
+        let width = frame.width as usize;
+        let height = frame.height as usize;
+        let chroma_width = (width + 1) / 2;
+        let chroma_height = (height + 1) / 2;
+        if frame.y_plane.len() < width * height
+            || frame.u_plane.len() < chroma_width * chroma_height
+            || frame.v_plane.len() < chroma_width * chroma_height {
+            return Err(Vp8EncoderError::FrameMismatch("plane sizes do not match frame dimensions".to_string()));
+        }
+
+        Ok(Planes {
+            y: Plane::from_padded(&frame.y_plane, width, height, self.mb_cols * 16, self.mb_rows * 16),
+            u: Plane::from_padded(&frame.u_plane, chroma_width, chroma_height, self.mb_cols * 8, self.mb_rows * 8),
+            v: Plane::from_padded(&frame.v_plane, chroma_width, chroma_height, self.mb_cols * 8, self.mb_rows * 8),
+        })
+    }
+
+    /// Choose prediction modes, quantize the residual and write the reconstruction
+    fn encode_macroblock(
+        source: &Planes,
+        reference: Option<&Planes>,
+        recon: &mut Planes,
+        mbx: usize,
+        mby: usize,
+        q: &Quantizer,
+    ) -> (MacroblockMode, MacroblockCoeffs) {
+        let src_y: [[u8; 16]; 16] = source.y.block(mbx * 16, mby * 16);
+        let src_u: [[u8; 8]; 8] = source.u.block(mbx * 8, mby * 8);
+        let src_v: [[u8; 8]; 8] = source.v.block(mbx * 8, mby * 8);
+
+        // Only use modes whose edge pixels lie inside the frame
+        let mut candidates = vec![DC_PRED];
+        if mby > 0 {
+            candidates.push(V_PRED);
+        }
+        if mbx > 0 {
+            candidates.push(H_PRED);
+        }
+        if mbx > 0 && mby > 0 {
+            candidates.push(TM_PRED);
+        }
+
+        let (y_mode, intra_y, intra_y_sad) = candidates.iter()
+            .map(|&mode| {
+                let pred: [[u8; 16]; 16] = recon.y.intra_predict(mbx, mby, mode);
+                let cost = sad(&src_y, &pred);
+                (mode, pred, cost)
+            })
+            .min_by_key(|&(_, _, cost)| cost)
+            .unwrap();
+
+        let inter_pred = reference.map(|r| {
+            let y: [[u8; 16]; 16] = r.y.block(mbx * 16, mby * 16);
+            let u: [[u8; 8]; 8] = r.u.block(mbx * 8, mby * 8);
+            let v: [[u8; 8]; 8] = r.v.block(mbx * 8, mby * 8);
+            (y, u, v)
+        });
+
+        // Prefer the previous frame unless intra prediction is clearly better,
+        // since inter macroblocks are cheaper to signal
+        let use_inter = match &inter_pred {
+            Some((y, _, _)) => sad(&src_y, y) <= intra_y_sad + 256,
+            None => false,
+        };
+
+        let (pred_y, pred_u, pred_v, uv_mode) = if use_inter {
+            let (y, u, v) = inter_pred.unwrap();
+            (y, u, v, DC_PRED)
+        } else {
+            let (uv_mode, pred_u, pred_v, _) = candidates.iter()
+                .map(|&mode| {
+                    let u: [[u8; 8]; 8] = recon.u.intra_predict(mbx, mby, mode);
+                    let v: [[u8; 8]; 8] = recon.v.intra_predict(mbx, mby, mode);
+                    let cost = sad(&src_u, &u) + sad(&src_v, &v);
+                    (mode, u, v, cost)
+                })
+                .min_by_key(|&(_, _, _, cost)| cost)
+                .unwrap();
+            (intra_y, pred_u, pred_v, uv_mode)
+        };
+
+        let (y2, y, recon_y) = encode_luma(&src_y, &pred_y, q);
+        let (u, recon_u) = encode_chroma(&src_u, &pred_u, q);
+        let (v, recon_v) = encode_chroma(&src_v, &pred_v, q);
+
+        recon.y.put_block(mbx * 16, mby * 16, &recon_y);
+        recon.u.put_block(mbx * 8, mby * 8, &recon_u);
+        recon.v.put_block(mbx * 8, mby * 8, &recon_v);
+
+        let mode = MacroblockMode {
+            inter: use_inter,
+            y_mode: if use_inter { DC_PRED } else { y_mode },
+            uv_mode,
+            skip: false,
+        };
+        (mode, MacroblockCoeffs { y2, y, u, v })
+    }
+
+    fn write_macroblock_tokens(bw: &mut BoolEncoder, coeffs: &MacroblockCoeffs, above: &mut [u8; 9], left: &mut [u8; 9]) {
+        let ctx = (above[CTX_Y2] + left[CTX_Y2]) as usize;
+        let nz = write_block_tokens(bw, &COEFF_PROBS[PLANE_Y2], &coeffs.y2, 0, ctx) as u8;
+        above[CTX_Y2] = nz;
+        left[CTX_Y2] = nz;
+
+        for (b, block) in coeffs.y.iter().enumerate() {
+            let (bx, by) = (b % 4, b / 4);
+            let ctx = (above[bx] + left[by]) as usize;
+            let nz = write_block_tokens(bw, &COEFF_PROBS[PLANE_Y_AFTER_Y2], block, 1, ctx) as u8;
+            above[bx] = nz;
+            left[by] = nz;
+        }
+
+        for (offset, blocks) in [(CTX_U, &coeffs.u), (CTX_V, &coeffs.v)] {
+            for (b, block) in blocks.iter().enumerate() {
+                let (bx, by) = (offset + b % 2, offset + b / 2);
+                let ctx = (above[bx] + left[by]) as usize;
+                let nz = write_block_tokens(bw, &COEFF_PROBS[PLANE_CHROMA], block, 0, ctx) as u8;
+                above[bx] = nz;
+                left[by] = nz;
+            }
+        }
+    }
+
+    /// Frame header and per-macroblock modes (sections 9 and 19.2)
+    fn write_first_partition(&self, modes: &[MacroblockMode], is_keyframe: bool, quantizer: u8) -> Vec<u8> {
+        let mut bw = BoolEncoder::new();
+        let total = modes.len().max(1);
+        let coded = modes.iter().filter(|m| !m.skip).count();
+        let intra = modes.iter().filter(|m| !m.inter).count();
+        let prob_skip_false = (coded * 256 / total).clamp(1, 255) as u32;
+        let prob_intra = (intra * 256 / total).clamp(1, 255) as u32;
+
+        if is_keyframe {
+            bw.put_literal(0, 1); // color space: YUV (BT.601)
+            bw.put_literal(0, 1); // clamping required
+        }
+        bw.put_flag(false);       // segmentation disabled
+        bw.put_literal(0, 1);     // normal loop filter type
+        bw.put_literal(0, 6);     // loop filter level 0 (disabled)
+        bw.put_literal(0, 3);     // sharpness
+        bw.put_flag(false);       // no loop filter adjustments
+        bw.put_literal(0, 2);     // single token partition
+        bw.put_literal(quantizer as u32, 7);
+        for _ in 0..5 {
+            bw.put_flag(false);   // no quantizer deltas
+        }
+
+        if is_keyframe {
+            bw.put_flag(true);    // refresh entropy probabilities
+        } else {
+            bw.put_flag(false);   // refresh golden frame
+            bw.put_flag(false);   // refresh alternate frame
+            bw.put_literal(0, 2); // copy buffer to golden
+            bw.put_literal(0, 2); // copy buffer to alternate
+            bw.put_flag(false);   // golden sign bias
+            bw.put_flag(false);   // alternate sign bias
+            bw.put_flag(true);    // refresh entropy probabilities
+            bw.put_flag(true);    // refresh last frame
+        }
+
+        // Keep the default token probabilities
+        for plane in COEFF_UPDATE_PROBS.iter() {
+            for band in plane.iter() {
+                for ctx in band.iter() {
+                    for &prob in ctx.iter() {
+                        bw.put(false, prob);
+                    }
+                }
+            }
+        }
+
+        bw.put_flag(true);        // macroblock skip flags present
+        bw.put_literal(prob_skip_false, 8);
+
+        if !is_keyframe {
+            bw.put_literal(prob_intra, 8);
+            bw.put_literal(255, 8); // prob_last: always the last frame
+            bw.put_literal(128, 8); // prob_gf
+            bw.put_flag(false);     // keep luma mode probabilities
+            bw.put_flag(false);     // keep chroma mode probabilities
+            for component in MV_UPDATE_PROBS.iter() {
+                for &prob in component.iter() {
+                    bw.put(false, prob);
+                }
+            }
+        }
+
+        for (i, mode) in modes.iter().enumerate() {
+            let (mbx, mby) = (i % self.mb_cols, i / self.mb_cols);
+            bw.put(mode.skip, prob_skip_false as u8);
+
+            if is_keyframe {
+                Self::write_kf_y_mode(&mut bw, mode.y_mode);
+                Self::write_uv_mode(&mut bw, mode.uv_mode, &KF_UV_MODE_PROBS);
+                continue;
+            }
+
+            bw.put(mode.inter, prob_intra as u8);
+            if mode.inter {
+                // Neighbouring inter macroblocks all carry zero motion vectors,
+                // so only the zero-MV count selects the mode context (section 16.3)
+                let is_inter = |x: Option<usize>, y: Option<usize>| match (x, y) {
+                    (Some(x), Some(y)) => modes[y * self.mb_cols + x].inter,
+                    _ => false,
+                };
+                let left = mbx.checked_sub(1);
+                let up = mby.checked_sub(1);
+                let zero_count = 2 * is_inter(Some(mbx), up) as usize
+                    + 2 * is_inter(left, Some(mby)) as usize
+                    + is_inter(left, up) as usize;
+
+                bw.put(false, 255);                             // reference: last frame
+                bw.put(false, MODE_CONTEXTS[zero_count][0]);    // ZEROMV
+            } else {
+                Self::write_y_mode(&mut bw, mode.y_mode);
+                Self::write_uv_mode(&mut bw, mode.uv_mode, &UV_MODE_PROBS);
+            }
+        }
+
+        bw.finish()
+    }
+
+    fn write_kf_y_mode(bw: &mut BoolEncoder, mode: u8) {
+        let p = &KF_YMODE_PROBS;
+        bw.put(true, p[0]); // not B_PRED
+        match mode {
+            DC_PRED | V_PRED => {
+                bw.put(false, p[1]);
+                bw.put(mode == V_PRED, p[2]);
+            }
+            _ => {
+                bw.put(true, p[1]);
+                bw.put(mode == TM_PRED, p[3]);
+            }
+        }
+    }
+
+    fn write_y_mode(bw: &mut BoolEncoder, mode: u8) {
+        let p = &YMODE_PROBS;
+        if mode == DC_PRED {
+            bw.put(false, p[0]);
+            return;
+        }
+        bw.put(true, p[0]);
+        match mode {
+            V_PRED | H_PRED => {
+                bw.put(false, p[1]);
+                bw.put(mode == H_PRED, p[2]);
+            }
+            _ => {
+                bw.put(true, p[1]);
+                bw.put(false, p[3]); // TM_PRED rather than B_PRED
+            }
+        }
+    }
+
+    fn write_uv_mode(bw: &mut BoolEncoder, mode: u8, p: &[u8; 3]) {
+        if mode == DC_PRED {
+            bw.put(false, p[0]);
+            return;
+        }
+        bw.put(true, p[0]);
+        if mode == V_PRED {
+            bw.put(false, p[1]);
+            return;
+        }
+        bw.put(true, p[1]);
+        bw.put(mode == TM_PRED, p[2]);
+    }
+
+    /// Nudge the quantizer towards the per-frame byte budget
+    fn update_rate_control(&mut self, frame_bytes: usize, is_keyframe: bool) {
+        let budget = (self.config.bitrate as usize * 1000 / 8 / self.config.framerate.max(1) as usize).max(1);
+        let max_q = self.config.max_quantizer.min(127) as i32;
+        let min_q = self.config.min_quantizer.min(max_q as u8) as i32;
+        let q = self.quantizer as i32;
+
+        // Key frames are expected to be several times larger than the budget
+        let budget = if is_keyframe { budget * 8 } else { budget };
+
+        let step = if frame_bytes > budget * 2 {
+            4
+        } else if frame_bytes > budget + budget / 4 {
+            1
+        } else if frame_bytes < budget / 4 {
+            -2
+        } else if frame_bytes < budget * 3 / 4 {
+            -1
+        } else {
+            0
+        };
+
+        self.quantizer = (q + step).clamp(min_q, max_q) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::vp8_decoder::{DecodedFrame, Vp8Decoder};
+    use std::io::Cursor;
+
+    fn test_frame(width: u32, height: u32, offset: u32) -> YUV420Frame {
+        let (w, h) = (width as usize, height as usize);
+        let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+        YUV420Frame {
+            y_plane: (0..w * h).map(|i| (((i % w) as u32 * 3 + (i / w) as u32 * 2 + offset) % 160 + 40) as u8).collect(),
+            u_plane: (0..cw * ch).map(|i| (120 + (i % cw) % 16) as u8).collect(),
+            v_plane: (0..cw * ch).map(|i| (136 - (i / cw) % 16) as u8).collect(),
+            width,
+            height,
+            frame_number: 0,
+            timestamp: 0,
+            is_keyframe: false,
+        }
+    }
+
+    /// Encoder held at one quantizer, wherever rate control would take it
+    fn fixed_quantizer_encoder(width: u32, height: u32, quantizer: u8) -> Vp8Encoder {
+        Vp8Encoder::new(Vp8Config {
+            width,
+            height,
+            min_quantizer: quantizer,
+            max_quantizer: quantizer,
+            initial_quantizer: quantizer,
+            ..Vp8Config::default()
+        }).unwrap()
+    }
+
+    /// The visible `width` x `height` part of a plane `stride` pixels wide
+    fn crop(plane: &[u8], stride: usize, width: usize, height: usize) -> Vec<u8> {
+        (0..height).flat_map(|y| &plane[y * stride..][..width]).copied().collect()
+    }
+
+    /// The decoder must end up with exactly the picture the encoder predicts the next frame from
+    fn assert_matches_reconstruction(encoder: &Vp8Encoder, decoded: &DecodedFrame) {
+        let reference = encoder.reference.as_ref().unwrap();
+        let (width, height) = (decoded.width, decoded.height);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let planes = [
+            ("Y", &decoded.y, &reference.y, width, height),
+            ("U", &decoded.u, &reference.u, chroma_width, chroma_height),
+            ("V", &decoded.v, &reference.v, chroma_width, chroma_height),
+        ];
+        for (name, decoded, reference, width, height) in planes {
+            let expected = crop(&reference.data, reference.stride, width, height);
+            assert!(*decoded == expected, "{} differs at q={}", name, encoder.quantizer);
+        }
+    }
+
+    /// `frame` with a flat window drawn over part of it
+    fn with_window(mut frame: YUV420Frame) -> YUV420Frame {
+        let width = frame.width as usize;
+        for y in 16..64 {
+            frame.y_plane[y * width + 16..y * width + 80].fill(200);
+        }
+        let chroma_width = width.div_ceil(2);
+        for y in 8..32 {
+            frame.u_plane[y * chroma_width + 8..y * chroma_width + 40].fill(90);
+        }
+        frame
+    }
+
+    #[test]
+    fn keyframes_decode_identically_at_every_quantizer() {
+        let frame = test_frame(100, 70, 0);
+        for quantizer in [0, 4, 40, 80, 127] {
+            let mut encoder = fixed_quantizer_encoder(100, 70, quantizer);
+            let encoded = encoder.encode(&frame, false).unwrap();
+            assert!(encoded.is_keyframe);
+            assert_eq!(&encoded.data[3..6], &[0x9d, 0x01, 0x2a]);
+
+            // image-webp is an independent key frame decoder
+            let independent = image_webp::vp8::Vp8Decoder::decode_frame(Cursor::new(&encoded.data))
+                .expect("key frame should decode");
+            let decoded = Vp8Decoder::new().decode(&encoded.data);
+            assert_eq!((independent.width, independent.height), (100, 70));
+            // It keeps whole macroblocks
+            let (stride, chroma_stride) = (112, 56);
+            assert!(
+                crop(&independent.ybuf, stride, 100, 70) == decoded.y
+                    && crop(&independent.ubuf, chroma_stride, 50, 35) == decoded.u
+                    && crop(&independent.vbuf, chroma_stride, 50, 35) == decoded.v,
+                "decoders disagree at q={}", quantizer
+            );
+            assert_matches_reconstruction(&encoder, &decoded);
+
+            if quantizer == 4 {
+                let total_error: u64 = decoded.y.iter().zip(&frame.y_plane)
+                    .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64)
+                    .sum();
+                let mean_error = total_error as f64 / (100.0 * 70.0);
+                assert!(mean_error < 2.0, "mean luma error too high: {}", mean_error);
+            }
+        }
+    }
+
+    #[test]
+    fn inter_frames_decode_to_the_encoder_reconstruction() {
+        let background = test_frame(100, 70, 0);
+        let scrolled = test_frame(100, 70, 6);
+        let window = with_window(scrolled.clone());
+        // Static, changed everywhere, partly intra, unchanged, forced key frame, then back
+        let sequence = [&background, &background, &scrolled, &window, &window, &window, &background];
+
+        for quantizer in [4, 40, 80, 127] {
+            let mut encoder = fixed_quantizer_encoder(100, 70, quantizer);
+            let mut decoder = Vp8Decoder::new();
+            for (i, frame) in sequence.iter().enumerate() {
+                let encoded = encoder.encode(frame, i == 5).unwrap();
+                assert_eq!(encoded.is_keyframe, i == 0 || i == 5);
+
+                let decoded = decoder.decode(&encoded.data);
+                assert_matches_reconstruction(&encoder, &decoded);
+                if i == 3 {
+                    // The window is predicted from itself, the rest from the previous frame
+                    assert!(decoded.intra_macroblocks > 0 && decoded.intra_macroblocks < 35, "q={}", quantizer);
+                }
+            }
+        }
+    }
+
+    /// The encoder's reference picture as packed I420
+    fn reconstruction(encoder: &Vp8Encoder) -> Vec<u8> {
+        let reference = encoder.reference.as_ref().unwrap();
+        let (width, height) = (encoder.config.width as usize, encoder.config.height as usize);
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let mut picture = crop(&reference.y.data, reference.y.stride, width, height);
+        picture.extend(crop(&reference.u.data, reference.u.stride, chroma_width, chroma_height));
+        picture.extend(crop(&reference.v.data, reference.v.stride, chroma_width, chroma_height));
+        picture
+    }
+
+    /// Wrap VP8 frames in an IVF file, the container ffmpeg reads raw VP8 from
+    fn ivf(width: u32, height: u32, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"DKIF".to_vec();
+        file.extend(0u16.to_le_bytes());
+        file.extend(32u16.to_le_bytes());
+        file.extend(b"VP80");
+        file.extend((width as u16).to_le_bytes());
+        file.extend((height as u16).to_le_bytes());
+        file.extend(30u32.to_le_bytes());
+        file.extend(1u32.to_le_bytes());
+        file.extend((frames.len() as u32).to_le_bytes());
+        file.extend(0u32.to_le_bytes());
+        for (pts, frame) in frames.iter().enumerate() {
+            file.extend((frame.len() as u32).to_le_bytes());
+            file.extend((pts as u64).to_le_bytes());
+            file.extend(frame);
+        }
+        file
+    }
+
+    /// Decode an IVF stream to packed I420 frames with ffmpeg's own VP8 decoder
+    fn ffmpeg_decode(ivf: Vec<u8>) -> Vec<u8> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let mut ffmpeg = Command::new("ffmpeg")
+            .args(["-v", "error", "-f", "ivf", "-i", "pipe:0", "-vsync", "passthrough"])
+            .args(["-f", "rawvideo", "-pix_fmt", "yuv420p", "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("ffmpeg should be on PATH");
+        let mut stdin = ffmpeg.stdin.take().unwrap();
+        let writer = std::thread::spawn(move || stdin.write_all(&ivf));
+        let output = ffmpeg.wait_with_output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success(), "ffmpeg failed: {:?}", output.status);
+        output.stdout
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg on PATH"]
+    fn inter_frames_decode_the_same_in_ffmpeg() {
+        let background = test_frame(100, 70, 0);
+        let scrolled = test_frame(100, 70, 6);
+        let window = with_window(scrolled.clone());
+        let sequence = [&background, &background, &scrolled, &window, &window, &window, &background];
+
+        for quantizer in [4, 40, 127] {
+            let mut encoder = fixed_quantizer_encoder(100, 70, quantizer);
+            let mut frames = Vec::new();
+            let mut expected = Vec::new();
+            for (i, frame) in sequence.iter().enumerate() {
+                frames.push(encoder.encode(frame, i == 5).unwrap().data);
+                expected.push(reconstruction(&encoder));
+            }
+
+            let decoded = ffmpeg_decode(ivf(100, 70, &frames));
+            assert_eq!(decoded.len(), expected.len() * expected[0].len(), "q={}", quantizer);
+            for (i, (decoded, expected)) in decoded.chunks(expected[0].len()).zip(&expected).enumerate() {
+                assert!(decoded == &expected[..], "frame {} differs at q={}", i, quantizer);
+            }
+        }
+    }
+
+    #[test]
+    fn rate_controlled_stream_decodes_frame_by_frame() {
+        let mut encoder = Vp8Encoder::new(Vp8Config {
+            width: 100,
+            height: 70,
+            bitrate: 40,
+            ..Vp8Config::default()
+        }).unwrap();
+        let mut decoder = Vp8Decoder::new();
+
+        let mut quantizers = Vec::new();
+        for i in 0..30 {
+            let frame = if i % 4 == 3 { with_window(test_frame(100, 70, i)) } else { test_frame(100, 70, i) };
+            let encoded = encoder.encode(&frame, false).unwrap();
+            quantizers.push(encoded.quantizer);
+            assert_matches_reconstruction(&encoder, &decoder.decode(&encoded.data));
+        }
+        quantizers.dedup();
+        assert!(quantizers.len() > 3, "rate control never moved the quantizer: {:?}", quantizers);
+    }
+
+    #[test]
+    fn unchanged_frame_is_all_skipped() {
+        let frame = test_frame(64, 48, 0);
+        let mut encoder = Vp8Encoder::new(Vp8Config {
+            width: 64,
+            height: 48,
+            ..Vp8Config::default()
+        }).unwrap();
+
+        let key = encoder.encode(&frame, false).unwrap();
+        let first_inter = encoder.encode(&frame, false).unwrap();
+        let second_inter = encoder.encode(&frame, false).unwrap();
+
+        assert!(!first_inter.is_keyframe);
+        assert_eq!(first_inter.data[0] & 1, 1);
+        assert!(first_inter.data.len() < key.data.len() / 10);
+        assert_eq!(first_inter.data.len(), second_inter.data.len());
+    }
+
+    #[test]
+    fn rejects_mismatched_frames() {
+        let mut encoder = Vp8Encoder::new(Vp8Config {
+            width: 64,
+            height: 48,
+            ..Vp8Config::default()
+        }).unwrap();
+        assert!(encoder.encode(&test_frame(32, 32, 0), false).is_err());
+    }
+}
@@ -2,15 +2,18 @@ use anyhow::{Result, Context};
 use thiserror::Error;
 use log::{debug, error, info, warn};
 use std::time::{Duration, Instant};
-use std::sync::atomic::{AtomicU64, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU32, Ordering};
 use std::sync::Arc;
 use parking_lot::{Mutex, RwLock};
-use xcap::Monitor;
+use crate::core::capture::{open_capture_source, CaptureSource};
 use rayon::prelude::*;
 use image::{ImageBuffer, Rgba, DynamicImage};
 
-// For now, we'll create a simplified encoder that works with existing infrastructure
-// Future versions can add VP8/WebM support when dependencies are resolved
+use super::vp8_encoder::{Vp8Config, Vp8Encoder};
+use crate::streaming::frame::{FrameCodec, FrameHeader};
+
+// Frames are either VP8-compressed by the software encoder or sent as raw planes.
+// WebM/Opus muxing can be added on top once those dependencies are resolved
 
 /// Enhanced YUV420 video encoder errors
 #[derive(Error, Debug)]
@@ -29,6 +32,33 @@ pub enum YUV420EncoderError {
     Config(String),
 }
 
+/// Output codec of the YUV420 encoder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// Raw Y/U/V planes behind a "YUV" header
+    RawYuv,
+    /// VP8 bitstream behind a "VP8" header
+    Vp8,
+}
+
+impl VideoCodec {
+    /// Parse the `codec` query parameter; unknown names yield `None`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "vp8" => Some(VideoCodec::Vp8),
+            "yuv" | "yuv420" | "raw" => Some(VideoCodec::RawYuv),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoCodec::RawYuv => "yuv420",
+            VideoCodec::Vp8 => "vp8",
+        }
+    }
+}
+
 /// YUV420 encoder configuration with WebM support
 #[derive(Clone, Debug)]
 pub struct YUV420Config {
@@ -44,6 +74,7 @@ pub struct YUV420Config {
     pub opus_bitrate: u32,    // Audio bitrate in bps
     pub temporal_layers: u8,   // Number of temporal layers (1-4)
     pub spatial_layers: u8,    // Number of spatial layers (1-3)
+    pub codec: VideoCodec,
 }
 
 impl Default for YUV420Config {
@@ -61,6 +92,7 @@ impl Default for YUV420Config {
             opus_bitrate: 128000, // 128 kbps
             temporal_layers: 1,
             spatial_layers: 1,
+            codec: VideoCodec::Vp8,
         }
     }
 }
@@ -143,6 +175,62 @@ impl YUV420Frame {
         Ok(frame)
     }
     
+    /// Convert RGBA to YUV420 using BT.601 studio range, the color space VP8 decoders assume.
+    /// Chroma is averaged over each 2x2 block; odd dimensions round the chroma planes up.
+    pub fn from_rgba_bt601(rgba_data: &[u8], width: u32, height: u32, frame_number: u64) -> Result<Self, YUV420EncoderError> {
+        let (w, h) = (width as usize, height as usize);
+        if rgba_data.len() < w * h * 4 {
+            return Err(YUV420EncoderError::YUVConversion(format!(
+                "RGBA buffer too small: {} bytes for {}x{}", rgba_data.len(), width, height
+            )));
+        }
+
+        let (cw, ch) = ((w + 1) / 2, (h + 1) / 2);
+        let mut y_plane = vec![0u8; w * h];
+        let mut u_plane = vec![0u8; cw * ch];
+        let mut v_plane = vec![0u8; cw * ch];
+
+        y_plane.par_chunks_mut(w).enumerate().for_each(|(y, row)| {
+            let src = &rgba_data[y * w * 4..(y + 1) * w * 4];
+            for (x, px) in row.iter_mut().enumerate() {
+                let (r, g, b) = (src[x * 4] as i32, src[x * 4 + 1] as i32, src[x * 4 + 2] as i32);
+                *px = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            }
+        });
+
+        u_plane.par_chunks_mut(cw).zip(v_plane.par_chunks_mut(cw)).enumerate().for_each(|(cy, (u_row, v_row))| {
+            for cx in 0..cw {
+                let (mut r, mut g, mut b, mut n) = (0i32, 0i32, 0i32, 0i32);
+                for y in (cy * 2)..(cy * 2 + 2).min(h) {
+                    for x in (cx * 2)..(cx * 2 + 2).min(w) {
+                        let i = (y * w + x) * 4;
+                        r += rgba_data[i] as i32;
+                        g += rgba_data[i + 1] as i32;
+                        b += rgba_data[i + 2] as i32;
+                        n += 1;
+                    }
+                }
+                let (r, g, b) = (r / n, g / n, b / n);
+                u_row[cx] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                v_row[cx] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        });
+
+        Ok(Self {
+            y_plane,
+            u_plane,
+            v_plane,
+            width,
+            height,
+            frame_number,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            is_keyframe: false,
+        })
+    }
+    
     /// Get total frame size in bytes
     pub fn size(&self) -> usize {
         self.y_plane.len() + self.u_plane.len() + self.v_plane.len()
@@ -152,9 +240,13 @@ impl YUV420Frame {
 /// Simplified YUV420 encoder for stable screen streaming
 pub struct YUV420Encoder {
     config: YUV420Config,
-    monitor: Monitor,
+    source: Box<dyn CaptureSource>,
     frame_count: AtomicU64,
     last_keyframe: AtomicU64,
+    keyframe_requested: AtomicBool,
+    
+    // VP8 bitstream encoder, created on the first VP8 frame
+    vp8: Option<Vp8Encoder>,
     
     // Performance tracking
     encoding_stats: Arc<EncodingStats>,
@@ -210,6 +302,13 @@ impl EncodingStats {
 impl YUV420Encoder {
     /// Create a new simplified YUV420 encoder for stable streaming
     pub fn new(config: YUV420Config) -> Result<Self, YUV420EncoderError> {
+        let source = open_capture_source(config.monitor_id)
+            .map_err(|e| YUV420EncoderError::Capture(e.to_string()))?;
+        Self::with_source(config, source)
+    }
+    
+    /// Create an encoder that takes frames from the given capture source
+    pub fn with_source(config: YUV420Config, source: Box<dyn CaptureSource>) -> Result<Self, YUV420EncoderError> {
         info!("Initializing YUV420 encoder: {}x{} @ {}fps, {}kbps", 
               config.width, config.height, config.framerate, config.bitrate);
         
@@ -221,20 +320,16 @@ impl YUV420Encoder {
             return Err(YUV420EncoderError::Config("Invalid framerate".to_string()));
         }
         
-        // Get monitor
-        let monitors = Monitor::all()
-            .map_err(|e| YUV420EncoderError::Capture(format!("Failed to get monitors: {}", e)))?;
-        let monitor = monitors.get(config.monitor_id)
-            .ok_or_else(|| YUV420EncoderError::Capture(format!("Monitor {} not found", config.monitor_id)))?
-            .clone();
-        
-        info!("Using monitor: {} ({}x{})", monitor.name(), monitor.width(), monitor.height());
+        let (width, height) = source.dimensions();
+        info!("Using capture source: {} ({}x{})", source.name(), width, height);
         
         let encoder = Self {
             config: config.clone(),
-            monitor,
+            source,
             frame_count: AtomicU64::new(0),
             last_keyframe: AtomicU64::new(0),
+            keyframe_requested: AtomicBool::new(false),
+            vp8: None,
             encoding_stats: Arc::new(EncodingStats::new()),
             frame_buffer: Arc::new(Mutex::new(Vec::new())),
             max_buffer_size: 5, // Allow small buffer for smoothing
@@ -245,7 +340,10 @@ impl YUV420Encoder {
     
     /// Initialize encoder (simplified version for now)
     pub fn initialize_encoder(&mut self) -> Result<(), YUV420EncoderError> {
-        info!("Initializing simplified YUV420 encoder...");
+        info!("Initializing YUV420 encoder with {} output...", self.config.codec.name());
+        if self.config.codec == VideoCodec::Vp8 {
+            self.vp8 = Some(self.create_vp8_encoder(self.config.width, self.config.height)?);
+        }
         info!("✅ Encoder initialized successfully");
         Ok(())
     }
@@ -255,15 +353,14 @@ impl YUV420Encoder {
         let start_time = Instant::now();
         
         // Capture screen
-        let image = self.monitor.capture_image()
-            .map_err(|e| YUV420EncoderError::Capture(format!("Screen capture failed: {}", e)))?;
+        let frame = self.source.capture_frame()
+            .map_err(|e| YUV420EncoderError::Capture(e.to_string()))?;
         
         let capture_time = start_time.elapsed();
         
-        // Convert to RGBA if needed
-        let rgba_data = image.as_raw();
-        let width = image.width();
-        let height = image.height();
+        let rgba_data = &frame.rgba;
+        let width = frame.width;
+        let height = frame.height;
         
         // Resize if necessary
         let (final_width, final_height, processed_rgba) = if width != self.config.width || height != self.config.height {
@@ -275,11 +372,12 @@ impl YUV420Encoder {
         
         // Convert to YUV420
         let frame_number = self.frame_count.fetch_add(1, Ordering::Relaxed);
-        let yuv_frame = YUV420Frame::from_rgba(&processed_rgba, final_width, final_height, frame_number)
+        let yuv_frame = self.convert_rgba(&processed_rgba, final_width, final_height, frame_number)
             .map_err(|e| YUV420EncoderError::YUVConversion(format!("RGBA to YUV conversion failed: {}", e)))?;
         
         // Determine if this should be a keyframe
-        let should_keyframe = force_keyframe || 
+        let should_keyframe = force_keyframe ||
+            self.keyframe_requested.swap(false, Ordering::Relaxed) ||
             (frame_number - self.last_keyframe.load(Ordering::Relaxed)) >= self.config.keyframe_interval as u64;
         
         if should_keyframe {
@@ -307,36 +405,66 @@ impl YUV420Encoder {
         Ok(encoded_data)
     }
     
-    /// Encode a YUV420 frame (simplified version for now)
+    /// Convert RGBA using the color space expected by the configured codec
+    fn convert_rgba(&self, rgba_data: &[u8], width: u32, height: u32, frame_number: u64) -> Result<YUV420Frame, YUV420EncoderError> {
+        match self.config.codec {
+            VideoCodec::Vp8 => YUV420Frame::from_rgba_bt601(rgba_data, width, height, frame_number),
+            VideoCodec::RawYuv => YUV420Frame::from_rgba(rgba_data, width, height, frame_number),
+        }
+    }
+    
+    fn create_vp8_encoder(&self, width: u32, height: u32) -> Result<Vp8Encoder, YUV420EncoderError> {
+        Vp8Encoder::new(Vp8Config {
+            width,
+            height,
+            bitrate: self.config.bitrate,
+            framerate: self.config.framerate,
+            // Map the 0-63 quality scale onto VP8's 0-127 quantizer range
+            initial_quantizer: (self.config.quality.min(63) * 2) as u8,
+            ..Vp8Config::default()
+        }).map_err(|e| YUV420EncoderError::VP8Init(e.to_string()))
+    }
+    
+    /// Encode a YUV420 frame with the configured codec
     fn encode_yuv_frame(&mut self, yuv_frame: YUV420Frame, is_keyframe: bool) -> Result<Option<Vec<u8>>, YUV420EncoderError> {
-        // For now, return the raw YUV420 data as a simple encoded frame
-        // In a full implementation, this would use VP8 encoding
+        match self.config.codec {
+            VideoCodec::Vp8 => self.encode_vp8_frame(yuv_frame, is_keyframe),
+            VideoCodec::RawYuv => self.encode_raw_frame(yuv_frame, is_keyframe),
+        }
+    }
+    
+    /// Compress a frame to VP8 and wrap it in a media frame
+    fn encode_vp8_frame(&mut self, yuv_frame: YUV420Frame, is_keyframe: bool) -> Result<Option<Vec<u8>>, YUV420EncoderError> {
+        // Recreate the encoder when the frame size changes; the first frame is then a keyframe
+        let recreate = match &self.vp8 {
+            Some(encoder) => encoder.dimensions() != (yuv_frame.width, yuv_frame.height),
+            None => true,
+        };
+        if recreate {
+            self.vp8 = Some(self.create_vp8_encoder(yuv_frame.width, yuv_frame.height)?);
+        }
         
-        let mut encoded_data = Vec::new();
+        let encoder = self.vp8.as_mut().expect("VP8 encoder initialized above");
+        let encoded = encoder.encode(&yuv_frame, is_keyframe)
+            .map_err(|e| YUV420EncoderError::Encode(e.to_string()))?;
         
-        // Simple header with frame info
-        encoded_data.extend_from_slice(&[0x59, 0x55, 0x56]); // "YUV" signature
+        let encoded_data = FrameHeader::video(FrameCodec::Vp8, yuv_frame.width, yuv_frame.height, encoded.is_keyframe, yuv_frame.timestamp)
+            .encode(&encoded.data);
         
-        if is_keyframe {
-            encoded_data.push(0x01); // Keyframe marker
+        if encoded.is_keyframe {
+            info!("📹 Encoded VP8 keyframe {}: {} bytes (q={})", yuv_frame.frame_number, encoded_data.len(), encoded.quantizer);
         } else {
-            encoded_data.push(0x00); // Inter-frame marker
+            debug!("📹 Encoded VP8 frame {}: {} bytes (q={})", yuv_frame.frame_number, encoded_data.len(), encoded.quantizer);
         }
         
-        // Frame metadata
-        encoded_data.extend_from_slice(&yuv_frame.width.to_le_bytes());
-        encoded_data.extend_from_slice(&yuv_frame.height.to_le_bytes());
-        encoded_data.extend_from_slice(&yuv_frame.timestamp.to_le_bytes());
-        
-        // YUV420 data
-        encoded_data.extend_from_slice(&(yuv_frame.y_plane.len() as u32).to_le_bytes());
-        encoded_data.extend_from_slice(&yuv_frame.y_plane);
-        
-        encoded_data.extend_from_slice(&(yuv_frame.u_plane.len() as u32).to_le_bytes());
-        encoded_data.extend_from_slice(&yuv_frame.u_plane);
-        
-        encoded_data.extend_from_slice(&(yuv_frame.v_plane.len() as u32).to_le_bytes());
-        encoded_data.extend_from_slice(&yuv_frame.v_plane);
+        Ok(Some(encoded_data))
+    }
+    
+    /// Emit the raw YUV420 planes as an uncompressed frame
+    fn encode_raw_frame(&mut self, yuv_frame: YUV420Frame, is_keyframe: bool) -> Result<Option<Vec<u8>>, YUV420EncoderError> {
+        // Raw planes never depend on an earlier frame, so every one is a keyframe
+        let encoded_data = FrameHeader::video(FrameCodec::Yuv420, yuv_frame.width, yuv_frame.height, true, yuv_frame.timestamp)
+            .encode_parts(&[&yuv_frame.y_plane, &yuv_frame.u_plane, &yuv_frame.v_plane]);
         
         if is_keyframe {
             info!("📹 Encoded YUV420 keyframe {}: {} bytes", yuv_frame.frame_number, encoded_data.len());
@@ -369,26 +497,40 @@ impl YUV420Encoder {
     
     /// Force next frame to be a keyframe
     pub fn force_keyframe(&self) {
-        self.last_keyframe.store(0, Ordering::Relaxed);
+        self.keyframe_requested.store(true, Ordering::Relaxed);
     }
     
     /// Update encoder configuration dynamically
     pub fn update_config(&mut self, new_config: YUV420Config) -> Result<(), YUV420EncoderError> {
-        info!("Updating encoder configuration: {}x{} @ {}fps, {}kbps", 
+        debug!("Updating encoder configuration: {}x{} @ {}fps, {}kbps", 
               new_config.width, new_config.height, new_config.framerate, new_config.bitrate);
         
+        // Rate changes keep the VP8 state and need no keyframe, which matters since
+        // the congestion controller makes them often. A new size starts over.
+        if (new_config.width, new_config.height) != (self.config.width, self.config.height) {
+            self.vp8 = None;
+            self.force_keyframe();
+        } else if let Some(encoder) = self.vp8.as_mut() {
+            encoder.set_bitrate(new_config.bitrate, new_config.framerate);
+        }
+        
         // Update configuration
         self.config = new_config;
-        self.force_keyframe(); // Force keyframe after config change
         
         Ok(())
     }
     
+    /// Frame rate the capture loop should run at
+    pub fn framerate(&self) -> u32 {
+        self.config.framerate
+    }
+    
     /// Encode RGBA frame data directly (for compatibility)
     pub fn encode_rgba_frame(&mut self, rgba_data: &[u8], width: u32, height: u32, timestamp_us: u64, force_keyframe: bool) -> Result<Vec<u8>, YUV420EncoderError> {
         // Convert RGBA to YUV420
         let frame_number = self.frame_count.fetch_add(1, Ordering::Relaxed);
-        let yuv_frame = YUV420Frame::from_rgba(rgba_data, width, height, frame_number)?;
+        let mut yuv_frame = self.convert_rgba(rgba_data, width, height, frame_number)?;
+        yuv_frame.timestamp = timestamp_us;
         
         // Encode the frame
         match self.encode_yuv_frame(yuv_frame, force_keyframe)? {
@@ -463,3 +605,64 @@ pub mod yuv_utils {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::capture::{SyntheticPattern, SyntheticSource};
+    use crate::streaming::frame::parse_frame;
+
+    fn synthetic_encoder(codec: VideoCodec) -> YUV420Encoder {
+        let config = YUV420Config {
+            width: 64,
+            height: 48,
+            codec,
+            ..YUV420Config::default()
+        };
+        let source = Box::new(SyntheticSource::new(64, 48, SyntheticPattern::MovingBars));
+        let mut encoder = YUV420Encoder::with_source(config, source).unwrap();
+        encoder.initialize_encoder().unwrap();
+        encoder
+    }
+
+    #[test]
+    fn encodes_synthetic_frames_to_vp8_headless() {
+        let mut encoder = synthetic_encoder(VideoCodec::Vp8);
+
+        let key = encoder.capture_and_encode(true).unwrap().expect("keyframe");
+        let (header, payload) = parse_frame(&key).expect("media frame");
+        assert_eq!(header.codec, FrameCodec::Vp8);
+        assert!(header.keyframe);
+        assert_eq!((header.width, header.height), (64, 48));
+        assert_eq!(&payload[3..6], &[0x9d, 0x01, 0x2a]);
+
+        let inter = encoder.capture_and_encode(false).unwrap().expect("inter frame");
+        assert!(!parse_frame(&inter).expect("media frame").0.keyframe);
+    }
+
+    #[test]
+    fn resizes_synthetic_frames_to_configured_size() {
+        let config = YUV420Config {
+            width: 32,
+            height: 24,
+            codec: VideoCodec::Vp8,
+            ..YUV420Config::default()
+        };
+        let source = Box::new(SyntheticSource::new(64, 48, SyntheticPattern::Static));
+        let mut encoder = YUV420Encoder::with_source(config, source).unwrap();
+
+        let frame = encoder.capture_and_encode(true).unwrap().expect("keyframe");
+        let (header, _) = parse_frame(&frame).expect("media frame");
+        assert_eq!((header.width, header.height), (32, 24));
+    }
+
+    #[test]
+    fn raw_frames_carry_all_three_planes() {
+        let mut encoder = synthetic_encoder(VideoCodec::RawYuv);
+
+        let frame = encoder.capture_and_encode(true).unwrap().expect("keyframe");
+        let (header, payload) = parse_frame(&frame).expect("media frame");
+        assert_eq!(header.codec, FrameCodec::Yuv420);
+        assert_eq!(payload.len(), 64 * 48 + 2 * 32 * 24);
+    }
+}
@@ -0,0 +1,203 @@
+//! Server-side congestion control for media streams
+//!
+//! Clients used to report their own latency and loss, which is easy to fake and
+//! usually stale. The controller here only trusts what the server measures itself
+//! through the connection's `FrameWindow` (see [`NetworkEstimate`]).
+//!
+//! It is delay based. While the round trip stays near its minimum and socket
+//! writes do not block, the bitrate creeps up. Once frames start queueing, writes
+//! block or acks go missing, the bitrate drops to just under the rate the client
+//! is actually receiving. Resolution and frame rate follow the bitrate in steps,
+//! so a poor link gets fewer, smaller frames instead of blurry full-size ones.
+
+use log::info;
+use std::time::Duration;
+
+use super::flow_control::NetworkEstimate;
+
+/// How often handlers feed the controller a fresh estimate
+pub const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+/// Lowest bitrate the controller will ask for, in kbps
+pub const MIN_BITRATE_KBPS: u32 = 150;
+/// Round trip above the minimum that counts as queueing
+const MAX_QUEUEING_DELAY_MS: u32 = 40;
+/// Write time that means the socket buffer is full
+const MAX_SEND_DELAY_MS: u32 = 10;
+/// Growth per update while the link keeps up
+const INCREASE_FACTOR: f64 = 1.08;
+/// Share of the measured delivery rate kept after congestion
+const DECREASE_FACTOR: f64 = 0.85;
+/// How far past a step's threshold the bitrate must climb before moving back up
+const STEP_UP_MARGIN: f64 = 1.25;
+
+/// Encoder settings for each share of the maximum bitrate:
+/// (lowest share, resolution scale, frame rate cap)
+const QUALITY_STEPS: [(f64, f64, u32); 4] = [
+    (0.5, 1.0, u32::MAX),
+    (0.25, 1.0, 30),
+    (0.12, 0.75, 30),
+    (0.0, 0.5, 15),
+];
+
+/// Whether frames are queueing on the way to the client or socket writes are blocking
+pub fn is_congested(estimate: &NetworkEstimate) -> bool {
+    estimate.queueing_delay_ms() > MAX_QUEUEING_DELAY_MS || estimate.send_delay_ms > MAX_SEND_DELAY_MS
+}
+
+/// What the encoder should produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderTarget {
+    pub bitrate_kbps: u32,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: u32,
+}
+
+/// Picks encoder settings for one connection from its [`NetworkEstimate`]
+pub struct CongestionController {
+    /// Settings at full quality; the bitrate is the ceiling
+    full: EncoderTarget,
+    bitrate_kbps: f64,
+    step: usize,
+    lost_frames: u64,
+    /// Skip one increase right after backing off, so the queue can drain first
+    holding: bool,
+    target: EncoderTarget,
+}
+
+impl CongestionController {
+    /// Start at full quality and back off once the link says otherwise
+    pub fn new(full: EncoderTarget) -> Self {
+        Self {
+            full,
+            bitrate_kbps: full.bitrate_kbps as f64,
+            step: 0,
+            lost_frames: 0,
+            holding: false,
+            target: full,
+        }
+    }
+
+    /// Settings currently asked of the encoder
+    pub fn target(&self) -> EncoderTarget {
+        self.target
+    }
+
+    /// Change the ceiling, e.g. after a quality change, and return the new settings
+    pub fn set_max_bitrate(&mut self, bitrate_kbps: u32) -> EncoderTarget {
+        self.full.bitrate_kbps = bitrate_kbps.max(MIN_BITRATE_KBPS);
+        self.bitrate_kbps = self.bitrate_kbps.min(self.full.bitrate_kbps as f64);
+        self.retarget();
+        self.target
+    }
+
+    /// Feed the latest estimate; returns new settings when they changed
+    pub fn update(&mut self, estimate: &NetworkEstimate) -> Option<EncoderTarget> {
+        let lost = estimate.lost_frames > self.lost_frames;
+        self.lost_frames = estimate.lost_frames;
+
+        if is_congested(estimate) || lost {
+            // Without a rate sample yet, back off from where we are
+            let receiving = estimate
+                .delivery_rate_kbps
+                .map_or(self.bitrate_kbps, |rate| rate as f64);
+            self.bitrate_kbps = self.bitrate_kbps.min(receiving) * DECREASE_FACTOR;
+            self.holding = true;
+            info!(
+                "Congestion (queueing={}ms, send_delay={}ms, lost={}): backing off to {:.0}kbps",
+                estimate.queueing_delay_ms(), estimate.send_delay_ms, lost, self.bitrate_kbps
+            );
+        } else if self.holding {
+            self.holding = false;
+        } else {
+            self.bitrate_kbps *= INCREASE_FACTOR;
+        }
+        self.bitrate_kbps = self
+            .bitrate_kbps
+            .clamp(MIN_BITRATE_KBPS as f64, self.full.bitrate_kbps.max(MIN_BITRATE_KBPS) as f64);
+
+        let previous = self.target;
+        self.retarget();
+        (self.target != previous).then_some(self.target)
+    }
+
+    fn retarget(&mut self) {
+        let share = self.bitrate_kbps / self.full.bitrate_kbps.max(1) as f64;
+        while self.step + 1 < QUALITY_STEPS.len() && share < QUALITY_STEPS[self.step].0 {
+            self.step += 1;
+        }
+        while self.step > 0 && share >= QUALITY_STEPS[self.step - 1].0 * STEP_UP_MARGIN {
+            self.step -= 1;
+        }
+
+        let (_, scale, framerate_cap) = QUALITY_STEPS[self.step];
+        // Encoders want even dimensions for the half-size chroma planes
+        let scaled = |size: u32| ((size as f64 * scale) as u32 & !1).max(2);
+        self.target = EncoderTarget {
+            bitrate_kbps: self.bitrate_kbps as u32,
+            width: scaled(self.full.width),
+            height: scaled(self.full.height),
+            framerate: self.full.framerate.min(framerate_cap),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL: EncoderTarget = EncoderTarget { bitrate_kbps: 4000, width: 1920, height: 1080, framerate: 60 };
+
+    fn clear_link() -> NetworkEstimate {
+        NetworkEstimate { rtt_ms: Some(20), min_rtt_ms: Some(20), delivery_rate_kbps: Some(3000), ..Default::default() }
+    }
+
+    #[test]
+    fn backs_off_below_the_delivery_rate_when_frames_queue() {
+        let mut controller = CongestionController::new(FULL);
+        let congested = NetworkEstimate { rtt_ms: Some(120), ..clear_link() };
+
+        let target = controller.update(&congested).unwrap();
+        assert_eq!(target.bitrate_kbps, 2550);
+        assert_eq!((target.width, target.framerate), (1920, 60));
+
+        // Holds once so the queue drains, then grows again
+        assert_eq!(controller.update(&clear_link()), None);
+        assert_eq!(controller.update(&clear_link()).unwrap().bitrate_kbps, 2754);
+    }
+
+    #[test]
+    fn blocked_writes_and_lost_frames_count_as_congestion() {
+        let mut controller = CongestionController::new(FULL);
+        let blocked = NetworkEstimate { send_delay_ms: 50, ..clear_link() };
+        assert!(controller.update(&blocked).unwrap().bitrate_kbps < FULL.bitrate_kbps);
+
+        let mut controller = CongestionController::new(FULL);
+        let lossy = NetworkEstimate { lost_frames: 3, ..clear_link() };
+        assert_eq!(controller.update(&lossy).unwrap().bitrate_kbps, 2550);
+        // Only new losses matter
+        assert_eq!(controller.update(&lossy), None);
+        assert_eq!(controller.update(&lossy).unwrap().bitrate_kbps, 2754);
+    }
+
+    #[test]
+    fn low_rates_trade_resolution_and_frame_rate() {
+        let mut controller = CongestionController::new(FULL);
+        let starved = NetworkEstimate { rtt_ms: Some(300), delivery_rate_kbps: Some(500), ..clear_link() };
+
+        let target = controller.update(&starved).unwrap();
+        assert_eq!(target.bitrate_kbps, 425);
+        assert_eq!((target.width, target.height, target.framerate), (960, 540, 15));
+
+        // Never below the floor, however bad it gets
+        for _ in 0..20 {
+            controller.update(&starved);
+        }
+        assert_eq!(controller.target().bitrate_kbps, MIN_BITRATE_KBPS);
+
+        // A lower ceiling caps the current rate straight away
+        let mut controller = CongestionController::new(FULL);
+        let target = controller.set_max_bitrate(2000);
+        assert_eq!((target.bitrate_kbps, target.width, target.framerate), (2000, 1920, 60));
+    }
+}
@@ -1,14 +1,16 @@
 use anyhow::Result;
 use thiserror::Error;
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
 use std::time::{Duration, Instant};
 use std::sync::atomic::{AtomicU64, AtomicU32, AtomicBool, Ordering};
 use std::sync::Arc;
 use parking_lot::{Mutex, RwLock}; // High-performance locks
 use tokio::sync::mpsc;
-use xcap::Monitor;
+use crate::core::capture::{open_capture_source, CaptureError, CaptureSource};
 use rayon::prelude::*; // Parallel processing
 use crate::network::models::NetworkStats;
+use crate::streaming::codecs::tile_codec::{ChangeTracker, REFRESH_INTERVAL};
+use crate::streaming::frame::{frame_pts, FrameCodec, FrameHeader};
 
 /// Ultra-low latency codec errors
 #[derive(Error, Debug)]
@@ -23,6 +25,15 @@ pub enum UltraLowLatencyError {
     PerformanceBudget(f64),
 }
 
+impl From<CaptureError> for UltraLowLatencyError {
+    fn from(e: CaptureError) -> Self {
+        match e {
+            CaptureError::MonitorNotFound(index) => UltraLowLatencyError::MonitorNotFound(index),
+            e => UltraLowLatencyError::Capture(e.to_string()),
+        }
+    }
+}
+
 /// Performance targets for different quality modes
 #[derive(Clone, Debug)]
 pub struct PerformanceTarget {
@@ -180,7 +191,7 @@ impl UltraPerformanceStats {
 /// Ultra-high performance screen streaming encoder
 /// Designed for <16ms total latency with Google/Microsoft engineering practices
 pub struct UltraLowLatencyEncoder {
-    monitor: Monitor,
+    source: Mutex<Box<dyn CaptureSource>>,
     config: UltraLowLatencyConfig,
     frame_count: AtomicU64,
     last_keyframe: AtomicU64,
@@ -188,7 +199,6 @@ pub struct UltraLowLatencyEncoder {
     
     // High-performance frame processing
     frame_pool: Arc<RwLock<Vec<UltraFrame>>>, // Pre-allocated frame pool
-    previous_frame: Arc<Mutex<Option<Box<[u8]>>>>, // Previous frame for delta
     
     // SIMD optimization state
     simd_buffer: Arc<Mutex<Vec<u8>>>, // Aligned buffer for SIMD operations
@@ -264,11 +274,11 @@ impl AdaptiveQualityController {
 struct EncodingPipeline {
     // Pre-allocated compression buffers
     rle_buffer: Vec<u8>,
-    delta_buffer: Vec<u8>,
     output_buffer: Vec<u8>,
     
-    // SIMD-optimized comparison buffer
-    diff_mask: Vec<bool>,
+    // What changed or moved since the last frame
+    changes: ChangeTracker,
+    last_keyframe_time: Instant,
     
     // Performance optimization flags
     use_parallel_rle: bool,
@@ -276,12 +286,12 @@ struct EncodingPipeline {
 }
 
 impl EncodingPipeline {
-    fn new() -> Self {
+    fn new(width: u32, height: u32) -> Self {
         Self {
             rle_buffer: Vec::with_capacity(1920 * 1080 * 4),
-            delta_buffer: Vec::with_capacity(1920 * 1080),
             output_buffer: Vec::with_capacity(1920 * 1080 * 4),
-            diff_mask: Vec::with_capacity(1920 * 1080),
+            changes: ChangeTracker::new(width as usize, height as usize),
+            last_keyframe_time: Instant::now(),
             use_parallel_rle: true,
             chunk_size: 64 * 1024, // 64KB chunks for parallel processing
         }
@@ -340,63 +350,23 @@ impl EncodingPipeline {
         
         compressed
     }
-    
-    /// Ultra-fast delta compression with SIMD optimization
-    fn compress_delta_simd(&mut self, current: &[u8], previous: &[u8]) -> Option<&[u8]> {
-        if current.len() != previous.len() {
-            return None;
-        }
-        
-        self.delta_buffer.clear();
-        self.diff_mask.clear();
-        self.diff_mask.resize(current.len() / 4, false);
-        
-        // SIMD-accelerated difference detection
-        let pixel_count = current.len() / 4;
-        let changes = current.chunks_exact(4)
-            .zip(previous.chunks_exact(4))
-            .enumerate()
-            .filter_map(|(idx, (curr, prev))| {
-                if curr != prev {
-                    Some((idx as u32, [curr[0], curr[1], curr[2], curr[3]]))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
-        
-        // If too many changes, fall back to full frame
-        if changes.len() > pixel_count / 8 {
-            return None;
-        }
-        
-        // Encode delta changes efficiently
-        self.delta_buffer.extend_from_slice(&(changes.len() as u32).to_le_bytes());
-        for (index, pixel) in changes {
-            self.delta_buffer.extend_from_slice(&index.to_le_bytes());
-            self.delta_buffer.extend_from_slice(&pixel);
-        }
-        
-        Some(&self.delta_buffer)
-    }
 }
 
 impl UltraLowLatencyEncoder {
     pub fn new(config: UltraLowLatencyConfig) -> Result<Self, UltraLowLatencyError> {
+        let source = open_capture_source(config.monitor_id)?;
+        Ok(Self::with_source(config, source))
+    }
+    
+    /// Create an encoder that takes frames from the given capture source
+    pub fn with_source(config: UltraLowLatencyConfig, source: Box<dyn CaptureSource>) -> Self {
         info!("🚀 Initializing ULTRA-LOW LATENCY encoder (Google/Microsoft level)");
         info!("📊 Target: {}ms total latency, {}fps, quality adaptation: {}", 
               config.target_latency_ms, config.performance_target.target_fps, config.adaptive_quality);
         
-        // Get the specified monitor
-        let monitors = Monitor::all().map_err(|e| 
-            UltraLowLatencyError::MonitorNotFound(0))?;
-        
-        let monitor = monitors.get(config.monitor_id)
-            .ok_or_else(|| UltraLowLatencyError::MonitorNotFound(config.monitor_id))?
-            .clone();
-
-        info!("🖥️  Monitor: {} ({}x{}) - Hardware accel: {}, SIMD: {}, Parallel: {}", 
-              monitor.name(), monitor.width(), monitor.height(),
+        let (width, height) = source.dimensions();
+        info!("🖥️  Source: {} ({}x{}) - Hardware accel: {}, SIMD: {}, Parallel: {}", 
+              source.name(), width, height,
               config.use_hardware_acceleration, config.enable_simd_optimization, config.enable_parallel_processing);
 
         // Pre-allocate frame pool for zero-allocation operation
@@ -406,29 +376,27 @@ impl UltraLowLatencyEncoder {
             frame_pool.push(UltraFrame::new_aligned(config.width, config.height, i as u64));
         }
 
-        Ok(Self {
-            monitor,
+        Self {
+            source: Mutex::new(source),
             config: config.clone(),
             frame_count: AtomicU64::new(0),
             last_keyframe: AtomicU64::new(0),
             performance_stats: Arc::new(UltraPerformanceStats::new()),
             frame_pool: Arc::new(RwLock::new(frame_pool)),
-            previous_frame: Arc::new(Mutex::new(None)),
             simd_buffer: Arc::new(Mutex::new(Vec::with_capacity((config.width * config.height * 4) as usize))),
             quality_controller: Arc::new(Mutex::new(AdaptiveQualityController::new(config.performance_target.clone()))),
-            encoding_pipeline: Arc::new(Mutex::new(EncodingPipeline::new())),
-        })
+            encoding_pipeline: Arc::new(Mutex::new(EncodingPipeline::new(width, height))),
+        }
     }
     
-    /// Ultra-fast capture and encode with strict performance budgets
+    /// Ultra-fast capture and encode with strict performance budgets; `None` when the screen is unchanged
     pub fn capture_and_encode_ultra_fast(&self, force_keyframe: bool) -> Result<Option<Vec<u8>>, UltraLowLatencyError> {
         let total_start = Instant::now();
         let target_budget = Duration::from_millis(self.config.performance_target.total_budget_ms as u64);
         
         // PHASE 1: Ultra-fast screen capture (budget: 8ms)
         let capture_start = Instant::now();
-        let image = self.monitor.capture_image()
-            .map_err(|e| UltraLowLatencyError::Capture(format!("Capture failed: {:?}", e)))?;
+        let frame = self.source.lock().capture_frame()?;
         
         let capture_time = capture_start.elapsed();
         self.performance_stats.update_capture_time(capture_time.as_nanos() as u64);
@@ -441,9 +409,9 @@ impl UltraLowLatencyEncoder {
         }
         
         // PHASE 2: Zero-copy data preparation
-        let rgba_data = image.as_raw();
-        let width = image.width() as u32;
-        let height = image.height() as u32;
+        let rgba_data = &frame.rgba;
+        let width = frame.width;
+        let height = frame.height;
         
         // PHASE 3: Ultra-fast encoding (budget: 4ms)
         let encode_start = Instant::now();
@@ -466,18 +434,10 @@ impl UltraLowLatencyEncoder {
         // Update latency measurement
         self.performance_stats.current_latency_ms.store(total_ms as u32, Ordering::Relaxed);
         
-        // Check total budget
+        // Check total budget; a late frame is still sent, the quality controller adapts to the overrun
         if total_time > target_budget {
-            error!("🔴 TOTAL BUDGET EXCEEDED: {:.1}ms > {:.1}ms", total_ms, self.config.performance_target.total_budget_ms);
+            warn!("🔴 TOTAL BUDGET EXCEEDED: {:.1}ms > {:.1}ms", total_ms, self.config.performance_target.total_budget_ms);
             self.performance_stats.budget_violations.fetch_add(1, Ordering::Relaxed);
-            
-            // Trigger emergency performance adaptation
-            if self.config.adaptive_quality {
-                let mut quality_controller = self.quality_controller.lock();
-                quality_controller.should_adjust_quality(total_ms);
-            }
-            
-            return Err(UltraLowLatencyError::PerformanceBudget(total_ms));
         }
         
         // Adaptive quality adjustment
@@ -498,42 +458,53 @@ impl UltraLowLatencyEncoder {
                   capture_ms, encode_ms, total_ms, total_frames, dropped_frames, latency_ms);
         }
         
-        Ok(Some(encoded_data))
+        Ok(encoded_data)
     }
     
-    /// Ultra-fast frame encoding with direct RGBA format (no conversion overhead)
-    fn encode_frame_ultra_fast(&self, rgba_data: &[u8], width: u32, height: u32, force_keyframe: bool) -> Result<Vec<u8>, UltraLowLatencyError> {
+    /// Ultra-fast frame encoding: direct RGBA keyframes (no conversion overhead), copies and changed tiles in between
+    fn encode_frame_ultra_fast(&self, rgba_data: &[u8], width: u32, height: u32, force_keyframe: bool) -> Result<Option<Vec<u8>>, UltraLowLatencyError> {
         let frame_count = self.frame_count.load(Ordering::Relaxed);
-        let last_keyframe = self.last_keyframe.load(Ordering::Relaxed);
-        
-        // More frequent keyframes for better quality and error recovery
-        let should_keyframe = force_keyframe || 
-            (frame_count - last_keyframe) >= 60; // Keyframe every 1 second at 60fps
-        
-        // Create ultra-fast RGBA stream format (eliminating ALL conversion overhead)
-        let mut stream_frame = Vec::with_capacity(rgba_data.len() + 24);
-        
-        // Ultra-fast RGBA frame header (no VP8 overhead)
-        stream_frame.extend_from_slice(b"RGBA"); // Format signature (4 bytes)
-        stream_frame.extend_from_slice(&(width as u32).to_le_bytes()); // Width (4 bytes)
-        stream_frame.extend_from_slice(&(height as u32).to_le_bytes()); // Height (4 bytes)
-        stream_frame.extend_from_slice(&frame_count.to_le_bytes()); // Frame number (8 bytes)
-        stream_frame.extend_from_slice(&(rgba_data.len() as u32).to_le_bytes()); // Data length (4 bytes)
         
-        // Direct RGBA data - zero conversion overhead!
-        stream_frame.extend_from_slice(rgba_data);
+        let update = {
+            let mut pipeline = self.encoding_pipeline.lock();
+            let refresh_due = pipeline.last_keyframe_time.elapsed() >= REFRESH_INTERVAL;
+            match pipeline.changes.update(rgba_data, width as usize, height as usize) {
+                Some(changes) if !force_keyframe && !refresh_due => Some(changes),
+                // Nothing to patch against, asked for, or due a refresh
+                _ => {
+                    pipeline.last_keyframe_time = Instant::now();
+                    None
+                }
+            }
+        };
         
-        if should_keyframe {
-            self.last_keyframe.store(frame_count, Ordering::Relaxed);
+        match update {
+            None => {
+                self.last_keyframe.store(frame_count, Ordering::Relaxed);
+                // Direct RGBA data - zero conversion overhead! Decodes on its own
+                Ok(Some(FrameHeader::video(FrameCodec::Rgba, width, height, true, frame_pts()).encode(rgba_data)))
+            }
+            // Unchanged screen: nothing to send
+            Some(changes) if changes.is_empty() => Ok(None),
+            Some(changes) => {
+                let (codec, payload) = changes.encode(rgba_data, width);
+                Ok(Some(FrameHeader::video(codec, width, height, false, frame_pts()).encode(&payload)))
+            }
         }
-        
-        // Store current frame for next delta (zero-copy when possible)
-        if should_keyframe || rgba_data.len() <= 1920 * 1080 * 4 {
-            let mut previous_frame = self.previous_frame.lock();
-            *previous_frame = Some(rgba_data.to_vec().into_boxed_slice());
+    }
+    
+    /// Capture a plain RGBA keyframe from this encoder's own source, skipping the
+    /// budgets and tile tracking; the fallback when the fast path keeps failing
+    pub fn capture_keyframe(&self) -> Result<Vec<u8>, UltraLowLatencyError> {
+        let frame = self.source.lock().capture_frame()?;
+        {
+            // Later updates patch on top of this picture
+            let mut pipeline = self.encoding_pipeline.lock();
+            pipeline.changes.update(&frame.rgba, frame.width as usize, frame.height as usize);
+            pipeline.last_keyframe_time = Instant::now();
         }
-        
-        Ok(stream_frame)
+        self.last_keyframe.store(self.frame_count.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        Ok(FrameHeader::video(FrameCodec::Rgba, frame.width, frame.height, true, frame_pts()).encode(&frame.rgba))
     }
     
     // YUV conversion functions removed for ultra-fast RGBA streaming
@@ -554,13 +525,45 @@ impl UltraLowLatencyEncoder {
         self.performance_stats.adaptive_quality_level.store(50, Ordering::Relaxed);
     }
     
-    /// Get current dimensions
+    /// Size of the frames this encoder produces, as reported by its capture source
     pub fn get_dimensions(&self) -> (u32, u32) {
-        (self.config.width, self.config.height)
+        self.source.lock().dimensions()
     }
     
-    /// Force keyframe
-    pub fn force_keyframe(&self) {
-        self.last_keyframe.store(0, Ordering::Relaxed);
+    /// Whether the frame most recently returned by `capture_and_encode_ultra_fast` was a keyframe
+    pub fn last_frame_was_keyframe(&self) -> bool {
+        self.last_keyframe.load(Ordering::Relaxed) + 1 == self.frame_count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::capture::{SyntheticPattern, SyntheticSource};
+    use crate::streaming::frame::parse_frame;
+
+    #[test]
+    fn frames_over_budget_are_still_sent() {
+        let no_budget = PerformanceTarget { capture_budget_ms: 0.0, encode_budget_ms: 0.0, total_budget_ms: 0.0, target_fps: 60, max_frame_queue: 0 };
+        let config = UltraLowLatencyConfig { performance_target: no_budget, ..Default::default() };
+        let source = SyntheticSource::new(64, 48, SyntheticPattern::MovingBars);
+        let encoder = UltraLowLatencyEncoder::with_source(config, Box::new(source));
+
+        let keyframe = encoder.capture_and_encode_ultra_fast(false).unwrap().unwrap();
+        assert!(encoder.last_frame_was_keyframe());
+        assert!(parse_frame(&keyframe).unwrap().0.keyframe);
+
+        // The next frame still patches on top of the late one
+        let update = encoder.capture_and_encode_ultra_fast(false).unwrap().unwrap();
+        assert!(!encoder.last_frame_was_keyframe());
+        assert!(!parse_frame(&update).unwrap().0.keyframe);
+        assert!(encoder.performance_stats.budget_violations.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn dimensions_come_from_the_source() {
+        let source = SyntheticSource::new(64, 48, SyntheticPattern::Static);
+        let encoder = UltraLowLatencyEncoder::with_source(UltraLowLatencyConfig::default(), Box::new(source));
+        assert_eq!(encoder.get_dimensions(), (64, 48));
     }
 }
@@ -0,0 +1,391 @@
+//! Per-connection flow control for media frames
+//!
+//! Every frame a connection commits to is numbered and the client answers with a
+//! `frame_ack` carrying that number. Frames committed but not yet acknowledged are
+//! in flight; while the window is full a stream skips capture (or drops the frame
+//! it is holding), so the viewer gets the newest screen instead of a backlog.
+//!
+//! The window is sized from the lowest round trip seen recently: enough frames to
+//! cover one round trip at the target frame rate, plus one. More than that would
+//! only wait in a queue somewhere. Queueing itself shows up as a rising round
+//! trip, which is why the minimum is used rather than the average.
+//!
+//! The same acknowledgements give the server its own view of the link, see
+//! [`NetworkEstimate`]: how fast acknowledged bytes arrive, how far the round trip
+//! has risen above its minimum, how long socket writes block and how many frames
+//! were never acknowledged. `congestion` turns that into encoder settings.
+
+use log::debug;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::frame::{set_frame_sequence, FRAME_HEADER_LEN};
+
+/// Fewest frames allowed in flight, also used until the first acknowledgement
+pub const MIN_IN_FLIGHT: usize = 2;
+/// Most frames allowed in flight, however long the round trip
+pub const MAX_IN_FLIGHT: usize = 8;
+/// Frames unacknowledged for this long are given up on so a lost ack cannot stall the stream
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a minimum round trip is trusted before newer samples replace it
+const MIN_RTT_LIFETIME: Duration = Duration::from_secs(10);
+
+/// In-flight window for one connection's frames
+pub struct FrameWindow {
+    state: Mutex<WindowState>,
+}
+
+struct WindowState {
+    frame_interval: Duration,
+    next_sequence: u32,
+    /// Every unacknowledged frame, oldest first
+    in_flight: VecDeque<SentFrame>,
+    /// Lowest recent round trip and when it was measured
+    min_rtt: Option<(Duration, Instant)>,
+    smoothed_rtt: Option<Duration>,
+    /// Bytes acknowledged so far and when the last of them was
+    delivered: u64,
+    delivered_at: Option<Instant>,
+    /// Smoothed delivery rate in bytes per second
+    delivery_rate: Option<f64>,
+    /// Smoothed time a socket write blocked
+    send_delay: Duration,
+    lost_frames: u64,
+}
+
+struct SentFrame {
+    sequence: u32,
+    sent: Instant,
+    bytes: u64,
+    /// Delivery progress when the frame was sent, so its ack measures the rate since
+    delivered: u64,
+    delivered_at: Instant,
+}
+
+/// What the server measured about one connection, from its own acks and writes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct NetworkEstimate {
+    /// Smoothed round trip from frame sent to frame acknowledged
+    pub rtt_ms: Option<u32>,
+    /// Lowest recent round trip, the link without any queueing
+    pub min_rtt_ms: Option<u32>,
+    /// Rate at which the client acknowledges bytes
+    pub delivery_rate_kbps: Option<u32>,
+    /// How long writes to the socket block; high when its buffer is full
+    pub send_delay_ms: u32,
+    /// Frames never acknowledged since the connection started
+    pub lost_frames: u64,
+}
+
+impl NetworkEstimate {
+    /// Time frames spend waiting in queues on the way to the client
+    pub fn queueing_delay_ms(&self) -> u32 {
+        match (self.rtt_ms, self.min_rtt_ms) {
+            (Some(rtt), Some(min_rtt)) => rtt.saturating_sub(min_rtt),
+            _ => 0,
+        }
+    }
+}
+
+impl FrameWindow {
+    pub fn new(target_fps: u32) -> Self {
+        Self {
+            state: Mutex::new(WindowState {
+                frame_interval: frame_interval(target_fps),
+                next_sequence: 0,
+                in_flight: VecDeque::new(),
+                min_rtt: None,
+                smoothed_rtt: None,
+                delivered: 0,
+                delivered_at: None,
+                delivery_rate: None,
+                send_delay: Duration::ZERO,
+                lost_frames: 0,
+            }),
+        }
+    }
+
+    /// Resize the window for a new frame rate
+    pub fn set_frame_rate(&self, target_fps: u32) {
+        self.state.lock().frame_interval = frame_interval(target_fps);
+    }
+
+    /// Whether another frame may be committed now
+    pub fn has_room(&self) -> bool {
+        self.has_room_at(Instant::now())
+    }
+
+    /// Number `frame` for this connection and count it as in flight
+    pub fn start_frame(&self, frame: &mut [u8]) -> u32 {
+        self.start_frame_at(frame, Instant::now())
+    }
+
+    /// Number a frame that other connections send too, without writing to it.
+    /// Returns this connection's message: the header with its own sequence
+    /// number, followed by the shared payload.
+    pub fn start_shared_frame(&self, frame: &[u8]) -> Vec<u8> {
+        let sequence = self.register_frame_at(frame.len(), Instant::now());
+        let (header, payload) = frame.split_at(frame.len().min(FRAME_HEADER_LEN));
+        let mut message = Vec::with_capacity(frame.len());
+        message.extend_from_slice(header);
+        set_frame_sequence(&mut message, sequence);
+        message.extend_from_slice(payload);
+        message
+    }
+
+    /// Client's acknowledgement; also covers every earlier frame
+    pub fn acknowledge(&self, sequence: u32) {
+        self.acknowledge_at(sequence, Instant::now());
+    }
+
+    /// How long a write of a frame to the socket took
+    pub fn record_send(&self, blocked: Duration) {
+        let mut state = self.state.lock();
+        state.send_delay = (state.send_delay * 7 + blocked) / 8;
+    }
+
+    /// Frames currently allowed in flight
+    pub fn limit(&self) -> usize {
+        self.state.lock().limit()
+    }
+
+    /// Smoothed round trip from frame sent to frame acknowledged
+    pub fn rtt(&self) -> Option<Duration> {
+        self.state.lock().smoothed_rtt
+    }
+
+    /// Current view of the link
+    pub fn estimate(&self) -> NetworkEstimate {
+        let state = self.state.lock();
+        let millis = |duration: Duration| duration.as_millis().min(u32::MAX as u128) as u32;
+        NetworkEstimate {
+            rtt_ms: state.smoothed_rtt.map(millis),
+            min_rtt_ms: state.min_rtt.map(|(min_rtt, _)| millis(min_rtt)),
+            delivery_rate_kbps: state.delivery_rate.map(|rate| (rate * 8.0 / 1000.0) as u32),
+            send_delay_ms: millis(state.send_delay),
+            lost_frames: state.lost_frames,
+        }
+    }
+
+    fn has_room_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        while let Some(frame) = state.in_flight.front() {
+            if now.duration_since(frame.sent) < ACK_TIMEOUT {
+                break;
+            }
+            debug!("Frame {} was never acknowledged", frame.sequence);
+            state.in_flight.pop_front();
+            state.lost_frames += 1;
+        }
+        state.in_flight.len() < state.limit()
+    }
+
+    fn start_frame_at(&self, frame: &mut [u8], now: Instant) -> u32 {
+        let sequence = self.register_frame_at(frame.len(), now);
+        set_frame_sequence(frame, sequence);
+        sequence
+    }
+
+    /// Count a frame of `bytes` as in flight and return its sequence number
+    fn register_frame_at(&self, bytes: usize, now: Instant) -> u32 {
+        let mut state = self.state.lock();
+        let sequence = state.next_sequence;
+        state.next_sequence = sequence.wrapping_add(1);
+        // After an idle spell the rate is measured from this send, not the last ack
+        if state.in_flight.is_empty() {
+            state.delivered_at = Some(now);
+        }
+        let sent = SentFrame {
+            sequence,
+            sent: now,
+            bytes: bytes as u64,
+            delivered: state.delivered,
+            delivered_at: state.delivered_at.unwrap_or(now),
+        };
+        state.in_flight.push_back(sent);
+        sequence
+    }
+
+    fn acknowledge_at(&self, sequence: u32, now: Instant) {
+        let mut state = self.state.lock();
+        let mut sample = None;
+        // Sequence numbers wrap, so compare by distance rather than value
+        while let Some(frame) = state.in_flight.front() {
+            if (sequence.wrapping_sub(frame.sequence) as i32) < 0 {
+                break;
+            }
+            let frame = state.in_flight.pop_front().expect("front was just checked");
+            state.delivered += frame.bytes;
+            state.delivered_at = Some(now);
+            if frame.sequence == sequence {
+                sample = Some(frame);
+            }
+        }
+
+        let Some(frame) = sample else { return };
+
+        let elapsed = now.duration_since(frame.delivered_at).as_secs_f64();
+        if elapsed > 0.0 {
+            let rate = (state.delivered - frame.delivered) as f64 / elapsed;
+            state.delivery_rate = Some(match state.delivery_rate {
+                Some(smoothed) => (smoothed * 3.0 + rate) / 4.0,
+                None => rate,
+            });
+        }
+
+        let rtt = now.duration_since(frame.sent);
+        state.smoothed_rtt = Some(match state.smoothed_rtt {
+            Some(smoothed) => (smoothed * 7 + rtt) / 8,
+            None => rtt,
+        });
+        let replace_min = match state.min_rtt {
+            Some((min, measured)) => rtt <= min || now.duration_since(measured) > MIN_RTT_LIFETIME,
+            None => true,
+        };
+        if replace_min {
+            state.min_rtt = Some((rtt, now));
+        }
+    }
+
+}
+
+impl WindowState {
+    fn limit(&self) -> usize {
+        let Some((min_rtt, _)) = self.min_rtt else { return MIN_IN_FLIGHT };
+        let frames_per_rtt = min_rtt.as_nanos().div_ceil(self.frame_interval.as_nanos()) as usize;
+        (frames_per_rtt + 1).clamp(MIN_IN_FLIGHT, MAX_IN_FLIGHT)
+    }
+}
+
+fn frame_interval(target_fps: u32) -> Duration {
+    Duration::from_secs(1) / target_fps.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::frame::{parse_frame, FrameCodec, FrameHeader};
+
+    const FRAME: Duration = Duration::from_millis(16);
+
+    fn send(window: &FrameWindow, now: Instant) -> u32 {
+        window.start_frame_at(&mut [], now)
+    }
+
+    #[test]
+    fn window_fills_until_frames_are_acknowledged() {
+        let window = FrameWindow::new(60);
+        let start = Instant::now();
+
+        assert!(window.has_room_at(start));
+        send(&window, start);
+        let second = send(&window, start);
+        assert!(!window.has_room_at(start));
+
+        // One acknowledgement covers everything sent before it
+        window.acknowledge_at(second, start + FRAME);
+        assert!(window.has_room_at(start + FRAME));
+        assert_eq!(window.rtt(), Some(FRAME));
+    }
+
+    #[test]
+    fn shared_frames_are_numbered_per_connection_without_being_written() {
+        let shared = FrameHeader::video(FrameCodec::RgbaTiles, 4, 2, false, 7).encode(&[5; 12]);
+        let first = FrameWindow::new(60);
+        let second = FrameWindow::new(60);
+        send(&second, Instant::now());
+
+        let message = first.start_shared_frame(&shared);
+        let (header, payload) = parse_frame(&message).unwrap();
+        assert_eq!((header.sequence, header.pts, payload), (0, 7, &[5; 12][..]));
+
+        let (header, _) = parse_frame(&second.start_shared_frame(&shared)).unwrap();
+        assert_eq!(header.sequence, 1);
+        assert_eq!(parse_frame(&shared).unwrap().0.sequence, 0);
+        // The whole message counts toward delivery, not just the header
+        assert_eq!(first.state.lock().in_flight[0].bytes, shared.len() as u64);
+    }
+
+    #[test]
+    fn limit_follows_the_lowest_round_trip() {
+        let window = FrameWindow::new(60);
+        let start = Instant::now();
+        assert_eq!(window.limit(), MIN_IN_FLIGHT);
+
+        // 40ms takes three 16.7ms frames rounded up, plus one
+        let sequence = send(&window, start);
+        window.acknowledge_at(sequence, start + Duration::from_millis(40));
+        assert_eq!(window.limit(), 4);
+
+        // A slower sample from a queue building up does not widen the window
+        let sequence = send(&window, start + Duration::from_secs(1));
+        window.acknowledge_at(sequence, start + Duration::from_millis(1500));
+        assert_eq!(window.limit(), 4);
+
+        // Very long links are capped
+        let slow = FrameWindow::new(60);
+        let sequence = send(&slow, start);
+        slow.acknowledge_at(sequence, start + Duration::from_secs(1));
+        assert_eq!(slow.limit(), MAX_IN_FLIGHT);
+    }
+
+    #[test]
+    fn unacknowledged_frames_expire() {
+        let window = FrameWindow::new(60);
+        let start = Instant::now();
+        send(&window, start);
+        send(&window, start);
+        assert!(!window.has_room_at(start + Duration::from_secs(1)));
+        assert!(window.has_room_at(start + ACK_TIMEOUT));
+    }
+
+    #[test]
+    fn estimate_measures_delivery_rate_and_queueing() {
+        let window = FrameWindow::new(60);
+        let start = Instant::now();
+        assert_eq!(window.estimate(), NetworkEstimate::default());
+
+        // 10 KB acknowledged 20ms after it was sent is 4 Mbps
+        let first = window.start_frame_at(&mut [0; 10_000], start);
+        window.acknowledge_at(first, start + Duration::from_millis(20));
+        let estimate = window.estimate();
+        assert_eq!(estimate.delivery_rate_kbps, Some(4000));
+        assert_eq!(estimate.min_rtt_ms, Some(20));
+        assert_eq!(estimate.queueing_delay_ms(), 0);
+
+        // A frame that waited behind others raises the round trip above the minimum
+        let second = window.start_frame_at(&mut [0; 10_000], start + FRAME);
+        window.acknowledge_at(second, start + FRAME + Duration::from_millis(180));
+        let estimate = window.estimate();
+        assert_eq!(estimate.rtt_ms, Some(40));
+        assert_eq!(estimate.queueing_delay_ms(), 20);
+
+        window.record_send(Duration::from_millis(80));
+        assert_eq!(window.estimate().send_delay_ms, 10);
+    }
+
+    #[test]
+    fn expired_frames_count_as_lost() {
+        let window = FrameWindow::new(60);
+        let start = Instant::now();
+        send(&window, start);
+        window.has_room_at(start + ACK_TIMEOUT);
+        assert_eq!(window.estimate().lost_frames, 1);
+    }
+
+    #[test]
+    fn stale_and_unknown_acks_are_harmless() {
+        let window = FrameWindow::new(60);
+        let start = Instant::now();
+        let first = send(&window, start);
+        let second = send(&window, start);
+
+        window.acknowledge_at(second, start + FRAME);
+        window.acknowledge_at(first, start + FRAME * 2);
+        window.acknowledge_at(99, start + FRAME * 3);
+        assert_eq!(window.rtt(), Some(FRAME));
+        assert!(window.has_room_at(start + FRAME * 3));
+    }
+}
@@ -0,0 +1,214 @@
+//! Binary media frame format shared by every stream handler
+//!
+//! Each binary WebSocket message carries exactly one frame: a fixed
+//! [`FRAME_HEADER_LEN`]-byte header followed by the payload. All integers
+//! are little-endian.
+//!
+//! | Offset | Size | Field                                                   |
+//! |-------:|-----:|---------------------------------------------------------|
+//! | 0      | 4    | Magic `KVMF`                                            |
+//! | 4      | 1    | Format version, currently [`FRAME_VERSION`]             |
+//! | 5      | 1    | Stream id, [`VIDEO_STREAM`]; other ids are reserved     |
+//! | 6      | 1    | Payload codec, see [`FrameCodec`]                       |
+//! | 7      | 1    | Flags: bit 0 marks a keyframe, other bits are reserved  |
+//! | 8      | 4    | Width in pixels                                         |
+//! | 12     | 4    | Height in pixels                                        |
+//! | 16     | 8    | Presentation timestamp, microseconds since the epoch    |
+//! | 24     | 4    | Per-connection sequence number, echoed in `frame_ack`   |
+//! | 28     | 4    | Payload length in bytes                                 |
+//!
+//! The sequence number is written by whoever sends the frame on a connection
+//! (see `flow_control`), since one encoded frame may go to several viewers.
+//!
+//! `parseMediaFrame` in `web-client/kvm-client.js` reads the same layout;
+//! bump [`FRAME_VERSION`] when it changes.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Leading bytes of every frame
+pub const FRAME_MAGIC: [u8; 4] = *b"KVMF";
+/// Layout version written after the magic
+pub const FRAME_VERSION: u8 = 4;
+/// Size of the header in front of the payload
+pub const FRAME_HEADER_LEN: usize = 32;
+/// Offset of the sequence number, which is filled in when the frame is sent
+const SEQUENCE_OFFSET: usize = 24;
+/// Stream id of the monitor video
+pub const VIDEO_STREAM: u8 = 0;
+/// Flag bit set on frames that decode without any earlier frame
+pub const FLAG_KEYFRAME: u8 = 0x01;
+
+/// How the payload after the header is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameCodec {
+    /// VP8 bitstream, decoded with WebCodecs
+    Vp8 = 1,
+    /// Uncompressed I420: the Y plane, then U and V at half size rounded up
+    Yuv420 = 2,
+    /// Tightly packed RGBA pixels
+    Rgba = 3,
+    /// Runs of identical pixels: a count byte followed by one RGBA pixel
+    RgbaRle = 4,
+    /// Rectangles that changed since the previous frame, drawn over it; see `tile_codec`
+    RgbaTiles = 5,
+    /// Areas copied within the previous picture, e.g. after a scroll, then an `RgbaTiles` payload
+    RgbaCopyTiles = 6,
+}
+
+impl FrameCodec {
+    #[cfg(test)]
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FrameCodec::Vp8),
+            2 => Some(FrameCodec::Yuv420),
+            3 => Some(FrameCodec::Rgba),
+            4 => Some(FrameCodec::RgbaRle),
+            5 => Some(FrameCodec::RgbaTiles),
+            6 => Some(FrameCodec::RgbaCopyTiles),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata written in front of every media payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub stream: u8,
+    pub codec: FrameCodec,
+    pub keyframe: bool,
+    pub width: u32,
+    pub height: u32,
+    /// Capture time in microseconds since the Unix epoch
+    pub pts: u64,
+    /// Zero until [`set_frame_sequence`] stamps the frame for one connection
+    pub sequence: u32,
+}
+
+impl FrameHeader {
+    /// Header for a frame of the monitor video
+    pub fn video(codec: FrameCodec, width: u32, height: u32, keyframe: bool, pts: u64) -> Self {
+        Self { stream: VIDEO_STREAM, codec, keyframe, width, height, pts, sequence: 0 }
+    }
+
+    /// Header followed by the payload, ready to send as one binary message
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        self.encode_parts(&[payload])
+    }
+
+    /// Like [`encode`](Self::encode) for a payload stored in several pieces, such as YUV planes
+    pub fn encode_parts(&self, parts: &[&[u8]]) -> Vec<u8> {
+        let payload_len: usize = parts.iter().map(|part| part.len()).sum();
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload_len);
+        frame.extend_from_slice(&FRAME_MAGIC);
+        frame.push(FRAME_VERSION);
+        frame.push(self.stream);
+        frame.push(self.codec as u8);
+        frame.push(if self.keyframe { FLAG_KEYFRAME } else { 0 });
+        frame.extend_from_slice(&self.width.to_le_bytes());
+        frame.extend_from_slice(&self.height.to_le_bytes());
+        frame.extend_from_slice(&self.pts.to_le_bytes());
+        frame.extend_from_slice(&self.sequence.to_le_bytes());
+        frame.extend_from_slice(&(payload_len as u32).to_le_bytes());
+        for part in parts {
+            frame.extend_from_slice(part);
+        }
+        frame
+    }
+}
+
+/// Number an encoded frame for the connection it is about to be sent on.
+/// Frames can be shared between viewers, so this is done on the sender's copy.
+pub fn set_frame_sequence(frame: &mut [u8], sequence: u32) {
+    if frame.len() >= FRAME_HEADER_LEN {
+        frame[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 4].copy_from_slice(&sequence.to_le_bytes());
+    }
+}
+
+/// Current time as a frame timestamp
+pub fn frame_pts() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Split a frame back into header and payload; only the client does this outside tests
+#[cfg(test)]
+pub(crate) fn parse_frame(bytes: &[u8]) -> Option<(FrameHeader, &[u8])> {
+    if bytes.len() < FRAME_HEADER_LEN || bytes[..4] != FRAME_MAGIC || bytes[4] != FRAME_VERSION {
+        return None;
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+    let header = FrameHeader {
+        stream: bytes[5],
+        codec: FrameCodec::from_u8(bytes[6])?,
+        keyframe: bytes[7] & FLAG_KEYFRAME != 0,
+        width: u32_at(8),
+        height: u32_at(12),
+        pts: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        sequence: u32_at(SEQUENCE_OFFSET),
+    };
+    let payload = bytes.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + u32_at(28) as usize)?;
+    Some((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_documented_layout() {
+        let header = FrameHeader::video(FrameCodec::Vp8, 1920, 1080, true, 0x0102_0304_0506_0708);
+        let frame = header.encode(&[0xAA, 0xBB]);
+
+        assert_eq!(frame.len(), FRAME_HEADER_LEN + 2);
+        assert_eq!(&frame[..4], b"KVMF");
+        assert_eq!(frame[4], FRAME_VERSION);
+        assert_eq!(frame[5], VIDEO_STREAM);
+        assert_eq!(frame[6], 1);
+        assert_eq!(frame[7], FLAG_KEYFRAME);
+        assert_eq!(&frame[8..12], &1920u32.to_le_bytes());
+        assert_eq!(&frame[12..16], &1080u32.to_le_bytes());
+        assert_eq!(&frame[16..24], &[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(&frame[24..28], &0u32.to_le_bytes());
+        assert_eq!(&frame[28..32], &2u32.to_le_bytes());
+        assert_eq!(&frame[32..], &[0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn round_trips_split_payloads() {
+        let header = FrameHeader::video(FrameCodec::Yuv420, 4, 2, false, 42);
+        let frame = header.encode_parts(&[&[1; 8], &[2; 2], &[3; 2]]);
+
+        let (parsed, payload) = parse_frame(&frame).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload.len(), 12);
+        assert_eq!(payload[8..10], [2, 2]);
+    }
+
+    #[test]
+    fn sequence_is_stamped_in_place() {
+        let mut frame = FrameHeader::video(FrameCodec::Rgba, 1, 1, true, 7).encode(&[9; 4]);
+        set_frame_sequence(&mut frame, 513);
+
+        let (header, payload) = parse_frame(&frame).unwrap();
+        assert_eq!(header.sequence, 513);
+        assert_eq!(header.pts, 7);
+        assert_eq!(payload, &[9; 4]);
+    }
+
+    #[test]
+    fn rejects_foreign_and_truncated_frames() {
+        let frame = FrameHeader::video(FrameCodec::Rgba, 1, 1, true, 0).encode(&[0; 4]);
+
+        assert!(parse_frame(&frame[..frame.len() - 1]).is_none());
+        assert!(parse_frame(b"RGBA\x01\x00\x00\x00").is_none());
+
+        let mut newer = frame.clone();
+        newer[4] = FRAME_VERSION + 1;
+        assert!(parse_frame(&newer).is_none());
+    }
+}
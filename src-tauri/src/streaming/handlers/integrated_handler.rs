@@ -5,17 +5,22 @@ use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use parking_lot::{Mutex, RwLock};
 use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use axum::extract::ws::{Message, WebSocket};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::streaming::{
-    YUV420Encoder, YUV420Config, YUV420EncoderError,
+    YUV420Encoder, YUV420Config, YUV420EncoderError, VideoCodec, FrameWindow,
+    CongestionController, EncoderTarget, NetworkEstimate, UPDATE_INTERVAL,
     // EnhancedVideoEncoder, EnhancedVideoConfig, VideoEncoderError,
     // EnhancedAudioEncoder, EnhancedAudioConfig, AudioEncoderError,
     SystemAudioCapture,
 };
+use crate::core::{
+    check_protocol_version, list_monitors, ClientMessage, ClipboardSession, InputDispatcher, InputHandler, ProtocolError,
+    ServerMessage, SessionCommand, SessionContext, SessionRole, StreamRequest, TransferSession, PROTOCOL_VERSION,
+};
 
 /// Integrated streaming handler errors
 #[derive(Error, Debug)]
@@ -30,6 +35,10 @@ pub enum IntegratedStreamError {
     WebSocket(String),
     #[error("Configuration error: {0}")]
     Config(String),
+    #[error("Input error: {0}")]
+    Input(String),
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
 }
 
 /// Streaming configuration combining video and audio
@@ -75,6 +84,7 @@ impl IntegratedStreamConfig {
                 opus_bitrate: 256000, // 256 kbps for audio
                 temporal_layers: 1,
                 spatial_layers: 1,
+                codec: VideoCodec::Vp8,
             },
             // audio: EnhancedAudioConfig::for_high_quality(),
             enable_audio: false, // Temporarily disabled
@@ -101,6 +111,7 @@ impl IntegratedStreamConfig {
                 opus_bitrate: 128000, // 128 kbps for audio
                 temporal_layers: 1,
                 spatial_layers: 1,
+                codec: VideoCodec::Vp8,
             },
             // audio: EnhancedAudioConfig::default(),
             enable_audio: false, // Temporarily disabled
@@ -127,6 +138,7 @@ impl IntegratedStreamConfig {
                 opus_bitrate: 96000, // 96 kbps for audio
                 temporal_layers: 1,
                 spatial_layers: 1,
+                codec: VideoCodec::Vp8,
             },
             // audio: EnhancedAudioConfig::for_low_latency(),
             enable_audio: false, // Disable audio for lowest latency
@@ -153,6 +165,7 @@ impl IntegratedStreamConfig {
                 opus_bitrate: 320000, // 320 kbps for high-quality audio
                 temporal_layers: 2, // Use temporal layering for WebM
                 spatial_layers: 1,
+                codec: VideoCodec::Vp8,
             },
             // audio: EnhancedAudioConfig::for_webm(),
             enable_audio: false, // Temporarily disabled
@@ -179,6 +192,7 @@ impl IntegratedStreamConfig {
                 opus_bitrate: 0, // No audio
                 temporal_layers: 3, // More temporal layers for smooth playback
                 spatial_layers: 1,
+                codec: VideoCodec::Vp8,
             },
             // audio: EnhancedAudioConfig::default(),
             enable_audio: false,
@@ -190,31 +204,10 @@ impl IntegratedStreamConfig {
     }
 }
 
-/// Stream packet types
+/// JSON control packets; media travels as binary frames (see `streaming::frame`)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum StreamPacket {
-    #[serde(rename = "video_frame")]
-    VideoFrame {
-        data: Vec<u8>,
-        width: u32,
-        height: u32,
-        frame_number: u64,
-        is_keyframe: bool,
-        timestamp: u64,
-        format: String, // "yuv420_vp8" or "yuv420_webm"
-    },
-    
-    #[serde(rename = "audio_frame")]
-    AudioFrame {
-        data: Vec<u8>,
-        sample_rate: u32,
-        channels: u8,
-        frame_number: u64,
-        timestamp: u64,
-        format: String, // "opus"
-    },
-    
     #[serde(rename = "stream_info")]
     StreamInfo {
         video_config: VideoStreamInfo,
@@ -258,9 +251,12 @@ pub struct AudioStreamInfo {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerInfo {
     pub hostname: String,
+    /// Version of the control protocol, see `core::protocol`
+    pub protocol: u32,
     pub monitor_count: usize,
     pub current_monitor: usize,
     pub capabilities: Vec<String>,
+    pub role: SessionRole,
 }
 
 /// Integrated streaming handler with YUV420 video and Opus audio
@@ -274,11 +270,17 @@ pub struct IntegratedStreamHandler {
     is_active: Arc<AtomicBool>,
     frame_count: Arc<AtomicU64>,
     
+    // Frames in flight to the client, paced by its acknowledgements
+    frame_window: Arc<FrameWindow>,
+    
     // Performance monitoring
     stream_stats: Arc<StreamStats>,
     
-    // Adaptive quality control
-    quality_controller: Arc<Mutex<QualityController>>,
+    // Encoder settings picked from the server's own view of the link
+    congestion: Arc<Mutex<CongestionController>>,
+    
+    // Role of this connection and the admin command channel
+    session: SessionContext,
 }
 
 /// Streaming performance statistics
@@ -290,7 +292,8 @@ pub struct StreamStats {
     pub current_video_bitrate: AtomicU64,
     pub current_audio_bitrate: AtomicU64,
     pub avg_fps: AtomicU64,
-    pub client_latency_ms: AtomicU64,
+    pub rtt_ms: AtomicU64,
+    pub delivery_rate_kbps: AtomicU64,
     pub last_update: Mutex<Instant>,
 }
 
@@ -303,7 +306,8 @@ impl StreamStats {
             current_video_bitrate: AtomicU64::new(0),
             current_audio_bitrate: AtomicU64::new(0),
             avg_fps: AtomicU64::new(0),
-            client_latency_ms: AtomicU64::new(0),
+            rtt_ms: AtomicU64::new(0),
+            delivery_rate_kbps: AtomicU64::new(0),
             last_update: Mutex::new(Instant::now()),
         }
     }
@@ -318,7 +322,12 @@ impl StreamStats {
         self.total_bytes_sent.fetch_add(bytes_sent as u64, Ordering::Relaxed);
     }
     
-    pub fn get_stats(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
+    pub fn update_network_stats(&self, estimate: &NetworkEstimate) {
+        self.rtt_ms.store(estimate.rtt_ms.unwrap_or(0) as u64, Ordering::Relaxed);
+        self.delivery_rate_kbps.store(estimate.delivery_rate_kbps.unwrap_or(0) as u64, Ordering::Relaxed);
+    }
+    
+    pub fn get_stats(&self) -> (u64, u64, u64, u64, u64, u64, u64, u64) {
         (
             self.video_frames_sent.load(Ordering::Relaxed),
             self.audio_frames_sent.load(Ordering::Relaxed),
@@ -326,95 +335,12 @@ impl StreamStats {
             self.current_video_bitrate.load(Ordering::Relaxed),
             self.current_audio_bitrate.load(Ordering::Relaxed),
             self.avg_fps.load(Ordering::Relaxed),
-            self.client_latency_ms.load(Ordering::Relaxed),
+            self.rtt_ms.load(Ordering::Relaxed),
+            self.delivery_rate_kbps.load(Ordering::Relaxed),
         )
     }
 }
 
-/// Adaptive quality controller
-#[derive(Debug)]
-pub struct QualityController {
-    pub target_bandwidth: u32,
-    pub current_bandwidth: u32,
-    pub adaptation_history: Vec<QualityAdjustment>,
-    pub last_adjustment: Instant,
-    pub adjustment_interval: Duration,
-}
-
-#[derive(Debug, Clone)]
-pub struct QualityAdjustment {
-    pub timestamp: Instant,
-    pub video_bitrate: u32,
-    pub audio_bitrate: u32,
-    pub reason: String,
-}
-
-impl QualityController {
-    pub fn new(target_bandwidth: u32) -> Self {
-        Self {
-            target_bandwidth,
-            current_bandwidth: 0,
-            adaptation_history: Vec::new(),
-            last_adjustment: Instant::now(),
-            adjustment_interval: Duration::from_secs(5), // Adjust at most every 5 seconds
-        }
-    }
-    
-    pub fn should_adjust(&self, current_latency: u64, packet_loss: f64) -> bool {
-        let time_since_last = self.last_adjustment.elapsed();
-        time_since_last >= self.adjustment_interval && 
-        (current_latency > 200 || packet_loss > 2.0 || self.current_bandwidth > self.target_bandwidth)
-    }
-    
-    pub fn calculate_adjustment(&mut self, current_latency: u64, packet_loss: f64, current_video_bitrate: u32, current_audio_bitrate: u32) -> Option<QualityAdjustment> {
-        if !self.should_adjust(current_latency, packet_loss) {
-            return None;
-        }
-        
-        let (new_video_bitrate, new_audio_bitrate, reason) = if current_latency > 500 || packet_loss > 10.0 {
-            // Severe network issues - aggressive reduction
-            (
-                (current_video_bitrate as f32 * 0.5) as u32,
-                (current_audio_bitrate as f32 * 0.7) as u32,
-                "Severe network conditions".to_string()
-            )
-        } else if current_latency > 200 || packet_loss > 5.0 {
-            // Moderate network issues
-            (
-                (current_video_bitrate as f32 * 0.7) as u32,
-                (current_audio_bitrate as f32 * 0.8) as u32,
-                "Moderate network conditions".to_string()
-            )
-        } else if current_latency < 50 && packet_loss < 1.0 && self.current_bandwidth < self.target_bandwidth * 80 / 100 {
-            // Good network conditions - can increase quality
-            (
-                std::cmp::min((current_video_bitrate as f32 * 1.2) as u32, self.target_bandwidth * 80 / 100),
-                std::cmp::min((current_audio_bitrate as f32 * 1.1) as u32, 256000),
-                "Good network conditions".to_string()
-            )
-        } else {
-            return None; // No adjustment needed
-        };
-        
-        let adjustment = QualityAdjustment {
-            timestamp: Instant::now(),
-            video_bitrate: new_video_bitrate,
-            audio_bitrate: new_audio_bitrate,
-            reason,
-        };
-        
-        self.adaptation_history.push(adjustment.clone());
-        self.last_adjustment = Instant::now();
-        
-        // Keep only last 10 adjustments
-        if self.adaptation_history.len() > 10 {
-            self.adaptation_history.remove(0);
-        }
-        
-        Some(adjustment)
-    }
-}
-
 impl IntegratedStreamHandler {
     /// Create a new integrated streaming handler
     pub fn new(config: IntegratedStreamConfig) -> Result<Self, IntegratedStreamError> {
@@ -458,13 +384,26 @@ impl IntegratedStreamHandler {
             audio_capture,
             is_active: Arc::new(AtomicBool::new(false)),
             frame_count: Arc::new(AtomicU64::new(0)),
+            frame_window: Arc::new(FrameWindow::new(config.video.framerate)),
             stream_stats: Arc::new(StreamStats::new()),
-            quality_controller: Arc::new(Mutex::new(QualityController::new(config.max_bandwidth_kbps))),
+            congestion: Arc::new(Mutex::new(CongestionController::new(EncoderTarget {
+                bitrate_kbps: config.video.bitrate,
+                width: config.video.width,
+                height: config.video.height,
+                framerate: config.video.framerate,
+            }))),
+            session: SessionContext::standalone(),
         };
         
         Ok(handler)
     }
     
+    /// Set the session role; viewers cannot send input
+    pub fn with_session(mut self, session: SessionContext) -> Self {
+        self.session = session;
+        self
+    }
+    
     /// Handle WebSocket connection with integrated streaming
     pub async fn handle_connection(
         mut self,
@@ -489,6 +428,21 @@ impl IntegratedStreamHandler {
         
         self.is_active.store(true, Ordering::Relaxed);
         
+        // Host input runs on its own thread; its errors come back as text messages
+        let (control_tx, mut control_rx) = mpsc::channel::<String>(10);
+        let input_dispatcher = match InputDispatcher::for_session(InputHandler::for_monitor(self.config.monitor_id), self.session.role.can_control(), control_tx.clone()) {
+            Ok(dispatcher) => dispatcher,
+            Err(e) => {
+                error!("{}", IntegratedStreamError::Input(e));
+                let _ = websocket.close().await;
+                return;
+            }
+        };
+        
+        let clipboard = ClipboardSession::new(&self.session, control_tx.clone());
+        let transfers = TransferSession::new(&self.session, control_tx.clone());
+        let mut commands = self.session.subscribe();
+        
         // Create channels for frame processing
         let (video_tx, mut video_rx) = mpsc::unbounded_channel();
         let (audio_tx, mut audio_rx) = mpsc::unbounded_channel::<Vec<u8>>();
@@ -504,6 +458,8 @@ impl IntegratedStreamHandler {
             None
         };
         
+        let mut adapt_interval = tokio::time::interval(UPDATE_INTERVAL);
+        
         // Main streaming loop
         loop {
             tokio::select! {
@@ -530,6 +486,40 @@ impl IntegratedStreamHandler {
                     }
                 }
                 
+                // Retarget the encoder from what this connection measured and report it
+                _ = adapt_interval.tick() => {
+                    let stats = self.adapt_to_network();
+                    if let Err(e) = websocket.send(Message::Text(stats)).await {
+                        error!("Failed to send streaming stats: {}", e);
+                        break;
+                    }
+                }
+                
+                // Forward control replies (input errors) to the client
+                Some(text) = control_rx.recv() => {
+                    if let Err(e) = websocket.send(Message::Text(text)).await {
+                        error!("Failed to send control message: {}", e);
+                        break;
+                    }
+                }
+                
+                // Apply admin commands and tell the client what changed
+                command = commands.recv() => {
+                    match command {
+                        Ok(command) => {
+                            self.apply_session_command(&command);
+                            if let Err(e) = websocket.send(Message::Text(command.to_message())).await {
+                                error!("Failed to send session command: {}", e);
+                                break;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("Missed {} session commands", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => {}
+                    }
+                }
+                
                 // Handle audio frames - temporarily disabled
                 // frame_data = async {
                 //     if let Some(ref mut rx) = audio_rx.as_mut() {
@@ -550,7 +540,7 @@ impl IntegratedStreamHandler {
                 msg = websocket.next() => {
                     match msg {
                         Some(Ok(Message::Text(text))) => {
-                            if let Err(e) = self.handle_client_message(&text).await {
+                            if let Err(e) = self.handle_client_message(&text, &input_dispatcher, &clipboard, &transfers, &control_tx).await {
                                 warn!("Error handling client message: {}", e);
                             }
                         }
@@ -570,6 +560,7 @@ impl IntegratedStreamHandler {
         
         // Cleanup
         self.is_active.store(false, Ordering::Relaxed);
+        input_dispatcher.release_all();
         
         // Wait for handlers to finish
         let _ = video_handler.await;
@@ -618,8 +609,15 @@ impl IntegratedStreamHandler {
             height: self.config.video.height,
             framerate: self.config.video.framerate,
             bitrate: self.config.video.bitrate,
-            codec: "VP8".to_string(),
-            format: if self.config.video.use_webm_container { "webm" } else { "raw_vp8" }.to_string(),
+            codec: match self.config.video.codec {
+                VideoCodec::Vp8 => "VP8",
+                VideoCodec::RawYuv => "YUV420",
+            }.to_string(),
+            // Payload codec inside the binary media frames
+            format: match self.config.video.codec {
+                VideoCodec::Vp8 => "vp8",
+                VideoCodec::RawYuv => "yuv420",
+            }.to_string(),
         };
         
         let audio_config = if self.config.enable_audio {
@@ -637,14 +635,16 @@ impl IntegratedStreamHandler {
         
         let server_info = ServerInfo {
             hostname: gethostname::gethostname().to_string_lossy().to_string(),
-            monitor_count: xcap::Monitor::all().unwrap_or_default().len(),
+            protocol: PROTOCOL_VERSION,
+            monitor_count: list_monitors().map(|m| m.len()).unwrap_or(0),
             current_monitor: self.config.monitor_id,
             capabilities: vec![
                 "yuv420_vp8".to_string(),
-                "webm_container".to_string(),
+                "media_frames".to_string(),
                 if self.config.enable_audio { "opus_audio" } else { "no_audio" }.to_string(),
                 "adaptive_quality".to_string(),
             ],
+            role: self.session.role,
         };
         
         let stream_info = StreamPacket::StreamInfo {
@@ -668,10 +668,10 @@ impl IntegratedStreamHandler {
         let video_encoder = Arc::clone(&self.video_encoder);
         let is_active = Arc::clone(&self.is_active);
         let stream_stats = Arc::clone(&self.stream_stats);
+        let frame_window = Arc::clone(&self.frame_window);
         let framerate = self.config.video.framerate;
         
         tokio::spawn(async move {
-            let frame_duration = Duration::from_millis(1000 / framerate as u64);
             let mut last_keyframe = Instant::now();
             let keyframe_interval = Duration::from_secs(2); // Force keyframe every 2 seconds
             
@@ -679,6 +679,15 @@ impl IntegratedStreamHandler {
             
             while is_active.load(Ordering::Relaxed) {
                 let start_time = Instant::now();
+                // The congestion controller may lower the frame rate on a slow link
+                let frame_duration = Duration::from_secs(1) / video_encoder.lock().framerate().max(1);
+                
+                // Skip capture while the client is behind; VP8 inter frames must never be
+                // dropped once encoded, so the wait happens before the encoder sees anything
+                if !frame_window.has_room() {
+                    tokio::time::sleep(frame_duration).await;
+                    continue;
+                }
                 
                 // Force keyframe if needed
                 let force_keyframe = last_keyframe.elapsed() >= keyframe_interval;
@@ -686,10 +695,19 @@ impl IntegratedStreamHandler {
                     last_keyframe = Instant::now();
                 }
                 
-                // Capture and encode frame
-                match video_encoder.lock().capture_and_encode(force_keyframe) {
-                    Ok(Some(encoded_data)) => {
+                // Capture and encode frame, off the async workers since screen capture blocks
+                let encoder = Arc::clone(&video_encoder);
+                let encoded = match tokio::task::spawn_blocking(move || encoder.lock().capture_and_encode(force_keyframe)).await {
+                    Ok(encoded) => encoded,
+                    Err(e) => {
+                        error!("Video capture task failed: {}", e);
+                        break;
+                    }
+                };
+                match encoded {
+                    Ok(Some(mut encoded_data)) => {
                         stream_stats.update_video_stats(encoded_data.len());
+                        frame_window.start_frame(&mut encoded_data);
                         
                         if let Err(e) = tx.send(encoded_data) {
                             error!("Failed to send video frame to channel: {}", e);
@@ -767,65 +785,133 @@ impl IntegratedStreamHandler {
     //     })
     // }
     
-    /// Send video frame to client
+    /// Send an encoded video frame to the client
     async fn send_video_frame(&self, websocket: &mut WebSocket, frame_data: Vec<u8>) -> Result<(), IntegratedStreamError> {
-        let frame_number = self.frame_count.fetch_add(1, Ordering::Relaxed);
-        
-        let packet = StreamPacket::VideoFrame {
-            data: frame_data,
-            width: self.config.video.width,
-            height: self.config.video.height,
-            frame_number,
-            is_keyframe: frame_number % self.config.video.keyframe_interval as u64 == 0,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_micros() as u64,
-            format: if self.config.video.use_webm_container { "yuv420_webm" } else { "yuv420_vp8" }.to_string(),
-        };
-        
-        let json = serde_json::to_string(&packet)
-            .map_err(|e| IntegratedStreamError::WebSocket(format!("JSON serialization failed: {}", e)))?;
-        
-        websocket.send(Message::Text(json)).await
+        // The encoder already wrapped the payload in a media frame
+        self.frame_count.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        websocket.send(Message::Binary(frame_data)).await
             .map_err(|e| IntegratedStreamError::WebSocket(format!("Failed to send video frame: {}", e)))?;
-        
+        // A write that blocks means the socket buffer is full
+        self.frame_window.record_send(started.elapsed());
         Ok(())
     }
     
     /// Send audio frame to client - temporarily disabled
     // async fn send_audio_frame(&self, websocket: &mut WebSocket, frame_data: Vec<u8>) -> Result<(), IntegratedStreamError> {
-    //     let packet = StreamPacket::AudioFrame {
-    //         data: frame_data,
-    //         sample_rate: self.config.audio.sample_rate,
-    //         channels: self.config.audio.channels,
-    //         frame_number: 0, // Audio frames don't need frame numbers
-    //         timestamp: std::time::SystemTime::now()
-    //             .duration_since(std::time::UNIX_EPOCH)
-    //             .unwrap_or_default()
-    //             .as_micros() as u64,
-    //         format: "opus".to_string(),
+    //     // Audio needs its own stream id and an Opus entry in FrameCodec
+    //     let header = FrameHeader {
+    //         stream: AUDIO_STREAM,
+    //         codec: FrameCodec::Opus,
+    //         keyframe: true,
+    //         width: 0,
+    //         height: 0,
+    //         pts: frame_pts(),
     //     };
     //     
-    //     let json = serde_json::to_string(&packet)
-    //         .map_err(|e| IntegratedStreamError::WebSocket(format!("JSON serialization failed: {}", e)))?;
-    //     
-    //     websocket.send(Message::Text(json)).await
-    //         .map_err(|e| IntegratedStreamError::WebSocket(format!("Failed to send audio frame: {}", e)))?;
-    //     
-    //     Ok(())
+    //     websocket.send(Message::Binary(header.encode(&frame_data))).await
+    //         .map_err(|e| IntegratedStreamError::WebSocket(format!("Failed to send audio frame: {}", e)))
     // }
     
     /// Handle client messages
-    async fn handle_client_message(&self, message: &str) -> Result<(), IntegratedStreamError> {
-        // Parse client message (ping, quality requests, etc.)
-        // This is a placeholder for actual message handling
-        debug!("Received client message: {}", message);
+    async fn handle_client_message(&self, message: &str, input_dispatcher: &InputDispatcher, clipboard: &ClipboardSession, transfers: &TransferSession, control_tx: &mpsc::Sender<String>) -> Result<(), IntegratedStreamError> {
+        match ClientMessage::parse(message) {
+            Ok(ClientMessage::Stream(request)) => self.handle_stream_request(request, control_tx)?,
+            // Input events go straight to the input thread
+            Ok(ClientMessage::Input(event)) => input_dispatcher.handle_event(event),
+            Ok(ClientMessage::Admin(command)) => self.session.handle_command(command, control_tx),
+            Ok(ClientMessage::Clipboard(request)) => clipboard.handle_request(request),
+            Ok(ClientMessage::Transfer(request)) => transfers.handle_request(request),
+            Err(e) => {
+                let _ = control_tx.try_send(e.to_message());
+                return Err(e.into());
+            }
+        }
         Ok(())
     }
     
+    fn handle_stream_request(&self, request: StreamRequest, control_tx: &mpsc::Sender<String>) -> Result<(), IntegratedStreamError> {
+        match request {
+            StreamRequest::Hello { protocol } => {
+                if let Err(e) = check_protocol_version(protocol) {
+                    let _ = control_tx.try_send(e.to_message());
+                    return Err(e.into());
+                }
+            }
+            StreamRequest::Ping { timestamp } => {
+                let _ = control_tx.try_send(ServerMessage::pong(timestamp).to_message());
+            }
+            StreamRequest::FrameAck { sequence } => self.frame_window.acknowledge(sequence),
+            StreamRequest::RequestKeyframe => {
+                info!("🔑 Keyframe requested by client");
+                self.video_encoder.lock().force_keyframe();
+            }
+            // Adaptation uses the server's own measurements, see `adapt_to_network`
+            StreamRequest::NetworkStats { stats } => {
+                debug!("Client reports {}ms latency, {}% loss", stats.latency, stats.packet_loss);
+            }
+            // This client's own preference, applied like an admin's but to this session only
+            StreamRequest::QualityUpdate { quality, .. } => {
+                self.apply_session_command(&SessionCommand::SetQuality { quality: quality.clamp(10, 100) });
+            }
+            StreamRequest::PerformanceMode { .. } | StreamRequest::EmergencyReset => {
+                debug!("Performance modes only apply to RGBA streams");
+            }
+        }
+        Ok(())
+    }
+    
+    /// Quality changes take effect here; monitor changes are left to the client,
+    /// which reconnects to the new monitor when it sees the command
+    fn apply_session_command(&self, command: &SessionCommand) {
+        if let SessionCommand::SetQuality { quality } = *command {
+            // Quality sets the ceiling; the congestion controller stays below it
+            let bitrate = SessionCommand::scaled_bitrate(quality, self.config.video.bitrate);
+            let target = self.congestion.lock().set_max_bitrate(bitrate);
+            self.apply_target(target);
+        }
+    }
+    
+    /// Feed this connection's measurements to the congestion controller and
+    /// return the `streaming_stats` message reporting them
+    fn adapt_to_network(&self) -> String {
+        let estimate = self.frame_window.estimate();
+        self.stream_stats.update_network_stats(&estimate);
+        
+        let retarget = if self.config.adaptive_quality {
+            self.congestion.lock().update(&estimate)
+        } else {
+            None
+        };
+        if let Some(target) = retarget {
+            self.apply_target(target);
+        }
+        
+        ServerMessage::StreamingStats {
+            frames_sent: self.frame_count.load(Ordering::Relaxed),
+            current_bitrate_kbps: Some(self.congestion.lock().target().bitrate_kbps),
+            network: estimate,
+        }
+        .to_message()
+    }
+    
+    /// Reconfigure the encoder and pacing for new settings
+    fn apply_target(&self, target: EncoderTarget) {
+        let mut video_config = self.config.video.clone();
+        video_config.bitrate = target.bitrate_kbps;
+        video_config.width = target.width;
+        video_config.height = target.height;
+        video_config.framerate = target.framerate;
+        if let Err(e) = self.video_encoder.lock().update_config(video_config) {
+            warn!("Failed to apply encoder settings {:?}: {}", target, e);
+            return;
+        }
+        self.frame_window.set_frame_rate(target.framerate);
+        self.stream_stats.current_video_bitrate.store(target.bitrate_kbps as u64, Ordering::Relaxed);
+    }
+    
     /// Get streaming statistics
-    pub fn get_stats(&self) -> (u64, u64, u64, u64, u64, u64, u64) {
+    pub fn get_stats(&self) -> (u64, u64, u64, u64, u64, u64, u64, u64) {
         self.stream_stats.get_stats()
     }
 }
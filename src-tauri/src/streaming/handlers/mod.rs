@@ -6,7 +6,9 @@
 pub mod realtime_stream;
 pub mod integrated_handler;
 pub mod ultra_stream;
+pub mod capture_hub;
 
 pub use realtime_stream::*;
 pub use integrated_handler::*;
 pub use ultra_stream::*;
+pub use capture_hub::*;
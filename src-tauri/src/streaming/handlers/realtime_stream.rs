@@ -4,35 +4,51 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::{sync::{broadcast, Mutex}, time};
 use log::{debug, error, info, warn};
-use serde_json::json;
 use anyhow::Result;
 
-use crate::streaming::{RealtimeStreamEncoder, RealtimeConfig};
-use crate::core::InputHandler;
-use crate::network::models::NetworkStats;
+use crate::streaming::{CongestionController, FrameWindow, RealtimeStreamEncoder, RealtimeConfig, UPDATE_INTERVAL};
+use crate::core::{
+    check_protocol_version, ClientMessage, ClipboardSession, InputDispatcher, InputHandler, ServerMessage,
+    SessionCommand, SessionContext, StreamRequest, TransferSession,
+};
 
 pub struct RealtimeStreamHandler {
     encoder: Arc<Mutex<RealtimeStreamEncoder>>,
+    congestion: Arc<Mutex<CongestionController>>,
     input_handler: InputHandler,
+    session: SessionContext,
+    monitor: usize,
+    base_bitrate: u32,
     last_keyframe_time: Instant,
     frame_count: u64,
-    network_stats: NetworkStats,
 }
 
 impl RealtimeStreamHandler {
     pub fn new(config: RealtimeConfig) -> Result<Self> {
-        let encoder = Arc::new(Mutex::new(RealtimeStreamEncoder::new(config)?));
-        let input_handler = InputHandler::new();
+        let input_handler = InputHandler::for_monitor(config.monitor_id);
+        let monitor = config.monitor_id;
+        let base_bitrate = config.bitrate;
+        let encoder = RealtimeStreamEncoder::new(config)?;
+        let congestion = Arc::new(Mutex::new(CongestionController::new(encoder.target())));
         
         Ok(Self {
-            encoder,
+            encoder: Arc::new(Mutex::new(encoder)),
+            congestion,
             input_handler,
+            session: SessionContext::standalone(),
+            monitor,
+            base_bitrate,
             last_keyframe_time: Instant::now(),
             frame_count: 0,
-            network_stats: NetworkStats::default(),
         })
     }
 
+    /// Set the session role; viewers cannot send input
+    pub fn with_session(mut self, session: SessionContext) -> Self {
+        self.session = session;
+        self
+    }
+
     pub async fn handle_connection(
         mut self,
         socket: WebSocket,
@@ -44,73 +60,94 @@ impl RealtimeStreamHandler {
         let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(10);
         let (control_tx, mut control_rx) = tokio::sync::mpsc::channel::<String>(10);
         
+        // Host input runs on its own thread so enigo never blocks the runtime
+        let input_dispatcher = match InputDispatcher::for_session(self.input_handler, self.session.role.can_control(), control_tx.clone()) {
+            Ok(dispatcher) => dispatcher,
+            Err(e) => {
+                error!("Failed to start input thread: {}", e);
+                return;
+            }
+        };
+        
         // Pre-fetch monitor data to avoid Send issues
         let monitor_data = crate::core::capture::ScreenCapture::get_all_monitors().ok();
         
         // Send initial server info and monitor list
         {
-            let encoder = self.encoder.lock().await;
-            let (width, height) = encoder.get_dimensions();
-            
-            // Send server info
-            let server_info = json!({
-                "type": "server_info",
-                "width": width,
-                "height": height,
-                "hostname": "kvm-server",
-                "codec": "vp8",
-                "audio": false,
-                "tile_width": 64,
-                "tile_height": 64,
-                "tile_size": 64
-            });
-            
-            if let Err(e) = control_tx.send(server_info.to_string()).await {
+            let (width, height) = self.encoder.lock().await.get_dimensions();
+            let server_info = ServerMessage::server_info(width, height, self.monitor, self.session.role);
+            if let Err(e) = control_tx.send(server_info.to_message()).await {
                 error!("Failed to send server info: {}", e);
                 return;
             }
             
-            // Send monitor list
             if let Some(monitors) = monitor_data {
-                let monitor_list = json!({
-                    "type": "monitor_list",
-                    "monitors": monitors.iter().map(|m| json!({
-                        "id": m.id,
-                        "name": m.name,
-                        "width": m.width,
-                        "height": m.height,
-                        "is_primary": m.is_primary
-                    })).collect::<Vec<_>>()
-                });
-                
-                if let Err(e) = control_tx.send(monitor_list.to_string()).await {
+                if let Err(e) = control_tx.send(ServerMessage::monitors(&monitors).to_message()).await {
                     error!("Failed to send monitor list: {}", e);
                 }
             }
         }
         
+        // Frames in flight to this client, shared with the receive task for acks
+        let window = Arc::new(FrameWindow::new(60));
+        
         // Streaming task
         let encoder_clone = Arc::clone(&self.encoder);
+        let capture_congestion = Arc::clone(&self.congestion);
+        let capture_window = Arc::clone(&window);
+        let stats_tx = control_tx.clone();
         let streaming_task = {
             let tx = tx.clone();
             tokio::spawn(async move {
-                let mut interval = time::interval(Duration::from_millis(16)); // ~60 FPS for smoother streaming
+                let mut framerate = capture_congestion.lock().await.target().framerate;
+                let mut interval = time::interval(Duration::from_secs(1) / framerate.max(1));
                 let mut last_stats_time = Instant::now();
+                let mut last_adapt_time = Instant::now();
                 let mut frame_count = 0u64;
-                let mut last_keyframe_time = Instant::now();
                 
                 loop {
                     interval.tick().await;
                     
-                    // Check if we should force a keyframe
-                    let force_keyframe = last_keyframe_time.elapsed() > Duration::from_secs(2);
+                    // Retarget the encoder from what this connection measured and report it
+                    if last_adapt_time.elapsed() >= UPDATE_INTERVAL {
+                        last_adapt_time = Instant::now();
+                        let estimate = capture_window.estimate();
+                        let (retarget, target) = {
+                            let mut congestion = capture_congestion.lock().await;
+                            (congestion.update(&estimate), congestion.target())
+                        };
+                        if let Some(target) = retarget {
+                            encoder_clone.lock().await.retarget(target);
+                        }
+                        // Quality changes can move the frame rate too, so check either way
+                        if target.framerate != framerate {
+                            framerate = target.framerate;
+                            interval = time::interval(Duration::from_secs(1) / framerate.max(1));
+                            capture_window.set_frame_rate(framerate);
+                        }
+                        let stats = ServerMessage::StreamingStats {
+                            frames_sent: frame_count,
+                            current_bitrate_kbps: Some(target.bitrate_kbps),
+                            network: estimate,
+                        };
+                        if stats_tx.send(stats.to_message()).await.is_err() {
+                            break;
+                        }
+                    }
+                    
+                    // Skip capture while the client is behind; a later tick picks up a newer
+                    // screen, and tile updates stay valid because nothing was encoded
+                    if !capture_window.has_room() {
+                        continue;
+                    }
                     
-                    let encoded_data = {
+                    // The encoder sends its own periodic refresh keyframes
+                    let mut encoded_data = {
                         let mut encoder = encoder_clone.lock().await;
                         
-                        match encoder.capture_and_encode(force_keyframe) {
+                        match encoder.capture_and_encode(false) {
                             Ok(Some(data)) => data,
-                            Ok(None) => continue, // No data to send
+                            Ok(None) => continue, // Screen unchanged
                             Err(e) => {
                                 error!("Capture/encode error: {}", e);
                                 // Reduce error backoff time for better real-time performance
@@ -120,12 +157,11 @@ impl RealtimeStreamHandler {
                         }
                     };
                     
-                    if force_keyframe {
-                        last_keyframe_time = Instant::now();
-                    }
-                    
                     frame_count += 1;
                     
+                    // Numbered as soon as it is encoded, so frames waiting to be sent count too
+                    capture_window.start_frame(&mut encoded_data);
+                    
                     // Send binary data directly to client (what the client expects)
                     if let Err(_) = tx.send(encoded_data).await {
                         break; // Channel closed
@@ -137,9 +173,10 @@ impl RealtimeStreamHandler {
                             let encoder = encoder_clone.lock().await;
                             encoder.get_performance_stats()
                         };
-                        info!("Performance: capture={:.1}ms, encode={:.1}ms, frames={}, avg_fps={:.1}", 
+                        info!("Performance: capture={:.1}ms, encode={:.1}ms, frames={}, avg_fps={:.1}, rtt={:?}, window={}", 
                               capture_ms, encode_ms, total_frames, 
-                              frame_count as f64 / last_stats_time.elapsed().as_secs_f64());
+                              frame_count as f64 / last_stats_time.elapsed().as_secs_f64(),
+                              capture_window.rtt(), capture_window.limit());
                         last_stats_time = Instant::now();
                     }
                 }
@@ -149,15 +186,19 @@ impl RealtimeStreamHandler {
         };
         
         // Send task - handles both binary video data and text control messages
+        let send_window = Arc::clone(&window);
         let send_task = tokio::spawn(async move {
             loop {
                 tokio::select! {
                     // Binary video data
                     Some(data) = rx.recv() => {
+                        let started = Instant::now();
                         if let Err(e) = sender.send(Message::Binary(data)).await {
                             error!("Failed to send video data: {}", e);
                             break;
                         }
+                        // A write that blocks means the socket buffer is full
+                        send_window.record_send(started.elapsed());
                     }
                     // Text control messages  
                     Some(text) = control_rx.recv() => {
@@ -171,40 +212,87 @@ impl RealtimeStreamHandler {
             }
         });
         
+        // Admin commands: quality is applied here, the client handles monitor switches
+        let mut commands = self.session.subscribe();
+        let encoder_clone3 = Arc::clone(&self.encoder);
+        let command_congestion = Arc::clone(&self.congestion);
+        let base_bitrate = self.base_bitrate;
+        let command_tx = control_tx.clone();
+        let command_task = tokio::spawn(async move {
+            loop {
+                let command = match commands.recv().await {
+                    Ok(command) => command,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                // Quality sets the ceiling; the congestion controller stays below it
+                if let SessionCommand::SetQuality { quality } = command {
+                    let bitrate = SessionCommand::scaled_bitrate(quality, base_bitrate);
+                    let target = command_congestion.lock().await.set_max_bitrate(bitrate);
+                    encoder_clone3.lock().await.retarget(target);
+                }
+                if command_tx.send(command.to_message()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        
         // Receive task for handling client messages
         let encoder_clone2 = Arc::clone(&self.encoder);
+        let quality_congestion = Arc::clone(&self.congestion);
+        let ack_window = Arc::clone(&window);
         let control_tx_clone = control_tx.clone();
+        let session = self.session.clone();
+        let clipboard = ClipboardSession::new(&self.session, control_tx.clone());
+        let transfers = TransferSession::new(&self.session, control_tx.clone());
+        // Kept outside the receive task so held input is released however the session ends
+        let input_on_exit = input_dispatcher.clone();
         let receive_task = tokio::spawn(async move {
             while let Some(msg) = receiver.next().await {
                 match msg {
                     Ok(Message::Text(text)) => {
-                        if let Ok(json_msg) = serde_json::from_str::<serde_json::Value>(&text) {
-                            match json_msg.get("type").and_then(|t| t.as_str()) {
-                                Some("ping") => {
-                                    let pong = json!({
-                                        "type": "pong",
-                                        "timestamp": json_msg.get("timestamp")
-                                    });
-                                    if let Err(_) = control_tx_clone.send(pong.to_string()).await {
+                        match ClientMessage::parse(&text) {
+                            Ok(ClientMessage::Stream(request)) => match request {
+                                StreamRequest::Hello { protocol } => {
+                                    if let Err(e) = check_protocol_version(protocol) {
+                                        warn!("{}", e);
+                                        if control_tx_clone.send(e.to_message()).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                }
+                                StreamRequest::Ping { timestamp } => {
+                                    if control_tx_clone.send(ServerMessage::pong(timestamp).to_message()).await.is_err() {
                                         break;
                                     }
                                 }
-                                Some("request_keyframe") => {
+                                StreamRequest::FrameAck { sequence } => ack_window.acknowledge(sequence),
+                                StreamRequest::RequestKeyframe => {
                                     let mut encoder = encoder_clone2.lock().await;
                                     encoder.force_keyframe();
                                 }
-                                Some("network_stats") => {
-                                    if let Some(stats) = json_msg.get("stats") {
-                                        if let Ok(network_stats) = serde_json::from_value::<NetworkStats>(stats.clone()) {
-                                            let mut encoder = encoder_clone2.lock().await;
-                                            if let Err(e) = encoder.adapt_to_network_conditions(&network_stats) {
-                                                warn!("Failed to adapt to network conditions: {}", e);
-                                            }
-                                        }
-                                    }
+                                // Adaptation uses the server's own measurements from frame acks
+                                StreamRequest::NetworkStats { stats } => {
+                                    debug!("Client reports {}ms latency, {}% loss", stats.latency, stats.packet_loss);
+                                }
+                                // This client's own preference, unlike an admin's set_quality
+                                StreamRequest::QualityUpdate { quality, .. } => {
+                                    let bitrate = SessionCommand::scaled_bitrate(quality.clamp(10, 100), base_bitrate);
+                                    let target = quality_congestion.lock().await.set_max_bitrate(bitrate);
+                                    encoder_clone2.lock().await.retarget(target);
                                 }
-                                _ => {
-                                    debug!("Unknown message type: {}", text);
+                                StreamRequest::PerformanceMode { .. } | StreamRequest::EmergencyReset => {
+                                    debug!("Performance modes only apply to RGBA streams");
+                                }
+                            },
+                            Ok(ClientMessage::Input(event)) => input_dispatcher.handle_event(event),
+                            Ok(ClientMessage::Admin(command)) => session.handle_command(command, &control_tx_clone),
+                            Ok(ClientMessage::Clipboard(request)) => clipboard.handle_request(request),
+                            Ok(ClientMessage::Transfer(request)) => transfers.handle_request(request),
+                            Err(e) => {
+                                debug!("Rejected message: {}", e);
+                                if control_tx_clone.send(e.to_message()).await.is_err() {
+                                    break;
                                 }
                             }
                         }
@@ -234,6 +322,8 @@ impl RealtimeStreamHandler {
             } => info!("Stop signal received"),
         }
         
+        command_task.abort();
+        input_on_exit.release_all();
         info!("Real-time streaming session ended");
     }
 }
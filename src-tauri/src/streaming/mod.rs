@@ -4,10 +4,16 @@
 //! real-time codecs, enhanced performance implementations, and stream handlers.
 
 pub mod codecs;
+pub mod congestion;
 pub mod enhanced;
+pub mod flow_control;
+pub mod frame;
 pub mod handlers;
 
 // Re-export all public items for backward compatibility
 pub use codecs::*;
+pub use congestion::*;
 pub use enhanced::*;
+pub use flow_control::*;
+pub use frame::*;
 pub use handlers::*;
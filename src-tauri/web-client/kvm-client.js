@@ -1,8 +1,21 @@
+// Version of the control message schema, sent in the hello handshake
+const PROTOCOL_VERSION = 2;
+
+// Binary media frames, laid out as in src-tauri/src/streaming/frame.rs
+const FRAME_MAGIC = 0x4B564D46; // "KVMF" read big-endian
+const FRAME_VERSION = 4;
+const FRAME_HEADER_LEN = 32;
+const VIDEO_STREAM = 0;
+const FLAG_KEYFRAME = 0x01;
+const FRAME_CODEC = { VP8: 1, YUV420: 2, RGBA: 3, RGBA_RLE: 4, RGBA_TILES: 5, RGBA_COPY_TILES: 6 };
+
 class KVMClient {
     constructor(config) {
         this.config = config;
         this.ws = null;
         this.connected = false;
+        this.viewOnly = false; // Host granted view access only
+        this.role = 'controller'; // viewer, controller or admin, announced by the server
         this.lastFrame = 0;
         this.frameCount = 0;
         this.lastFpsUpdate = Date.now();
@@ -14,7 +27,9 @@ class KVMClient {
         this.qualityLevel = 85;
         this.availableMonitors = [];
         this.currentMonitor = config.monitor;
-        this.currentCodec = "yuv420_webm"; // Use YUV420 with WebM container for best quality
+        // VP8 needs WebCodecs to decode; otherwise fall back to raw RGBA frames
+        this.currentCodec = (config.codec !== 'rgba' && 'VideoDecoder' in window) ? 'vp8' : 'rgba';
+        this.vp8Decoder = null;
         this.mediaSource = null;
         this.sourceBuffer = null;
         this.videoQueue = [];
@@ -54,6 +69,11 @@ class KVMClient {
         this.gestureInProgress = false;
         this.initialTouchDistance = 0;
         this.initialTouchAngle = 0;
+        
+        // File transfers, keyed by path in the host's shared folder
+        this.transferDir = '';
+        this.uploads = new Map();
+        this.downloads = new Map();
 
         // WebRTC for audio
         this.peerConnection = null;
@@ -76,6 +96,7 @@ class KVMClient {
         // High-performance frame pipeline
         this.frameQueue = [];
         this.maxQueueSize = 3; // Aggressive frame dropping for low latency
+        this.awaitingKeyframe = false; // Set after dropping a tile update
         this.isDecompressing = false;
         this.lastFrameTime = 0;
         this.targetFrameTime = 16.67; // 60 FPS = 16.67ms per frame
@@ -109,6 +130,7 @@ class KVMClient {
         // Main elements - VP8 uses video element for display
         this.videoScreen = document.getElementById('video-screen');
         this.canvasLayer = document.getElementById('canvas-layer'); // Used only for input handling
+        this.imeInput = document.getElementById('ime-input'); // Focus target for IME composition
         this.audioElement = document.getElementById('remote-audio');
         
         // OSD elements
@@ -147,6 +169,18 @@ class KVMClient {
         this.settingAudio = document.getElementById('setting-audio');
         this.settingMute = document.getElementById('setting-mute');
         this.settingStats = document.getElementById('setting-stats');
+        this.settingKeyboard = document.getElementById('setting-keyboard');
+        this.settingClipboard = document.getElementById('setting-clipboard');
+        this.typeTextInput = document.getElementById('type-text-input');
+        this.typeTextDelay = document.getElementById('type-text-delay');
+        this.typeTextProgress = document.getElementById('type-text-progress');
+        this.typeTextStart = document.getElementById('type-text-start');
+        this.typeTextCancel = document.getElementById('type-text-cancel');
+        this.filesPanel = document.getElementById('files-panel');
+        this.filesPath = document.getElementById('files-path');
+        this.filesList = document.getElementById('files-list');
+        this.filesTransfers = document.getElementById('files-transfers');
+        this.filesUploadInput = document.getElementById('files-upload-input');
         
         // Fix: Use bitrate-slider instead of quality-slider
         this.bitrateSlider = document.getElementById('bitrate-slider');
@@ -157,6 +191,8 @@ class KVMClient {
         if (this.settingAudio) this.settingAudio.checked = this.config.audio;
         if (this.settingMute) this.settingMute.checked = this.config.mute;
         if (this.codecDropdown) this.codecDropdown.value = this.config.codec;
+        if (this.settingKeyboard) this.settingKeyboard.value = this.config.keyboard || 'keysym';
+        if (this.settingClipboard) this.settingClipboard.checked = this.config.clipboard;
         
         if (this.audioElement) this.audioElement.muted = this.config.mute;
         
@@ -250,6 +286,48 @@ class KVMClient {
             });
         }
 
+        const filesBtn = document.getElementById('files-btn');
+        if (filesBtn) {
+            filesBtn.addEventListener('click', () => {
+                this.toggleFiles();
+            });
+        }
+
+        const filesClose = document.getElementById('files-close');
+        if (filesClose) {
+            filesClose.addEventListener('click', () => {
+                this.hideFiles();
+            });
+        }
+
+        const filesUpload = document.getElementById('files-upload');
+        if (filesUpload && this.filesUploadInput) {
+            filesUpload.addEventListener('click', () => {
+                this.filesUploadInput.click();
+            });
+            this.filesUploadInput.addEventListener('change', () => {
+                this.uploadFiles(this.filesUploadInput.files);
+                this.filesUploadInput.value = '';
+            });
+        }
+
+        // Files dropped on the remote screen go to the current shared folder
+        const screenDropTarget = document.getElementById('screen');
+        if (screenDropTarget) {
+            screenDropTarget.addEventListener('dragover', (e) => {
+                if (e.dataTransfer && e.dataTransfer.types.includes('Files')) {
+                    e.preventDefault();
+                    e.dataTransfer.dropEffect = 'copy';
+                }
+            });
+            screenDropTarget.addEventListener('drop', (e) => {
+                if (e.dataTransfer && e.dataTransfer.files.length > 0) {
+                    e.preventDefault();
+                    this.uploadFiles(e.dataTransfer.files);
+                }
+            });
+        }
+
         const disconnectBtn = document.getElementById('disconnect-btn');
         if (disconnectBtn) {
             disconnectBtn.addEventListener('click', () => {
@@ -279,6 +357,18 @@ class KVMClient {
             });
         }
 
+        if (this.typeTextStart) {
+            this.typeTextStart.addEventListener('click', () => {
+                this.typeText();
+            });
+        }
+
+        if (this.typeTextCancel) {
+            this.typeTextCancel.addEventListener('click', () => {
+                this.sendInputEvent({ type: 'cancel_typing' });
+            });
+        }
+
         // Settings controls - only add listeners if elements exist
         if (this.bitrateSlider && this.bitrateValue) {
             this.bitrateSlider.addEventListener('input', (e) => {
@@ -297,13 +387,23 @@ class KVMClient {
         if (this.monitorDropdown) {
             this.monitorDropdown.addEventListener('change', (e) => {
                 const newMonitor = parseInt(e.target.value);
-                if (newMonitor !== this.currentMonitor) {
+                if (this.role === 'admin') {
+                    // The server echoes this to every session, including ours
+                    this.sendMessage({ type: 'set_monitor', monitor: newMonitor });
+                } else if (newMonitor !== this.currentMonitor) {
                     this.switchMonitor(newMonitor);
                 }
             });
         }
 
-        // Codec dropdown is no longer needed - using WebRTC VP8 only
+        if (this.codecDropdown) {
+            this.codecDropdown.addEventListener('change', (e) => {
+                const newCodec = e.target.value;
+                if (newCodec !== this.currentCodec) {
+                    this.switchCodec(newCodec);
+                }
+            });
+        }
 
         if (this.qualityDropdown) {
             this.qualityDropdown.addEventListener('change', (e) => {
@@ -314,6 +414,10 @@ class KVMClient {
                 } else {
                     this.adaptiveQuality = false;
                     this.switchQuality(selectedQuality);
+                    if (this.role === 'admin') {
+                        const qualityMap = { low: 65, medium: 80, high: 95 };
+                        this.sendMessage({ type: 'set_quality', quality: qualityMap[selectedQuality] || 80 });
+                    }
                 }
             });
         }
@@ -332,6 +436,8 @@ class KVMClient {
             if (e.key === 'Escape') {
                 if (this.settingsPanel && this.settingsPanel.classList.contains('visible')) {
                     this.hideSettings();
+                } else if (this.filesPanel && this.filesPanel.classList.contains('visible')) {
+                    this.hideFiles();
                 } else if (document.fullscreenElement) {
                     document.exitFullscreen();
                 }
@@ -411,7 +517,34 @@ class KVMClient {
         // Keyboard events
         document.addEventListener('keydown', (e) => this.handleKeyEvent(e, 'keydown'));
         document.addEventListener('keyup', (e) => this.handleKeyEvent(e, 'keyup'));
-        
+
+        // IMEs only compose into editable elements, so keep the hidden input focused
+        // and send what the IME commits as text instead of its keystrokes
+        if (this.imeInput) {
+            if (screenContainer) {
+                screenContainer.addEventListener('mousedown', () => this.imeInput.focus({ preventScroll: true }));
+            }
+            this.imeInput.addEventListener('compositionend', (e) => {
+                if (e.data) {
+                    this.sendInputEvent({ type: 'text', text: e.data });
+                }
+                this.imeInput.value = '';
+            });
+            // Fallback for browsers that cannot read the clipboard on focus
+            this.imeInput.addEventListener('paste', (e) => {
+                const text = e.clipboardData && e.clipboardData.getData('text/plain');
+                if (text) {
+                    this.sendClipboard({ format: 'text', text });
+                }
+            });
+        }
+
+        // Browsers cannot watch the clipboard, so check it whenever the user comes back
+        window.addEventListener('focus', () => this.syncLocalClipboard());
+
+        // Key and button releases are lost while the window is unfocused, so let go of everything
+        window.addEventListener('blur', () => this.sendInputEvent({ type: 'release_all' }));
+
         // Prevent context menu on video screen and screen container
         if (this.videoScreen) {
             this.videoScreen.addEventListener('contextmenu', (e) => e.preventDefault());
@@ -462,8 +595,10 @@ class KVMClient {
             case 'wheel':
                 e.preventDefault();
                 eventData.type = 'wheel';
-                eventData.delta_y = e.deltaY;
-                eventData.delta_x = e.deltaX;
+                // Server expects integer deltas; normalize line/page scrolling to pixels
+                const wheelScale = e.deltaMode === 1 ? 40 : (e.deltaMode === 2 ? 800 : 1);
+                eventData.delta_y = Math.round(e.deltaY * wheelScale);
+                eventData.delta_x = Math.round(e.deltaX * wheelScale);
                 this.sendInputEvent(eventData);
                 break;
         }
@@ -477,6 +612,11 @@ class KVMClient {
             return;
         }
         
+        // Keystrokes that belong to an IME composition are committed as text on compositionend
+        if (e.isComposing || e.keyCode === 229) {
+            return;
+        }
+        
         // Let some special keys pass through
         if (['F11', 'F12'].includes(e.key) || 
             (e.key === 's' && (e.ctrlKey || e.metaKey))) {
@@ -485,16 +625,23 @@ class KVMClient {
         
         e.preventDefault();
         
-        this.sendInputEvent({
+        const modifiers = [];
+        if (e.ctrlKey) modifiers.push('Control');
+        if (e.altKey) modifiers.push('Alt');
+        if (e.shiftKey) modifiers.push('Shift');
+        if (e.metaKey) modifiers.push('Meta');
+        
+        const eventData = {
             type: type,
             key: e.key,
-            keyCode: e.keyCode,
-            ctrlKey: e.ctrlKey,
-            altKey: e.altKey,
-            shiftKey: e.shiftKey,
-            metaKey: e.metaKey,
-            monitor_id: this.getActiveMonitorId()
-        });
+            code: e.code || null,
+            modifiers: modifiers
+        };
+        if (type === 'keydown') {
+            eventData.repeat = e.repeat;
+        }
+        
+        this.sendInputEvent(eventData);
     }
 
     handleTouchEvent(e) {
@@ -525,11 +672,388 @@ class KVMClient {
     }
 
     sendInputEvent(event) {
-        if (this.connected && this.ws.readyState === WebSocket.OPEN) {
+        if (this.connected && !this.viewOnly && this.ws.readyState === WebSocket.OPEN) {
             this.ws.send(JSON.stringify(event));
         }
     }
 
+    // Types the text box into the host as paced keystrokes, for consoles without clipboard access
+    typeText() {
+        if (!this.typeTextInput || !this.typeTextInput.value) return;
+        if (this.viewOnly) {
+            this.showNotification('Typing is not available in view-only sessions', 2000);
+            return;
+        }
+        
+        const delay = parseInt(this.typeTextDelay ? this.typeTextDelay.value : '', 10);
+        this.sendInputEvent({
+            type: 'type_text',
+            text: this.typeTextInput.value,
+            delay_ms: Number.isFinite(delay) && delay >= 0 ? delay : undefined,
+        });
+    }
+
+    handleTypingProgress(data) {
+        const typing = data.state === 'typing';
+        if (this.typeTextStart) this.typeTextStart.disabled = typing;
+        if (this.typeTextCancel) this.typeTextCancel.disabled = !typing;
+        if (this.typeTextProgress) {
+            this.typeTextProgress.textContent = typing
+                ? `Typing ${data.typed}/${data.total}`
+                : `${data.state === 'done' ? 'Typed' : 'Stopped at'} ${data.typed}/${data.total}`;
+        }
+        if (data.state === 'done' && this.typeTextInput) {
+            // Don't leave passwords sitting in the page
+            this.typeTextInput.value = '';
+        }
+    }
+
+    // The server only pushes the host clipboard to sessions that turned sync on
+    sendClipboardSync() {
+        this.sendInputEvent({ type: 'clipboard_sync', enabled: !!this.config.clipboard });
+        if (this.config.clipboard) {
+            this.syncLocalClipboard();
+        }
+    }
+
+    async handleHostClipboard(data) {
+        if (!this.config.clipboard || !navigator.clipboard) return;
+        try {
+            if (data.format === 'text') {
+                this.lastClipboard = `text:${data.text}`;
+                await navigator.clipboard.writeText(data.text);
+            } else if (data.format === 'image') {
+                this.lastClipboard = `image:${data.data}`;
+                const bytes = Uint8Array.from(atob(data.data), c => c.charCodeAt(0));
+                await navigator.clipboard.write([
+                    new ClipboardItem({ 'image/png': new Blob([bytes], { type: 'image/png' }) })
+                ]);
+            }
+        } catch (e) {
+            // Writes need a secure context and a focused page
+            console.warn('Could not update local clipboard:', e.message);
+        }
+    }
+
+    async syncLocalClipboard() {
+        if (!this.connected || this.viewOnly || !this.config.clipboard || !navigator.clipboard) return;
+        try {
+            if (!navigator.clipboard.read) {
+                const text = await navigator.clipboard.readText();
+                if (text) this.sendClipboard({ format: 'text', text });
+                return;
+            }
+            for (const item of await navigator.clipboard.read()) {
+                if (item.types.includes('image/png')) {
+                    const blob = await item.getType('image/png');
+                    this.sendClipboard({ format: 'image', data: await this.blobToBase64(blob) });
+                    return;
+                }
+                if (item.types.includes('text/plain')) {
+                    const text = await (await item.getType('text/plain')).text();
+                    this.sendClipboard({ format: 'text', text });
+                    return;
+                }
+            }
+        } catch (e) {
+            console.debug('Local clipboard not readable:', e.message);
+        }
+    }
+
+    // Skips content that just came from, or was just sent to, the host
+    sendClipboard(content) {
+        const key = content.format === 'text' ? `text:${content.text}` : `image:${content.data}`;
+        if (key === this.lastClipboard) return;
+        this.lastClipboard = key;
+        this.sendInputEvent({ type: 'clipboard', ...content });
+    }
+
+    blobToBase64(blob) {
+        return new Promise((resolve, reject) => {
+            const reader = new FileReader();
+            reader.onload = () => resolve(reader.result.slice(reader.result.indexOf(',') + 1));
+            reader.onerror = () => reject(reader.error);
+            reader.readAsDataURL(blob);
+        });
+    }
+
+    toggleFiles() {
+        if (!this.filesPanel) return;
+        if (this.filesPanel.classList.contains('visible')) {
+            this.hideFiles();
+        } else {
+            this.filesPanel.classList.add('visible');
+            this.listFiles(this.transferDir);
+        }
+    }
+
+    hideFiles() {
+        if (this.filesPanel) {
+            this.filesPanel.classList.remove('visible');
+        }
+    }
+
+    listFiles(path) {
+        if (this.viewOnly) {
+            this.showNotification('File transfer is not available in view-only sessions', 2000);
+            return;
+        }
+        this.sendInputEvent({ type: 'file_list', path });
+    }
+
+    renderFileList(data) {
+        this.transferDir = data.path;
+        if (this.filesPath) {
+            this.filesPath.textContent = '/' + data.path;
+        }
+        if (!this.filesList) return;
+
+        // Built from text nodes only, file names come from the host
+        this.filesList.replaceChildren();
+        const addEntry = (label, detail, onClick) => {
+            const entry = document.createElement('div');
+            entry.className = 'files-entry';
+            const name = document.createElement('span');
+            name.textContent = label;
+            const info = document.createElement('span');
+            info.className = 'files-entry-detail';
+            info.textContent = detail;
+            entry.append(name, info);
+            entry.addEventListener('click', onClick);
+            this.filesList.appendChild(entry);
+        };
+
+        if (data.path) {
+            addEntry('📁 ..', '', () => this.listFiles(data.path.split('/').slice(0, -1).join('/')));
+        }
+        for (const item of data.entries) {
+            const path = data.path ? `${data.path}/${item.name}` : item.name;
+            if (item.dir) {
+                addEntry(`📁 ${item.name}`, '', () => this.listFiles(path));
+            } else {
+                addEntry(`📄 ${item.name}`, formatBytes(item.size), () => this.downloadFile(path));
+            }
+        }
+    }
+
+    async uploadFiles(files) {
+        if (this.viewOnly) {
+            this.showNotification('File transfer is not available in view-only sessions', 2000);
+            return;
+        }
+        for (const file of Array.from(files)) {
+            const path = this.transferDir ? `${this.transferDir}/${file.name}` : file.name;
+            if (this.uploads.has(path)) continue;
+
+            const upload = { path, file, id: null, sha256: null, sent: 0, retries: 0, overwrite: false };
+            this.uploads.set(path, upload);
+            this.renderTransfers();
+            try {
+                upload.sha256 = await hashFile(file);
+            } catch (e) {
+                this.uploads.delete(path);
+                this.showNotification(`Could not read ${file.name}: ${e.message}`, 3000);
+                continue;
+            }
+            this.startUpload(upload);
+        }
+    }
+
+    startUpload(upload) {
+        this.sendInputEvent({
+            type: 'file_upload_start',
+            path: upload.path,
+            size: upload.file.size,
+            sha256: upload.sha256,
+            overwrite: upload.overwrite,
+        });
+    }
+
+    async sendUploadChunk(upload, offset) {
+        const end = Math.min(offset + upload.chunkSize, upload.file.size);
+        const chunk = new Uint8Array(await upload.file.slice(offset, end).arrayBuffer());
+        upload.sent = offset;
+        this.sendInputEvent({
+            type: 'file_upload_chunk',
+            id: upload.id,
+            offset,
+            data: bytesToBase64(chunk),
+            sha256: await sha256Hex(chunk),
+        });
+        this.renderTransfers();
+    }
+
+    findUpload(data) {
+        for (const upload of this.uploads.values()) {
+            if (data.id && upload.id === data.id) return upload;
+        }
+        return data.path ? this.uploads.get(data.path) : undefined;
+    }
+
+    handleUploadProgress(data) {
+        const upload = this.findUpload(data);
+        if (!upload) return;
+
+        switch (data.type) {
+            case 'file_upload_ready':
+                upload.id = data.id;
+                upload.chunkSize = data.chunk_size;
+                if (data.offset > 0) {
+                    console.log(`Resuming upload of ${upload.path} at ${data.offset} bytes`);
+                }
+                this.sendUploadChunk(upload, data.offset);
+                break;
+            case 'file_upload_ack':
+                upload.retries = 0;
+                this.sendUploadChunk(upload, data.offset);
+                break;
+            case 'file_upload_done':
+                this.uploads.delete(upload.path);
+                this.showNotification(`Uploaded ${upload.file.name}`, 2000);
+                this.listFiles(this.transferDir);
+                break;
+            case 'file_upload_cancelled':
+                this.uploads.delete(upload.path);
+                break;
+        }
+        this.renderTransfers();
+    }
+
+    downloadFile(path) {
+        if (this.downloads.has(path)) return;
+        this.downloads.set(path, { path, size: null, sha256: null, chunks: [], received: 0, retries: 0 });
+        this.sendInputEvent({ type: 'file_download_start', path });
+        this.renderTransfers();
+    }
+
+    async handleDownloadProgress(data) {
+        const download = this.downloads.get(data.path);
+        if (!download) return;
+
+        if (data.type === 'file_download_ready') {
+            download.size = data.size;
+            download.sha256 = data.sha256;
+        } else if (data.offset === download.received) {
+            const chunk = base64ToBytes(data.data);
+            if (await sha256Hex(chunk) !== data.sha256) {
+                // Ask for the same chunk again
+                if (++download.retries > 3) {
+                    this.failDownload(download, 'repeated checksum failures');
+                    return;
+                }
+                this.sendInputEvent({ type: 'file_download_chunk', path: download.path, offset: download.received });
+                return;
+            }
+            download.retries = 0;
+            download.chunks.push(chunk);
+            download.received += chunk.length;
+        } else {
+            return;
+        }
+
+        if (download.received < download.size) {
+            this.sendInputEvent({ type: 'file_download_chunk', path: download.path, offset: download.received });
+        } else {
+            this.finishDownload(download);
+        }
+        this.renderTransfers();
+    }
+
+    async finishDownload(download) {
+        this.downloads.delete(download.path);
+        this.renderTransfers();
+
+        const hash = new Sha256();
+        download.chunks.forEach(chunk => hash.update(chunk));
+        if (hash.hex() !== download.sha256) {
+            this.showNotification(`Download of ${download.path} failed its checksum`, 3000);
+            return;
+        }
+
+        const link = document.createElement('a');
+        link.href = URL.createObjectURL(new Blob(download.chunks));
+        link.download = download.path.split('/').pop();
+        link.click();
+        setTimeout(() => URL.revokeObjectURL(link.href), 10000);
+    }
+
+    failDownload(download, reason) {
+        this.downloads.delete(download.path);
+        this.showNotification(`Download of ${download.path} failed: ${reason}`, 3000);
+        this.renderTransfers();
+    }
+
+    handleFileError(data) {
+        console.warn(`'${data.request}' failed:`, data.error);
+        if (data.request === 'file_list') {
+            this.showNotification(data.error, 3000);
+            return;
+        }
+
+        if (data.request.startsWith('file_download')) {
+            const download = this.downloads.get(data.path);
+            if (download) this.failDownload(download, data.error);
+            return;
+        }
+
+        const upload = this.findUpload(data);
+        if (!upload) return;
+        // The server tells us how much it has, so a bad chunk is just sent again
+        if (data.request === 'file_upload_chunk' && data.offset !== null && upload.retries++ < 3) {
+            this.sendUploadChunk(upload, data.offset);
+            return;
+        }
+        if (data.request === 'file_upload_start' && !upload.overwrite && data.error.endsWith('already exists')
+            && window.confirm(`${upload.path} already exists on the host. Replace it?`)) {
+            upload.overwrite = true;
+            this.startUpload(upload);
+            return;
+        }
+        this.uploads.delete(upload.path);
+        this.showNotification(`Upload of ${upload.file.name} failed: ${data.error}`, 3000);
+        this.renderTransfers();
+    }
+
+    // After a reconnect the host still has the partial files, so pick up where we left off
+    resumeTransfers() {
+        for (const upload of this.uploads.values()) {
+            if (upload.sha256) {
+                upload.retries = 0;
+                this.startUpload(upload);
+            }
+        }
+        for (const download of this.downloads.values()) {
+            if (download.size === null) {
+                this.sendInputEvent({ type: 'file_download_start', path: download.path });
+            } else {
+                this.sendInputEvent({ type: 'file_download_chunk', path: download.path, offset: download.received });
+            }
+        }
+    }
+
+    renderTransfers() {
+        if (!this.filesTransfers) return;
+        this.filesTransfers.replaceChildren();
+        const addRow = (label, done, total) => {
+            const row = document.createElement('div');
+            row.textContent = total
+                ? `${label} ${Math.floor(done / total * 100)}% of ${formatBytes(total)}`
+                : `${label}…`;
+            this.filesTransfers.appendChild(row);
+        };
+        for (const upload of this.uploads.values()) {
+            addRow(`⬆ ${upload.file.name}`, upload.sent, upload.sha256 ? upload.file.size : 0);
+        }
+        for (const download of this.downloads.values()) {
+            addRow(`⬇ ${download.path.split('/').pop()}`, download.received, download.size);
+        }
+    }
+
+    // keysym types the same characters on the host's layout, scancode presses the same key positions
+    sendKeyboardMode() {
+        this.sendInputEvent({ type: 'keyboard_mode', mode: this.config.keyboard || 'keysym' });
+    }
+
     sendMessage(message) {
         if (this.connected && this.ws.readyState === WebSocket.OPEN) {
             this.ws.send(JSON.stringify(message));
@@ -561,33 +1085,6 @@ class KVMClient {
         }
     }
 
-    sendNetworkStats() {
-        const stats = {
-            latency: this.latency,
-            bandwidth: this.estimateBandwidth(),
-            packet_loss: this.estimatePacketLoss()
-        };
-        
-        if (this.connected && this.ws.readyState === WebSocket.OPEN) {
-            this.ws.send(JSON.stringify({
-                type: 'network_stats',
-                stats: stats
-            }));
-        }
-    }
-
-    estimateBandwidth() {
-        // Simple bandwidth estimation based on frame rate and quality
-        const bytesPerFrame = (this.screenWidth * this.screenHeight * this.qualityLevel) / 1000;
-        const fps = this.frameCount;
-        return (bytesPerFrame * fps * 8) / 1024; // kbps
-    }
-
-    estimatePacketLoss() {
-        // Simplified packet loss estimation
-        return Math.max(0, (this.latency - 50) / 500);
-    }
-
     showNotification(message, duration = 3000) {
         if (!this.notificationArea) return;
         
@@ -674,6 +1171,14 @@ class KVMClient {
                 this.audioElement.muted = this.config.mute;
             }
         }
+        if (this.settingClipboard && this.settingClipboard.checked !== this.config.clipboard) {
+            this.config.clipboard = this.settingClipboard.checked;
+            this.sendClipboardSync();
+        }
+        if (this.settingKeyboard && this.settingKeyboard.value !== this.config.keyboard) {
+            this.config.keyboard = this.settingKeyboard.value;
+            this.sendKeyboardMode();
+        }
         
         // Apply stretch setting to video
         if (this.videoScreen) {
@@ -724,6 +1229,22 @@ class KVMClient {
         }
     }
 
+    switchCodec(codec) {
+        if (codec === 'vp8' && !('VideoDecoder' in window)) {
+            this.showNotification('VP8 decoding is not supported by this browser', 3000);
+            if (this.codecDropdown) this.codecDropdown.value = this.currentCodec;
+            return;
+        }
+        
+        console.log('Switching to codec:', codec);
+        this.currentCodec = codec;
+        this.resetVp8Decoder();
+        // Reconnect with new codec
+        if (this.ws) {
+            this.ws.close();
+        }
+    }
+
     sendQualitySetting(quality) {
         if (this.connected && this.ws.readyState === WebSocket.OPEN) {
             this.ws.send(JSON.stringify({
@@ -809,8 +1330,109 @@ class KVMClient {
         }
     }
 
+    resolveServerHost() {
+        const hostname = window.location.hostname;
+        
+        // Check for manual server override in URL parameters
+        const urlParams = new URLSearchParams(window.location.search);
+        const serverOverride = urlParams.get('server');
+        
+        // Determine the server host
+        let wsHost;
+        if (serverOverride) {
+            // Manual server override via URL parameter: ?server=192.168.1.100:9921
+            wsHost = serverOverride;
+            console.log('Using server override from URL:', wsHost);
+        } else if (window.location.port && window.location.port !== '80' && window.location.port !== '443') {
+            // If we're on a custom port (like the Vite dev server), use the hostname with port 9921
+            wsHost = `${hostname}:9921`;
+        } else {
+            // If we're on standard HTTP/HTTPS ports, assume KVM is also on the same host with port 9921
+            wsHost = `${hostname}:9921`;
+        }
+        return wsHost;
+    }
+
+    serverHttpUrl(path) {
+        return `${window.location.protocol}//${this.resolveServerHost()}${path}`;
+    }
+
+    // Session token handling
+    getSessionToken() {
+        try {
+            const stored = JSON.parse(sessionStorage.getItem('kvm-session') || 'null');
+            // Treat tokens about to expire as already gone
+            if (stored && stored.token && stored.expires_at * 1000 > Date.now() + 30000) {
+                return stored.token;
+            }
+        } catch (e) {
+            console.warn('Ignoring malformed session token');
+        }
+        sessionStorage.removeItem('kvm-session');
+        return null;
+    }
+
+    clearSessionToken() {
+        sessionStorage.removeItem('kvm-session');
+    }
+
+    showLogin(message = '') {
+        const panel = document.getElementById('login-panel');
+        if (!panel) return;
+        
+        this.hideStatusDisplay();
+        panel.style.display = 'flex';
+        
+        const error = document.getElementById('login-error');
+        if (error) error.textContent = message;
+        
+        const input = document.getElementById('login-secret');
+        if (input) {
+            input.value = '';
+            input.focus();
+        }
+        
+        const form = document.getElementById('login-form');
+        if (form && !form.dataset.bound) {
+            form.dataset.bound = 'true';
+            form.addEventListener('submit', (e) => {
+                e.preventDefault();
+                this.submitLogin(input ? input.value : '');
+            });
+        }
+    }
+
+    async submitLogin(secret) {
+        const error = document.getElementById('login-error');
+        try {
+            const response = await fetch(this.serverHttpUrl('/auth/login'), {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ secret })
+            });
+            const body = await response.json().catch(() => ({}));
+            
+            if (!response.ok) {
+                this.showLogin(body.error || `Login failed (${response.status})`);
+                return;
+            }
+            
+            sessionStorage.setItem('kvm-session', JSON.stringify(body));
+            document.getElementById('login-panel').style.display = 'none';
+            this.connect();
+        } catch (e) {
+            if (error) error.textContent = `Could not reach server: ${e.message}`;
+        }
+    }
+
     // Connect method
     connect() {
+        const token = this.getSessionToken();
+        if (!token) {
+            this.showLogin();
+            return;
+        }
+        
         this.updateStatus('Connecting', 'Establishing connection to server...', true);
         
         // Send a test HTTP request to verify connectivity
@@ -828,27 +1450,11 @@ class KVMClient {
         console.log('Hostname extracted:', hostname);
         console.log('Port from location:', window.location.port);
         
-        // Check for manual server override in URL parameters
-        const urlParams = new URLSearchParams(window.location.search);
-        const serverOverride = urlParams.get('server');
-        
-        // Determine the WebSocket host
-        let wsHost;
-        if (serverOverride) {
-            // Manual server override via URL parameter: ?server=192.168.1.100:9921
-            wsHost = serverOverride;
-            console.log('Using server override from URL:', wsHost);
-        } else if (window.location.port && window.location.port !== '80' && window.location.port !== '443') {
-            // If we're on a custom port (like the Vite dev server), use the hostname with port 9921
-            wsHost = `${hostname}:9921`;
-        } else {
-            // If we're on standard HTTP/HTTPS ports, assume KVM is also on the same host with port 9921
-            wsHost = `${hostname}:9921`;
-        }
+        const wsHost = this.resolveServerHost();
         
-        const wsUrl = `${protocol}//${wsHost}/ws?monitor=${this.currentMonitor}&codec=${this.currentCodec}${this.config.audio ? '&audio=true' : ''}`;
+        const wsUrl = `${protocol}//${wsHost}/ws?monitor=${this.currentMonitor}&codec=${this.currentCodec}${this.config.audio ? '&audio=true' : ''}&token=${encodeURIComponent(token)}`;
         
-        console.log('Connecting to WebSocket:', wsUrl);
+        console.log('Connecting to WebSocket:', wsUrl.replace(/token=[^&]+/, 'token=***'));
         console.log('WebSocket host resolved to:', wsHost);
         
         this.ws = new WebSocket(wsUrl);
@@ -909,6 +1515,7 @@ class KVMClient {
         
         this.ws.onclose = (event) => {
             this.connected = false;
+            this.resetVp8Decoder();
             if (this.pingInterval) {
                 clearInterval(this.pingInterval);
                 this.pingInterval = null;
@@ -916,8 +1523,22 @@ class KVMClient {
             
             console.log('WebSocket closed. Code:', event.code, 'Reason:', event.reason);
             
+            if (event.code === 4003) {
+                this.updateStatus('Access Denied', 'The host declined this connection or did not answer in time.');
+                return;
+            }
+            
             if (event.code === 1006) {
                 this.updateStatus('Connection Failed', `Could not connect to KVM server at ${wsHost}. Please check that the server is running and accessible.`);
+                // A rejected upgrade looks the same as a network failure, so ask the server
+                fetch(this.serverHttpUrl(`/auth/check?token=${encodeURIComponent(token)}`))
+                    .then(response => {
+                        if (response.status === 401) {
+                            this.clearSessionToken();
+                            this.showLogin('Session expired, please sign in again');
+                        }
+                    })
+                    .catch(() => {});
             } else {
                 this.updateStatus('Disconnected', 'Connection closed');
             }
@@ -949,9 +1570,6 @@ class KVMClient {
             case 'stream_info':
                 this.handleStreamInfo(data);
                 break;
-            case 'video_frame':
-                this.handleVideoFrame(data);
-                break;
             case 'pong':
                 this.handlePingResponse();
                 break;
@@ -970,13 +1588,93 @@ class KVMClient {
             case 'webrtc_frame':
                 this.handleWebRTCFrame(data);
                 break;
+            case 'input_error':
+                console.warn(`Input event '${data.event}' rejected by server:`, data.error);
+                if (data.event === 'type_text') {
+                    this.showNotification(`Typing failed: ${data.error}`, 3000);
+                }
+                break;
+            case 'clipboard':
+                this.handleHostClipboard(data);
+                break;
+            case 'clipboard_error':
+                console.warn('Clipboard sync error:', data.error);
+                break;
+            case 'typing_progress':
+                this.handleTypingProgress(data);
+                break;
+            case 'file_list':
+                this.renderFileList(data);
+                break;
+            case 'file_upload_ready':
+            case 'file_upload_ack':
+            case 'file_upload_done':
+            case 'file_upload_cancelled':
+                this.handleUploadProgress(data);
+                break;
+            case 'file_download_ready':
+            case 'file_download_data':
+                this.handleDownloadProgress(data);
+                break;
+            case 'file_error':
+                this.handleFileError(data);
+                break;
+            case 'consent_pending':
+                this.updateStatus('Waiting for Approval', 'The host has been asked to allow this connection...', true);
+                break;
+            case 'consent_granted':
+                this.applyRole(data.role || (data.control ? 'controller' : 'viewer'));
+                this.updateStatus('Connected', this.viewOnly ? 'View-only access granted' : 'Full control granted');
+                break;
+            case 'set_quality':
+                this.handleQualityUpdate(data);
+                this.showNotification(`Host admin set quality to ${data.quality}%`, 2000);
+                break;
+            case 'set_monitor':
+                if (data.monitor !== this.currentMonitor) {
+                    this.showNotification(`Host admin switched to monitor ${data.monitor}`, 2000);
+                    this.switchMonitor(data.monitor);
+                }
+                break;
+            case 'permission_denied':
+                console.warn(`'${data.action}' requires the admin role (this session is ${data.role})`);
+                break;
+            case 'error':
+                console.warn(`Server rejected ${data.request || 'message'} (${data.code}):`, data.error);
+                if (data.code === 'unsupported_version') {
+                    this.showNotification('This page is out of date for the host, reload it', 5000);
+                }
+                break;
+            case 'consent_denied':
+                console.warn('Connection denied by host');
+                break;
             default:
                 console.log('Unknown message type:', data.type);
         }
     }
 
+    // Answers the server's first message with our protocol version, then restores session settings
+    startSession(serverProtocol) {
+        if (serverProtocol !== undefined && serverProtocol !== PROTOCOL_VERSION) {
+            console.warn(`Server speaks protocol ${serverProtocol}, this client speaks ${PROTOCOL_VERSION}`);
+        }
+        this.sendMessage({ type: 'hello', protocol: PROTOCOL_VERSION });
+        this.sendKeyboardMode();
+        this.sendClipboardSync();
+        this.resumeTransfers();
+    }
+
+    applyRole(role) {
+        if (!role) return;
+        this.role = role;
+        // Viewers' input is dropped by the server, so stop capturing it
+        this.viewOnly = role === 'viewer';
+    }
+
     handleServerInfo(data) {
         console.log('Server info received:', data);
+        this.applyRole(data.role);
+        this.startSession(data.protocol);
         
         this.screenWidth = data.width;
         this.screenHeight = data.height;
@@ -1034,6 +1732,8 @@ class KVMClient {
         // Extract video configuration
         const videoConfig = data.video_config;
         const serverInfo = data.server_info;
+        this.applyRole(serverInfo.role);
+        this.startSession(serverInfo.protocol);
         
         this.screenWidth = videoConfig.width;
         this.screenHeight = videoConfig.height;
@@ -1242,12 +1942,23 @@ class KVMClient {
         this.frameLogCounter++;
 
         try {
-            // Check if this is a WebM container frame
-            if (this.isWebMFrame(binaryData)) {
-                this.handleWebMFrame(binaryData);
+            const frame = parseMediaFrame(binaryData);
+            if (!frame) {
+                if (this.frameLogCounter % 100 === 1) {
+                    console.error('Dropping binary message that is not a media frame');
+                }
+                return;
+            }
+            
+            // The server holds back new frames until earlier ones are acknowledged
+            this.sendMessage({ type: 'frame_ack', sequence: frame.sequence });
+            if (frame.stream !== VIDEO_STREAM) return;
+            
+            // VP8 frames from the software encoder are decoded with WebCodecs
+            if (frame.codec === FRAME_CODEC.VP8) {
+                this.handleVp8Frame(frame);
             } else {
-                // Fall back to custom frame parsing
-                this.parseAndRenderFrame(binaryData);
+                this.parseAndRenderFrame(frame);
             }
             
             this.updateFrameStats();
@@ -1260,192 +1971,92 @@ class KVMClient {
         }
     }
 
-    isWebMFrame(binaryData) {
-        // Check for WebM container signature (EBML header)
-        const dataView = new DataView(binaryData);
-        if (dataView.byteLength < 4) return false;
+    handleVp8Frame(frame) {
+        const { keyframe: isKeyframe, width, height } = frame;
         
-        // WebM files start with EBML header (0x1A45DFA3)
-        const ebmlHeader = dataView.getUint32(0, false);
-        return ebmlHeader === 0x1A45DFA3;
-    }
-
-    handleWebMFrame(webmData) {
-        if (this.webmSupported && this.sourceBuffer && !this.sourceBuffer.updating) {
-            try {
-                // Queue WebM frame for native browser decoding
-                this.videoQueue.push(webmData);
-                this.processVideoQueue();
-                console.log('🎬 WebM frame queued for native decoding');
-            } catch (error) {
-                console.error('❌ WebM frame processing error:', error);
-                // Fall back to custom decoding
-                this.parseWebMFrame(webmData);
-            }
-        } else {
-            // Custom WebM demuxing and VP8 decoding
-            this.parseWebMFrame(webmData);
-        }
-    }
-
-    parseWebMFrame(webmData) {
-        try {
-            // Basic WebM demuxing to extract VP8 payload
-            const vp8Payload = this.extractVP8FromWebM(webmData);
-            if (vp8Payload) {
-                this.handleVP8Frame(vp8Payload);
-            }
-        } catch (error) {
-            console.error('❌ WebM parsing error:', error);
+        // Inter frames are useless until a keyframe has been decoded
+        if (!this.vp8Decoder && !isKeyframe) {
+            this.requestKeyframe();
+            return;
         }
-    }
-
-    extractVP8FromWebM(webmData) {
-        // Simplified WebM demuxer - look for VP8 track data
-        const dataView = new DataView(webmData);
-        let offset = 0;
         
-        // Skip EBML header and find the first cluster
-        // This is a simplified implementation
-        while (offset < dataView.byteLength - 8) {
-            const elementId = dataView.getUint32(offset, false);
-            
-            if (elementId === 0x1F43B675) { // Cluster element
-                // Found cluster, look for SimpleBlock with VP8 data
-                offset += 4;
-                const clusterSize = this.parseEBMLSize(dataView, offset);
-                // Extract VP8 payload from the cluster
-                // This would need more detailed implementation
-                break;
-            }
-            offset++;
+        if (isKeyframe && (!this.vp8Decoder || this.vp8Decoder.width !== width || this.vp8Decoder.height !== height)) {
+            this.resetVp8Decoder();
+            this.vp8Decoder = this.createVp8Decoder(width, height);
         }
         
-        // For now, return null - would need full WebM demuxer
-        return null;
+        this.vp8Decoder.decoder.decode(new EncodedVideoChunk({
+            type: isKeyframe ? 'key' : 'delta',
+            timestamp: frame.pts,
+            data: frame.payload
+        }));
     }
 
-    handleVP8Frame(vp8Data) {
-        // Handle raw VP8 frame data
-        if (this.yuv420Canvas && this.yuv420Ctx) {
-            this.renderVP8ToCanvas(vp8Data);
-        }
-    }
-
-    renderVP8ToCanvas(vp8Data) {
-        // Custom VP8 decoder implementation would go here
-        // For now, we'll simulate frame rendering
-        console.log('🎬 Rendering VP8 frame to canvas');
+    createVp8Decoder(width, height) {
+        const decoder = new VideoDecoder({
+            output: (frame) => {
+                const renderStart = performance.now();
+                if (!this.realCanvas) {
+                    this.initializeOptimizedCanvas(frame.displayWidth, frame.displayHeight);
+                }
+                if (this.realCanvas.width !== frame.displayWidth || this.realCanvas.height !== frame.displayHeight) {
+                    this.realCanvas.width = frame.displayWidth;
+                    this.realCanvas.height = frame.displayHeight;
+                }
+                this.realCtx.drawImage(frame, 0, 0);
+                frame.close();
+                
+                this.perfStats.renderTime = performance.now() - renderStart;
+                this.perfStats.totalFrames++;
+            },
+            error: (e) => {
+                console.error('❌ VP8 decoder error:', e.message);
+                this.resetVp8Decoder();
+                this.requestKeyframe();
+            }
+        });
+        
+        decoder.configure({
+            codec: 'vp8',
+            codedWidth: width,
+            codedHeight: height,
+            optimizeForLatency: true
+        });
+        
+        console.log(`🎞️ VP8 decoder configured: ${width}x${height}`);
+        return { decoder, width, height };
     }
 
-    parseEBMLSize(dataView, offset) {
-        // Parse EBML variable-size integer
-        const firstByte = dataView.getUint8(offset);
-        let size = 0;
-        let length = 0;
-        
-        // Find the length indicator
-        if (firstByte & 0x80) {
-            length = 1;
-            size = firstByte & 0x7F;
-        } else if (firstByte & 0x40) {
-            length = 2;
-            size = ((firstByte & 0x3F) << 8) | dataView.getUint8(offset + 1);
-        } else if (firstByte & 0x20) {
-            length = 3;
-            size = ((firstByte & 0x1F) << 16) | 
-                   (dataView.getUint8(offset + 1) << 8) | 
-                   dataView.getUint8(offset + 2);
-        } else if (firstByte & 0x10) {
-            length = 4;
-            size = ((firstByte & 0x0F) << 24) |
-                   (dataView.getUint8(offset + 1) << 16) |
-                   (dataView.getUint8(offset + 2) << 8) |
-                   dataView.getUint8(offset + 3);
+    resetVp8Decoder() {
+        if (this.vp8Decoder) {
+            if (this.vp8Decoder.decoder.state !== 'closed') {
+                this.vp8Decoder.decoder.close();
+            }
+            this.vp8Decoder = null;
         }
-        
-        return size;
     }
 
-    parseAndRenderFrame(arrayBuffer) {
-        const now = performance.now();
-        
+    parseAndRenderFrame(frame) {
         // Aggressive frame dropping for ultra-low latency
-        if (this.frameQueue.length >= this.maxQueueSize) {
+        if (frame.keyframe) {
+            // A keyframe supersedes everything still queued
+            if (this.frameQueue.length >= this.maxQueueSize) {
+                this.perfStats.droppedFrames += this.frameQueue.length;
+                this.frameQueue = [];
+            }
+            this.awaitingKeyframe = false;
+        } else if (this.awaitingKeyframe || this.frameQueue.length >= this.maxQueueSize) {
+            // A dropped tile update breaks the chain, so skip updates until the next keyframe
             this.perfStats.droppedFrames++;
-            return; // Drop frame to maintain low latency
-        }
-        
-        const dataView = new DataView(arrayBuffer);
-        let offset = 0;
-        
-        // Check for ultra-fast RGBA format from backend (starts with "RGBA")
-        if (dataView.byteLength < 24) return;
-        
-        const rgbaSignature = dataView.getUint32(0, false) === 0x52474241; // "RGBA" in big-endian
-        
-        if (rgbaSignature) {
-            // New ultra-fast RGBA format from optimized backend - zero conversion overhead!
-            offset = 4; // Skip "RGBA" signature
-            
-            const width = dataView.getUint32(offset, true); offset += 4;
-            const height = dataView.getUint32(offset, true); offset += 4;
-            const frameNumber = dataView.getBigUint64(offset, true); offset += 8;
-            const dataLength = dataView.getUint32(offset, true); offset += 4;
-            
-            console.log(`� RGBA frame: ${width}x${height}, frame #${frameNumber}, data: ${dataLength} bytes, total: ${dataView.byteLength} bytes`);
-            
-            if (dataView.byteLength < offset + dataLength) {
-                console.error(`❌ RGBA frame truncated: need ${offset + dataLength} bytes, got ${dataView.byteLength} bytes`);
-                return;
-            }
-            
-            // Direct RGBA data - zero conversion needed!
-            const rgbaData = new Uint8Array(arrayBuffer, offset, dataLength);
-            
-            this.frameQueue.push({
-                rgbaData,
-                width,
-                height,
-                isKeyframe: true,
-                frameNumber,
-                timestamp: now,
-                format: 'rgba_direct' // Ultra-fast format
-            });
-        } else {
-            // Legacy RLE format fallback
-            const header = dataView.getUint32(offset, false);
-            offset += 3;
-            
-            if ((header >>> 8) !== 0xAABB01 && (header >>> 8) !== 0xAABB02) {
-                console.error('Invalid frame header');
-                return;
-            }
-            
-            const isKeyframe = (header & 0xFF) === 0x01;
-            const width = dataView.getUint32(offset, true); offset += 4;
-            const height = dataView.getUint32(offset, true); offset += 4;
-            const frameNumber = dataView.getBigUint64(offset, true); offset += 8;
-            const compressedLength = dataView.getUint32(offset, true); offset += 4;
-            
-            if (dataView.byteLength < offset + compressedLength) {
-                console.error('Frame truncated');
-                return;
+            if (!this.awaitingKeyframe) {
+                this.awaitingKeyframe = true;
+                this.requestKeyframe();
             }
-            
-            const compressedData = new Uint8Array(arrayBuffer, offset, compressedLength);
-            
-            this.frameQueue.push({
-                compressedData,
-                width,
-                height,
-                isKeyframe,
-                frameNumber,
-                timestamp: now,
-                format: 'rle' // Legacy format
-            });
+            return;
         }
         
+        this.frameQueue.push(frame);
+        
         // Process frames asynchronously
         this.processFrameQueue();
     }
@@ -1465,10 +2076,11 @@ class KVMClient {
             this.perfStats.decompressTime = performance.now() - decompressStart;
             
             if (rgbaData) {
+                // Kept right away: the next frame may be tile updates on top of this one
+                this.previousFrameData = rgbaData;
                 // Render on next animation frame for smooth 60fps
                 requestAnimationFrame(() => {
                     this.fastRenderFrame(rgbaData, frame.width, frame.height);
-                    this.previousFrameData = rgbaData; // Store for next delta
                 });
             }
             
@@ -1485,20 +2097,27 @@ class KVMClient {
     }
 
     async fastDecompressFrame(frame) {
-        const { rgbaData, compressedData, width, height, isKeyframe, format } = frame;
-        
-        if (format === 'rgba_direct') {
-            // Ultra-fast RGBA format - zero decompression needed!
-            return rgbaData;
-        } else if (format === 'vp8_yuv') {
-            // Legacy VP8 YUV format from optimized backend
-            return this.decompressVP8YUV(compressedData, width, height);
-        } else if (isKeyframe || !this.previousFrameData) {
-            // Legacy RLE decompression
-            return this.fastDecompressRLE(compressedData, width * height * 4);
-        } else {
-            // Fast delta application for legacy format
-            return this.fastApplyDelta(compressedData, this.previousFrameData);
+        const { payload, width, height, codec } = frame;
+        
+        switch (codec) {
+            case FRAME_CODEC.RGBA:
+                // Ultra-fast RGBA format - zero decompression needed!
+                return payload;
+            case FRAME_CODEC.RGBA_RLE:
+                return this.fastDecompressRLE(payload, width * height * 4);
+            case FRAME_CODEC.RGBA_TILES:
+            case FRAME_CODEC.RGBA_COPY_TILES:
+                // Tile updates are useless without the picture they patch
+                if (!this.previousFrameData || this.previousFrameData.length !== width * height * 4) {
+                    this.requestKeyframe();
+                    return null;
+                }
+                return this.fastApplyTiles(payload, this.previousFrameData, width, codec === FRAME_CODEC.RGBA_COPY_TILES);
+            case FRAME_CODEC.YUV420:
+                return this.yuv420ToRGBA(payload, width, height);
+            default:
+                console.warn('Unsupported frame codec:', codec);
+                return null;
         }
     }
 
@@ -1553,67 +2172,12 @@ class KVMClient {
         return rgbaData;
     }
 
-    decompressVP8YUV(compressedData, width, height) {
-        console.log(`🎥 Decompressing VP8 YUV: ${compressedData.length} bytes, ${width}x${height}`);
-        
-        // Check if data is compressed (old format) or uncompressed (new ultra-fast format)
-        const expectedYUVSize = width * height * 1.5; // Y + U/4 + V/4
-        
-        let yuvData;
-        if (compressedData.length >= expectedYUVSize * 0.8 && compressedData.length <= expectedYUVSize * 1.2) {
-            // Data appears to be uncompressed (new ultra-fast format)
-            console.log(`📊 Using uncompressed YUV data: ${compressedData.length} bytes`);
-            yuvData = compressedData;
-        } else {
-            // Data is compressed with RLE (legacy format)
-            console.log(`📊 Decompressing RLE YUV data: ${compressedData.length} bytes`);
-            yuvData = this.decompressSimpleRLE(compressedData);
-        }
-        
-        console.log(`📊 YUV data ready: ${yuvData.length} bytes (expected: ${expectedYUVSize})`);
-        
-        // Convert YUV420 back to RGBA for display
-        const rgbaData = this.yuv420ToRGBA(yuvData, width, height);
-        
-        console.log(`🎨 RGBA converted: ${rgbaData.length} bytes (expected: ${width * height * 4})`);
-        
-        return rgbaData;
-    }
-
-    decompressSimpleRLE(compressedData) {
-        const decompressed = new Uint8Array(compressedData.length * 2); // Estimate
-        let outputIndex = 0;
-        let inputIndex = 0;
-        
-        if (compressedData.length === 0) return decompressed;
-        
-        // First byte is always raw
-        decompressed[outputIndex++] = compressedData[inputIndex++];
-        
-        while (inputIndex < compressedData.length) {
-            const value = compressedData[inputIndex++];
-            
-            if (value === 0xFF && inputIndex + 1 < compressedData.length) {
-                // RLE marker: next byte is count, byte after is value
-                const count = compressedData[inputIndex++];
-                const repeatValue = compressedData[inputIndex++];
-                
-                for (let i = 0; i < count; i++) {
-                    decompressed[outputIndex++] = repeatValue;
-                }
-            } else {
-                // Regular byte
-                decompressed[outputIndex++] = value;
-            }
-        }
-        
-        return decompressed.slice(0, outputIndex);
-    }
-
     yuv420ToRGBA(yuvData, width, height) {
         const rgbaData = new Uint8Array(width * height * 4);
         const ySize = width * height;
-        const uvSize = (width / 2) * (height / 2);
+        // Chroma planes are half size, rounded up for odd dimensions
+        const uvWidth = Math.ceil(width / 2);
+        const uvSize = uvWidth * Math.ceil(height / 2);
         
         // Planar YUV420 layout: Y plane, then U plane, then V plane
         const yPlane = 0;
@@ -1630,7 +2194,7 @@ class KVMClient {
                 // Get U,V values from separate planes (subsampled)
                 const uvX = Math.floor(x / 2);
                 const uvY = Math.floor(y / 2);
-                const uvIndex = uvY * (width / 2) + uvX;
+                const uvIndex = uvY * uvWidth + uvX;
                 
                 const uVal = yuvData[uPlane + uvIndex] || 128;
                 const vVal = yuvData[vPlane + uvIndex] || 128;
@@ -1660,34 +2224,70 @@ class KVMClient {
         return rgbaData;
     }
 
-    fastApplyDelta(compressedData, previousFrame) {
-        // Create copy using set() for fast cloning
+    fastApplyTiles(payload, previousFrame, frameWidth, hasCopies) {
+        // Patch a copy, the previous picture may still be waiting to be drawn
         const rgbaData = new Uint8Array(previousFrame.length);
         rgbaData.set(previousFrame);
         
-        const dataView = new DataView(compressedData.buffer, compressedData.byteOffset, compressedData.byteLength);
+        const dataView = new DataView(payload.buffer, payload.byteOffset, payload.byteLength);
+        let offset = 0;
         
-        if (compressedData.length < 4) return rgbaData;
+        // Scrolled or moved areas: copy rows from where they were in the previous picture
+        if (hasCopies && payload.length >= 4) {
+            const copyCount = dataView.getUint32(0, true);
+            offset = 4;
+            for (let c = 0; c < copyCount && offset + 12 <= payload.length; c++, offset += 12) {
+                const srcX = dataView.getUint16(offset, true);
+                const srcY = dataView.getUint16(offset + 2, true);
+                const dstX = dataView.getUint16(offset + 4, true);
+                const dstY = dataView.getUint16(offset + 6, true);
+                const rowBytes = dataView.getUint16(offset + 8, true) * 4;
+                const height = dataView.getUint16(offset + 10, true);
+                for (let row = 0; row < height; row++) {
+                    const from = ((srcY + row) * frameWidth + srcX) * 4;
+                    const to = ((dstY + row) * frameWidth + dstX) * 4;
+                    if (from + rowBytes > previousFrame.length || to + rowBytes > rgbaData.length) break;
+                    rgbaData.set(previousFrame.subarray(from, from + rowBytes), to);
+                }
+            }
+        }
         
-        const changeCount = dataView.getUint32(0, true);
-        let offset = 4;
+        if (offset + 4 > payload.length) return rgbaData;
         
-        // Batch delta application with bounds checking
-        const maxChanges = Math.min(changeCount, (compressedData.length - 4) / 8);
+        const rectCount = dataView.getUint32(offset, true);
+        offset += 4;
         
-        for (let i = 0; i < maxChanges; i++) {
-            const pixelIndex = dataView.getUint32(offset, true);
-            offset += 4;
+        for (let r = 0; r < rectCount && offset + 12 <= payload.length; r++) {
+            const x = dataView.getUint16(offset, true);
+            const y = dataView.getUint16(offset + 2, true);
+            const width = dataView.getUint16(offset + 4, true);
+            const height = dataView.getUint16(offset + 6, true);
+            const end = Math.min(offset + 12 + dataView.getUint32(offset + 8, true), payload.length);
+            offset += 12;
             
-            const byteIndex = pixelIndex * 4;
-            if (byteIndex + 3 < rgbaData.length) {
-                // Unrolled pixel copy for speed
-                rgbaData[byteIndex] = compressedData[offset];
-                rgbaData[byteIndex + 1] = compressedData[offset + 1];
-                rgbaData[byteIndex + 2] = compressedData[offset + 2];
-                rgbaData[byteIndex + 3] = compressedData[offset + 3];
+            // Runs of count + RGBA fill the rectangle row by row
+            const rowSkip = (frameWidth - width) * 4;
+            let remaining = width * height;
+            let column = 0;
+            let byteIndex = (y * frameWidth + x) * 4;
+            for (; offset + 5 <= end && remaining > 0; offset += 5) {
+                const count = Math.min(payload[offset], remaining);
+                remaining -= count;
+                for (let i = 0; i < count; i++) {
+                    if (byteIndex + 3 < rgbaData.length) {
+                        rgbaData[byteIndex] = payload[offset + 1];
+                        rgbaData[byteIndex + 1] = payload[offset + 2];
+                        rgbaData[byteIndex + 2] = payload[offset + 3];
+                        rgbaData[byteIndex + 3] = payload[offset + 4];
+                    }
+                    byteIndex += 4;
+                    if (++column === width) {
+                        column = 0;
+                        byteIndex += rowSkip;
+                    }
+                }
             }
-            offset += 4;
+            offset = end;
         }
         
         return rgbaData;
@@ -2576,16 +3176,14 @@ class KVMClient {
     }
 
     handleStreamingStats(data) {
-        console.log('Streaming stats:', data);
-        
-        // Update network stats display
-        if (this.networkStats) {
-            this.networkStats.innerHTML = `
-                <div>Frames: ${data.frames_sent || 0}</div>
-                <div>Bitrate: ${data.current_bitrate_kbps || 0} kbps</div>
-                <div>Latency: ~${this.latency || 0}ms</div>
-            `;
-        }
+        // Measured by the server from frame acks and socket writes
+        const network = data.network || {};
+        const framesSent = data.frames_sent || 0;
+        this.updateNetworkStats({
+            bandwidth: network.delivery_rate_kbps || 0,
+            latency: network.rtt_ms || 0,
+            packetLoss: framesSent > 0 ? (network.lost_frames || 0) * 100 / framesSent : 0
+        });
     }
 
     // WebRTC quality switching
@@ -2625,9 +3223,8 @@ class KVMClient {
 
     // Start network monitoring and adaptive quality
     startNetworkMonitoring() {
-        // Send network stats every 5 seconds
+        // The server measures the link itself and reports it in streaming_stats
         this.networkMonitoringInterval = setInterval(() => {
-            this.sendNetworkStats();
             if (this.adaptiveQuality) {
                 this.autoAdaptQuality();
             }
@@ -2735,8 +3332,9 @@ class KVMClient {
 
 // Initialize the KVM client when the page loads
 document.addEventListener('DOMContentLoaded', () => {
-    // Get configuration from global variable set by the template
-    const config = window.KVM_CONFIG || {
+    // Get configuration from the JSON block rendered by the template
+    const configElement = document.getElementById('kvm-config');
+    const config = (configElement && JSON.parse(configElement.textContent)) || {
         stretch: false,
         mute: false,
         audio: false,
@@ -2832,3 +3430,144 @@ class WebMConverter {
         return cluster;
     }
 }
+
+// SHA-256 that can be fed in pieces; crypto.subtle is missing on plain http and can't hash incrementally
+// Split a binary media frame into its header fields and payload, or null if it is not one
+function parseMediaFrame(buffer) {
+    if (buffer.byteLength < FRAME_HEADER_LEN) return null;
+    
+    const view = new DataView(buffer);
+    if (view.getUint32(0, false) !== FRAME_MAGIC || view.getUint8(4) !== FRAME_VERSION) return null;
+    
+    const payloadLength = view.getUint32(28, true);
+    if (buffer.byteLength < FRAME_HEADER_LEN + payloadLength) return null;
+    
+    return {
+        stream: view.getUint8(5),
+        codec: view.getUint8(6),
+        keyframe: (view.getUint8(7) & FLAG_KEYFRAME) !== 0,
+        width: view.getUint32(8, true),
+        height: view.getUint32(12, true),
+        pts: Number(view.getBigUint64(16, true)),
+        sequence: view.getUint32(24, true),
+        payload: new Uint8Array(buffer, FRAME_HEADER_LEN, payloadLength)
+    };
+}
+
+class Sha256 {
+    constructor() {
+        this.state = new Uint32Array([
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+        ]);
+        this.block = new Uint8Array(64);
+        this.blockLength = 0;
+        this.length = 0;
+        this.words = new Uint32Array(64);
+    }
+
+    update(bytes) {
+        this.length += bytes.length;
+        let i = 0;
+        while (i < bytes.length) {
+            const take = Math.min(64 - this.blockLength, bytes.length - i);
+            this.block.set(bytes.subarray(i, i + take), this.blockLength);
+            this.blockLength += take;
+            i += take;
+            if (this.blockLength === 64) {
+                this.compress();
+                this.blockLength = 0;
+            }
+        }
+        return this;
+    }
+
+    hex() {
+        const bits = this.length * 8;
+        const padding = new Uint8Array((this.blockLength < 56 ? 56 : 120) - this.blockLength + 8);
+        padding[0] = 0x80;
+        const view = new DataView(padding.buffer);
+        view.setUint32(padding.length - 8, Math.floor(bits / 0x100000000));
+        view.setUint32(padding.length - 4, bits >>> 0);
+        this.update(padding);
+        return Array.from(this.state, word => word.toString(16).padStart(8, '0')).join('');
+    }
+
+    compress() {
+        const w = this.words;
+        for (let i = 0; i < 16; i++) {
+            w[i] = (this.block[i * 4] << 24) | (this.block[i * 4 + 1] << 16) | (this.block[i * 4 + 2] << 8) | this.block[i * 4 + 3];
+        }
+        for (let i = 16; i < 64; i++) {
+            const s0 = rotr(w[i - 15], 7) ^ rotr(w[i - 15], 18) ^ (w[i - 15] >>> 3);
+            const s1 = rotr(w[i - 2], 17) ^ rotr(w[i - 2], 19) ^ (w[i - 2] >>> 10);
+            w[i] = (w[i - 16] + s0 + w[i - 7] + s1) | 0;
+        }
+
+        let [a, b, c, d, e, f, g, h] = this.state;
+        for (let i = 0; i < 64; i++) {
+            const t1 = (h + (rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25)) + ((e & f) ^ (~e & g)) + SHA256_K[i] + w[i]) | 0;
+            const t2 = ((rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22)) + ((a & b) ^ (a & c) ^ (b & c))) | 0;
+            h = g; g = f; f = e; e = (d + t1) | 0;
+            d = c; c = b; b = a; a = (t1 + t2) | 0;
+        }
+        const add = [a, b, c, d, e, f, g, h];
+        for (let i = 0; i < 8; i++) {
+            this.state[i] = (this.state[i] + add[i]) | 0;
+        }
+    }
+}
+
+const SHA256_K = new Uint32Array([
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+]);
+
+function rotr(x, n) {
+    return (x >>> n) | (x << (32 - n));
+}
+
+async function sha256Hex(bytes) {
+    if (window.crypto && crypto.subtle) {
+        const digest = new Uint8Array(await crypto.subtle.digest('SHA-256', bytes));
+        return Array.from(digest, b => b.toString(16).padStart(2, '0')).join('');
+    }
+    return new Sha256().update(bytes).hex();
+}
+
+// Hashes in slices so large files are never held in memory at once
+async function hashFile(file) {
+    const hash = new Sha256();
+    const slice = 4 * 1024 * 1024;
+    for (let offset = 0; offset < file.size; offset += slice) {
+        hash.update(new Uint8Array(await file.slice(offset, offset + slice).arrayBuffer()));
+    }
+    return hash.hex();
+}
+
+function bytesToBase64(bytes) {
+    let binary = '';
+    for (let i = 0; i < bytes.length; i += 0x8000) {
+        binary += String.fromCharCode.apply(null, bytes.subarray(i, i + 0x8000));
+    }
+    return btoa(binary);
+}
+
+function base64ToBytes(base64) {
+    return Uint8Array.from(atob(base64), c => c.charCodeAt(0));
+}
+
+function formatBytes(bytes) {
+    const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+    let i = 0;
+    while (bytes >= 1024 && i < units.length - 1) {
+        bytes /= 1024;
+        i++;
+    }
+    return `${i === 0 ? bytes : bytes.toFixed(1)} ${units[i]}`;
+}
@@ -5,3 +5,4 @@ export { default as ConnectionOptions } from './ConnectionOptions.vue'
 export { default as AdvancedSettings } from './AdvancedSettings.vue'
 export { default as PresetSelector } from './PresetSelector.vue'
 export { default as LogViewer } from './LogViewer.vue'
+export { default as ConsentDialog } from './ConsentDialog.vue'
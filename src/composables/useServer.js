@@ -9,6 +9,8 @@ export function useServer() {
   const errorMessage = ref("");
   const monitors = ref([]);
   const loadingMonitors = ref(false);
+  const accessInfo = ref({ pin: "", password_set: false });
+  const tlsFingerprint = ref("");
 
   // Status check interval
   let statusCheckInterval = null;
@@ -25,6 +27,10 @@ export function useServer() {
     deltaEncoding: true,
     adaptiveQuality: true,
     encryptionEnabled: false,
+    tlsCertPath: "",
+    tlsKeyPath: "",
+    allowedOrigins: "",
+    transferRoot: "",
     useWebRTC: true,
     useVP8: true,
     hardwareAcceleration: false,
@@ -55,6 +61,49 @@ export function useServer() {
     }
   }
 
+  async function loadAccessInfo() {
+    try {
+      accessInfo.value = await invoke("get_access_info");
+    } catch (error) {
+      console.error("Failed to load access info:", error);
+    }
+  }
+
+  async function loadTlsFingerprint() {
+    try {
+      tlsFingerprint.value = (await invoke("get_tls_fingerprint")) || "";
+    } catch (error) {
+      console.error("Failed to load certificate fingerprint:", error);
+    }
+  }
+
+  async function regeneratePin() {
+    try {
+      await invoke("regenerate_access_pin");
+      await loadAccessInfo();
+    } catch (error) {
+      errorMessage.value = `Failed to regenerate PIN: ${error}`;
+    }
+  }
+
+  async function setAccessPassword(password) {
+    errorMessage.value = "";
+    try {
+      await invoke("set_access_password", { password: password || null });
+      await loadAccessInfo();
+    } catch (error) {
+      errorMessage.value = `Failed to set password: ${error}`;
+    }
+  }
+
+  async function forgetTrustedClients() {
+    try {
+      await invoke("forget_trusted_clients");
+    } catch (error) {
+      errorMessage.value = `Failed to forget trusted clients: ${error}`;
+    }
+  }
+
   async function checkServerStatus() {
     try {
       const status = await invoke("get_server_status");
@@ -75,6 +124,8 @@ export function useServer() {
       }
       
       await loadMonitors();
+      await loadAccessInfo();
+      await loadTlsFingerprint();
     } catch (error) {
       console.error("Failed to check server status:", error);
       errorMessage.value = `Failed to check server status: ${error}`;
@@ -116,6 +167,13 @@ export function useServer() {
           deltaEncoding: settings.deltaEncoding,
           adaptiveQuality: settings.adaptiveQuality,
           encryption: settings.encryptionEnabled,
+          tls_cert_path: settings.tlsCertPath || null,
+          tls_key_path: settings.tlsKeyPath || null,
+          allowed_origins: settings.allowedOrigins
+            .split(',')
+            .map(origin => origin.trim())
+            .filter(Boolean),
+          transfer_root: settings.transferRoot || null,
           webrtc: settings.useWebRTC,
           vp8: true, // Always use VP8 via WebRTC
           hardwareAcceleration: settings.hardwareAcceleration,
@@ -192,7 +250,7 @@ export function useServer() {
     }
     
     if (params.length > 0) {
-      url += (url.includes('?') ? ';' : '?') + params.join(';');
+      url += (url.includes('?') ? '&' : '?') + params.join('&');
     }
     
     return url;
@@ -226,6 +284,8 @@ export function useServer() {
     settings,
     monitors,
     loadingMonitors,
+    accessInfo,
+    tlsFingerprint,
     selectedCodec,
     checkServerStatus,
     startServer,
@@ -233,6 +293,10 @@ export function useServer() {
     openUrl,
     copyUrl,
     loadMonitors,
+    loadAccessInfo,
+    regeneratePin,
+    setAccessPassword,
+    forgetTrustedClients,
     startStatusMonitoring,
     stopStatusMonitoring
   };